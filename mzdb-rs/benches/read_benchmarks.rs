@@ -0,0 +1,126 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rusqlite::Connection;
+
+use mzdb::blob_cursor::BlobCursor;
+use mzdb::iterator::{for_each_bb, for_each_spectrum};
+use mzdb::model::*;
+use mzdb::mzdb::create_entity_cache;
+use mzdb::queries::*;
+
+const MZDB_FILE_PATH: &str = "./data/OVEMB150205_12.mzDB";
+
+fn bench_entity_cache(c: &mut Criterion) {
+    let db = Connection::open(MZDB_FILE_PATH).unwrap();
+
+    c.bench_function("create_entity_cache", |b| {
+        b.iter(|| create_entity_cache(black_box(&db)).unwrap())
+    });
+}
+
+fn bench_full_iteration(c: &mut Criterion) {
+    let db = Connection::open(MZDB_FILE_PATH).unwrap();
+    let entity_cache = create_entity_cache(&db).unwrap();
+
+    let mut group = c.benchmark_group("for_each_spectrum");
+    group.sample_size(10);
+
+    for ms_level in [None, Some(1), Some(2)] {
+        group.bench_function(format!("ms_level={:?}", ms_level), |b| {
+            b.iter(|| {
+                let mut peaks_count = 0;
+                for_each_spectrum(&db, &entity_cache, ms_level, |s: &Spectrum| {
+                    peaks_count += s.data.peak_count;
+                    Ok(())
+                }).unwrap();
+
+                peaks_count
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_random_access(c: &mut Criterion) {
+    let db = Connection::open(MZDB_FILE_PATH).unwrap();
+    let entity_cache = create_entity_cache(&db).unwrap();
+    let spectra_count = entity_cache.spectrum_headers.len() as i64;
+
+    // Spread the accesses over the whole file using a fixed stride
+    let spectrum_ids: Vec<i64> = (0..100).map(|i| 1 + (i * 7919) % spectra_count).collect();
+
+    c.bench_function("get_spectrum x100", |b| {
+        b.iter(|| {
+            for spectrum_id in spectrum_ids.iter() {
+                get_spectrum(&db, *spectrum_id, &entity_cache).unwrap();
+            }
+        })
+    });
+}
+
+fn bench_blob_decoding(c: &mut Criterion) {
+    let db = Connection::open(MZDB_FILE_PATH).unwrap();
+    let entity_cache = create_entity_cache(&db).unwrap();
+    let de_cache = &entity_cache.data_encodings_cache;
+
+    let mut bboxes = Vec::new();
+    for_each_bb(&db, Some(1), |bb: BoundingBox| {
+        bboxes.push(bb);
+        Ok(())
+    }).unwrap();
+
+    c.bench_function("index_and_decode_ms1_bboxes", |b| {
+        b.iter(|| {
+            let mut peaks_count = 0;
+            for bb in bboxes.iter() {
+                let bb_index = index_bbox(bb, de_cache).unwrap();
+                for slice_idx in 0..bb_index.spectrum_slices_count {
+                    let spectrum_id = bb_index.spectra_ids[slice_idx];
+                    let de = de_cache.get_data_encoding_by_spectrum_id(&spectrum_id).unwrap();
+                    let sd = read_spectrum_slice_data_at(bb, &bb_index, de, slice_idx, None, None).unwrap();
+                    peaks_count += sd.peak_count;
+                }
+            }
+
+            peaks_count
+        })
+    });
+
+    c.bench_function("blob_cursor_decode_ms1_bboxes", |b| {
+        b.iter(|| {
+            let mut peaks_count = 0;
+            for bb in bboxes.iter() {
+                for slice_view_res in BlobCursor::new(&bb.blob_data, de_cache) {
+                    peaks_count += slice_view_res.unwrap().to_spectrum_data().peak_count;
+                }
+            }
+
+            peaks_count
+        })
+    });
+}
+
+fn bench_spectrum_clone(c: &mut Criterion) {
+    let db = Connection::open(MZDB_FILE_PATH).unwrap();
+    let entity_cache = create_entity_cache(&db).unwrap();
+
+    let mut ms1_spectra = Vec::new();
+    for_each_spectrum(&db, &entity_cache, Some(1), |s: &Spectrum| {
+        ms1_spectra.push(s.clone());
+        Ok(())
+    }).unwrap();
+
+    c.bench_function("clone_ms1_spectra", |b| {
+        b.iter(|| black_box(&ms1_spectra).clone())
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_entity_cache,
+    bench_full_iteration,
+    bench_random_access,
+    bench_blob_decoding,
+    bench_spectrum_clone
+);
+criterion_main!(benches);
@@ -0,0 +1,123 @@
+#ifndef MZDB_H
+#define MZDB_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit manually */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Opaque reader handle, created by mzdb_open() and released by mzdb_close()
+typedef struct MzdbReader MzdbReader;
+
+typedef struct MzdbSpectrumHeader {
+  int64_t id;
+  int64_t cycle;
+  double time;
+  int64_t ms_level;
+  float tic;
+  double base_peak_mz;
+  float base_peak_intensity;
+  double precursor_mz;
+  int32_t precursor_charge;
+  int64_t peaks_count;
+} MzdbSpectrumHeader;
+
+// Callback of mzdb_for_each_spectrum_header(), the iteration stops as soon as it returns a non-zero value
+typedef int (*MzdbSpectrumHeaderCallback)(const struct MzdbSpectrumHeader *header, void *user_data);
+
+// Peak arrays of a spectrum, allocated by mzdb_get_spectrum_data() and released by mzdb_free_spectrum_data()
+typedef struct MzdbSpectrumData {
+  size_t peaks_count;
+  double *mz_array;
+  float *intensity_array;
+} MzdbSpectrumData;
+
+// Points of an XIC, allocated by mzdb_get_xic() and released by mzdb_free_xic_data()
+typedef struct MzdbXicData {
+  size_t points_count;
+  int64_t *spectrum_ids;
+  double *time_array;
+  double *mz_array;
+  float *intensity_array;
+} MzdbXicData;
+
+// Returns the message of the last error which occurred in the calling thread, or NULL if there is none.
+// The returned string is owned by the library and valid until the next failing call of the thread.
+const char *mzdb_last_error(void);
+
+// Open a mzDB file, returns NULL on failure
+//
+// # Safety
+// path must be NULL or a valid NUL-terminated string.
+struct MzdbReader *mzdb_open(const char *path);
+
+// Close the file and release the reader (the pointer must not be used afterwards).
+// On failure, the reader is not released: it is still owned by the caller, and mzdb_close() may be called again.
+//
+// # Safety
+// reader must be NULL or a pointer returned by mzdb_open() which has not been released yet.
+int mzdb_close(struct MzdbReader *reader);
+
+// Returns the number of spectra of the file, or -1 on failure
+//
+// # Safety
+// reader must be NULL or a valid pointer returned by mzdb_open().
+int64_t mzdb_get_spectra_count(const struct MzdbReader *reader);
+
+// Write the header of a spectrum into header
+//
+// # Safety
+// reader must be NULL or a valid pointer returned by mzdb_open(), and header must be NULL or valid for writes.
+int mzdb_get_spectrum_header(const struct MzdbReader *reader,
+                             int64_t spectrum_id,
+                             struct MzdbSpectrumHeader *header);
+
+// Call the callback for each spectrum header, in spectrum ID order.
+// Note: the header passed to the callback is only valid during the call.
+//
+// # Safety
+// reader must be NULL or a valid pointer returned by mzdb_open(), user_data is passed as is to the callback.
+int mzdb_for_each_spectrum_header(const struct MzdbReader *reader,
+                                  MzdbSpectrumHeaderCallback callback,
+                                  void *user_data);
+
+// Decode the peaks of a spectrum, the arrays must be released using mzdb_free_spectrum_data()
+//
+// # Safety
+// reader must be NULL or a valid pointer returned by mzdb_open(), and data must be NULL or valid for writes.
+int mzdb_get_spectrum_data(const struct MzdbReader *reader,
+                           int64_t spectrum_id,
+                           struct MzdbSpectrumData *data);
+
+// Release the arrays of a MzdbSpectrumData, which is reset to an empty spectrum
+//
+// # Safety
+// data must be NULL or point to a MzdbSpectrumData filled by mzdb_get_spectrum_data() (or having NULL arrays).
+void mzdb_free_spectrum_data(struct MzdbSpectrumData *data);
+
+// Extract the XIC of target_mz (+/- tol_ppm) from the spectra of the provided MS level (see xic::get_xic()),
+// the arrays must be released using mzdb_free_xic_data().
+// parent_mz restricts the MSn spectra to the ones isolating it, it is ignored if NaN (and must be NaN for MS1).
+// min_time and max_time (in seconds, inclusive) bound the retention times, each one is ignored if NaN.
+//
+// # Safety
+// reader must be NULL or a valid pointer returned by mzdb_open(), and data must be NULL or valid for writes.
+int mzdb_get_xic(const struct MzdbReader *reader,
+                 double target_mz,
+                 double tol_ppm,
+                 uint8_t ms_level,
+                 double parent_mz,
+                 double min_time,
+                 double max_time,
+                 struct MzdbXicData *data);
+
+// Release the arrays of a MzdbXicData, which is reset to an empty XIC
+//
+// # Safety
+// data must be NULL or point to a MzdbXicData filled by mzdb_get_xic() (or having NULL arrays).
+void mzdb_free_xic_data(struct MzdbXicData *data);
+
+#endif /* MZDB_H */
@@ -0,0 +1,352 @@
+// Terminal browser for mzDB files, intended for quick triage on headless servers.
+// Usage: cargo run --features browse --bin mzdb-browse -- <file.mzDB>
+
+use std::io::stdout;
+use std::time::Duration;
+
+use anyhow::*;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::ExecutableCommand;
+use ratatui::prelude::*;
+use ratatui::widgets::{Axis, Block, Borders, Chart, Dataset, GraphType, List, ListItem, ListState, Paragraph, Tabs};
+use rusqlite::{Connection, OpenFlags};
+
+use mzdb::metadata::*;
+use mzdb::model::*;
+use mzdb::mzdb::create_light_entity_cache;
+use mzdb::queries::*;
+use mzdb::xic::{get_ms1_xic, Xic};
+
+const PAGE_SIZE: usize = 20;
+const XIC_TOL_PPM: f64 = 10.0;
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Pane {
+    SPECTRUM,
+    TIC,
+    XIC,
+    METADATA,
+}
+
+const PANES: [Pane; 4] = [Pane::SPECTRUM, Pane::TIC, Pane::XIC, Pane::METADATA];
+
+struct App {
+    db: Connection,
+    entity_cache: EntityCache,
+    list_state: ListState,
+    pane: Pane,
+    spectrum: Option<Spectrum>,
+    xic: Option<Xic>,
+    metadata_lines: Vec<String>,
+    status: String,
+}
+
+impl App {
+    fn new(file_path: &str) -> Result<App> {
+        let db = Connection::open_with_flags(file_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .context(format!("can't open file {}", file_path))?;
+        let entity_cache = create_light_entity_cache(&db)?;
+        if entity_cache.spectrum_headers.is_empty() {
+            bail!("the file {} contains no spectrum", file_path);
+        }
+
+        let metadata_lines = _build_metadata_lines(&db, &entity_cache, file_path)?;
+
+        let mut app = App {
+            db,
+            entity_cache,
+            list_state: ListState::default(),
+            pane: Pane::SPECTRUM,
+            spectrum: None,
+            xic: None,
+            metadata_lines,
+            status: String::new(),
+        };
+        app.select(0);
+
+        Ok(app)
+    }
+
+    fn selected_header(&self) -> &SpectrumHeader {
+        &self.entity_cache.spectrum_headers[self.list_state.selected().unwrap_or(0)]
+    }
+
+    fn select(&mut self, header_idx: usize) {
+        let header_idx = header_idx.min(self.entity_cache.spectrum_headers.len() - 1);
+        self.list_state.select(Some(header_idx));
+
+        let spectrum_id = self.entity_cache.spectrum_headers[header_idx].id;
+        match get_spectrum(&self.db, spectrum_id, &self.entity_cache) {
+            Result::Ok(spectrum) => {
+                self.status = format!("spectrum {} loaded ({} peaks)", spectrum_id, spectrum.data.peak_count);
+                self.spectrum = Some(spectrum);
+            }
+            Err(e) => {
+                self.status = format!("can't load spectrum {}: {}", spectrum_id, e);
+                self.spectrum = None;
+            }
+        }
+    }
+
+    fn move_by(&mut self, offset: isize) {
+        let cur_idx = self.list_state.selected().unwrap_or(0) as isize;
+        self.select((cur_idx + offset).max(0) as usize);
+    }
+
+    /// Select the first spectrum of the previous/next cycle
+    fn move_to_cycle(&mut self, forward: bool) {
+        let headers = &self.entity_cache.spectrum_headers;
+        let cur_idx = self.list_state.selected().unwrap_or(0);
+        let cur_cycle = headers[cur_idx].cycle;
+
+        let target_idx_opt = if forward {
+            headers.iter().position(|sh| sh.cycle > cur_cycle)
+        } else {
+            headers.iter().rposition(|sh| sh.cycle < cur_cycle)
+                .map(|idx| headers.iter().position(|sh| sh.cycle == headers[idx].cycle).unwrap())
+        };
+
+        if let Some(target_idx) = target_idx_opt {
+            self.select(target_idx);
+        }
+    }
+
+    /// Extract the MS1 XIC of the base peak of the selected spectrum (precursor m/z for MSn spectra)
+    fn extract_xic(&mut self) {
+        let header = self.selected_header();
+        let target_mz = if header.ms_level > 1 { header.precursor_mz.unwrap_or(header.base_peak_mz) } else { header.base_peak_mz };
+
+        match get_ms1_xic(&self.db, &self.entity_cache, target_mz, XIC_TOL_PPM, None) {
+            Result::Ok(xic) => {
+                self.status = format!("XIC extracted for m/z {:.4} (+/- {} ppm, {} peaks)", target_mz, XIC_TOL_PPM, xic.peaks.len());
+                self.xic = Some(xic);
+                self.pane = Pane::XIC;
+            }
+            Err(e) => self.status = format!("can't extract XIC: {}", e),
+        }
+    }
+}
+
+fn _build_metadata_lines(db: &Connection, entity_cache: &EntityCache, file_path: &str) -> Result<Vec<String>> {
+    let mut lines = vec![format!("File: {}", file_path)];
+
+    if let Some(mzdb_metadata) = get_mzdb_metadata(db)? {
+        lines.push(format!("mzDB version: {}", mzdb_metadata.version));
+        lines.push(format!("Creation timestamp: {}", mzdb_metadata.creation_timestamp));
+    }
+
+    lines.push(String::new());
+    lines.push("Runs:".to_string());
+    for run in list_runs(db)? {
+        lines.push(format!("  {} (started at {})", run.name, run.start_timestamp.unwrap_or_default()));
+    }
+
+    lines.push("Source files:".to_string());
+    for source_file in list_source_files(db)? {
+        lines.push(format!("  {} ({})", source_file.name, source_file.location));
+    }
+
+    lines.push("Software:".to_string());
+    for software in list_software(db)? {
+        lines.push(format!("  {} {}", software.name, software.version));
+    }
+
+    let instrument_summary = get_instrument_summary(db)?;
+    lines.push("Instrument:".to_string());
+    lines.push(format!("  sources: {}", instrument_summary.source_names.join(", ")));
+    lines.push(format!("  analyzers: {}", instrument_summary.analyzer_names.join(", ")));
+    lines.push(format!("  detectors: {}", instrument_summary.detector_names.join(", ")));
+
+    lines.push(String::new());
+    let headers = &entity_cache.spectrum_headers;
+    lines.push(format!("Spectra: {}", headers.len()));
+    let max_ms_level = headers.iter().map(|sh| sh.ms_level).max().unwrap_or(0);
+    for ms_level in 1..=max_ms_level {
+        let count = headers.iter().filter(|sh| sh.ms_level == ms_level).count();
+        lines.push(format!("  MS{}: {}", ms_level, count));
+    }
+    lines.push(format!("Cycles: {}", headers.last().map(|sh| sh.cycle).unwrap_or(0)));
+    lines.push(format!("RT range: {:.2} - {:.2} min", headers[0].time / 60.0, headers.last().unwrap().time / 60.0));
+
+    lines.push("Data encodings:".to_string());
+    for de in list_data_encodings(db)? {
+        lines.push(format!("  #{}: {:?} / {:?} / {:?} / compression={}", de.id, de.mode, de.peak_encoding, de.byte_order, de.compression));
+    }
+
+    Ok(lines)
+}
+
+fn _create_chart<'a>(title: String, datasets: Vec<Dataset<'a>>, x_label: &'a str, x_bounds: [f64; 2], y_max: f64) -> Chart<'a> {
+    let bound_labels = |bounds: [f64; 2], precision: usize| -> Vec<Span<'a>> {
+        vec![
+            Span::raw(format!("{:.*}", precision, bounds[0])),
+            Span::raw(format!("{:.*}", precision, (bounds[0] + bounds[1]) / 2.0)),
+            Span::raw(format!("{:.*}", precision, bounds[1])),
+        ]
+    };
+
+    Chart::new(datasets)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .x_axis(Axis::default().title(x_label).bounds(x_bounds).labels(bound_labels(x_bounds, 2)))
+        .y_axis(Axis::default().title("intensity").bounds([0.0, y_max]).labels(bound_labels([0.0, y_max], 0)))
+}
+
+fn _render_line_chart(f: &mut Frame, area: Rect, title: String, points: &[(f64, f64)], x_label: &str) {
+    let x_min = points.first().map(|p| p.0).unwrap_or(0.0);
+    let x_max = points.last().map(|p| p.0).unwrap_or(1.0).max(x_min + 1e-6);
+    let y_max = points.iter().map(|p| p.1).fold(0.0, f64::max).max(1.0);
+
+    let dataset = Dataset::default()
+        .marker(symbols::Marker::Braille)
+        .graph_type(GraphType::Line)
+        .style(Style::default().fg(Color::Cyan))
+        .data(points);
+
+    f.render_widget(_create_chart(title, vec![dataset], x_label, [x_min, x_max], y_max), area);
+}
+
+fn _render_spectrum(f: &mut Frame, area: Rect, app: &App) {
+    let header = app.selected_header();
+    let mut title = format!("Spectrum {} (MS{}, RT={:.2} min)", header.id, header.ms_level, header.time / 60.0);
+    if let Some(precursor_mz) = header.precursor_mz {
+        title += &format!(" precursor m/z={:.4} z={}", precursor_mz, header.precursor_charge.unwrap_or(0));
+    }
+
+    let spectrum_data = match app.spectrum.as_ref() {
+        Some(spectrum) if spectrum.data.peak_count > 0 => &spectrum.data,
+        _ => {
+            f.render_widget(Paragraph::new("no data").block(Block::default().borders(Borders::ALL).title(title)), area);
+            return;
+        }
+    };
+
+    // Each peak is drawn as a stick
+    let mut stick_points = Vec::with_capacity(spectrum_data.peak_count * 3);
+    for peak in spectrum_data.iter_peaks() {
+        stick_points.push((peak.mz, 0.0));
+        stick_points.push((peak.mz, peak.intensity as f64));
+        stick_points.push((peak.mz, 0.0));
+    }
+
+    let x_bounds = [spectrum_data.mz_array[0], spectrum_data.mz_array[spectrum_data.peak_count - 1].max(spectrum_data.mz_array[0] + 1.0)];
+    let y_max = spectrum_data.base_peak().map(|peak| peak.intensity as f64).unwrap_or(1.0).max(1.0);
+
+    let dataset = Dataset::default()
+        .marker(symbols::Marker::Braille)
+        .graph_type(GraphType::Line)
+        .style(Style::default().fg(Color::Yellow))
+        .data(&stick_points);
+
+    f.render_widget(_create_chart(title, vec![dataset], "m/z", x_bounds, y_max), area);
+}
+
+fn _render_ui(f: &mut Frame, app: &mut App) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(5), Constraint::Length(2)])
+        .split(f.size());
+
+    let pane_titles: Vec<&str> = vec!["Spectrum", "TIC", "XIC", "Metadata"];
+    let selected_pane_idx = PANES.iter().position(|pane| *pane == app.pane).unwrap();
+    let tabs = Tabs::new(pane_titles)
+        .block(Block::default().borders(Borders::ALL).title("mzdb-browse"))
+        .select(selected_pane_idx)
+        .highlight_style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD));
+    f.render_widget(tabs, rows[0]);
+
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Length(44), Constraint::Min(20)])
+        .split(rows[1]);
+
+    let items: Vec<ListItem> = app.entity_cache.spectrum_headers.iter().map(|sh| {
+        let precursor = sh.precursor_mz.map(|mz| format!("{:.4}", mz)).unwrap_or_default();
+        ListItem::new(format!("{:>6} {:>5} {:>7.2} MS{} {}", sh.id, sh.cycle, sh.time / 60.0, sh.ms_level, precursor))
+    }).collect();
+
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("    id cycle  RT(min) level precursor"))
+        .highlight_style(Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD));
+    f.render_stateful_widget(list, columns[0], &mut app.list_state);
+
+    match app.pane {
+        Pane::SPECTRUM => _render_spectrum(f, columns[1], app),
+        Pane::TIC => {
+            let tic_points: Vec<(f64, f64)> = app.entity_cache.spectrum_headers.iter()
+                .filter(|sh| sh.ms_level == 1)
+                .map(|sh| (sh.time as f64 / 60.0, sh.tic as f64))
+                .collect();
+            _render_line_chart(f, columns[1], "MS1 TIC".to_string(), &tic_points, "RT (min)");
+        }
+        Pane::XIC => match app.xic.as_ref() {
+            Some(xic) => {
+                let title = format!("MS1 XIC m/z={:.4} (+/- {} ppm)", xic.target_mz, xic.tol_ppm);
+                let xic_points: Vec<(f64, f64)> = xic.peaks.iter().map(|peak| (peak.time / 60.0, peak.intensity as f64)).collect();
+                _render_line_chart(f, columns[1], title, &xic_points, "RT (min)");
+            }
+            None => {
+                let block = Block::default().borders(Borders::ALL).title("XIC");
+                f.render_widget(Paragraph::new("press 'x' to extract the XIC of the selected spectrum").block(block), columns[1]);
+            }
+        },
+        Pane::METADATA => {
+            let lines: Vec<Line> = app.metadata_lines.iter().map(|line| Line::from(line.as_str())).collect();
+            f.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Metadata")), columns[1]);
+        }
+    }
+
+    let help = "q: quit | up/down, PgUp/PgDn, Home/End: move | [ ]: previous/next cycle | Tab: switch pane | x: XIC";
+    f.render_widget(Paragraph::new(vec![Line::from(app.status.as_str()), Line::from(help)]), rows[2]);
+}
+
+fn _run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, app: &mut App) -> Result<()> {
+    loop {
+        terminal.draw(|f| _render_ui(f, app))?;
+
+        if !event::poll(Duration::from_millis(250))? {
+            continue;
+        }
+
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => app.move_by(1),
+                KeyCode::Up | KeyCode::Char('k') => app.move_by(-1),
+                KeyCode::PageDown => app.move_by(PAGE_SIZE as isize),
+                KeyCode::PageUp => app.move_by(-(PAGE_SIZE as isize)),
+                KeyCode::Home => app.select(0),
+                KeyCode::End => app.select(usize::MAX),
+                KeyCode::Char(']') => app.move_to_cycle(true),
+                KeyCode::Char('[') => app.move_to_cycle(false),
+                KeyCode::Tab => {
+                    let pane_idx = PANES.iter().position(|pane| *pane == app.pane).unwrap();
+                    app.pane = PANES[(pane_idx + 1) % PANES.len()];
+                }
+                KeyCode::Char('x') => app.extract_xic(),
+                _ => (),
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let file_path = std::env::args().nth(1).context("usage: mzdb-browse <file.mzDB>")?;
+    let mut app = App::new(&file_path)?;
+
+    enable_raw_mode()?;
+    stdout().execute(EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+
+    let app_res = _run_app(&mut terminal, &mut app);
+
+    disable_raw_mode()?;
+    stdout().execute(LeaveAlternateScreen)?;
+
+    app_res
+}
@@ -0,0 +1,279 @@
+// Streaming parser of bounding box blobs.
+// A blob is a sequence of spectrum slices, each one made of the spectrum ID (int32), the number of peaks (int32)
+// and the peaks themselves, whose layout is given by the data encoding of the spectrum.
+// The BlobCursor walks the blob once and yields views borrowing the blob bytes, peaks are only decoded on demand.
+
+use std::ops::Range;
+
+use anyhow::*;
+
+use crate::model::*;
+
+const SLICE_HEADER_SIZE: usize = 8; // spectrum ID and peaks count
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SpectrumSliceView<'a> {
+    pub spectrum_id: i64,
+    pub peaks_count: usize,
+    pub slice_pos: usize, // position of the spectrum slice in the blob
+    pub data_encoding: &'a DataEncoding,
+    peaks_bytes: &'a [u8],
+}
+
+impl<'a> SpectrumSliceView<'a> {
+
+    pub(crate) fn new(spectrum_id: i64, peaks_count: usize, slice_pos: usize, data_encoding: &'a DataEncoding, peaks_bytes: &'a [u8]) -> Self {
+        SpectrumSliceView { spectrum_id, peaks_count, slice_pos, data_encoding, peaks_bytes }
+    }
+
+    /// Returns the raw bytes of the peaks
+    pub fn peaks_bytes(&self) -> &'a [u8] {
+        self.peaks_bytes
+    }
+
+    pub fn get_mz_at(&self, peak_idx: usize) -> Option<f64> {
+        if peak_idx >= self.peaks_count {
+            return None;
+        }
+
+        let layout = PeakLayout::new(self.data_encoding);
+        Some(layout.read_mz(self._peak_bytes(peak_idx, &layout)))
+    }
+
+    pub fn get_intensity_at(&self, peak_idx: usize) -> Option<f32> {
+        if peak_idx >= self.peaks_count {
+            return None;
+        }
+
+        let layout = PeakLayout::new(self.data_encoding);
+        Some(layout.read_intensity(self._peak_bytes(peak_idx, &layout)))
+    }
+
+    /// Decode all the peaks of the spectrum slice
+    pub fn to_spectrum_data(self) -> SpectrumData {
+        self._decode_peaks(0..self.peaks_count, true)
+    }
+
+    /// Decode the peaks of the spectrum slice whose m/z is in the provided range (bounds are inclusive)
+    pub fn to_spectrum_data_in_mz_range(self, min_mz: Option<f64>, max_mz: Option<f64>) -> SpectrumData {
+        self._decode_peaks_in_mz_range(min_mz, max_mz, true)
+    }
+
+    /// Same as to_spectrum_data_in_mz_range() but fitted peaks are decoded as centroids: their HWHMs are not read
+    /// and the mode of the returned data encoding is CENTROID. Peaks of the other modes are decoded as usual.
+    pub fn to_centroid_data_in_mz_range(self, min_mz: Option<f64>, max_mz: Option<f64>) -> SpectrumData {
+        self._decode_peaks_in_mz_range(min_mz, max_mz, false)
+    }
+
+    fn _decode_peaks_in_mz_range(&self, min_mz: Option<f64>, max_mz: Option<f64>, with_hwhm: bool) -> SpectrumData {
+        if min_mz.is_none() && max_mz.is_none() {
+            return self._decode_peaks(0..self.peaks_count, with_hwhm);
+        }
+
+        let min_mz = min_mz.unwrap_or(f64::MIN);
+        let max_mz = max_mz.unwrap_or(f64::MAX);
+
+        // Peaks are sorted by m/z, thus the matching peaks are contiguous
+        let layout = PeakLayout::new(self.data_encoding);
+        let first_peak_idx = (0..self.peaks_count)
+            .find(|peak_idx| layout.read_mz(self._peak_bytes(*peak_idx, &layout)) >= min_mz)
+            .unwrap_or(self.peaks_count);
+        let last_peak_idx = (first_peak_idx..self.peaks_count)
+            .find(|peak_idx| layout.read_mz(self._peak_bytes(*peak_idx, &layout)) > max_mz)
+            .unwrap_or(self.peaks_count);
+
+        self._decode_peaks(first_peak_idx..last_peak_idx, with_hwhm)
+    }
+
+    fn _peak_bytes(&self, peak_idx: usize, layout: &PeakLayout) -> &'a [u8] {
+        let peak_pos = peak_idx * layout.peak_size;
+        &self.peaks_bytes[peak_pos..peak_pos + layout.peak_size]
+    }
+
+    fn _decode_peaks(&self, peak_range: Range<usize>, with_hwhm: bool) -> SpectrumData {
+        let layout = PeakLayout::new(self.data_encoding);
+        let peaks_count = peak_range.len();
+        let read_hwhm = layout.is_fitted && with_hwhm;
+
+        let mut mz_array: Vec<f64> = Vec::with_capacity(peaks_count);
+        let mut intensity_array: Vec<f32> = Vec::with_capacity(peaks_count);
+        let mut lwhm_array: Vec<f32> = Vec::with_capacity(if read_hwhm { peaks_count } else { 0 });
+        let mut rwhm_array: Vec<f32> = Vec::with_capacity(if read_hwhm { peaks_count } else { 0 });
+
+        // Each array is filled by its own contiguous pass over the peaks, the layout being resolved once per pass
+        let range_bytes = &self.peaks_bytes[peak_range.start * layout.peak_size..peak_range.end * layout.peak_size];
+        layout.read_mz_column(range_bytes, &mut mz_array);
+        layout.read_intensity_column(range_bytes, &mut intensity_array);
+
+        // Read left and right HWHMs if needed
+        if read_hwhm {
+            layout.read_f32_column(range_bytes, layout.hwhm_offset, &mut lwhm_array);
+            layout.read_f32_column(range_bytes, layout.hwhm_offset + 4, &mut rwhm_array);
+        }
+
+        let mut data_encoding = self.data_encoding.clone();
+        if layout.is_fitted && !with_hwhm {
+            data_encoding.mode = DataMode::CENTROID;
+        }
+
+        SpectrumData {
+            data_encoding,
+            peak_count: peaks_count,
+            mz_array: mz_array.into(),
+            intensity_array: intensity_array.into(),
+            lwhm_array: lwhm_array.into(),
+            rwhm_array: rwhm_array.into(),
+        }
+    }
+}
+
+// Positions and sizes of the values of a peak, as defined by its data encoding
+struct PeakLayout {
+    peak_size: usize,
+    mz_size: usize,
+    intensity_size: usize,
+    hwhm_offset: usize,
+    is_fitted: bool,
+    big_endian: bool,
+}
+
+impl PeakLayout {
+    fn new(de: &DataEncoding) -> PeakLayout {
+        let pe = de.peak_encoding;
+        let mz_size = if pe == PeakEncoding::LOW_RES_PEAK { 4 } else { 8 };
+        let intensity_size = if pe == PeakEncoding::NO_LOSS_PEAK { 8 } else { 4 };
+
+        PeakLayout {
+            peak_size: de.get_peak_size(),
+            mz_size,
+            intensity_size,
+            hwhm_offset: mz_size + intensity_size,
+            is_fitted: de.mode == DataMode::FITTED,
+            big_endian: de.byte_order == ByteOrder::BIG_ENDIAN,
+        }
+    }
+
+    #[inline]
+    fn read_mz(&self, peak_bytes: &[u8]) -> f64 {
+        if self.mz_size == 4 { self.read_f32(peak_bytes) as f64 } else { self.read_f64(peak_bytes) }
+    }
+
+    #[inline]
+    fn read_intensity(&self, peak_bytes: &[u8]) -> f32 {
+        let intensity_bytes = &peak_bytes[self.mz_size..];
+        if self.intensity_size == 4 { self.read_f32(intensity_bytes) } else { self.read_f64(intensity_bytes) as f32 }
+    }
+
+    fn read_mz_column(&self, range_bytes: &[u8], mz_column: &mut Vec<f64>) {
+        match (self.mz_size, self.big_endian) {
+            (4, false) => _read_column(range_bytes, self.peak_size, 0, |bytes| f32::from_le_bytes(bytes) as f64, mz_column),
+            (4, true) => _read_column(range_bytes, self.peak_size, 0, |bytes| f32::from_be_bytes(bytes) as f64, mz_column),
+            (_, false) => _read_column(range_bytes, self.peak_size, 0, f64::from_le_bytes, mz_column),
+            (_, true) => _read_column(range_bytes, self.peak_size, 0, f64::from_be_bytes, mz_column),
+        }
+    }
+
+    fn read_intensity_column(&self, range_bytes: &[u8], intensity_column: &mut Vec<f32>) {
+        match (self.intensity_size, self.big_endian) {
+            (4, _) => self.read_f32_column(range_bytes, self.mz_size, intensity_column),
+            (_, false) => _read_column(range_bytes, self.peak_size, self.mz_size, |bytes| f64::from_le_bytes(bytes) as f32, intensity_column),
+            (_, true) => _read_column(range_bytes, self.peak_size, self.mz_size, |bytes| f64::from_be_bytes(bytes) as f32, intensity_column),
+        }
+    }
+
+    fn read_f32_column(&self, range_bytes: &[u8], offset: usize, column: &mut Vec<f32>) {
+        if self.big_endian {
+            _read_column(range_bytes, self.peak_size, offset, f32::from_be_bytes, column)
+        } else {
+            _read_column(range_bytes, self.peak_size, offset, f32::from_le_bytes, column)
+        }
+    }
+
+    #[inline]
+    fn read_f32(&self, bytes: &[u8]) -> f32 {
+        let float_bytes: [u8; 4] = bytes[..4].try_into().unwrap();
+        if self.big_endian { f32::from_be_bytes(float_bytes) } else { f32::from_le_bytes(float_bytes) }
+    }
+
+    #[inline]
+    fn read_f64(&self, bytes: &[u8]) -> f64 {
+        let double_bytes: [u8; 8] = bytes[..8].try_into().unwrap();
+        if self.big_endian { f64::from_be_bytes(double_bytes) } else { f64::from_le_bytes(double_bytes) }
+    }
+}
+
+pub struct BlobCursor<'a> {
+    blob: &'a [u8],
+    pos: usize,
+    de_cache: &'a DataEncodingsCache,
+}
+
+impl<'a> BlobCursor<'a> {
+    pub fn new(blob: &'a [u8], de_cache: &'a DataEncodingsCache) -> BlobCursor<'a> {
+        BlobCursor { blob, pos: 0, de_cache }
+    }
+
+    fn _next_slice(&mut self) -> Result<SpectrumSliceView<'a>> {
+        let slice_pos = self.pos;
+        if self.blob.len() - slice_pos < SLICE_HEADER_SIZE {
+            bail!("truncated spectrum slice header at position {} of the blob", slice_pos);
+        }
+
+        let spectrum_id = _read_i32(&self.blob[slice_pos..]) as i64;
+        let peaks_count = _read_u32(&self.blob[slice_pos + 4..]) as usize;
+
+        let data_encoding = self.de_cache.get_data_encoding_by_spectrum_id(&spectrum_id)
+            .context(format!("can't retrieve data encoding for spectrum ID={}", spectrum_id))?;
+
+        let peaks_start_pos = slice_pos + SLICE_HEADER_SIZE;
+        let peaks_end_pos = peaks_count.checked_mul(data_encoding.get_peak_size())
+            .and_then(|peaks_size| peaks_start_pos.checked_add(peaks_size))
+            .filter(|peaks_end_pos| *peaks_end_pos <= self.blob.len())
+            .context(format!("truncated peaks of spectrum with ID={} at position {} of the blob", spectrum_id, slice_pos))?;
+
+        self.pos = peaks_end_pos;
+
+        Ok(SpectrumSliceView::new(spectrum_id, peaks_count, slice_pos, data_encoding, &self.blob[peaks_start_pos..peaks_end_pos]))
+    }
+}
+
+impl<'a> Iterator for BlobCursor<'a> {
+    type Item = Result<SpectrumSliceView<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.blob.len() {
+            return None;
+        }
+
+        let slice_res = self._next_slice();
+
+        // Stop the iteration after an error since the position of the next slice is unknown
+        if slice_res.is_err() {
+            self.pos = self.blob.len();
+        }
+
+        Some(slice_res)
+    }
+}
+
+// Decode the value found at the same offset of each peak, the decoding function being monomorphized so that the loop has no branch
+#[inline]
+fn _read_column<T, F, const N: usize>(range_bytes: &[u8], peak_size: usize, offset: usize, decode: F, column: &mut Vec<T>)
+where F: Fn([u8; N]) -> T {
+    column.extend(range_bytes.chunks_exact(peak_size).map(|peak_bytes| {
+        let mut value_bytes = [0u8; N];
+        value_bytes.copy_from_slice(&peak_bytes[offset..offset + N]);
+        decode(value_bytes)
+    }));
+}
+
+#[inline]
+fn _read_i32(bytes: &[u8]) -> i32 {
+    i32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+// Peaks counts are read as unsigned integers, so that a corrupted count can't be sign-extended to a huge usize
+#[inline]
+fn _read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
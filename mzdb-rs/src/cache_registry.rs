@@ -0,0 +1,121 @@
+// Process-wide sharing of the immutable caches of a mzDB file (entity cache, BB sizes) between the readers of this file,
+// so that servers opening a reader per request don't rebuild them each time.
+// Entries are keyed by file path, modification time and size: caches of a file which has been modified are rebuilt.
+// Note: changes which are still pending in a WAL file don't modify the main file and are thus not detected.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::SystemTime;
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::model::{BBSizes, EntityCache};
+use crate::mzdb::{create_entity_cache, create_light_entity_cache, get_bb_sizes};
+
+#[derive(Debug)]
+pub struct SharedCaches {
+    pub entity_cache: Arc<EntityCache>,
+    pub bb_sizes: Option<BBSizes>, // None if the BB sizes are neither declared nor inferable
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    path: PathBuf,
+    light_headers: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FileVersion {
+    modified: SystemTime,
+    size: u64,
+}
+
+type CacheEntries = HashMap<CacheKey, (FileVersion, Arc<SharedCaches>)>;
+
+#[derive(Debug, Default)]
+pub struct ReaderCacheRegistry {
+    entries: Mutex<CacheEntries>,
+}
+
+impl ReaderCacheRegistry {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the registry shared by the whole process
+    pub fn global() -> &'static ReaderCacheRegistry {
+        static GLOBAL_REGISTRY: OnceLock<ReaderCacheRegistry> = OnceLock::new();
+        GLOBAL_REGISTRY.get_or_init(ReaderCacheRegistry::new)
+    }
+
+    /// Returns the caches of the file opened by db, creating them if they are not registered or if the file has been modified.
+    /// The provided path must be the one of the db connection.
+    pub fn get_or_create(&self, path: &str, db: &Connection, light_headers: bool) -> Result<Arc<SharedCaches>> {
+        let key = CacheKey {
+            path: std::fs::canonicalize(path).context(format!("can't resolve path {}", path)).location(here!())?,
+            light_headers,
+        };
+        let file_version = _get_file_version(&key.path).location(here!())?;
+
+        if let Some((cached_version, caches)) = self._lock()?.get(&key) {
+            if *cached_version == file_version {
+                return Ok(Arc::clone(caches));
+            }
+        }
+
+        // Caches are built without holding the lock, so that the readers of other files are not blocked
+        let entity_cache = if light_headers {
+            create_light_entity_cache(db).location(here!())?
+        } else {
+            create_entity_cache(db).location(here!())?
+        };
+
+        let caches = Arc::new(SharedCaches {
+            entity_cache: Arc::new(entity_cache),
+            bb_sizes: get_bb_sizes(db).ok(),
+        });
+
+        self._lock()?.insert(key, (file_version, Arc::clone(&caches)));
+
+        Ok(caches)
+    }
+
+    /// Remove the caches of a file (readers holding them are not affected)
+    pub fn invalidate(&self, path: &str) -> Result<()> {
+        let path = std::fs::canonicalize(path).context(format!("can't resolve path {}", path)).location(here!())?;
+        self._lock()?.retain(|key, _entry| key.path != path);
+
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self._lock()?.clear();
+        Ok(())
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        Ok(self._lock()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self._lock()?.is_empty())
+    }
+
+    fn _lock(&self) -> Result<MutexGuard<'_, CacheEntries>> {
+        self.entries.lock().map_err(|_| anyhow!("cache registry lock is poisoned (a previous access panicked)"))
+    }
+}
+
+fn _get_file_version(path: &PathBuf) -> Result<FileVersion> {
+    let metadata = std::fs::metadata(path).location(here!())?;
+
+    Ok(FileVersion {
+        modified: metadata.modified().location(here!())?,
+        size: metadata.len(),
+    })
+}
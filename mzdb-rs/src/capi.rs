@@ -0,0 +1,322 @@
+// C API of the reader (mzdb-capi feature), intended to replace the libmzdb C library in C/C++/C# software.
+// The header is generated with cbindgen (see cbindgen.toml), and the shared (or static) library can be built using:
+//   cargo rustc --release --lib --features mzdb-capi --crate-type cdylib (or staticlib)
+// Conventions: functions returning an int return 0 on success and -1 on failure, functions returning a pointer return NULL
+// on failure. The message of the last failure of the calling thread is available through mzdb_last_error().
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+use anyhow::*;
+
+use crate::ffi_support::SharedReader;
+use crate::model::SpectrumHeader;
+use crate::queries::get_spectrum;
+use crate::xic::get_xic;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Opaque reader handle, created by mzdb_open() and released by mzdb_close()
+pub struct MzdbReader {
+    reader: SharedReader,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MzdbSpectrumHeader {
+    pub id: i64,
+    pub cycle: i64,
+    pub time: f64, // in seconds
+    pub ms_level: i64,
+    pub tic: f32,
+    pub base_peak_mz: f64,
+    pub base_peak_intensity: f32,
+    pub precursor_mz: f64, // NaN if the spectrum has no precursor
+    pub precursor_charge: i32, // 0 if unknown
+    pub peaks_count: i64,
+}
+
+/// Peak arrays of a spectrum, allocated by mzdb_get_spectrum_data() and released by mzdb_free_spectrum_data()
+#[repr(C)]
+pub struct MzdbSpectrumData {
+    pub peaks_count: usize,
+    pub mz_array: *mut f64,
+    pub intensity_array: *mut f32,
+}
+
+/// Points of an XIC, allocated by mzdb_get_xic() and released by mzdb_free_xic_data()
+#[repr(C)]
+pub struct MzdbXicData {
+    pub points_count: usize,
+    pub spectrum_ids: *mut i64, // 0 for the data points of a stored chromatogram
+    pub time_array: *mut f64, // in seconds
+    pub mz_array: *mut f64,
+    pub intensity_array: *mut f32,
+}
+
+/// Callback of mzdb_for_each_spectrum_header(), the iteration stops as soon as it returns a non-zero value
+pub type MzdbSpectrumHeaderCallback = extern "C" fn(header: *const MzdbSpectrumHeader, user_data: *mut c_void) -> c_int;
+
+/// Returns the message of the last error which occurred in the calling thread, or NULL if there is none.
+/// The returned string is owned by the library and valid until the next failing call of the thread.
+#[no_mangle]
+pub extern "C" fn mzdb_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+/// Open a mzDB file, returns NULL on failure
+///
+/// # Safety
+/// path must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mzdb_open(path: *const c_char) -> *mut MzdbReader {
+    _call(ptr::null_mut(), || {
+        let path = _to_str(path)?;
+        let reader = SharedReader::open(path)?;
+
+        Ok(Box::into_raw(Box::new(MzdbReader { reader })))
+    })
+}
+
+/// Close the file and release the reader (the pointer must not be used afterwards).
+/// On failure, the reader is not released: it is still owned by the caller, and mzdb_close() may be called again.
+///
+/// # Safety
+/// reader must be NULL or a pointer returned by mzdb_open() which has not been released yet.
+#[no_mangle]
+pub unsafe extern "C" fn mzdb_close(reader: *mut MzdbReader) -> c_int {
+    _call(-1, || {
+        _to_reader(reader)?.reader.close()?;
+
+        // The reader is only released once closed, so that a failed close can be retried
+        drop(Box::from_raw(reader));
+
+        Ok(0)
+    })
+}
+
+/// Returns the number of spectra of the file, or -1 on failure
+///
+/// # Safety
+/// reader must be NULL or a valid pointer returned by mzdb_open().
+#[no_mangle]
+pub unsafe extern "C" fn mzdb_get_spectra_count(reader: *const MzdbReader) -> i64 {
+    _call(-1, || Ok(_to_reader(reader)?.reader.entity_cache().spectrum_headers.len() as i64))
+}
+
+/// Write the header of a spectrum into header
+///
+/// # Safety
+/// reader must be NULL or a valid pointer returned by mzdb_open(), and header must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mzdb_get_spectrum_header(reader: *const MzdbReader, spectrum_id: i64, header: *mut MzdbSpectrumHeader) -> c_int {
+    _call(-1, || {
+        if header.is_null() {
+            bail!("header is NULL");
+        }
+
+        let spectrum_header = _get_spectrum_header(_to_reader(reader)?, spectrum_id)?;
+        header.write(_to_c_header(spectrum_header));
+
+        Ok(0)
+    })
+}
+
+/// Call the callback for each spectrum header, in spectrum ID order.
+/// Note: the header passed to the callback is only valid during the call.
+///
+/// # Safety
+/// reader must be NULL or a valid pointer returned by mzdb_open(), user_data is passed as is to the callback.
+#[no_mangle]
+pub unsafe extern "C" fn mzdb_for_each_spectrum_header(
+    reader: *const MzdbReader,
+    callback: MzdbSpectrumHeaderCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    _call(-1, || {
+        for spectrum_header in _to_reader(reader)?.reader.entity_cache().spectrum_headers.iter() {
+            let c_header = _to_c_header(spectrum_header);
+            if callback(&c_header, user_data) != 0 {
+                break;
+            }
+        }
+
+        Ok(0)
+    })
+}
+
+/// Decode the peaks of a spectrum, the arrays must be released using mzdb_free_spectrum_data()
+///
+/// # Safety
+/// reader must be NULL or a valid pointer returned by mzdb_open(), and data must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mzdb_get_spectrum_data(reader: *const MzdbReader, spectrum_id: i64, data: *mut MzdbSpectrumData) -> c_int {
+    _call(-1, || {
+        if data.is_null() {
+            bail!("data is NULL");
+        }
+
+        let reader = _to_reader(reader)?;
+        let spectrum = reader.reader.with_connection(|db, entity_cache| get_spectrum(db, spectrum_id, entity_cache))?;
+
+        data.write(MzdbSpectrumData {
+            peaks_count: spectrum.data.mz_array.len(),
+            mz_array: _to_raw_array(spectrum.data.mz_array.iter().copied()),
+            intensity_array: _to_raw_array(spectrum.data.intensity_array.iter().copied()),
+        });
+
+        Ok(0)
+    })
+}
+
+/// Release the arrays of a MzdbSpectrumData, which is reset to an empty spectrum
+///
+/// # Safety
+/// data must be NULL or point to a MzdbSpectrumData filled by mzdb_get_spectrum_data() (or having NULL arrays).
+#[no_mangle]
+pub unsafe extern "C" fn mzdb_free_spectrum_data(data: *mut MzdbSpectrumData) {
+    let data = match data.as_mut() {
+        Some(data) => data,
+        None => return,
+    };
+
+    _free_raw_array(&mut data.mz_array, data.peaks_count);
+    _free_raw_array(&mut data.intensity_array, data.peaks_count);
+    data.peaks_count = 0;
+}
+
+/// Extract the XIC of target_mz (+/- tol_ppm) from the spectra of the provided MS level (see xic::get_xic()),
+/// the arrays must be released using mzdb_free_xic_data().
+/// parent_mz restricts the MSn spectra to the ones isolating it, it is ignored if NaN (and must be NaN for MS1).
+/// min_time and max_time (in seconds, inclusive) bound the retention times, each one is ignored if NaN.
+///
+/// # Safety
+/// reader must be NULL or a valid pointer returned by mzdb_open(), and data must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mzdb_get_xic(
+    reader: *const MzdbReader,
+    target_mz: f64,
+    tol_ppm: f64,
+    ms_level: u8,
+    parent_mz: f64,
+    min_time: f64,
+    max_time: f64,
+    data: *mut MzdbXicData,
+) -> c_int {
+    _call(-1, || {
+        if data.is_null() {
+            bail!("data is NULL");
+        }
+
+        let parent_mz_opt = if parent_mz.is_nan() { None } else { Some(parent_mz) };
+        let rt_range = if min_time.is_nan() && max_time.is_nan() {
+            None
+        } else {
+            Some((if min_time.is_nan() { f64::MIN } else { min_time }, if max_time.is_nan() { f64::MAX } else { max_time }))
+        };
+
+        let reader = _to_reader(reader)?;
+        let xic = reader.reader.with_connection(|db, entity_cache| {
+            get_xic(db, entity_cache, target_mz, tol_ppm, ms_level, parent_mz_opt, rt_range)
+        })?;
+
+        data.write(MzdbXicData {
+            points_count: xic.peaks.len(),
+            spectrum_ids: _to_raw_array(xic.peaks.iter().map(|peak| peak.spectrum_id.unwrap_or(0))),
+            time_array: _to_raw_array(xic.peaks.iter().map(|peak| peak.time)),
+            mz_array: _to_raw_array(xic.peaks.iter().map(|peak| peak.mz)),
+            intensity_array: _to_raw_array(xic.peaks.iter().map(|peak| peak.intensity)),
+        });
+
+        Ok(0)
+    })
+}
+
+/// Release the arrays of a MzdbXicData, which is reset to an empty XIC
+///
+/// # Safety
+/// data must be NULL or point to a MzdbXicData filled by mzdb_get_xic() (or having NULL arrays).
+#[no_mangle]
+pub unsafe extern "C" fn mzdb_free_xic_data(data: *mut MzdbXicData) {
+    let data = match data.as_mut() {
+        Some(data) => data,
+        None => return,
+    };
+
+    _free_raw_array(&mut data.spectrum_ids, data.points_count);
+    _free_raw_array(&mut data.time_array, data.points_count);
+    _free_raw_array(&mut data.mz_array, data.points_count);
+    _free_raw_array(&mut data.intensity_array, data.points_count);
+    data.points_count = 0;
+}
+
+// Run the function, recording its error (or panic, which must not unwind across the FFI boundary) as the last error
+fn _call<T, F>(error_value: T, f: F) -> T where F: FnOnce() -> Result<T> {
+    let error_msg = match catch_unwind(AssertUnwindSafe(f)) {
+        std::result::Result::Ok(Result::Ok(value)) => return value,
+        std::result::Result::Ok(Err(e)) => format!("{:#}", e),
+        Err(_) => "unexpected panic in mzdb".to_string(),
+    };
+
+    LAST_ERROR.with(|last_error| {
+        *last_error.borrow_mut() = Some(CString::new(error_msg.replace('\0', " ")).unwrap());
+    });
+
+    error_value
+}
+
+// Allocate an array to be released by _free_raw_array()
+fn _to_raw_array<T, I>(values: I) -> *mut T where I: Iterator<Item = T> {
+    Box::into_raw(values.collect::<Box<[T]>>()) as *mut T
+}
+
+// Safety: array must be NULL or allocated by _to_raw_array() with len values, it is reset to NULL
+unsafe fn _free_raw_array<T>(array: &mut *mut T, len: usize) {
+    if !array.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(*array, len)));
+    }
+
+    *array = ptr::null_mut();
+}
+
+// Safety: c_str must be NULL or a valid NUL-terminated string
+unsafe fn _to_str<'a>(c_str: *const c_char) -> Result<&'a str> {
+    if c_str.is_null() {
+        bail!("string is NULL");
+    }
+
+    CStr::from_ptr(c_str).to_str().context("string is not valid UTF-8")
+}
+
+// Safety: reader must be NULL or a valid pointer returned by mzdb_open()
+unsafe fn _to_reader<'a>(reader: *const MzdbReader) -> Result<&'a MzdbReader> {
+    reader.as_ref().context("reader is NULL")
+}
+
+fn _get_spectrum_header(reader: &MzdbReader, spectrum_id: i64) -> Result<&SpectrumHeader> {
+    let spectrum_headers = &reader.reader.entity_cache().spectrum_headers;
+
+    usize::try_from(spectrum_id - 1).ok()
+        .and_then(|spectrum_idx| spectrum_headers.get(spectrum_idx))
+        .context(format!("can't retrieve spectrum with ID={}", spectrum_id))
+}
+
+fn _to_c_header(spectrum_header: &SpectrumHeader) -> MzdbSpectrumHeader {
+    MzdbSpectrumHeader {
+        id: spectrum_header.id,
+        cycle: spectrum_header.cycle,
+        time: spectrum_header.time_f64,
+        ms_level: spectrum_header.ms_level,
+        tic: spectrum_header.tic,
+        base_peak_mz: spectrum_header.base_peak_mz,
+        base_peak_intensity: spectrum_header.base_peak_intensity,
+        precursor_mz: spectrum_header.precursor_mz.unwrap_or(f64::NAN),
+        precursor_charge: spectrum_header.precursor_charge.unwrap_or(0),
+        peaks_count: spectrum_header.peaks_count,
+    }
+}
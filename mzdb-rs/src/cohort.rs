@@ -0,0 +1,208 @@
+// Access to a cohort of mzDB files opened together (e.g. all the runs of a study).
+// Light entity caches are used so that the memory footprint doesn't scale with the XML content of each file,
+// and the data encoding maps are shared by the files having the same encoding pattern (e.g. runs acquired with the same method).
+// The header strings (titles and activation types) are interned in a table shared by the files of the cohort (see StringInterner).
+// MultiMzDb attaches the files to a single SQLite connection instead, which allows cross-file SQL queries.
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::{params, Connection, OpenFlags};
+
+use crate::iterator;
+use crate::model::*;
+use crate::mzdb::create_light_entity_cache_with_interner;
+use crate::quant::{self, IsotopeXic};
+use crate::queries;
+use crate::xic::{self, Xic};
+
+pub struct CohortFile {
+    pub path: String,
+    pub db: Connection,
+    pub entity_cache: EntityCache,
+}
+
+pub struct CohortReader {
+    pub files: Vec<CohortFile>,
+}
+
+impl CohortReader {
+    /// Open all the provided files in read-only mode
+    pub fn open(paths: &[&str]) -> Result<CohortReader> {
+        let mut interner = StringInterner::new();
+        let mut files: Vec<CohortFile> = Vec::with_capacity(paths.len());
+        for path in paths {
+            let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .context(format!("can't open file {}", path)).location(here!())?;
+            let mut entity_cache = create_light_entity_cache_with_interner(&db, &mut interner).location(here!())?;
+
+            // Reuse the data encoding maps of a previously opened file when they are identical
+            for opened_file in files.iter() {
+                if entity_cache.data_encodings_cache.share_maps_with(&opened_file.entity_cache.data_encodings_cache) {
+                    break;
+                }
+            }
+
+            files.push(CohortFile { path: path.to_string(), db, entity_cache });
+        }
+
+        Ok(CohortReader { files })
+    }
+
+    pub fn files_count(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn get_spectrum(&self, file_idx: usize, spectrum_id: i64) -> Result<Spectrum> {
+        let file = self.files.get(file_idx).context(format!("invalid file index {}", file_idx)).location(here!())?;
+        queries::get_spectrum(&file.db, spectrum_id, &file.entity_cache).location(here!())
+    }
+
+    /// Iterate the spectra of all the files, one file after the other (in the order of the open() paths).
+    /// The callback receives the index of the file the spectrum belongs to.
+    pub fn for_each_spectrum<F>(&self, ms_level: Option<u8>, mut on_each_spectrum: F) -> Result<()>
+        where F: FnMut(usize, &Spectrum) -> Result<()> {
+
+        for (file_idx, file) in self.files.iter().enumerate() {
+            iterator::for_each_spectrum(&file.db, &file.entity_cache, ms_level, |spectrum: &Spectrum| {
+                on_each_spectrum(file_idx, spectrum)
+            }).context(format!("can't iterate spectra of file {}", file.path)).location(here!())?;
+        }
+
+        Ok(())
+    }
+
+    /// Extract an XIC in each file (see xic::get_xic()), in the order of the open() paths
+    pub fn get_xic(
+        &self,
+        target_mz: f64,
+        tol_ppm: f64,
+        ms_level: u8,
+        parent_mz: Option<f64>,
+        rt_range: Option<(f64, f64)>,
+    ) -> Result<Vec<Xic>> {
+        self.files.iter().map(|file| {
+            xic::get_xic(&file.db, &file.entity_cache, target_mz, tol_ppm, ms_level, parent_mz, rt_range)
+                .context(format!("can't extract XIC from file {}", file.path)).location(here!())
+        }).collect()
+    }
+
+    /// Extract the isotope XICs of an ion in each file (see quant::get_xic_isotopes()), in the order of the open() paths
+    pub fn get_xic_isotopes(
+        &self,
+        mono_mz: f64,
+        charge: i32,
+        n_isotopes: usize,
+        tol_ppm: f64,
+        rt_range: Option<(f64, f64)>,
+    ) -> Result<Vec<IsotopeXic>> {
+        self.files.iter().map(|file| {
+            quant::get_xic_isotopes(&file.db, &file.entity_cache, mono_mz, charge, n_isotopes, tol_ppm, rt_range)
+                .context(format!("can't extract XICs from file {}", file.path)).location(here!())
+        }).collect()
+    }
+
+    /// Returns the spectrum headers of each file having the provided MS level
+    pub fn get_spectrum_headers_by_ms_level(&self, ms_level: i64) -> Vec<Vec<&SpectrumHeader>> {
+        self.files.iter().map(|file| {
+            file.entity_cache.spectrum_headers.iter().filter(|sh| sh.ms_level == ms_level).collect()
+        }).collect()
+    }
+}
+
+const SCHEMA_PLACEHOLDER: &str = "{schema}";
+
+/// Several mzDB files attached to a single in-memory SQLite connection, each one under the schema "file_<idx>".
+/// Note: SQLite limits the number of attached databases (10 by default).
+pub struct MultiMzDb {
+    pub db: Connection,
+    pub paths: Vec<String>,
+}
+
+impl MultiMzDb {
+    /// Attach all the provided files in read-only mode
+    pub fn attach(paths: &[&str]) -> Result<MultiMzDb> {
+        let db = Connection::open_in_memory_with_flags(
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_URI
+        ).location(here!())?;
+
+        for (file_idx, path) in paths.iter().enumerate() {
+            // ATTACH would silently create a new database if the file doesn't exist
+            if !std::path::Path::new(path).is_file() {
+                bail!("can't attach file {}: file not found", path);
+            }
+
+            let uri = format!("file:{}?mode=ro", path.replace('%', "%25").replace('?', "%3f").replace('#', "%23"));
+            db.execute(format!("ATTACH DATABASE ?1 AS {}", Self::_schema_name(file_idx)).as_str(), params![uri])
+                .context(format!("can't attach file {}", path)).location(here!())?;
+        }
+
+        Ok(MultiMzDb { db, paths: paths.iter().map(|path| path.to_string()).collect() })
+    }
+
+    pub fn files_count(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Returns the schema name of a file, to be used in cross-file queries (e.g. file_0.spectrum)
+    pub fn schema_name(&self, file_idx: usize) -> Result<String> {
+        if file_idx >= self.paths.len() {
+            bail!("invalid file index {}", file_idx);
+        }
+
+        Ok(Self::_schema_name(file_idx))
+    }
+
+    /// Build the UNION ALL of a query applied to each file.
+    /// The query must reference the tables using the {schema} placeholder (e.g. "SELECT time, tic FROM {schema}.spectrum"),
+    /// and the index of the file is prepended to the selected columns as file_idx.
+    /// Each query is wrapped in a subquery, thus it may use any SELECT syntax (WITH, DISTINCT, ORDER BY, LIMIT...).
+    pub fn build_union_query(&self, query_template: &str) -> Result<String> {
+        if query_template.trim().is_empty() {
+            bail!("the query template must be a SELECT statement");
+        }
+
+        let file_queries: Vec<String> = (0..self.paths.len()).map(|file_idx| {
+            format!(
+                "SELECT {} AS file_idx, sub.* FROM ({}) sub",
+                file_idx,
+                query_template.replace(SCHEMA_PLACEHOLDER, &Self::_schema_name(file_idx))
+            )
+        }).collect();
+
+        Ok(file_queries.join(" UNION ALL "))
+    }
+
+    /// Run the provided function with a connection whose unqualified table names refer to the tables of a given file,
+    /// so that the functions of this crate (queries, iterator...) can be used on this file without opening a new connection.
+    /// This is achieved by creating temporary views, which are dropped before returning.
+    /// Note: views have no rowid and sqlite_sequence is not exposed, thus CountMode::SEQUENCE/HINTED counts are not supported.
+    pub fn with_file<T, F>(&self, file_idx: usize, f: F) -> Result<T> where F: FnOnce(&Connection) -> Result<T> {
+        let schema_name = self.schema_name(file_idx).location(here!())?;
+
+        let mut stmt = self.db.prepare(
+            format!("SELECT name FROM {}.sqlite_master WHERE type IN ('table', 'view') AND name NOT LIKE 'sqlite_%'", schema_name).as_str()
+        ).location(here!())?;
+        let table_names = stmt.query_map([], |row| row.get::<_, String>(0)).location(here!())?
+            .collect::<rusqlite::Result<Vec<String>>>().location(here!())?;
+
+        let mut create_views_sql = String::new();
+        let mut drop_views_sql = String::new();
+        for table_name in table_names.iter() {
+            let quoted_name = queries::quote_identifier(table_name);
+            create_views_sql.push_str(&format!("CREATE TEMP VIEW {} AS SELECT * FROM {}.{};", quoted_name, schema_name, quoted_name));
+            drop_views_sql.push_str(&format!("DROP VIEW IF EXISTS temp.{};", quoted_name));
+        }
+
+        let result = self.db.execute_batch(&create_views_sql).location(here!()).and_then(|_| f(&self.db));
+
+        // The views are dropped even if the function failed
+        self.db.execute_batch(&drop_views_sql).location(here!())?;
+
+        result.context(format!("can't process file {}", self.paths[file_idx]))
+    }
+
+    fn _schema_name(file_idx: usize) -> String {
+        format!("file_{}", file_idx)
+    }
+}
@@ -0,0 +1,201 @@
+// Compatibility layer for mzDB files written by the different producers (pwiz-mzdb, mzdb4s, Thermo2mzDB...)
+// Known quirks are detected from the file content and corrected at read time when possible.
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::model::*;
+use crate::mzdb::create_entity_cache;
+use crate::xml::parse_cv_params;
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProducerQuirk {
+    BB_FIRST_SPECTRUM_ID_OFFSET(i64), // offset to add to spectrum.bb_first_spectrum_id
+    INCONSISTENT_BB_FIRST_SPECTRUM_ID, // spectrum.bb_first_spectrum_id values matching no bounding box, whatever the offset
+    RT_IN_MINUTES,
+    EMPTY_MSN_RTREE,
+    NON_STANDARD_COMPRESSION { data_encoding_id: i64, compression: Option<String> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompatReport {
+    pub producer_name: Option<String>,
+    pub producer_version: Option<String>,
+    pub quirks: Vec<ProducerQuirk>,
+    pub applied_corrections: Vec<String>,
+}
+
+/// Normalize the compression string of a data encoding ("None", "" or NULL are all mapped to "none")
+pub fn normalize_compression(compression_opt: Option<String>) -> String {
+    match compression_opt {
+        None => "none".to_string(),
+        Some(compression) => {
+            let lower_compression = compression.trim().to_lowercase();
+            if lower_compression.is_empty() { "none".to_string() } else { lower_compression }
+        }
+    }
+}
+
+fn _detect_bb_first_spectrum_id_quirk(db: &Connection) -> Result<Option<ProducerQuirk>> {
+    let count_query = |offset: i64| -> Result<i64> {
+        let count = db.query_row(
+            format!(
+                "SELECT count(*) FROM spectrum WHERE bb_first_spectrum_id + {} NOT IN (SELECT first_spectrum_id FROM bounding_box)",
+                offset
+            ).as_str(),
+            [],
+            |row| row.get(0)
+        ).location(here!())?;
+
+        Ok(count)
+    };
+
+    if count_query(0)? == 0 {
+        return Ok(None);
+    }
+
+    for offset in [-1, 1] {
+        if count_query(offset)? == 0 {
+            return Ok(Some(ProducerQuirk::BB_FIRST_SPECTRUM_ID_OFFSET(offset)));
+        }
+    }
+
+    // Reported as a quirk rather than an error, so that the other quirks can still be detected and corrected
+    Ok(Some(ProducerQuirk::INCONSISTENT_BB_FIRST_SPECTRUM_ID))
+}
+
+/// Detect files whose spectrum.time values are in minutes instead of seconds, using the unit of the scan start time of the last spectrum
+pub fn detect_rt_in_minutes(db: &Connection) -> Result<bool> {
+    let last_spectrum_opt: Option<(f64, Option<String>)> = db.query_row(
+        "SELECT time, scan_list FROM spectrum ORDER BY id DESC LIMIT 1",
+        [],
+        |row| rusqlite::Result::Ok((row.get(0)?, row.get(1)?))
+    ).optional().location(here!())?;
+
+    if last_spectrum_opt.is_none() {
+        return Ok(false);
+    }
+
+    let (time, scan_list_opt) = last_spectrum_opt.unwrap();
+    if scan_list_opt.is_none() {
+        return Ok(false);
+    }
+
+    let cv_params = parse_cv_params(&scan_list_opt.unwrap()).location(here!())?;
+    let scan_start_time_opt = cv_params.iter().find(|cv_param| cv_param.accession == SCAN_START_TIME);
+
+    let rt_in_minutes = scan_start_time_opt.is_some_and(|scan_start_time| {
+        let value_res = scan_start_time.value.parse::<f64>();
+        if scan_start_time.unit_accession != UNIT_MINUTE || value_res.is_err() {
+            return false;
+        }
+
+        let value_in_min = value_res.unwrap();
+        (time - value_in_min).abs() < (time - value_in_min * 60.0).abs()
+    });
+
+    Ok(rt_in_minutes)
+}
+
+fn _detect_empty_msn_rtree(db: &Connection) -> Result<bool> {
+    let msn_rtree_count: i64 = db.query_row("SELECT count(*) FROM bounding_box_msn_rtree", [], |row| row.get(0)).location(here!())?;
+    if msn_rtree_count > 0 {
+        return Ok(false);
+    }
+
+    // The MSn R-tree is only required for DIA files, where the same isolation windows are repeated at each cycle
+    let (ms2_count, precursors_count): (i64, i64) = db.query_row(
+        "SELECT count(*), count(DISTINCT main_precursor_mz) FROM spectrum WHERE ms_level = 2",
+        [],
+        |row| rusqlite::Result::Ok((row.get(0)?, row.get(1)?))
+    ).location(here!())?;
+
+    Ok(precursors_count > 0 && ms2_count >= 2 * precursors_count)
+}
+
+/// Detect the known producer quirks of a mzDB file
+pub fn detect_quirks(db: &Connection) -> Result<CompatReport> {
+    let producer_opt: Option<(String, String)> = db.query_row(
+        "SELECT name, version FROM software WHERE lower(name) LIKE '%mzdb%' ORDER BY id DESC LIMIT 1",
+        [],
+        |row| rusqlite::Result::Ok((row.get(0)?, row.get(1)?))
+    ).optional().location(here!())?;
+
+    let mut quirks = Vec::new();
+
+    if let Some(quirk) = _detect_bb_first_spectrum_id_quirk(db).location(here!())? {
+        quirks.push(quirk);
+    }
+
+    if detect_rt_in_minutes(db).location(here!())? {
+        quirks.push(ProducerQuirk::RT_IN_MINUTES);
+    }
+
+    if _detect_empty_msn_rtree(db).location(here!())? {
+        quirks.push(ProducerQuirk::EMPTY_MSN_RTREE);
+    }
+
+    let mut stmt = db.prepare("SELECT id, compression FROM data_encoding").location(here!())?;
+    let mut rows = stmt.query([]).location(here!())?;
+    while let Some(row) = rows.next().location(here!())? {
+        let data_encoding_id: i64 = row.get(0).location(here!())?;
+        let compression: Option<String> = row.get(1).location(here!())?;
+
+        if compression.as_ref().is_none_or(|c| c.as_str() != normalize_compression(compression.clone())) {
+            quirks.push(ProducerQuirk::NON_STANDARD_COMPRESSION { data_encoding_id, compression });
+        }
+    }
+
+    Ok(CompatReport {
+        producer_name: producer_opt.as_ref().map(|p| p.0.clone()),
+        producer_version: producer_opt.map(|p| p.1),
+        quirks,
+        applied_corrections: Vec::new(),
+    })
+}
+
+/// Apply the corrections of the detected quirks to the entity cache and record them in the report
+pub fn apply_corrections(entity_cache: &mut EntityCache, report: &mut CompatReport) {
+    for quirk in report.quirks.iter() {
+        match quirk {
+            ProducerQuirk::BB_FIRST_SPECTRUM_ID_OFFSET(offset) => {
+                for sh in entity_cache.spectrum_headers.iter_mut() {
+                    sh.bb_first_spectrum_id += offset;
+                }
+                report.applied_corrections.push(format!("shifted spectrum.bb_first_spectrum_id values by {}", offset));
+            }
+            ProducerQuirk::INCONSISTENT_BB_FIRST_SPECTRUM_ID => {
+                report.applied_corrections.push("no correction available for the inconsistent spectrum.bb_first_spectrum_id values".to_string());
+            }
+            ProducerQuirk::RT_IN_MINUTES => {
+                // The queries taking the entity cache convert the stored times (spectrum table and R-trees) with the same factor
+                crate::mzdb::set_time_factor(entity_cache, 60.0);
+                report.applied_corrections.push(
+                    "converted spectrum.time values from minutes to seconds (EntityCache.time_factor is also used by the time queries)".to_string()
+                );
+            }
+            ProducerQuirk::EMPTY_MSN_RTREE => {
+                report.applied_corrections.push("no correction available for the empty MSn R-tree".to_string());
+            }
+            ProducerQuirk::NON_STANDARD_COMPRESSION { data_encoding_id, compression } => {
+                // Already normalized by queries::list_data_encodings()
+                report.applied_corrections.push(
+                    format!("normalized compression {:?} of data encoding with ID={}", compression, data_encoding_id)
+                );
+            }
+        }
+    }
+}
+
+/// Create an entity cache with the corrections of the detected quirks already applied
+pub fn create_compatible_entity_cache(db: &Connection) -> Result<(EntityCache, CompatReport)> {
+    let mut report = detect_quirks(db).location(here!())?;
+    let mut entity_cache = create_entity_cache(db).location(here!())?;
+
+    apply_corrections(&mut entity_cache, &mut report);
+
+    Ok((entity_cache, report))
+}
@@ -0,0 +1,78 @@
+// Transparent opening of compressed mzDB files (.mzDB.gz, .mzDB.zst).
+// The file is decompressed to a temporary file which lives as long as the returned AutoOpenedDb.
+// Note: decompressing to memory would require the SQLite deserialize API, which is not exposed by rusqlite 0.27.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::{Connection, OpenFlags};
+
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ContainerFormat {
+    SQLITE,
+    GZIP,
+    ZSTD,
+    UNKNOWN,
+}
+
+pub struct AutoOpenedDb {
+    // Note: the connection is declared first so that it is closed before the temporary file is deleted
+    pub db: Connection,
+    pub container_format: ContainerFormat,
+    _temp_file: Option<tempfile::NamedTempFile>,
+}
+
+/// Detect the container format of a file from its magic number
+pub fn detect_container_format(path: &str) -> Result<ContainerFormat> {
+    let mut header = Vec::with_capacity(SQLITE_MAGIC.len());
+    File::open(path).location(here!())?
+        .take(SQLITE_MAGIC.len() as u64)
+        .read_to_end(&mut header).location(here!())?;
+
+    let format = if header.starts_with(SQLITE_MAGIC) {
+        ContainerFormat::SQLITE
+    } else if header.starts_with(GZIP_MAGIC) {
+        ContainerFormat::GZIP
+    } else if header.starts_with(ZSTD_MAGIC) {
+        ContainerFormat::ZSTD
+    } else {
+        ContainerFormat::UNKNOWN
+    };
+
+    Ok(format)
+}
+
+/// Open a mzDB file in read-only mode, decompressing it first if it is a gzip or zstd container
+pub fn open_auto(path: &str) -> Result<AutoOpenedDb> {
+    let container_format = detect_container_format(path).location(here!())?;
+
+    let decoder: Box<dyn Read> = match container_format {
+        ContainerFormat::SQLITE => {
+            let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).location(here!())?;
+            return Ok(AutoOpenedDb { db, container_format, _temp_file: None });
+        }
+        ContainerFormat::GZIP => Box::new(flate2::read::GzDecoder::new(BufReader::new(File::open(path).location(here!())?))),
+        ContainerFormat::ZSTD => Box::new(zstd::stream::read::Decoder::new(File::open(path).location(here!())?).location(here!())?),
+        ContainerFormat::UNKNOWN => bail!("the file {} is neither a SQLite database nor a gzip/zstd container", path),
+    };
+
+    log::info!("decompressing {:?} container {} to a temporary file", container_format, path);
+
+    let mut temp_file = tempfile::Builder::new().suffix(".mzDB").tempfile().location(here!())?;
+    let bytes_count = std::io::copy(&mut BufReader::new(decoder), temp_file.as_file_mut())
+        .context(format!("can't decompress file {}", path)).location(here!())?;
+
+    log::info!("{} decompressed to {:?} ({} bytes)", path, temp_file.path(), bytes_count);
+
+    let db = Connection::open_with_flags(temp_file.path(), OpenFlags::SQLITE_OPEN_READ_ONLY).location(here!())?;
+
+    Ok(AutoOpenedDb { db, container_format, _temp_file: Some(temp_file) })
+}
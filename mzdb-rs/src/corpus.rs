@@ -0,0 +1,217 @@
+// Compatibility checks run over a corpus of real-world mzDB files (see tests/corpus.rs).
+// Each file goes through the main read paths of the crate, and the failures are collected instead of being propagated,
+// so that a single report covers the whole corpus.
+
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::{Connection, OpenFlags};
+
+use crate::compat::create_compatible_entity_cache;
+use crate::diagnostics::{collect_diagnostics, Diagnostics};
+use crate::model::*;
+use crate::progress::{check_cancellation, CancellationToken, Cancelled, ProgressObserver, ProgressTracker};
+use crate::queries::{get_chromatogram_data, get_spectrum, list_chromatogram_headers};
+use crate::xic::get_ms1_xic;
+
+const RANDOM_SPECTRA_COUNT: i64 = 10;
+const STEPS_COUNT: usize = 6;
+const XIC_TOL_PPM: f64 = 10.0;
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CorpusStep {
+    OPEN,
+    ENTITY_CACHE,
+    RANDOM_SPECTRA,
+    XIC,
+    CHROMATOGRAMS,
+    VALIDATION,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorpusStepResult {
+    pub step: CorpusStep,
+    pub error: Option<String>,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorpusReport {
+    pub path: String,
+    pub step_results: Vec<CorpusStepResult>, // steps following a failed OPEN or ENTITY_CACHE step are not run
+    pub quirks: Vec<String>,
+    pub diagnostics_count: usize,
+}
+
+impl CorpusReport {
+    pub fn is_ok(&self) -> bool {
+        self.step_results.iter().all(|step_result| step_result.error.is_none())
+    }
+
+    pub fn failed_steps(&self) -> Vec<&CorpusStepResult> {
+        self.step_results.iter().filter(|step_result| step_result.error.is_some()).collect()
+    }
+}
+
+/// Run the compatibility checks on a single file, the errors being reported in the returned CorpusReport.
+/// The progress is reported after each step.
+/// Only a cancellation requested through the provided token (see progress::Cancelled) is returned as an error.
+pub fn check_file(
+    path: &str,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<CorpusReport> {
+    let mut report = CorpusReport {
+        path: path.to_string(),
+        step_results: Vec::new(),
+        quirks: Vec::new(),
+        diagnostics_count: 0,
+    };
+    let mut progress = ProgressTracker::new(progress_observer, STEPS_COUNT);
+
+    let db_opt = _run_step(&mut report, CorpusStep::OPEN, &mut progress, cancellation_token, || {
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).location(here!())
+    })?;
+    let db = match db_opt {
+        Some(db) => db,
+        None => {
+            progress.finish();
+            return Ok(report);
+        }
+    };
+
+    let cache_opt = _run_step(&mut report, CorpusStep::ENTITY_CACHE, &mut progress, cancellation_token, || {
+        create_compatible_entity_cache(&db).location(here!())
+    })?;
+    let entity_cache = match cache_opt {
+        Some((entity_cache, compat_report)) => {
+            report.quirks = compat_report.quirks.iter().map(|quirk| format!("{:?}", quirk)).collect();
+            entity_cache
+        }
+        None => {
+            progress.finish();
+            return Ok(report);
+        }
+    };
+
+    _run_step(&mut report, CorpusStep::RANDOM_SPECTRA, &mut progress, cancellation_token, || _read_random_spectra(&db, &entity_cache, cancellation_token))?;
+    _run_step(&mut report, CorpusStep::XIC, &mut progress, cancellation_token, || _extract_base_peak_xic(&db, &entity_cache))?;
+    _run_step(&mut report, CorpusStep::CHROMATOGRAMS, &mut progress, cancellation_token, || _decode_chromatograms(&db, cancellation_token))?;
+
+    let diagnostics_opt = _run_step(&mut report, CorpusStep::VALIDATION, &mut progress, cancellation_token, || {
+        let mut diagnostics = Diagnostics::new();
+        collect_diagnostics(&db, &entity_cache, &mut diagnostics, None, cancellation_token).location(here!())?;
+        Ok(diagnostics)
+    })?;
+    report.diagnostics_count = diagnostics_opt.map_or(0, |diagnostics| diagnostics.len());
+
+    Ok(report)
+}
+
+/// Run the compatibility checks on all the mzDB files (.mzDB extension, case insensitive) of a directory, ordered by name.
+/// The progress is reported after each checked file.
+pub fn check_directory(
+    dir_path: &str,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<Vec<CorpusReport>> {
+    let mut file_paths = Vec::new();
+    for entry_res in std::fs::read_dir(dir_path).context(format!("can't read directory {}", dir_path)).location(here!())? {
+        let path = entry_res.location(here!())?.path();
+        let is_mzdb_file = path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("mzdb"));
+        if is_mzdb_file {
+            file_paths.push(path.to_string_lossy().to_string());
+        }
+    }
+
+    file_paths.sort();
+
+    let mut progress = ProgressTracker::new(progress_observer, file_paths.len());
+    let mut reports = Vec::with_capacity(file_paths.len());
+    for file_path in file_paths.iter() {
+        reports.push(check_file(file_path, None, cancellation_token).location(here!())?);
+        progress.increment();
+    }
+
+    Ok(reports)
+}
+
+// Run a step and record its result, a cancellation being returned as an error instead
+fn _run_step<T, F>(
+    report: &mut CorpusReport,
+    step: CorpusStep,
+    progress: &mut ProgressTracker,
+    cancellation_token: Option<&CancellationToken>,
+    f: F
+) -> Result<Option<T>> where F: FnOnce() -> Result<T> {
+
+    check_cancellation(cancellation_token)?;
+
+    let start_time = Instant::now();
+    let result = f();
+
+    if let Err(e) = result.as_ref() {
+        if e.chain().any(|cause| cause.is::<Cancelled>()) {
+            return Err(Cancelled).location(here!());
+        }
+    }
+
+    report.step_results.push(CorpusStepResult {
+        step,
+        error: result.as_ref().err().map(|e| format!("{:?}", e)),
+        duration: start_time.elapsed(),
+    });
+    progress.increment();
+
+    Ok(result.ok())
+}
+
+fn _read_random_spectra(db: &Connection, entity_cache: &EntityCache, cancellation_token: Option<&CancellationToken>) -> Result<()> {
+    let spectra_count = entity_cache.spectrum_headers.len() as i64;
+
+    // Spread the reads over the whole file using a fixed stride
+    for i in 0..RANDOM_SPECTRA_COUNT.min(spectra_count) {
+        check_cancellation(cancellation_token)?;
+
+        let spectrum_header = &entity_cache.spectrum_headers[((i * 7919) % spectra_count) as usize];
+        let spectrum = get_spectrum(db, spectrum_header.id, entity_cache).location(here!())?;
+
+        if spectrum.data.peak_count != spectrum.data.mz_array.len() {
+            bail!("spectrum with ID={} has {} peaks but {} m/z values", spectrum_header.id, spectrum.data.peak_count, spectrum.data.mz_array.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Extract the MS1 XIC of the base peak of the most intense MS1 spectrum (see xic::get_ms1_xic())
+fn _extract_base_peak_xic(db: &Connection, entity_cache: &EntityCache) -> Result<()> {
+    let ms1_headers = entity_cache.spectrum_headers.iter().filter(|sh| sh.ms_level == 1);
+    let target_header = match ms1_headers.max_by(|sh1, sh2| sh1.base_peak_intensity.total_cmp(&sh2.base_peak_intensity)) {
+        Some(sh) => sh,
+        None => return Ok(()),
+    };
+
+    let xic = get_ms1_xic(db, entity_cache, target_header.base_peak_mz, XIC_TOL_PPM, None).location(here!())?;
+
+    if xic.peaks.iter().all(|peak| peak.intensity <= 0.0) {
+        bail!("empty XIC for the base peak m/z {} of spectrum with ID={}", target_header.base_peak_mz, target_header.id);
+    }
+
+    Ok(())
+}
+
+/// Decode the data points of all the chromatograms
+fn _decode_chromatograms(db: &Connection, cancellation_token: Option<&CancellationToken>) -> Result<()> {
+    for chrom_header in list_chromatogram_headers(db).location(here!())? {
+        check_cancellation(cancellation_token)?;
+
+        get_chromatogram_data(db, chrom_header.id).location(here!())?
+            .context(format!("can't find chromatogram with ID={}", chrom_header.id)).location(here!())?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,192 @@
+// Processing of DIA (data-independent acquisition) files.
+// Pseudo-MS2 spectra are built by aggregating the MS2 spectra of an isolation window acquired around a given retention time,
+// which allows DDA-style database searching of DIA files (a lightweight alternative to a full demultiplexing).
+
+use std::borrow::Cow;
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::model::*;
+use crate::mzdb::build_isolation_window_index;
+use crate::queries::get_spectrum;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PseudoSpectrum {
+    pub isolation_window: IsolationWindow,
+    pub rt_center: f64, // in seconds
+    pub spectrum_ids: Vec<i64>, // aggregated MS2 spectra, sorted by ID
+    pub mz_array: Vec<f64>, // intensity weighted m/z of each peak cluster, sorted
+    pub intensity_array: Vec<f32>, // summed intensity of each peak cluster
+    pub occurrences: Vec<usize>, // number of aggregated spectra contributing to each peak cluster
+}
+
+impl PseudoSpectrum {
+    /// Returns a copy of this pseudo-spectrum keeping only the peaks found in at least min_occurrences spectra
+    pub fn filter_by_occurrences(&self, min_occurrences: usize) -> PseudoSpectrum {
+        let kept_indices: Vec<usize> = (0..self.mz_array.len()).filter(|idx| self.occurrences[*idx] >= min_occurrences).collect();
+
+        PseudoSpectrum {
+            isolation_window: self.isolation_window,
+            rt_center: self.rt_center,
+            spectrum_ids: self.spectrum_ids.clone(),
+            mz_array: kept_indices.iter().map(|idx| self.mz_array[*idx]).collect(),
+            intensity_array: kept_indices.iter().map(|idx| self.intensity_array[*idx]).collect(),
+            occurrences: kept_indices.iter().map(|idx| self.occurrences[*idx]).collect(),
+        }
+    }
+}
+
+/// Build the pseudo-spectrum of the isolation window at the provided index (see IsolationWindowIndex),
+/// by aggregating its MS2 spectra whose time is in [rt_center - rt_tol, rt_center + rt_tol] (in seconds).
+/// Peaks are clustered across spectra using the m/z tolerance. Returns None if no spectrum matches.
+/// The isolation window index of the entity cache is used when available, otherwise it is built on the fly.
+pub fn build_pseudo_spectrum(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    window_idx: usize,
+    rt_center: f64,
+    rt_tol: f64,
+    mz_tolerance: &MzTolerance,
+) -> Result<Option<PseudoSpectrum>> {
+    let window_index = get_isolation_window_index(db, entity_cache).location(here!())?;
+
+    if window_idx >= window_index.isolation_windows.len() {
+        bail!("invalid isolation window index {}", window_idx);
+    }
+
+    _build_pseudo_spectrum(db, entity_cache, &window_index, window_idx, rt_center, rt_tol, mz_tolerance)
+}
+
+/// Build the pseudo-spectra of all the isolation windows at the provided retention time (see build_pseudo_spectrum()).
+/// Windows without MS2 spectra in the RT range are skipped, the returned pseudo-spectra are sorted by isolation window.
+pub fn build_pseudo_spectra(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    rt_center: f64,
+    rt_tol: f64,
+    mz_tolerance: &MzTolerance,
+) -> Result<Vec<PseudoSpectrum>> {
+    let window_index = get_isolation_window_index(db, entity_cache).location(here!())?;
+
+    let mut pseudo_spectra = Vec::new();
+    for window_idx in 0..window_index.isolation_windows.len() {
+        let pseudo_spectrum_opt = _build_pseudo_spectrum(
+            db, entity_cache, &window_index, window_idx, rt_center, rt_tol, mz_tolerance
+        ).location(here!())?;
+
+        pseudo_spectra.extend(pseudo_spectrum_opt);
+    }
+
+    Ok(pseudo_spectra)
+}
+
+/// Returns the isolation window index of the entity cache when available, otherwise it is built on the fly
+pub(crate) fn get_isolation_window_index<'a>(db: &Connection, entity_cache: &'a EntityCache) -> Result<Cow<'a, IsolationWindowIndex>> {
+    match entity_cache.isolation_window_index.as_ref() {
+        Some(window_index) => Ok(Cow::Borrowed(window_index)),
+        None => Ok(Cow::Owned(build_isolation_window_index(db, entity_cache).location(here!())?)),
+    }
+}
+
+fn _build_pseudo_spectrum(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    window_index: &IsolationWindowIndex,
+    window_idx: usize,
+    rt_center: f64,
+    rt_tol: f64,
+    mz_tolerance: &MzTolerance,
+) -> Result<Option<PseudoSpectrum>> {
+    let (min_rt, max_rt) = (rt_center - rt_tol, rt_center + rt_tol);
+
+    let spectrum_ids: Vec<i64> = window_index.get_spectrum_ids(window_idx).map_or(Vec::new(), |spectrum_ids| {
+        spectrum_ids.iter().copied()
+            .filter(|spectrum_id| {
+                let time = entity_cache.spectrum_headers[(*spectrum_id - 1) as usize].time_f64;
+                time >= min_rt && time <= max_rt
+            })
+            .collect()
+    });
+
+    if spectrum_ids.is_empty() {
+        return Ok(None);
+    }
+
+    // Collect the peaks of all the spectra, tagged by the index of their spectrum
+    let mut peaks: Vec<(f64, f32, usize)> = Vec::new();
+    for (spectrum_idx, spectrum_id) in spectrum_ids.iter().enumerate() {
+        let spectrum = get_spectrum(db, *spectrum_id, entity_cache).location(here!())?;
+        peaks.extend(spectrum.data.mz_array.iter().zip(spectrum.data.intensity_array.iter()).map(|(mz, intensity)| (*mz, *intensity, spectrum_idx)));
+    }
+
+    peaks.sort_by(|p1, p2| p1.0.total_cmp(&p2.0));
+
+    let mut pseudo_spectrum = PseudoSpectrum {
+        isolation_window: window_index.isolation_windows[window_idx],
+        rt_center,
+        spectrum_ids,
+        mz_array: Vec::new(),
+        intensity_array: Vec::new(),
+        occurrences: Vec::new(),
+    };
+
+    // Greedy clustering: a peak joins the current cluster if it is in the m/z tolerance of the cluster weighted m/z
+    let mut cluster = PeakCluster::default();
+    for (mz, intensity, spectrum_idx) in peaks {
+        if cluster.peak_count > 0 {
+            let cluster_mz = cluster.weighted_mz();
+            if mz - cluster_mz > mz_tolerance.to_da(cluster_mz) {
+                cluster.push_to(&mut pseudo_spectrum);
+                cluster = PeakCluster::default();
+            }
+        }
+
+        cluster.add_peak(mz, intensity, spectrum_idx);
+    }
+
+    if cluster.peak_count > 0 {
+        cluster.push_to(&mut pseudo_spectrum);
+    }
+
+    Ok(Some(pseudo_spectrum))
+}
+
+// Running sums of the peaks of a cluster, so that its weighted m/z is updated in constant time
+#[derive(Default)]
+struct PeakCluster {
+    peak_count: usize,
+    mz_sum: f64,
+    weighted_mz_sum: f64,
+    intensity_sum: f64,
+    spectrum_indices: Vec<usize>,
+}
+
+impl PeakCluster {
+    fn add_peak(&mut self, mz: f64, intensity: f32, spectrum_idx: usize) {
+        self.peak_count += 1;
+        self.mz_sum += mz;
+        self.weighted_mz_sum += mz * intensity as f64;
+        self.intensity_sum += intensity as f64;
+        self.spectrum_indices.push(spectrum_idx);
+    }
+
+    fn weighted_mz(&self) -> f64 {
+        if self.intensity_sum <= 0.0 {
+            return self.mz_sum / self.peak_count as f64;
+        }
+
+        self.weighted_mz_sum / self.intensity_sum
+    }
+
+    fn push_to(mut self, pseudo_spectrum: &mut PseudoSpectrum) {
+        self.spectrum_indices.sort_unstable();
+        self.spectrum_indices.dedup();
+
+        pseudo_spectrum.mz_array.push(self.weighted_mz());
+        pseudo_spectrum.intensity_array.push(self.intensity_sum as f32);
+        pseudo_spectrum.occurrences.push(self.spectrum_indices.len());
+    }
+}
@@ -0,0 +1,303 @@
+// Collection of the non-fatal anomalies of a mzDB file (schema differences, missing R-tree rows, inconsistent peak counts, unsorted m/z arrays, unknown CV terms...).
+// Such issues don't prevent the file from being read but may lead to incomplete or unexpected results.
+// collect_diagnostics() scans a whole file, while the anomalies encountered by the read operations are pushed to the DiagnosticsSink
+// of the EntityCache (e.g. unsorted m/z arrays found when decoding spectra).
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::iterator::for_each_bb;
+use crate::model::*;
+use crate::progress::{check_cancellation, CancellationToken, ProgressObserver, ProgressTracker};
+use crate::queries::{index_bbox, read_spectrum_slice_data_at};
+use crate::schema::schema_diff;
+use crate::xml::parse_cv_params;
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DiagnosticKind {
+    MISSING_TABLE,
+    MISSING_COLUMN,
+    EXTRA_COLUMN,
+    COLUMN_TYPE_MISMATCH,
+    MISSING_RTREE_ROW,
+    INCONSISTENT_PEAK_COUNT,
+    UNSORTED_MZ_ARRAY,
+    UNKNOWN_CV_TERM,
+    INVALID_PRECURSOR_LIST,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Diagnostics {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, kind: DiagnosticKind, message: String) {
+        self.diagnostics.push(Diagnostic { kind, message });
+    }
+
+    pub fn len(&self) -> usize {
+        self.diagnostics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter()
+    }
+
+    /// Returns the collected diagnostics and clears the collector
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+}
+
+/// Diagnostics shared by the read operations of an EntityCache, which may run concurrently.
+/// Clones hold a copy of the collected diagnostics.
+#[derive(Debug, Default)]
+pub struct DiagnosticsSink {
+    diagnostics: Mutex<Diagnostics>,
+}
+
+impl DiagnosticsSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, kind: DiagnosticKind, message: String) {
+        self._lock().push(kind, message);
+    }
+
+    pub fn len(&self) -> usize {
+        self._lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self._lock().is_empty()
+    }
+
+    /// Returns the collected diagnostics and clears the sink
+    pub fn take_diagnostics(&self) -> Vec<Diagnostic> {
+        self._lock().take_diagnostics()
+    }
+
+    // Diagnostics remain valid if a thread panicked while pushing one
+    fn _lock(&self) -> MutexGuard<'_, Diagnostics> {
+        self.diagnostics.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Clone for DiagnosticsSink {
+    fn clone(&self) -> Self {
+        DiagnosticsSink { diagnostics: Mutex::new(self._lock().clone()) }
+    }
+}
+
+impl PartialEq for DiagnosticsSink {
+    fn eq(&self, other: &Self) -> bool {
+        // The mutex can't be locked twice
+        std::ptr::eq(self, other) || *self._lock() == *other._lock()
+    }
+}
+
+/// Scan the file and collect its anomalies in the provided Diagnostics.
+/// Note: CV terms are only checked when the cv_term table is populated and the spectrum headers hold their param_tree.
+/// The progress is reported per bounding box (read twice) and per spectrum header.
+/// If a cancellation token is provided, it is polled for each bounding box and spectrum header (see progress::Cancelled).
+pub fn collect_diagnostics(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    diagnostics: &mut Diagnostics,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<()> {
+    let bb_count: i64 = db.query_row("SELECT count(*) FROM bounding_box", [], |row| row.get(0)).location(here!())?;
+    let mut progress = ProgressTracker::new(progress_observer, 2 * bb_count as usize + entity_cache.spectrum_headers.len());
+
+    _check_schema(db, diagnostics).location(here!())?;
+    _check_missing_rtree_rows(db, diagnostics, cancellation_token).location(here!())?;
+    _check_peak_counts(db, entity_cache, diagnostics, &mut progress, cancellation_token).location(here!())?;
+    _check_mz_order(db, entity_cache, diagnostics, &mut progress, cancellation_token).location(here!())?;
+    _check_cv_terms(db, entity_cache, diagnostics, &mut progress, cancellation_token).location(here!())?;
+
+    progress.finish();
+
+    Ok(())
+}
+
+fn _check_schema(db: &Connection, diagnostics: &mut Diagnostics) -> Result<()> {
+    let diff = schema_diff(db).location(here!())?;
+
+    for table_name in diff.missing_tables {
+        diagnostics.push(DiagnosticKind::MISSING_TABLE, format!("required table '{}' is missing", table_name));
+    }
+    for (table_name, column_name) in diff.missing_columns {
+        diagnostics.push(DiagnosticKind::MISSING_COLUMN, format!("required column '{}' of table '{}' is missing", column_name, table_name));
+    }
+    for (table_name, column_name) in diff.extra_columns {
+        diagnostics.push(DiagnosticKind::EXTRA_COLUMN, format!("column '{}' of table '{}' is not part of the mzDB schema", column_name, table_name));
+    }
+    for mismatch in diff.type_mismatches {
+        diagnostics.push(
+            DiagnosticKind::COLUMN_TYPE_MISMATCH,
+            format!(
+                "column '{}' of table '{}' is declared as '{}' but {:?} is expected",
+                mismatch.column_name, mismatch.table_name, mismatch.declared_type, mismatch.expected_type
+            )
+        );
+    }
+
+    Ok(())
+}
+
+fn _check_missing_rtree_rows(db: &Connection, diagnostics: &mut Diagnostics, cancellation_token: Option<&CancellationToken>) -> Result<()> {
+    let mut stmt = db.prepare(
+        "SELECT bounding_box.id FROM bounding_box, run_slice \
+        WHERE run_slice.id = bounding_box.run_slice_id AND run_slice.ms_level = 1 \
+        AND bounding_box.id NOT IN (SELECT id FROM bounding_box_rtree)"
+    ).location(here!())?;
+
+    let mut rows = stmt.query([]).location(here!())?;
+    while let Some(row) = rows.next().location(here!())? {
+        check_cancellation(cancellation_token)?;
+
+        let bb_id: i64 = row.get(0).location(here!())?;
+        diagnostics.push(
+            DiagnosticKind::MISSING_RTREE_ROW,
+            format!("MS1 bounding box with ID={} is not referenced in bounding_box_rtree", bb_id)
+        );
+    }
+
+    Ok(())
+}
+
+fn _check_peak_counts(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    diagnostics: &mut Diagnostics,
+    progress: &mut ProgressTracker,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<()> {
+    let mut peak_count_by_spectrum_id: HashMap<i64, usize> = HashMap::with_capacity(entity_cache.spectrum_headers.len());
+
+    for_each_bb(db, None, |bb: BoundingBox| {
+        check_cancellation(cancellation_token)?;
+
+        let bb_index = index_bbox(&bb, &entity_cache.data_encodings_cache).location(here!())?;
+        for (spectrum_id, peak_count) in bb_index.spectra_ids.iter().zip(bb_index.peaks_counts.iter()) {
+            *peak_count_by_spectrum_id.entry(*spectrum_id).or_insert(0) += peak_count;
+        }
+        progress.increment();
+
+        Ok(())
+    }).location(here!())?;
+
+    for spectrum_header in entity_cache.spectrum_headers.iter() {
+        let stored_peak_count = peak_count_by_spectrum_id.get(&spectrum_header.id).copied().unwrap_or(0);
+        if stored_peak_count as i64 != spectrum_header.peaks_count {
+            diagnostics.push(
+                DiagnosticKind::INCONSISTENT_PEAK_COUNT,
+                format!(
+                    "spectrum with ID={} declares {} peaks but {} are stored in its bounding boxes",
+                    spectrum_header.id, spectrum_header.peaks_count, stored_peak_count
+                )
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn _check_mz_order(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    diagnostics: &mut Diagnostics,
+    progress: &mut ProgressTracker,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<()> {
+    let de_cache = &entity_cache.data_encodings_cache;
+    let mut unsorted_spectrum_ids = BTreeSet::new();
+
+    for_each_bb(db, None, |bb: BoundingBox| {
+        check_cancellation(cancellation_token)?;
+
+        let bb_index = index_bbox(&bb, de_cache).location(here!())?;
+
+        for (slice_idx, spectrum_id) in bb_index.spectra_ids.iter().enumerate() {
+            let data_encoding = de_cache.get_data_encoding_by_spectrum_id(spectrum_id)
+                .context(format!("can't retrieve data encoding for spectrum ID={}", spectrum_id)).location(here!())?;
+
+            let slice_data = read_spectrum_slice_data_at(&bb, &bb_index, data_encoding, slice_idx, None, None).location(here!())?;
+            if !slice_data.is_mz_sorted() {
+                unsorted_spectrum_ids.insert(*spectrum_id);
+            }
+        }
+        progress.increment();
+
+        Ok(())
+    }).location(here!())?;
+
+    for spectrum_id in unsorted_spectrum_ids {
+        diagnostics.push(
+            DiagnosticKind::UNSORTED_MZ_ARRAY,
+            format!("spectrum with ID={} has unsorted m/z values in its bounding boxes", spectrum_id)
+        );
+    }
+
+    Ok(())
+}
+
+fn _check_cv_terms(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    diagnostics: &mut Diagnostics,
+    progress: &mut ProgressTracker,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<()> {
+    let has_cv_terms: bool = db.query_row("SELECT EXISTS(SELECT 1 FROM cv_term)", [], |row| row.get(0)).location(here!())?;
+    if !has_cv_terms {
+        return Ok(());
+    }
+
+    let mut stmt = db.prepare("SELECT accession FROM cv_term").location(here!())?;
+    let known_accessions = stmt.query_map([], |row| row.get::<_, String>(0)).location(here!())?
+        .collect::<rusqlite::Result<HashSet<String>>>().location(here!())?;
+
+    // Each unknown accession is reported only once
+    let mut unknown_accessions = HashSet::new();
+    for spectrum_header in entity_cache.spectrum_headers.iter() {
+        check_cancellation(cancellation_token)?;
+
+        if let Some(param_tree_str) = spectrum_header.param_tree_str.as_ref() {
+            for cv_param in parse_cv_params(param_tree_str).location(here!())? {
+                if !known_accessions.contains(&cv_param.accession) && unknown_accessions.insert(cv_param.accession.clone()) {
+                    diagnostics.push(
+                        DiagnosticKind::UNKNOWN_CV_TERM,
+                        format!("CV term {} ({}) used by spectrum with ID={} is not defined in cv_term", cv_param.accession, cv_param.name, spectrum_header.id)
+                    );
+                }
+            }
+        }
+        progress.increment();
+    }
+
+    Ok(())
+}
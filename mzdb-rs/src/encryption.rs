@@ -0,0 +1,62 @@
+// Encryption at rest of mzDB files, backed by SQLCipher (sqlcipher feature).
+// Without the feature, the functions of this module return an explicit error instead of the generic "file is not a database".
+
+use anyhow::*;
+#[cfg(feature = "sqlcipher")]
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+#[cfg(feature = "sqlcipher")]
+use rusqlite::OpenFlags;
+
+#[cfg(not(feature = "sqlcipher"))]
+const SQLCIPHER_FEATURE_ERROR: &str = "encrypted mzDB files are only supported when mzdb-rs is built with the sqlcipher feature";
+
+/// Open an encrypted mzDB file in read-only mode
+#[cfg(feature = "sqlcipher")]
+pub fn open_encrypted(path: &str, key: &str) -> Result<Connection> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).location(here!())?;
+    db.pragma_update(None, "key", key).location(here!())?;
+
+    // The key is only checked when the first page is read
+    _check_key(&db).context(format!("can't decrypt file {}: invalid key or not an encrypted mzDB file", path)).location(here!())?;
+
+    Ok(db)
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+pub fn open_encrypted(path: &str, _key: &str) -> Result<Connection> {
+    bail!("can't open file {}: {}", path, SQLCIPHER_FEATURE_ERROR)
+}
+
+/// Write an encrypted copy of a plain mzDB file (dest_path must not exist)
+#[cfg(feature = "sqlcipher")]
+pub fn encrypt_file(src_path: &str, dest_path: &str, key: &str) -> Result<()> {
+    if !std::path::Path::new(src_path).is_file() {
+        bail!("can't encrypt {}: file not found", src_path);
+    }
+    if std::path::Path::new(dest_path).exists() {
+        bail!("can't encrypt {} to {}: the destination file already exists", src_path, dest_path);
+    }
+
+    // Note: the attached database inherits the open flags of the connection, which must thus allow its creation
+    let db = Connection::open_with_flags(src_path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE).location(here!())?;
+    _check_key(&db).context(format!("file {} is not a plain mzDB file", src_path)).location(here!())?;
+
+    db.execute("ATTACH DATABASE ?1 AS encrypted KEY ?2", [dest_path, key]).location(here!())?;
+    db.query_row("SELECT sqlcipher_export('encrypted')", [], |_row| rusqlite::Result::Ok(())).location(here!())?;
+    db.execute("DETACH DATABASE encrypted", []).location(here!())?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+pub fn encrypt_file(src_path: &str, _dest_path: &str, _key: &str) -> Result<()> {
+    bail!("can't encrypt file {}: {}", src_path, SQLCIPHER_FEATURE_ERROR)
+}
+
+#[cfg(feature = "sqlcipher")]
+fn _check_key(db: &Connection) -> Result<()> {
+    db.query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0)).location(here!())?;
+    Ok(())
+}
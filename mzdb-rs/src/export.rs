@@ -0,0 +1,454 @@
+// Export of a selection of spectra to mzML, and of chromatograms to CSV/TSV.
+// The XML fragments stored in the spectrum table (scan_list, precursor_list and product_list) are already mzML elements,
+// they are copied verbatim so that search engines see the original metadata (injection times, filter strings, precursors...).
+// Peaks are written uncompressed, with 64-bit m/z values and 32-bit intensities.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::metadata::{get_chromatogram_type, get_file_metadata};
+use crate::model::*;
+use crate::mzdb::is_lossless;
+use crate::progress::{check_cancellation, CancellationToken, ProgressObserver, ProgressTracker};
+use crate::queries::{get_chromatogram_data, get_spectrum, list_chromatogram_headers};
+use crate::xml::{parse_cv_params, parse_user_params};
+
+const MZML_NAMESPACE: &str = "http://psi.hupo.org/ms/mzml";
+const MZML_VERSION: &str = "1.1.0";
+const INSTRUMENT_CONFIGURATION_REF_ATTR: &str = "instrumentConfigurationRef=\"";
+
+// XML fields of a spectrum header, fetched from the database when the EntityCache has been created in light mode
+struct SpectrumXmlFields {
+    param_tree: Option<String>,
+    scan_list: Option<String>,
+    precursor_list: Option<String>,
+    product_list: Option<String>,
+}
+
+/// Write the provided MS2 (or higher MS level) spectra to a mzML file, in the order of spectrum_ids.
+/// The file, software, instrument and data processing metadata of the mzDB file are written too,
+/// so that the instrumentConfigurationRef of the copied scan lists remain valid.
+/// Instrument configurations referenced by the scan lists but missing from the mzDB file are written as empty placeholders.
+/// Note: a warning is logged for lossless files, since intensities are decoded and written as 32-bit floats.
+/// The progress is reported after each written spectrum.
+/// If a cancellation token is provided, it is polled before each written spectrum (the file is then left incomplete).
+/// Returns the number of written spectra.
+pub fn write_msms_mzml(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    spectrum_ids: &[i64],
+    path: &str,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<usize> {
+    // Check the selection before creating the file
+    let mut xml_fields_list = Vec::with_capacity(spectrum_ids.len());
+    for spectrum_id in spectrum_ids {
+        let spectrum_header = entity_cache.spectrum_headers.get((*spectrum_id - 1) as usize)
+            .filter(|sh| sh.id == *spectrum_id)
+            .context(format!("can't retrieve header for spectrum ID={}", spectrum_id)).location(here!())?;
+
+        if spectrum_header.ms_level < 2 {
+            bail!("spectrum with ID={} is not a MSn spectrum (MS level = {})", spectrum_id, spectrum_header.ms_level);
+        }
+
+        xml_fields_list.push(_get_spectrum_xml_fields(db, spectrum_header).location(here!())?);
+    }
+
+    if is_lossless(db).location(here!())? {
+        log::warn!("the mzDB file is lossless but intensities are exported as 32-bit floats, precision will be lost");
+    }
+
+    let metadata = get_file_metadata(db).location(here!())?;
+
+    let mut missing_instrument_names: Vec<String> = Vec::new();
+    for scan_list in xml_fields_list.iter().filter_map(|xml_fields| xml_fields.scan_list.as_ref()) {
+        for instrument_name in _find_instrument_configuration_refs(scan_list) {
+            let is_missing = metadata.instruments.iter().all(|instrument| instrument.configuration.name != instrument_name);
+            if is_missing && !missing_instrument_names.iter().any(|name| name == instrument_name) {
+                missing_instrument_names.push(instrument_name.to_string());
+            }
+        }
+    }
+    let run = metadata.runs.first().context("no run found in the mzDB file").location(here!())?;
+
+    let file = File::create(path).context(format!("can't create file {}", path)).location(here!())?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "<?xml version=\"1.0\" encoding=\"utf-8\"?>")?;
+    writeln!(writer, "<mzML xmlns=\"{}\" version=\"{}\">", MZML_NAMESPACE, MZML_VERSION)?;
+    writeln!(writer, "  <cvList count=\"2\">")?;
+    writeln!(writer, "    <cv id=\"MS\" fullName=\"Proteomics Standards Initiative Mass Spectrometry Ontology\" URI=\"https://raw.githubusercontent.com/HUPO-PSI/psi-ms-CV/master/psi-ms.obo\"/>")?;
+    writeln!(writer, "    <cv id=\"UO\" fullName=\"Unit Ontology\" URI=\"https://raw.githubusercontent.com/bio-ontology-research-group/unit-ontology/master/unit.obo\"/>")?;
+    writeln!(writer, "  </cvList>")?;
+
+    _write_file_description(&mut writer, &metadata).location(here!())?;
+    _write_software_list(&mut writer, &metadata).location(here!())?;
+    _write_instrument_configuration_list(&mut writer, &metadata, &missing_instrument_names).location(here!())?;
+    _write_data_processing_list(&mut writer, &metadata).location(here!())?;
+
+    let instrument_name_by_id: HashMap<i64, &str> = metadata.instruments.iter()
+        .map(|instrument| (instrument.configuration.id, instrument.configuration.name.as_str()))
+        .collect();
+    let default_instrument_name = instrument_name_by_id.get(&run.default_instrument_config_id)
+        .context(format!("can't retrieve instrument configuration with ID={}", run.default_instrument_config_id)).location(here!())?;
+
+    write!(writer, "  <run id=\"{}\" defaultInstrumentConfigurationRef=\"{}\"", _escape(&run.name), _escape(default_instrument_name))?;
+    if let Some(source_file_id) = run.default_source_file_id {
+        write!(writer, " defaultSourceFileRef=\"{}\"", _source_file_ref(source_file_id))?;
+    }
+    writeln!(writer, ">")?;
+    writeln!(writer, "    <spectrumList count=\"{}\" defaultDataProcessingRef=\"{}\">", spectrum_ids.len(), _data_processing_ref(run.default_scan_processing_id))?;
+
+    let mut progress = ProgressTracker::new(progress_observer, spectrum_ids.len());
+    for (spectrum_idx, (spectrum_id, xml_fields)) in spectrum_ids.iter().zip(xml_fields_list.iter()).enumerate() {
+        check_cancellation(cancellation_token)?;
+
+        let spectrum = get_spectrum(db, *spectrum_id, entity_cache).location(here!())?;
+        _write_spectrum(&mut writer, spectrum_idx, &spectrum, xml_fields).location(here!())?;
+        progress.increment();
+    }
+
+    writeln!(writer, "    </spectrumList>")?;
+    writeln!(writer, "  </run>")?;
+    writeln!(writer, "</mzML>")?;
+
+    writer.flush().location(here!())?;
+
+    Ok(spectrum_ids.len())
+}
+
+// Entry of the JSON sidecar written by write_chromatograms_csv()
+#[derive(Serialize)]
+struct ChromatogramSidecarEntry<'a> {
+    #[serde(flatten)]
+    header: &'a ChromatogramHeader,
+    chromatogram_type: String,
+    data_points_count: usize,
+}
+
+/// Write the data points of the selected chromatograms (all of them if chromatogram_ids is None) in long format,
+/// one row per data point with the columns chrom_id, name, type, rt (in seconds) and intensity.
+/// The separator is a tab for .tsv files and a comma otherwise.
+/// The chromatogram headers are written to a JSON sidecar file, having the same path with the .json extension.
+/// The progress is reported after each written chromatogram.
+/// If a cancellation token is provided, it is polled before each written chromatogram (the sidecar file is then not written).
+/// Returns the number of written rows.
+pub fn write_chromatograms_csv(
+    db: &Connection,
+    chromatogram_ids: Option<&[i64]>,
+    path: &str,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<usize> {
+    let chrom_headers = list_chromatogram_headers(db).location(here!())?;
+
+    // Check the selection before creating the files
+    let selected_headers: Vec<&ChromatogramHeader> = match chromatogram_ids {
+        None => chrom_headers.iter().collect(),
+        Some(ids) => {
+            let mut selected_headers = Vec::with_capacity(ids.len());
+            for chrom_id in ids {
+                let chrom_header = chrom_headers.iter().find(|ch| ch.id == *chrom_id)
+                    .context(format!("can't retrieve chromatogram with ID={}", chrom_id)).location(here!())?;
+                selected_headers.push(chrom_header);
+            }
+            selected_headers
+        }
+    };
+
+    let separator = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("tsv") => '\t',
+        _ => ',',
+    };
+
+    let file = File::create(path).context(format!("can't create file {}", path)).location(here!())?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "chrom_id{0}name{0}type{0}rt{0}intensity", separator)?;
+
+    let mut rows_count = 0;
+    let mut sidecar_entries = Vec::with_capacity(selected_headers.len());
+    let mut progress = ProgressTracker::new(progress_observer, selected_headers.len());
+    for chrom_header in selected_headers {
+        check_cancellation(cancellation_token)?;
+
+        let chrom_type = format!("{:?}", get_chromatogram_type(db, chrom_header).location(here!())?);
+        let chrom_data = get_chromatogram_data(db, chrom_header.id).location(here!())?
+            .context(format!("can't retrieve data points of chromatogram with ID={}", chrom_header.id)).location(here!())?;
+
+        let name = _escape_csv_field(&chrom_header.name, separator);
+        for (rt, intensity) in chrom_data.time_array.iter().zip(chrom_data.intensity_array.iter()) {
+            writeln!(writer, "{1}{0}{2}{0}{3}{0}{4}{0}{5}", separator, chrom_header.id, name, chrom_type, rt, intensity)?;
+        }
+        rows_count += chrom_data.time_array.len();
+
+        sidecar_entries.push(ChromatogramSidecarEntry {
+            header: chrom_header,
+            chromatogram_type: chrom_type,
+            data_points_count: chrom_data.time_array.len(),
+        });
+        progress.increment();
+    }
+
+    writer.flush().location(here!())?;
+
+    let sidecar_path = Path::new(path).with_extension("json");
+    let sidecar_file = File::create(&sidecar_path).context(format!("can't create file {}", sidecar_path.display())).location(here!())?;
+    serde_json::to_writer_pretty(BufWriter::new(sidecar_file), &sidecar_entries).location(here!())?;
+
+    Ok(rows_count)
+}
+
+// Quote a CSV field if it contains the separator, a quote or a line break (quotes being doubled)
+fn _escape_csv_field(value: &str, separator: char) -> String {
+    if value.contains(separator) || value.contains('"') || value.contains('\n') || value.contains('\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn _write_file_description<W: Write>(writer: &mut W, metadata: &FileMetadata) -> Result<()> {
+    writeln!(writer, "  <fileDescription>")?;
+    writeln!(writer, "    <fileContent>")?;
+    writeln!(writer, "      <cvParam cvRef=\"MS\" accession=\"MS:1000580\" name=\"MSn spectrum\" value=\"\"/>")?;
+    writeln!(writer, "    </fileContent>")?;
+
+    if !metadata.source_files.is_empty() {
+        writeln!(writer, "    <sourceFileList count=\"{}\">", metadata.source_files.len())?;
+        for source_file in metadata.source_files.iter() {
+            writeln!(
+                writer, "      <sourceFile id=\"{}\" name=\"{}\" location=\"{}\">",
+                _source_file_ref(source_file.id), _escape(&source_file.name), _escape(&source_file.location)
+            )?;
+            _write_param_tree(writer, "        ", Some(&source_file.param_tree)).location(here!())?;
+            writeln!(writer, "      </sourceFile>")?;
+        }
+        writeln!(writer, "    </sourceFileList>")?;
+    }
+
+    writeln!(writer, "  </fileDescription>")?;
+
+    Ok(())
+}
+
+fn _write_software_list<W: Write>(writer: &mut W, metadata: &FileMetadata) -> Result<()> {
+    writeln!(writer, "  <softwareList count=\"{}\">", metadata.software.len())?;
+    for software in metadata.software.iter() {
+        writeln!(writer, "    <software id=\"{}\" version=\"{}\">", _software_ref(software.id), _escape(&software.version))?;
+        _write_param_tree(writer, "      ", Some(&software.param_tree)).location(here!())?;
+        writeln!(writer, "    </software>")?;
+    }
+    writeln!(writer, "  </softwareList>")?;
+
+    Ok(())
+}
+
+fn _write_instrument_configuration_list<W: Write>(writer: &mut W, metadata: &FileMetadata, missing_instrument_names: &[String]) -> Result<()> {
+    let instruments_count = metadata.instruments.len() + missing_instrument_names.len();
+    writeln!(writer, "  <instrumentConfigurationList count=\"{}\">", instruments_count)?;
+    for instrument in metadata.instruments.iter() {
+        writeln!(writer, "    <instrumentConfiguration id=\"{}\">", _escape(&instrument.configuration.name))?;
+        _write_param_tree(writer, "      ", instrument.configuration.param_tree.as_deref()).location(here!())?;
+
+        let components = &instrument.components.components;
+        writeln!(writer, "      <componentList count=\"{}\">", components.len())?;
+        for component in components.iter() {
+            let element_name = match component.component_type {
+                ComponentType::SOURCE => "source",
+                ComponentType::ANALYZER => "analyzer",
+                ComponentType::DETECTOR => "detector",
+            };
+
+            writeln!(writer, "        <{} order=\"{}\">", element_name, component.order)?;
+            _write_params(writer, "          ", &component.cv_params, &component.user_params).location(here!())?;
+            writeln!(writer, "        </{}>", element_name)?;
+        }
+        writeln!(writer, "      </componentList>")?;
+
+        writeln!(writer, "      <softwareRef ref=\"{}\"/>", _software_ref(instrument.configuration.software_id))?;
+        writeln!(writer, "    </instrumentConfiguration>")?;
+    }
+
+    for instrument_name in missing_instrument_names {
+        writeln!(writer, "    <instrumentConfiguration id=\"{}\">", _escape(instrument_name))?;
+        writeln!(writer, "      <userParam name=\"instrument configuration missing from the mzDB file\" value=\"\"/>")?;
+        writeln!(writer, "    </instrumentConfiguration>")?;
+    }
+    writeln!(writer, "  </instrumentConfigurationList>")?;
+
+    Ok(())
+}
+
+fn _write_data_processing_list<W: Write>(writer: &mut W, metadata: &FileMetadata) -> Result<()> {
+    writeln!(writer, "  <dataProcessingList count=\"{}\">", metadata.data_processings.len())?;
+    for data_processing in metadata.data_processings.iter() {
+        writeln!(writer, "    <dataProcessing id=\"{}\">", _data_processing_ref(data_processing.id))?;
+
+        let processing_methods = metadata.processing_methods.iter().filter(|pm| pm.data_processing_id == data_processing.id);
+        for processing_method in processing_methods {
+            writeln!(
+                writer, "      <processingMethod order=\"{}\" softwareRef=\"{}\">",
+                processing_method.number, _software_ref(processing_method.software_id)
+            )?;
+            _write_param_tree(writer, "        ", Some(&processing_method.param_tree)).location(here!())?;
+            writeln!(writer, "      </processingMethod>")?;
+        }
+
+        writeln!(writer, "    </dataProcessing>")?;
+    }
+    writeln!(writer, "  </dataProcessingList>")?;
+
+    Ok(())
+}
+
+fn _get_spectrum_xml_fields(db: &Connection, header: &SpectrumHeader) -> Result<SpectrumXmlFields> {
+    if header.param_tree_str.is_some() {
+        return Ok(SpectrumXmlFields {
+            param_tree: header.param_tree_str.clone(),
+            scan_list: header.scan_list_str.clone(),
+            precursor_list: header.precursor_list_str.clone(),
+            product_list: header.product_list_str.clone(),
+        });
+    }
+
+    db.query_row(
+        "SELECT param_tree, scan_list, precursor_list, product_list FROM spectrum WHERE id = ?",
+        [header.id],
+        |row| rusqlite::Result::Ok(SpectrumXmlFields {
+            param_tree: row.get(0)?,
+            scan_list: row.get(1)?,
+            precursor_list: row.get(2)?,
+            product_list: row.get(3)?,
+        }),
+    ).location(here!())
+}
+
+fn _find_instrument_configuration_refs(scan_list: &str) -> Vec<&str> {
+    scan_list.match_indices(INSTRUMENT_CONFIGURATION_REF_ATTR)
+        .filter_map(|(attr_pos, _)| {
+            let value_start = attr_pos + INSTRUMENT_CONFIGURATION_REF_ATTR.len();
+            scan_list[value_start..].find('"').map(|value_len| &scan_list[value_start..value_start + value_len])
+        })
+        .collect()
+}
+
+fn _write_spectrum<W: Write>(writer: &mut W, spectrum_idx: usize, spectrum: &Spectrum, xml_fields: &SpectrumXmlFields) -> Result<()> {
+    let header = &spectrum.header;
+    let data = &spectrum.data;
+
+    writeln!(
+        writer, "      <spectrum index=\"{}\" id=\"{}\" defaultArrayLength=\"{}\">",
+        spectrum_idx, _escape(&header.title), data.peak_count
+    )?;
+
+    _write_param_tree(writer, "        ", xml_fields.param_tree.as_deref()).location(here!())?;
+
+    for xml_fragment in [&xml_fields.scan_list, &xml_fields.precursor_list, &xml_fields.product_list].into_iter().flatten() {
+        if !xml_fragment.trim().is_empty() {
+            writeln!(writer, "{}", xml_fragment.trim_end())?;
+        }
+    }
+
+    let mz_bytes: Vec<u8> = data.mz_array.iter().flat_map(|mz| mz.to_le_bytes()).collect();
+    let intensity_bytes: Vec<u8> = data.intensity_array.iter().flat_map(|intensity| intensity.to_le_bytes()).collect();
+
+    writeln!(writer, "        <binaryDataArrayList count=\"2\">")?;
+    _write_binary_data_array(
+        writer,
+        &mz_bytes,
+        "<cvParam cvRef=\"MS\" accession=\"MS:1000523\" name=\"64-bit float\" value=\"\"/>",
+        "<cvParam cvRef=\"MS\" accession=\"MS:1000514\" name=\"m/z array\" value=\"\" unitCvRef=\"MS\" unitAccession=\"MS:1000040\" unitName=\"m/z\"/>",
+    ).location(here!())?;
+    _write_binary_data_array(
+        writer,
+        &intensity_bytes,
+        "<cvParam cvRef=\"MS\" accession=\"MS:1000521\" name=\"32-bit float\" value=\"\"/>",
+        "<cvParam cvRef=\"MS\" accession=\"MS:1000515\" name=\"intensity array\" value=\"\" unitCvRef=\"MS\" unitAccession=\"MS:1000131\" unitName=\"number of detector counts\"/>",
+    ).location(here!())?;
+    writeln!(writer, "        </binaryDataArrayList>")?;
+
+    writeln!(writer, "      </spectrum>")?;
+
+    Ok(())
+}
+
+fn _write_binary_data_array<W: Write>(writer: &mut W, bytes: &[u8], precision_cv_param: &str, array_cv_param: &str) -> Result<()> {
+    let encoded_data = base64::encode(bytes);
+
+    writeln!(writer, "          <binaryDataArray encodedLength=\"{}\">", encoded_data.len())?;
+    writeln!(writer, "            {}", precision_cv_param)?;
+    writeln!(writer, "            <cvParam cvRef=\"MS\" accession=\"MS:1000576\" name=\"no compression\" value=\"\"/>")?;
+    writeln!(writer, "            {}", array_cv_param)?;
+    writeln!(writer, "            <binary>{}</binary>", encoded_data)?;
+    writeln!(writer, "          </binaryDataArray>")?;
+
+    Ok(())
+}
+
+/// Write the params of a mzDB param tree (<params><cvParams>...</cvParams><userParams>...</userParams></params>) as mzML params
+fn _write_param_tree<W: Write>(writer: &mut W, indent: &str, param_tree_opt: Option<&str>) -> Result<()> {
+    let param_tree = match param_tree_opt {
+        Some(param_tree) if !param_tree.trim().is_empty() => param_tree,
+        _ => return Ok(()),
+    };
+
+    let cv_params = parse_cv_params(param_tree).location(here!())?;
+    let user_params = parse_user_params(param_tree).location(here!())?;
+
+    _write_params(writer, indent, &cv_params, &user_params)
+}
+
+fn _write_params<W: Write>(writer: &mut W, indent: &str, cv_params: &[CvParam], user_params: &[UserParam]) -> Result<()> {
+    for cv_param in cv_params {
+        write!(
+            writer, "{}<cvParam cvRef=\"{}\" accession=\"{}\" name=\"{}\" value=\"{}\"",
+            indent, _escape(&cv_param.cv_ref), _escape(&cv_param.accession), _escape(&cv_param.name), _escape(&cv_param.value)
+        )?;
+        if !cv_param.unit_accession.is_empty() {
+            write!(
+                writer, " unitCvRef=\"{}\" unitAccession=\"{}\" unitName=\"{}\"",
+                _escape(&cv_param.unit_cv_ref), _escape(&cv_param.unit_accession), _escape(&cv_param.unit_name)
+            )?;
+        }
+        writeln!(writer, "/>")?;
+    }
+
+    for user_param in user_params {
+        write!(writer, "{}<userParam name=\"{}\" value=\"{}\"", indent, _escape(&user_param.name), _escape(&user_param.value))?;
+        if !user_param.r#type.is_empty() {
+            write!(writer, " type=\"{}\"", _escape(&user_param.r#type))?;
+        }
+        writeln!(writer, "/>")?;
+    }
+
+    Ok(())
+}
+
+fn _software_ref(software_id: i64) -> String {
+    format!("software_{}", software_id)
+}
+
+fn _source_file_ref(source_file_id: i64) -> String {
+    format!("source_file_{}", source_file_id)
+}
+
+fn _data_processing_ref(data_processing_id: i64) -> String {
+    format!("data_processing_{}", data_processing_id)
+}
+
+fn _escape(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
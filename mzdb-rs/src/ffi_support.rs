@@ -0,0 +1,207 @@
+// Safe core shared by the language bindings (pymzdb, rmzdb).
+// The bindings wrap a SharedReader instead of storing the address of the raw sqlite3 handle,
+// which made the connection leak on drop and allowed its use after being closed.
+
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::{Connection, OpenFlags};
+
+use crate::cache_registry::ReaderCacheRegistry;
+use crate::diagnostics::Diagnostic;
+use crate::model::EntityCache;
+use crate::mzdb::create_entity_cache;
+use crate::queries::{get_last_cycle_number, get_last_time, get_max_ms_level};
+
+/// A mzDB connection and its entity cache, which can be cloned and sent across threads.
+/// The connection is closed when close() is called or when the last clone is dropped.
+#[derive(Clone, Debug)]
+pub struct SharedReader {
+    connection: Arc<Mutex<Option<Connection>>>,
+    entity_cache: Arc<EntityCache>,
+    metadata_scalars: Arc<Mutex<MetadataScalars>>,
+}
+
+/// Metadata scalars read on first access, None until then
+#[derive(Clone, Debug, Default)]
+struct MetadataScalars {
+    last_cycle_number: Option<Option<i64>>,
+    last_time: Option<Option<f32>>,
+    max_ms_level: Option<Option<i64>>,
+}
+
+/// Exclusive access to the connection of a SharedReader, released when dropped.
+/// Note: the blocking methods of the reader must not be called again before the guard is dropped (e.g. from a callback),
+/// this would deadlock. The bindings use the try_ methods instead, which return an error when the reader is in use.
+pub struct SharedConnection<'a> {
+    guard: MutexGuard<'a, Option<Connection>>,
+}
+
+impl<'a> Deref for SharedConnection<'a> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        // The guard can only be created when the connection is open (see SharedReader::connection)
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl SharedReader {
+
+    /// Open a mzDB file in read-only mode
+    pub fn open(path: &str) -> Result<Self> {
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .context(format!("can't open file {}", path)).location(here!())?;
+        Self::from_connection(db)
+    }
+
+    /// Open a mzDB file in read-only mode, reusing the caches of the registry (see cache_registry)
+    pub fn open_with_registry(path: &str, registry: &ReaderCacheRegistry) -> Result<Self> {
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .context(format!("can't open file {}", path)).location(here!())?;
+        let caches = registry.get_or_create(path, &db, false).location(here!())?;
+
+        Ok(SharedReader {
+            connection: Arc::new(Mutex::new(Some(db))),
+            entity_cache: Arc::clone(&caches.entity_cache),
+            metadata_scalars: Arc::new(Mutex::new(MetadataScalars::default())),
+        })
+    }
+
+    pub fn from_connection(db: Connection) -> Result<Self> {
+        let entity_cache = create_entity_cache(&db).location(here!())?;
+
+        Ok(SharedReader {
+            connection: Arc::new(Mutex::new(Some(db))),
+            entity_cache: Arc::new(entity_cache),
+            metadata_scalars: Arc::new(Mutex::new(MetadataScalars::default())),
+        })
+    }
+
+    /// Doesn't block: a reader being in use is open
+    pub fn is_closed(&self) -> bool {
+        match self.connection.try_lock() {
+            Result::Ok(guard) => guard.is_none(),
+            Err(TryLockError::WouldBlock) => false,
+            Err(TryLockError::Poisoned(_)) => true,
+        }
+    }
+
+    /// Close the connection for all the clones of this reader (closing an already closed reader is a no-op)
+    pub fn close(&self) -> Result<()> {
+        Self::_close(self._lock()?)
+    }
+
+    /// Same as close() but an error is returned instead of waiting if the reader is in use
+    pub fn try_close(&self) -> Result<()> {
+        Self::_close(self._try_lock()?)
+    }
+
+    fn _close(mut guard: MutexGuard<'_, Option<Connection>>) -> Result<()> {
+        if let Some(db) = guard.take() {
+            if let Err((db, e)) = db.close() {
+                // Keep the connection so that closing can be attempted again
+                *guard = Some(db);
+                return Err(e).context("can't close connection").location(here!());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn connection(&self) -> Result<SharedConnection<'_>> {
+        Self::_to_shared_connection(self._lock()?)
+    }
+
+    /// Same as connection() but an error is returned instead of waiting if the reader is in use,
+    /// which prevents deadlocks when the reader is used again from a callback receiving the spectra of an iteration
+    pub fn try_connection(&self) -> Result<SharedConnection<'_>> {
+        Self::_to_shared_connection(self._try_lock()?)
+    }
+
+    fn _to_shared_connection(guard: MutexGuard<'_, Option<Connection>>) -> Result<SharedConnection<'_>> {
+        if guard.is_none() {
+            bail!("database is closed");
+        }
+
+        Ok(SharedConnection { guard })
+    }
+
+    pub fn entity_cache(&self) -> &EntityCache {
+        &self.entity_cache
+    }
+
+    /// Returns the anomalies encountered by the read operations since the last call (see EntityCache.diagnostics).
+    /// Note: the entity caches provided by a ReaderCacheRegistry are shared, and so are their diagnostics.
+    pub fn take_diagnostics(&self) -> Vec<Diagnostic> {
+        self.entity_cache.diagnostics.take_diagnostics()
+    }
+
+    /// Run the provided function with exclusive access to the connection
+    pub fn with_connection<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&Connection, &EntityCache) -> Result<T> {
+
+        let db = self.connection().location(here!())?;
+        f(&db, &self.entity_cache)
+    }
+
+    /// Same as queries::get_last_cycle_number(), the value being read on first access only (see refresh())
+    pub fn get_last_cycle_number(&self) -> Result<Option<i64>> {
+        self._get_memoized_scalar(|scalars| &mut scalars.last_cycle_number, get_last_cycle_number)
+    }
+
+    /// Same as queries::get_last_time(), the value being read on first access only (see refresh())
+    pub fn get_last_time(&self) -> Result<Option<f32>> {
+        self._get_memoized_scalar(|scalars| &mut scalars.last_time, get_last_time)
+    }
+
+    /// Same as queries::get_max_ms_level(), the value being read on first access only (see refresh())
+    pub fn get_max_ms_level(&self) -> Result<Option<i64>> {
+        self._get_memoized_scalar(|scalars| &mut scalars.max_ms_level, get_max_ms_level)
+    }
+
+    /// Forget the memoized metadata scalars, which are read again on next access (e.g. for a file being written concurrently).
+    /// Note: the entity cache is not refreshed.
+    pub fn refresh(&self) -> Result<()> {
+        *self._lock_metadata_scalars()? = MetadataScalars::default();
+        Ok(())
+    }
+
+    // Note: the connection is accessed with try_connection(), since the bindings call the getters from iteration callbacks
+    fn _get_memoized_scalar<T: Copy>(
+        &self,
+        scalar_field: fn(&mut MetadataScalars) -> &mut Option<Option<T>>,
+        load_scalar: fn(&Connection) -> Result<Option<T>>
+    ) -> Result<Option<T>> {
+        if let Some(value_opt) = *scalar_field(&mut *self._lock_metadata_scalars()?) {
+            return Ok(value_opt);
+        }
+
+        let value_opt = {
+            let db = self.try_connection().location(here!())?;
+            load_scalar(&db).location(here!())?
+        };
+        *scalar_field(&mut *self._lock_metadata_scalars()?) = Some(value_opt);
+
+        Ok(value_opt)
+    }
+
+    fn _lock_metadata_scalars(&self) -> Result<MutexGuard<'_, MetadataScalars>> {
+        self.metadata_scalars.lock().map_err(|_| anyhow!("metadata scalars lock is poisoned (a previous access panicked)"))
+    }
+
+    fn _lock(&self) -> Result<MutexGuard<'_, Option<Connection>>> {
+        self.connection.lock().map_err(|_| anyhow!("connection lock is poisoned (a previous access panicked)"))
+    }
+
+    fn _try_lock(&self) -> Result<MutexGuard<'_, Option<Connection>>> {
+        match self.connection.try_lock() {
+            Result::Ok(guard) => Ok(guard),
+            Err(TryLockError::WouldBlock) => bail!("the reader is already in use (e.g. by the iteration calling this callback)"),
+            Err(TryLockError::Poisoned(_)) => bail!("connection lock is poisoned (a previous access panicked)"),
+        }
+    }
+}
@@ -1,12 +1,18 @@
 
 
+use std::collections::VecDeque;
+use std::sync::Arc;
+
 use anyhow::*;
 use itertools::Itertools;
 use rusqlite::{Connection, Statement};
 //use rusqlite::types::Type::Null;
 
 use crate::anyhow_ext::*;
+use crate::blob_cursor::BlobCursor;
+use crate::metadata::parse_shared_param_trees;
 use crate::model::*;
+use crate::progress::{check_cancellation, CancellationToken, ProgressObserver};
 use crate::queries::*;
 
 const SQLQUERY_ALLMSLEVELS: &'static str = "SELECT bounding_box.* FROM bounding_box, spectrum WHERE spectrum.id = bounding_box.first_spectrum_id";
@@ -170,6 +176,187 @@ pub fn for_each_spectrum<F>(db: &Connection, entity_cache: &EntityCache, ms_leve
      */
 }
 
+/// Iterate the run slices of the file (or of a single MS level) ordered by MS level and number.
+/// The bounding boxes of each run slice are loaded using a single SQL query, and their spectrum slices are ordered by spectrum ID.
+pub fn for_each_run_slice<F>(db: &Connection, entity_cache: &EntityCache, ms_level: Option<u8>, mut on_each_run_slice: F) -> Result<()>
+    where F: FnMut(&RunSlice) -> Result<()> {
+
+    let run_slice_headers = list_run_slice_headers(db).location(here!())?;
+
+    let mut bb_stmt = db.prepare(
+        "SELECT * FROM bounding_box WHERE bounding_box.run_slice_id = ? ORDER BY first_spectrum_id"
+    ).location(here!())?;
+
+    for run_slice_header in run_slice_headers {
+        if ms_level.is_some_and(|level| level as i64 != run_slice_header.ms_level) {
+            continue;
+        }
+
+        let mut spectrum_slices = Vec::new();
+
+        let mut rows = bb_stmt.query([run_slice_header.id]).location(here!())?;
+        while let Some(row) = rows.next().location(here!())? {
+            let bb = create_bbox(row).location(here!())?;
+            _bb_to_spectrum_slices(&bb, entity_cache, &mut spectrum_slices).location(here!())?;
+        }
+
+        let run_slice = RunSlice {
+            data: RunSliceData { id: run_slice_header.id, spectrum_slice: spectrum_slices },
+            header: run_slice_header,
+        };
+
+        on_each_run_slice(&run_slice).location(here!())?;
+    }
+
+    Ok(())
+}
+
+fn _bb_to_spectrum_slices(bb: &BoundingBox, entity_cache: &EntityCache, spectrum_slices: &mut Vec<SpectrumSlice>) -> Result<()> {
+    for slice_view_res in BlobCursor::new(&bb.blob_data, &entity_cache.data_encodings_cache) {
+        let slice_view = slice_view_res.location(here!())?;
+
+        let spectrum_header = entity_cache.spectrum_headers.get((slice_view.spectrum_id - 1) as usize)
+            .context(format!("can't retrieve header of spectrum with ID={}", slice_view.spectrum_id)).location(here!())?;
+
+        spectrum_slices.push(SpectrumSlice {
+            spectrum: Spectrum { header: spectrum_header.clone(), data: slice_view.to_spectrum_data() },
+            run_slice_id: bb.run_slice_id,
+        });
+    }
+
+    Ok(())
+}
+
+/// Same as for_each_spectrum() but notifies the progress observer after each iterated spectrum.
+/// If a cancellation token is provided, it is polled before each spectrum and a progress::Cancelled error is returned
+/// as soon as the cancellation is requested.
+pub fn for_each_spectrum_with_progress<F>(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    ms_level: Option<u8>,
+    progress_observer: &mut dyn ProgressObserver,
+    cancellation_token: Option<&CancellationToken>,
+    mut on_each_spectrum: F
+) -> Result<()> where F: FnMut(&Spectrum) -> Result<()> {
+
+    let total_count = entity_cache.spectrum_headers.iter()
+        .filter(|sh| ms_level.is_none() || sh.ms_level == ms_level.unwrap() as i64)
+        .count();
+
+    let mut processed_count = 0;
+    for_each_spectrum(db, entity_cache, ms_level, |spectrum: &Spectrum| {
+        check_cancellation(cancellation_token)?;
+
+        on_each_spectrum(spectrum)?;
+
+        processed_count += 1;
+        progress_observer.on_progress(processed_count, total_count);
+
+        Ok(())
+    })
+}
+
+/// Same as for_each_spectrum() but only iterates the spectra having the provided polarity
+/// (e.g. to process separately the positive and negative scans of polarity switching experiments).
+/// Polarities are read from the spectrum headers, or from the spectrum table when the entity cache holds light headers.
+pub fn for_each_spectrum_with_polarity<F>(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    ms_level: Option<u8>,
+    polarity: Polarity,
+    mut on_each_spectrum: F
+) -> Result<()> where F: FnMut(&Spectrum) -> Result<()> {
+
+    let spectrum_polarities = _get_spectrum_polarities(db, entity_cache).location(here!())?;
+
+    for_each_spectrum(db, entity_cache, ms_level, |spectrum: &Spectrum| {
+        if spectrum_polarities.get((spectrum.header.id - 1) as usize) == Some(&polarity) {
+            on_each_spectrum(spectrum)?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Iterate overlapping windows of window_size consecutive MS1 spectra (ordered by time), a new window starting every step spectra.
+/// Each spectrum is decoded once and shared by the windows containing it, so that they can be kept by cloning the Arc pointers.
+/// Only full windows are provided: trailing spectra which can't fill a window are not iterated.
+pub fn for_each_ms1_window<F>(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    window_size: usize,
+    step: usize,
+    mut on_each_window: F
+) -> Result<()> where F: FnMut(&[Arc<Spectrum>]) -> Result<()> {
+
+    if window_size == 0 || step == 0 {
+        bail!("invalid window size {} or step {}, both must be greater than 0", window_size, step);
+    }
+
+    let mut window: VecDeque<Arc<Spectrum>> = VecDeque::with_capacity(window_size);
+    let mut spectra_to_skip = 0; // spectra lying between two windows when step is greater than window_size
+
+    for_each_spectrum(db, entity_cache, Some(1), |spectrum: &Spectrum| {
+        if spectra_to_skip > 0 {
+            spectra_to_skip -= 1;
+            return Ok(());
+        }
+
+        window.push_back(Arc::new(spectrum.clone()));
+
+        if window.len() == window_size {
+            on_each_window(window.make_contiguous()).location(here!())?;
+
+            window.drain(..step.min(window_size));
+            spectra_to_skip = step.saturating_sub(window_size);
+        }
+
+        Ok(())
+    })
+}
+
+// Returns the polarities indexed by spectrum ID - 1 (see metadata::get_spectrum_polarity(), the shared param trees being parsed only once)
+fn _get_spectrum_polarities(db: &Connection, entity_cache: &EntityCache) -> Result<Vec<Polarity>> {
+    let shared_param_trees = parse_shared_param_trees(db).location(here!())?;
+
+    let resolve_polarity = |param_tree: Option<&str>, scan_list: Option<&str>, shared_param_tree_id: Option<i64>| -> Result<Polarity> {
+        let own_polarity = Polarity::from_xml_fields(param_tree, None).location(here!())?;
+        if own_polarity != Polarity::UNKNOWN {
+            return Ok(own_polarity);
+        }
+
+        let shared_polarity = shared_param_tree_id
+            .and_then(|id| shared_param_trees.get(&id))
+            .map_or(Polarity::UNKNOWN, |shared_param_tree| Polarity::from_cv_params(&shared_param_tree.cv_params));
+        if shared_polarity != Polarity::UNKNOWN {
+            return Ok(shared_polarity);
+        }
+
+        Polarity::from_xml_fields(None, scan_list)
+    };
+
+    let has_xml_fields = entity_cache.spectrum_headers.iter().all(|sh| sh.param_tree_str.is_some());
+    if has_xml_fields {
+        return entity_cache.spectrum_headers.iter()
+            .map(|sh| resolve_polarity(sh.param_tree_str.as_deref(), sh.scan_list_str.as_deref(), sh.shared_param_tree_id))
+            .collect();
+    }
+
+    let mut stmt = db.prepare("SELECT param_tree, scan_list, shared_param_tree_id FROM spectrum ORDER BY id").location(here!())?;
+    let mut rows = stmt.query([]).location(here!())?;
+
+    let mut polarities = Vec::with_capacity(entity_cache.spectrum_headers.len());
+    while let Some(row) = rows.next().location(here!())? {
+        let param_tree: Option<String> = row.get(0).location(here!())?;
+        let scan_list: Option<String> = row.get(1).location(here!())?;
+        let shared_param_tree_id: Option<i64> = row.get(2).location(here!())?;
+
+        polarities.push(resolve_polarity(param_tree.as_deref(), scan_list.as_deref(), shared_param_tree_id).location(here!())?);
+    }
+
+    Ok(polarities)
+}
+
 fn _bb_row_buffer_to_spectrum_buffer(bb_row_buffer: &Vec<BoundingBox>, spectrum_buffer: &mut Vec<Spectrum>, entity_cache: &EntityCache) -> Result<()> {
 
     let de_cache = &entity_cache.data_encodings_cache;
@@ -179,6 +366,8 @@ fn _bb_row_buffer_to_spectrum_buffer(bb_row_buffer: &Vec<BoundingBox>, spectrum_
     let first_bb_index = indexed_bbs[0].as_ref().unwrap();
     let n_spectra = first_bb_index.spectra_ids.len();
 
+    let read_slice_data = if entity_cache.fitted_as_centroid { read_spectrum_slice_centroids_at } else { read_spectrum_slice_data_at };
+
     for spectrum_slice_idx in 0..n_spectra {
         let mut spectrum_peak_count = 0;
         let mut spectrum_slices = Vec::with_capacity(bb_count);
@@ -197,7 +386,7 @@ fn _bb_row_buffer_to_spectrum_buffer(bb_row_buffer: &Vec<BoundingBox>, spectrum_
             let bb = &bb_row_buffer[bb_idx];
             let bb_index = indexed_bbs[bb_idx].as_ref().unwrap();
 
-            let spectrum_slice_data = read_spectrum_slice_data_at(
+            let spectrum_slice_data = read_slice_data(
                 bb,
                 bb_index,
                 data_encoding,
@@ -212,6 +401,7 @@ fn _bb_row_buffer_to_spectrum_buffer(bb_row_buffer: &Vec<BoundingBox>, spectrum_
         }
 
         let spectrum_data = merge_spectrum_slices(&mut spectrum_slices, spectrum_peak_count).location(here!())?;
+        let spectrum_data = check_mz_order(spectrum_data, spectrum_id, entity_cache.sort_mz_arrays, Some(&entity_cache.diagnostics));
 
         let spectrum = Spectrum {
             header: spectrum_header.clone(),
@@ -5,7 +5,36 @@ unused_variables
 )]*/
 
 pub mod anyhow_ext;
+pub mod blob_cursor;
+pub mod cache_registry;
+#[cfg(feature = "mzdb-capi")]
+pub mod capi;
+pub mod cohort;
+pub mod compat;
+pub mod corpus;
+#[cfg(feature = "compressed")]
+pub mod container;
+pub mod diagnostics;
+pub mod dia;
+pub mod encryption;
+pub mod export;
+pub mod ffi_support;
+pub mod metadata;
 pub mod model;
 pub mod mzdb;
+pub mod progress;
+pub mod qc;
+pub mod quant;
 pub mod queries;
-pub mod iterator;
\ No newline at end of file
+pub mod rtree;
+pub mod schema;
+pub mod iterator;
+pub mod live;
+pub mod maintenance;
+pub mod mass;
+pub mod search;
+pub mod spectrum_query;
+pub mod time_axis;
+pub mod usi;
+pub mod xic;
+pub mod xml;
\ No newline at end of file
@@ -0,0 +1,185 @@
+// Reading of mzDB files which are still being written (e.g. by pwiz-mzdb during an acquisition).
+// The writer holds locks on the file while inserting, thus readers must wait for them (busy timeout) and retry on failure.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::{Connection, ErrorCode, OpenFlags};
+
+use crate::model::Spectrum;
+use crate::progress::CancellationToken;
+use crate::queries::{get_spectrum_uncached_from_table, table_exists};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpenOptions {
+    pub busy_timeout: Duration, // time SQLite waits for the locks of the writer before returning SQLITE_BUSY
+    pub max_retries: u32, // number of additional attempts after a SQLITE_BUSY/SQLITE_LOCKED error
+    pub retry_delay: Duration, // delay before the first retry, doubled after each attempt
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            busy_timeout: Duration::from_secs(5),
+            max_retries: 3,
+            retry_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TailOptions {
+    pub poll_interval: Duration,
+    pub idle_timeout: Option<Duration>, // stop the iteration when no new spectrum appears during this duration
+    pub max_read_retries: u32, // number of additional attempts, one per poll, to read a spectrum failing for another reason than a lock (e.g. its bounding boxes being partially written)
+}
+
+impl Default for TailOptions {
+    fn default() -> Self {
+        TailOptions {
+            poll_interval: Duration::from_secs(1),
+            idle_timeout: None,
+            max_read_retries: 3,
+        }
+    }
+}
+
+/// Open a mzDB file in read-only mode, retrying with an exponential backoff while the file is locked by a writer
+pub fn open_with_options(path: &str, options: &OpenOptions) -> Result<Connection> {
+    let mut retry_delay = options.retry_delay;
+    let mut attempt = 0;
+
+    loop {
+        match _try_open(path, options) {
+            Result::Ok(db) => return Ok(db),
+            Err(e) if _is_busy_error(&e) && attempt < options.max_retries => {
+                log::debug!("file {} is locked (attempt {}), retrying in {:?}", path, attempt + 1, retry_delay);
+                thread::sleep(retry_delay);
+                retry_delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e).context(format!("can't open file {} after {} attempts", path, attempt + 1)).location(here!()),
+        }
+    }
+}
+
+/// Iterate over the spectra of a file being written, starting at first_spectrum_id.
+/// The iterator blocks until the next spectrum is available (both its header and its bounding boxes have been written),
+/// and ends when the cancellation token is cancelled or when the idle timeout of the options is reached.
+/// Writers inserting the headers in tmp_spectrum until the file is finalized are supported: this table is read when it exists.
+/// A spectrum which can't be read is retried at the next polls, and only skipped once it has been read: an error returned
+/// after the read retries are exhausted leaves the iterator on the same spectrum.
+pub fn tail_spectra<'a>(db: &'a Connection, first_spectrum_id: i64, options: TailOptions, cancellation_token: CancellationToken) -> SpectrumTail<'a> {
+    SpectrumTail {
+        db,
+        next_spectrum_id: first_spectrum_id,
+        last_available_id: 0,
+        failed_reads: 0,
+        options,
+        cancellation_token,
+    }
+}
+
+pub struct SpectrumTail<'a> {
+    db: &'a Connection,
+    next_spectrum_id: i64,
+    last_available_id: i64,
+    failed_reads: u32, // failed attempts to read next_spectrum_id, locks excluded
+    options: TailOptions,
+    cancellation_token: CancellationToken,
+}
+
+impl<'a> SpectrumTail<'a> {
+    // The spectrum table is only filled when the file is finalized by some writers, which insert the headers in tmp_spectrum before
+    fn _get_spectrum_table(&self) -> Result<&'static str> {
+        let has_tmp_spectrum = table_exists(self.db, "tmp_spectrum").location(here!())?;
+
+        Ok(if has_tmp_spectrum { "tmp_spectrum" } else { "spectrum" })
+    }
+
+    // Spectra are only available once both their header and the bounding boxes of their row have been written
+    fn _get_last_available_id(&self, spectrum_table: &str) -> Result<i64> {
+        self.db.query_row(
+            format!(
+                "SELECT min(ifnull((SELECT max(id) FROM {}), 0), ifnull((SELECT max(last_spectrum_id) FROM bounding_box), 0))",
+                spectrum_table
+            ).as_str(),
+            [],
+            |row| row.get(0)
+        ).location(here!())
+    }
+}
+
+impl<'a> Iterator for SpectrumTail<'a> {
+    type Item = Result<Spectrum>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let idle_start_time = Instant::now();
+
+        loop {
+            if self.cancellation_token.is_cancelled() {
+                return None;
+            }
+
+            if self.next_spectrum_id > self.last_available_id {
+                match self._get_spectrum_table().and_then(|spectrum_table| self._get_last_available_id(spectrum_table)) {
+                    Result::Ok(last_available_id) => self.last_available_id = last_available_id,
+                    Err(e) if _is_busy_error(&e) => (), // the writer holds the lock, try again at the next poll
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            if self.next_spectrum_id <= self.last_available_id {
+                // The table is looked up again since the file may have been finalized meanwhile
+                let spectrum_res = self._get_spectrum_table()
+                    .and_then(|spectrum_table| get_spectrum_uncached_from_table(self.db, spectrum_table, self.next_spectrum_id))
+                    .location(here!());
+
+                match spectrum_res {
+                    Result::Ok(spectrum) => {
+                        self.next_spectrum_id += 1;
+                        self.failed_reads = 0;
+                        return Some(Ok(spectrum));
+                    }
+                    Err(e) if _is_busy_error(&e) => (),
+                    Err(e) if self.failed_reads < self.options.max_read_retries => {
+                        log::debug!("can't read spectrum with ID={} (attempt {}), retrying: {}", self.next_spectrum_id, self.failed_reads + 1, e);
+                        self.failed_reads += 1;
+                    }
+                    Err(e) => {
+                        self.failed_reads = 0;
+                        return Some(Err(e));
+                    }
+                }
+            }
+
+            if self.options.idle_timeout.is_some_and(|idle_timeout| idle_start_time.elapsed() >= idle_timeout) {
+                return None;
+            }
+
+            thread::sleep(self.options.poll_interval);
+        }
+    }
+}
+
+fn _try_open(path: &str, options: &OpenOptions) -> Result<Connection> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).location(here!())?;
+    db.busy_timeout(options.busy_timeout).location(here!())?;
+
+    // Locks are only acquired by the first query
+    db.query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0)).location(here!())?;
+
+    Ok(db)
+}
+
+fn _is_busy_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| match cause.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::SqliteFailure(ffi_error, _)) => {
+            ffi_error.code == ErrorCode::DatabaseBusy || ffi_error.code == ErrorCode::DatabaseLocked
+        }
+        _ => false,
+    })
+}
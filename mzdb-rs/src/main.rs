@@ -7,11 +7,39 @@ unused_imports
 
 mod anyhow_ext; // has to be first?
 mod bb_iterator_v1;
+mod blob_cursor;
+mod cache_registry;
+mod cohort;
+mod compat;
+mod corpus;
+#[cfg(feature = "compressed")]
+mod container;
+mod diagnostics;
+mod dia;
+mod encryption;
+mod export;
+mod ffi_support;
+mod metadata;
 mod model;
 mod mzdb;
+mod progress;
+mod qc;
+mod quant;
 mod queries;
+mod rtree;
+mod schema;
 mod iterator;
+mod live;
+mod maintenance;
+mod mass;
+mod search;
+mod spectrum_query;
+#[cfg(test)]
 mod test;
+mod time_axis;
+mod usi;
+mod xic;
+mod xml;
 
 use crate::model::BoundingBox;
 
@@ -0,0 +1,409 @@
+// Maintenance operations modifying a mzDB file in place.
+// Warning: these functions open the file in read-write mode, work on a copy if the original file must be preserved.
+// The optional progress observer of each function is notified for each processed item (spectrum, table, index...),
+// and the optional cancellation token is polled in the same loops: a cancelled operation leaves the file unmodified,
+// since its transaction is not committed.
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::iterator::for_each_spectrum;
+use crate::model::*;
+use crate::mzdb::create_light_entity_cache;
+use crate::progress::{check_cancellation, CancellationToken, ProgressObserver, ProgressTracker};
+use crate::queries::{parse_scan_metadata_table, quote_identifier, ION_MAP_THUMBNAIL_NAME, SCAN_METADATA_TABLE_NAME, SPECTRUM_FTS_TABLE_NAME, THUMBNAIL_TABLE_NAME, TIC_THUMBNAIL_NAME};
+use crate::xml::{find_cv_param_value, parse_cv_params_with_mode, XmlParsingMode};
+
+const SPECTRUM_TITLE_ACCESSION: &str = "MS:1000796";
+
+// Secondary indexes which are not part of the mzDB specification, prefixed to be easily told apart from the standard ones
+const OPTIONAL_INDEXES: &[(&str, &str)] = &[
+    ("idx_mzdbrs_spectrum_time", "spectrum (time)"),
+    ("idx_mzdbrs_spectrum_main_precursor_mz", "spectrum (main_precursor_mz)"),
+];
+
+struct SpectrumSummary {
+    spectrum_id: i64,
+    tic: f32,
+    base_peak_mz: f64,
+    base_peak_intensity: f32,
+    peaks_count: i64,
+}
+
+/// Recompute the TIC, base peak and peaks count of each spectrum from its data, and update the spectrum table accordingly.
+/// This is useful for files produced by converters writing 0 in these columns: only the NULL or 0 values are replaced,
+/// the values written by the converter being kept otherwise (they may have been computed from the raw data).
+/// Returns the number of spectra whose summary has been modified.
+pub fn recompute_spectrum_summaries(
+    path: &str,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<usize> {
+    let mut db = Connection::open(path).context(format!("can't open file {} in read-write mode", path)).location(here!())?;
+
+    let entity_cache = create_light_entity_cache(&db).location(here!())?;
+
+    // Each spectrum is decoded once, and only the summaries replacing a missing stored value are kept
+    let mut modified_summaries = Vec::new();
+    let mut progress = ProgressTracker::new(progress_observer, entity_cache.spectrum_headers.len());
+    for_each_spectrum(&db, &entity_cache, None, |spectrum: &Spectrum| {
+        check_cancellation(cancellation_token)?;
+
+        let summary = _compute_spectrum_summary(spectrum);
+        let header = &spectrum.header;
+
+        if (header.tic == 0.0 && summary.tic != 0.0)
+            || (header.base_peak_mz == 0.0 && summary.base_peak_mz != 0.0)
+            || (header.base_peak_intensity == 0.0 && summary.base_peak_intensity != 0.0)
+            || (header.peaks_count == 0 && summary.peaks_count != 0) {
+            modified_summaries.push(summary);
+        }
+        progress.increment();
+
+        Ok(())
+    }).location(here!())?;
+
+    let mut modified_count = 0;
+
+    let tx = db.transaction().location(here!())?;
+    {
+        // ifnull(nullif(column, 0), value) only replaces the NULL or 0 values
+        let mut stmt = tx.prepare(
+            "UPDATE spectrum SET tic = ifnull(nullif(tic, 0), ?1), base_peak_mz = ifnull(nullif(base_peak_mz, 0), ?2), \
+            base_peak_intensity = ifnull(nullif(base_peak_intensity, 0), ?3), data_points_count = ifnull(nullif(data_points_count, 0), ?4) \
+            WHERE id = ?5 AND (tic IS NULL OR tic = 0 OR base_peak_mz IS NULL OR base_peak_mz = 0 \
+            OR base_peak_intensity IS NULL OR base_peak_intensity = 0 OR data_points_count IS NULL OR data_points_count = 0)"
+        ).location(here!())?;
+
+        for summary in modified_summaries.iter() {
+            modified_count += stmt.execute(params![
+                summary.tic,
+                summary.base_peak_mz,
+                summary.base_peak_intensity,
+                summary.peaks_count,
+                summary.spectrum_id
+            ]).location(here!())?;
+        }
+    }
+    tx.commit().location(here!())?;
+
+    Ok(modified_count)
+}
+
+/// Generate the thumbnails of the file (a downsampled TIC trace and a rasterized MS1 ion map) and store them in the thumbnail table,
+/// so that file browsers can display previews using queries::get_tic_thumbnail() and queries::get_ion_map_thumbnail().
+/// The memory usage only depends on the thumbnail sizes. Existing thumbnails are replaced.
+pub fn generate_thumbnails(
+    path: &str,
+    tic_points_count: usize,
+    ion_map_width: usize,
+    ion_map_height: usize,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<()> {
+    if tic_points_count == 0 || ion_map_width == 0 || ion_map_height == 0 {
+        bail!("thumbnail sizes must be greater than 0");
+    }
+
+    let mut db = Connection::open(path).context(format!("can't open file {} in read-write mode", path)).location(here!())?;
+
+    let entity_cache = create_light_entity_cache(&db).location(here!())?;
+    let ms1_headers: Vec<&SpectrumHeader> = entity_cache.spectrum_headers.iter().filter(|sh| sh.ms_level == 1).collect();
+    if ms1_headers.is_empty() {
+        bail!("can't generate thumbnails of a file without MS1 spectra");
+    }
+
+    let min_time = ms1_headers[0].time;
+    let max_time = ms1_headers.last().unwrap().time;
+    let time_range = (max_time - min_time).max(f32::EPSILON);
+    let time_to_bin = |time: f32, bins_count: usize| (((time - min_time) / time_range) * (bins_count - 1) as f32).round() as usize;
+
+    // Keep the most intense spectrum of each time bin, to preserve the TIC apexes
+    let mut tic_bins: Vec<Option<(f32, f32)>> = vec![None; tic_points_count];
+    for sh in ms1_headers.iter() {
+        let tic_bin = &mut tic_bins[time_to_bin(sh.time, tic_points_count)];
+        if tic_bin.is_none_or(|(_time, tic)| sh.tic > tic) {
+            *tic_bin = Some((sh.time, sh.tic));
+        }
+    }
+
+    let tic_bytes: Vec<u8> = tic_bins.iter().flatten()
+        .flat_map(|(time, tic)| time.to_le_bytes().into_iter().chain(tic.to_le_bytes()))
+        .collect();
+
+    let (min_mz, max_mz): (f64, f64) = db.query_row(
+        "SELECT min(begin_mz), max(end_mz) FROM run_slice WHERE ms_level = 1", [], |row| rusqlite::Result::Ok((row.get(0)?, row.get(1)?))
+    ).location(here!())?;
+    let mz_range = (max_mz - min_mz).max(f64::EPSILON);
+
+    let mut ion_map_sums = vec![0.0f64; ion_map_width * ion_map_height];
+    let mut progress = ProgressTracker::new(progress_observer, ms1_headers.len());
+    for_each_spectrum(&db, &entity_cache, Some(1), |spectrum: &Spectrum| {
+        check_cancellation(cancellation_token)?;
+
+        let time_idx = time_to_bin(spectrum.header.time, ion_map_width);
+
+        for (mz, intensity) in spectrum.data.mz_array.iter().zip(spectrum.data.intensity_array.iter()) {
+            let mz_idx = (((mz - min_mz) / mz_range) * ion_map_height as f64).max(0.0) as usize;
+            ion_map_sums[mz_idx.min(ion_map_height - 1) * ion_map_width + time_idx] += *intensity as f64;
+        }
+        progress.increment();
+
+        Ok(())
+    }).location(here!())?;
+
+    // Log scaling keeps the low abundance ions visible
+    let max_log_sum = ion_map_sums.iter().map(|sum| sum.ln_1p()).fold(0.0, f64::max).max(f64::EPSILON);
+    let ion_map_pixels: Vec<u8> = ion_map_sums.iter().map(|sum| ((sum.ln_1p() / max_log_sum) * 255.0).round() as u8).collect();
+
+    let tx = db.transaction().location(here!())?;
+    tx.execute(
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (name TEXT PRIMARY KEY, width INTEGER NOT NULL, height INTEGER NOT NULL, \
+            min_x REAL NOT NULL, max_x REAL NOT NULL, min_y REAL NOT NULL, max_y REAL NOT NULL, data BLOB NOT NULL)",
+            THUMBNAIL_TABLE_NAME
+        ).as_str(),
+        [],
+    ).location(here!())?;
+
+    {
+        let mut stmt = tx.prepare(
+            format!("INSERT OR REPLACE INTO {} VALUES (?, ?, ?, ?, ?, ?, ?, ?)", THUMBNAIL_TABLE_NAME).as_str()
+        ).location(here!())?;
+
+        let tic_values = tic_bins.iter().flatten();
+        let (min_tic, max_tic) = tic_values.fold((f32::MAX, 0.0f32), |(min, max), (_time, tic)| (min.min(*tic), max.max(*tic)));
+        stmt.execute(params![
+            TIC_THUMBNAIL_NAME, (tic_bytes.len() / 8) as i64, 1, min_time, max_time, min_tic, max_tic, tic_bytes
+        ]).location(here!())?;
+
+        stmt.execute(params![
+            ION_MAP_THUMBNAIL_NAME, ion_map_width as i64, ion_map_height as i64, min_time, max_time, min_mz, max_mz, ion_map_pixels
+        ]).location(here!())?;
+    }
+    tx.commit().location(here!())?;
+
+    Ok(())
+}
+
+fn _compute_spectrum_summary(spectrum: &Spectrum) -> SpectrumSummary {
+    let data = &spectrum.data;
+
+    let mut tic = 0.0f64;
+    let mut base_peak_idx_opt: Option<usize> = None;
+    for (peak_idx, intensity) in data.intensity_array.iter().enumerate() {
+        tic += *intensity as f64;
+        if base_peak_idx_opt.is_none_or(|bp_idx| *intensity > data.intensity_array[bp_idx]) {
+            base_peak_idx_opt = Some(peak_idx);
+        }
+    }
+
+    let (base_peak_mz, base_peak_intensity) = match base_peak_idx_opt {
+        Some(bp_idx) => (data.mz_array[bp_idx], data.intensity_array[bp_idx]),
+        None => (0.0, 0.0),
+    };
+
+    SpectrumSummary {
+        spectrum_id: spectrum.header.id,
+        tic: tic as f32,
+        base_peak_mz,
+        base_peak_intensity,
+        peaks_count: data.peak_count as i64,
+    }
+}
+
+/// Build a FTS5 full-text index of the spectrum headers, to be queried with search::query_headers_fts().
+/// Indexed texts are the spectrum titles and selected param values: the filter strings, the spectrum titles stored as cvParams,
+/// and the names of the flag cvParams (e.g. "positive scan", "collision-induced dissociation") found in the param trees,
+/// scan lists and precursor lists. An existing index is rebuilt. Returns the number of indexed spectra.
+pub fn build_fts_index(
+    path: &str,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<usize> {
+    let mut db = Connection::open(path).context(format!("can't open file {} in read-write mode", path)).location(here!())?;
+
+    let tx = db.transaction().location(here!())?;
+    tx.execute(format!("DROP TABLE IF EXISTS {}", SPECTRUM_FTS_TABLE_NAME).as_str(), []).location(here!())?;
+    tx.execute(format!("CREATE VIRTUAL TABLE {} USING fts5(title, params)", SPECTRUM_FTS_TABLE_NAME).as_str(), [])
+        .context("can't create the full-text index (is FTS5 enabled in SQLite?)").location(here!())?;
+
+    let spectra_count: i64 = tx.query_row("SELECT count(*) FROM spectrum", [], |row| row.get(0)).location(here!())?;
+    let mut progress = ProgressTracker::new(progress_observer, spectra_count as usize);
+
+    let mut indexed_spectra_count = 0;
+    {
+        let mut select_stmt = tx.prepare("SELECT id, title, param_tree, scan_list, precursor_list FROM spectrum").location(here!())?;
+        let mut insert_stmt = tx.prepare(
+            format!("INSERT INTO {} (rowid, title, params) VALUES (?, ?, ?)", SPECTRUM_FTS_TABLE_NAME).as_str()
+        ).location(here!())?;
+
+        let mut rows = select_stmt.query([]).location(here!())?;
+        while let Some(row) = rows.next().location(here!())? {
+            check_cancellation(cancellation_token)?;
+
+            let spectrum_id: i64 = row.get(0).location(here!())?;
+            let title: Option<String> = row.get(1).location(here!())?;
+
+            let mut param_texts = Vec::new();
+            for col_idx in 2..=4 {
+                let xml_opt: Option<String> = row.get(col_idx).location(here!())?;
+                if let Some(xml) = xml_opt {
+                    let cv_params = parse_cv_params_with_mode(&xml, XmlParsingMode::LENIENT)
+                        .context(format!("can't parse the params of spectrum {}", spectrum_id)).location(here!())?;
+
+                    param_texts.extend(
+                        [FILTER_STRING, SPECTRUM_TITLE_ACCESSION].iter()
+                            .filter_map(|accession| find_cv_param_value(&cv_params, accession))
+                            .map(|value| value.to_string())
+                    );
+                    param_texts.extend(cv_params.into_iter().filter(|cv_param| cv_param.value.is_empty()).map(|cv_param| cv_param.name));
+                }
+            }
+
+            insert_stmt.execute(params![spectrum_id, title.unwrap_or_default(), param_texts.join("\n")]).location(here!())?;
+            indexed_spectra_count += 1;
+            progress.increment();
+        }
+    }
+    tx.commit().location(here!())?;
+
+    Ok(indexed_spectra_count)
+}
+
+/// Store the scan metadata parsed from the scan and precursor lists (see queries::get_scan_metadata_table()) in an auxiliary table,
+/// so that they can then be read without any XML parsing. An existing table is rebuilt. Returns the number of stored rows.
+pub fn build_scan_metadata_cache(
+    path: &str,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<usize> {
+    let mut db = Connection::open(path).context(format!("can't open file {} in read-write mode", path)).location(here!())?;
+
+    let table = parse_scan_metadata_table(&db).location(here!())?;
+
+    let tx = db.transaction().location(here!())?;
+    tx.execute(format!("DROP TABLE IF EXISTS {}", SCAN_METADATA_TABLE_NAME).as_str(), []).location(here!())?;
+    tx.execute(
+        format!(
+            "CREATE TABLE {} (id INTEGER PRIMARY KEY, injection_time REAL, filter_string TEXT, \
+            scan_window_lower_limit REAL, scan_window_upper_limit REAL, precursor_intensity REAL)",
+            SCAN_METADATA_TABLE_NAME
+        ).as_str(),
+        []
+    ).location(here!())?;
+
+    {
+        let mut insert_stmt = tx.prepare(
+            format!("INSERT INTO {} VALUES (?, ?, ?, ?, ?, ?)", SCAN_METADATA_TABLE_NAME).as_str()
+        ).location(here!())?;
+
+        let mut progress = ProgressTracker::new(progress_observer, table.ids.len());
+        for (idx, spectrum_id) in table.ids.iter().enumerate() {
+            check_cancellation(cancellation_token)?;
+
+            let scan_window = table.scan_windows[idx];
+            insert_stmt.execute(params![
+                spectrum_id,
+                table.injection_times[idx],
+                table.filter_strings[idx],
+                scan_window.map(|window| window.0),
+                scan_window.map(|window| window.1),
+                table.precursor_intensities[idx],
+            ]).location(here!())?;
+            progress.increment();
+        }
+    }
+    tx.commit().location(here!())?;
+
+    Ok(table.ids.len())
+}
+
+/// Raise the sqlite_sequence values of all the AUTOINCREMENT tables which are lower than their max rowid (e.g. in files edited by other tools),
+/// which fixes the records counts read from sqlite_sequence (see queries::get_table_records_count()).
+/// Values greater than the max rowid (e.g. after deletions) are kept, since lowering them would allow the reuse of the IDs of deleted records.
+/// Tables already having a sqlite_sequence entry are included too (e.g. the spectrum table, created from tmp_spectrum by some writers).
+/// Missing sqlite_sequence entries of non-empty tables are added. Returns the number of inserted or modified entries.
+pub fn fix_sequences(
+    path: &str,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<usize> {
+    let mut db = Connection::open(path).context(format!("can't open file {} in read-write mode", path)).location(here!())?;
+
+    let table_names: Vec<String> = {
+        let mut stmt = db.prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table' \
+            AND (sql LIKE '%AUTOINCREMENT%' OR name IN (SELECT name FROM sqlite_sequence)) ORDER BY name"
+        ).location(here!())?;
+        let names = stmt.query_map([], |row| row.get(0)).location(here!())?
+            .collect::<rusqlite::Result<Vec<String>>>().location(here!())?;
+        names
+    };
+
+    let tx = db.transaction().location(here!())?;
+    let mut fixed_count = 0;
+    let mut progress = ProgressTracker::new(progress_observer, table_names.len());
+
+    for table_name in table_names {
+        check_cancellation(cancellation_token)?;
+
+        let max_rowid: i64 = tx.query_row(
+            format!("SELECT coalesce(max(rowid), 0) FROM {}", quote_identifier(&table_name)).as_str(), [], |row| row.get(0)
+        ).location(here!())?;
+
+        let seq_opt: Option<i64> = tx.query_row(
+            "SELECT seq FROM sqlite_sequence WHERE name = ?", [&table_name], |row| row.get(0)
+        ).optional().location(here!())?;
+
+        progress.increment();
+
+        match seq_opt {
+            Some(seq) if seq >= max_rowid => continue,
+            None if max_rowid == 0 => continue, // SQLite only adds the entry on the first insert
+            Some(_) => tx.execute("UPDATE sqlite_sequence SET seq = ? WHERE name = ?", params![max_rowid, table_name]),
+            None => tx.execute("INSERT INTO sqlite_sequence (name, seq) VALUES (?, ?)", params![table_name, max_rowid]),
+        }.location(here!())?;
+
+        fixed_count += 1;
+    }
+
+    tx.commit().location(here!())?;
+
+    Ok(fixed_count)
+}
+
+/// Create the secondary indexes which are not part of the mzDB specification (named idx_mzdbrs_*) but speed up some queries,
+/// e.g. queries::list_spectrum_ids_in_time_range() and queries::list_spectrum_ids_in_precursor_mz_range().
+/// They are used by SQLite when present. Existing indexes are kept. Returns the number of created indexes.
+pub fn create_optional_indexes(
+    path: &str,
+    progress_observer: Option<&mut dyn ProgressObserver>,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<usize> {
+    let mut db = Connection::open(path).context(format!("can't open file {} in read-write mode", path)).location(here!())?;
+
+    let tx = db.transaction().location(here!())?;
+    let mut created_count = 0;
+    let mut progress = ProgressTracker::new(progress_observer, OPTIONAL_INDEXES.len());
+
+    for (index_name, index_target) in OPTIONAL_INDEXES {
+        check_cancellation(cancellation_token)?;
+
+        let index_count: i64 = tx.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'index' AND name = ?", [index_name], |row| row.get(0)
+        ).location(here!())?;
+
+        if index_count == 0 {
+            tx.execute(format!("CREATE INDEX {} ON {}", index_name, index_target).as_str(), []).location(here!())?;
+            created_count += 1;
+        }
+        progress.increment();
+    }
+
+    tx.commit().location(here!())?;
+
+    Ok(created_count)
+}
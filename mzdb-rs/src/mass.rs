@@ -0,0 +1,60 @@
+// Mass and m/z transformations: ppm/Da tolerances, charge state conversions and Kendrick mass defects.
+// Masses are monoisotopic and in Da, charges are signed (negative for negative ions).
+
+pub const PROTON_MASS: f64 = 1.007276466621;
+pub const ELECTRON_MASS: f64 = 0.000548579909;
+
+// Masses of the cations commonly found as adducts (atomic mass minus one electron)
+pub const SODIUM_ION_MASS: f64 = 22.989221;
+pub const POTASSIUM_ION_MASS: f64 = 38.963158;
+pub const AMMONIUM_ION_MASS: f64 = 18.033826;
+
+pub const CH2_MASS: f64 = 14.015650064;
+
+// Mass difference between the 13C and 12C isotopes, used as the spacing of the isotope envelopes
+pub const C13_C12_MASS_DIFF: f64 = 1.0033548378;
+
+/// Returns the tolerance in Da corresponding to a ppm tolerance at the provided m/z (or mass)
+pub fn ppm_to_da(mz: f64, ppm: f64) -> f64 {
+    mz * ppm / 1e6
+}
+
+/// Returns the tolerance in ppm corresponding to a Da tolerance at the provided m/z (or mass)
+pub fn da_to_ppm(mz: f64, da: f64) -> f64 {
+    da * 1e6 / mz
+}
+
+/// Returns the signed error in ppm of an observed m/z (or mass) relatively to the theoretical one
+pub fn ppm_error(observed_mz: f64, theoretical_mz: f64) -> f64 {
+    da_to_ppm(theoretical_mz, observed_mz - theoretical_mz)
+}
+
+/// Returns the m/z of an ion of the provided neutral mass, charged by protonation (or deprotonation for negative charges).
+/// The charge must not be 0.
+pub fn neutral_mass_to_mz(neutral_mass: f64, charge: i32) -> f64 {
+    (neutral_mass + charge as f64 * PROTON_MASS) / charge.abs() as f64
+}
+
+/// Returns the neutral mass of an ion of the provided m/z, charged by protonation (or deprotonation for negative charges).
+/// The charge must not be 0.
+pub fn mz_to_neutral_mass(mz: f64, charge: i32) -> f64 {
+    mz * charge.abs() as f64 - charge as f64 * PROTON_MASS
+}
+
+/// Returns the m/z of the isotope of rank isotope_idx (0 for the monoisotopic peak) of an ion of the provided charge.
+/// The charge must not be 0.
+pub fn isotope_mz(mono_mz: f64, charge: i32, isotope_idx: usize) -> f64 {
+    mono_mz + isotope_idx as f64 * C13_C12_MASS_DIFF / charge.abs() as f64
+}
+
+/// Returns the Kendrick mass of a mass, rescaled so that the repeat unit (e.g. CH2_MASS) has an integer mass
+pub fn kendrick_mass(mass: f64, repeat_unit_mass: f64) -> f64 {
+    mass * repeat_unit_mass.round() / repeat_unit_mass
+}
+
+/// Returns the Kendrick mass defect of a mass, computed as the nominal Kendrick mass minus the Kendrick mass.
+/// Members of a homologous series (differing by the repeat unit) share the same Kendrick mass defect.
+pub fn kendrick_mass_defect(mass: f64, repeat_unit_mass: f64) -> f64 {
+    let kendrick_mass = kendrick_mass(mass, repeat_unit_mass);
+    kendrick_mass.round() - kendrick_mass
+}
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+use serde::de::DeserializeOwned;
+use serde_rusqlite::from_rows;
+
+use crate::model::*;
+use crate::queries::{get_param_tree_spectrum, get_scan_list_xml};
+use crate::xml::{parse_component_list, parse_param_tree, parse_user_params};
+
+// Maps every row returned by the query onto a record having the same field names as the table columns
+fn list_records<T: DeserializeOwned>(db: &Connection, query_str: &str) -> Result<Vec<T>> {
+    let mut stmt = db.prepare(query_str).location(here!())?;
+    let records = from_rows::<T>(stmt.query([]).location(here!())?);
+
+    let mut result = Vec::new();
+    for record_res in records {
+        result.push(record_res.location(here!())?);
+    }
+
+    Ok(result)
+}
+
+/// Get the single record of the mzdb table
+pub fn get_mzdb_metadata(db: &Connection) -> Result<Option<MzDbMetadata>> {
+    let mut records = list_records::<MzDbMetadata>(db, "SELECT * FROM mzdb LIMIT 1").location(here!())?;
+    Ok(records.pop())
+}
+
+/// List the controlled vocabularies
+pub fn list_cvs(db: &Connection) -> Result<Vec<Cv>> {
+    list_records(db, "SELECT * FROM cv")
+}
+
+/// List the controlled vocabulary terms
+pub fn list_cv_terms(db: &Connection) -> Result<Vec<CvTerm>> {
+    list_records(db, "SELECT * FROM cv_term")
+}
+
+/// List the controlled vocabulary units
+pub fn list_cv_units(db: &Connection) -> Result<Vec<CvUnit>> {
+    list_records(db, "SELECT * FROM cv_unit")
+}
+
+/// List the user defined terms
+pub fn list_user_terms(db: &Connection) -> Result<Vec<UserTerm>> {
+    list_records(db, "SELECT * FROM user_term")
+}
+
+/// List the param trees shared by several entities
+pub fn list_shared_param_trees(db: &Connection) -> Result<Vec<SharedParamTree>> {
+    list_records(db, "SELECT * FROM shared_param_tree")
+}
+
+/// Get a param tree shared by several entities
+pub fn get_shared_param_tree(db: &Connection, shared_param_tree_id: i64) -> Result<Option<SharedParamTree>> {
+    let mut records = list_records::<SharedParamTree>(
+        db,
+        format!("SELECT * FROM shared_param_tree WHERE id = {}", shared_param_tree_id).as_str()
+    ).location(here!())?;
+
+    Ok(records.pop())
+}
+
+/// Get the effective params of an entity (spectrum, run, sample...), merging its own param tree with the shared one
+/// referenced by its shared_param_tree_id. Own params take precedence over the shared params having the same accession (or name).
+pub fn resolve_param_tree(db: &Connection, own_xml: Option<&str>, shared_param_tree_id: Option<i64>) -> Result<ParamTree> {
+    let own_param_tree = match own_xml {
+        Some(xml) => parse_param_tree(xml).location(here!())?,
+        None => ParamTree { cv_params: Vec::new(), user_params: Vec::new(), user_texts: Vec::new() },
+    };
+
+    let shared_param_tree_id = match shared_param_tree_id {
+        Some(id) => id,
+        None => return Ok(own_param_tree),
+    };
+
+    let shared_param_tree = get_shared_param_tree(db, shared_param_tree_id).location(here!())?
+        .context(format!("can't find shared param tree with ID={}", shared_param_tree_id)).location(here!())?;
+    let shared_params = parse_param_tree(&shared_param_tree.data)
+        .context(format!("can't parse shared param tree with ID={}", shared_param_tree_id)).location(here!())?;
+
+    Ok(own_param_tree.merge_shared(shared_params))
+}
+
+/// Parse all the shared param trees, indexed by ID
+pub fn parse_shared_param_trees(db: &Connection) -> Result<HashMap<i64, ParamTree>> {
+    let mut param_tree_by_id = HashMap::new();
+    for shared_param_tree in list_shared_param_trees(db).location(here!())? {
+        let param_tree = parse_param_tree(&shared_param_tree.data)
+            .context(format!("can't parse shared param tree with ID={}", shared_param_tree.id)).location(here!())?;
+        param_tree_by_id.insert(shared_param_tree.id, param_tree);
+    }
+
+    Ok(param_tree_by_id)
+}
+
+/// Get the scan polarity of a spectrum, read from its param_tree merged with its shared param tree,
+/// or from its scan_list when missing there. The XML fields are fetched when the header is light.
+pub fn get_spectrum_polarity(db: &Connection, spectrum_header: &SpectrumHeader) -> Result<Polarity> {
+    let param_tree_opt = match spectrum_header.param_tree_str.as_ref() {
+        Some(param_tree) => Some(param_tree.clone()),
+        None => get_param_tree_spectrum(db, spectrum_header.id).location(here!())?,
+    };
+
+    let param_tree = resolve_param_tree(db, param_tree_opt.as_deref(), spectrum_header.shared_param_tree_id).location(here!())?;
+    let polarity = Polarity::from_cv_params(&param_tree.cv_params);
+    if polarity != Polarity::UNKNOWN {
+        return Ok(polarity);
+    }
+
+    let scan_list_opt = match spectrum_header.scan_list_str.as_ref() {
+        Some(scan_list) => Some(scan_list.clone()),
+        None => get_scan_list_xml(db, spectrum_header.id).location(here!())?,
+    };
+
+    Polarity::from_xml_fields(None, scan_list_opt.as_deref())
+}
+
+/// Get the type of a chromatogram, inferred from its param_tree merged with its shared param tree (see ChromatogramHeader::chromatogram_type())
+pub fn get_chromatogram_type(db: &Connection, chromatogram_header: &ChromatogramHeader) -> Result<ChromatogramType> {
+    let param_tree = resolve_param_tree(db, Some(&chromatogram_header.param_tree), chromatogram_header.shared_param_tree_id).location(here!())?;
+
+    Ok(chromatogram_header.chromatogram_type_from_cv_params(&param_tree.cv_params))
+}
+
+/// List the samples
+pub fn list_samples(db: &Connection) -> Result<Vec<Sample>> {
+    list_records(db, "SELECT * FROM sample")
+}
+
+/// List the software used to produce the file
+pub fn list_software(db: &Connection) -> Result<Vec<Software>> {
+    list_records(db, "SELECT * FROM software")
+}
+
+/// List the source files the data were converted from
+pub fn list_source_files(db: &Connection) -> Result<Vec<SourceFile>> {
+    list_records(db, "SELECT * FROM source_file")
+}
+
+/// List the instrument configurations
+pub fn list_instrument_configurations(db: &Connection) -> Result<Vec<InstrumentConfiguration>> {
+    list_records(db, "SELECT * FROM instrument_configuration")
+}
+
+/// List the data processings
+pub fn list_data_processings(db: &Connection) -> Result<Vec<DataProcessing>> {
+    list_records(db, "SELECT * FROM data_processing")
+}
+
+/// List the processing methods ordered by number
+pub fn list_processing_methods(db: &Connection) -> Result<Vec<ProcessingMethod>> {
+    list_records(db, "SELECT * FROM processing_method ORDER BY number")
+}
+
+/// Get the processing methods of a data processing, ordered by number, joined with their software.
+/// The param trees of both are merged with their shared param trees (see resolve_param_tree()).
+/// Returns None if the data processing doesn't exist.
+pub fn get_processing_chain(db: &Connection, data_processing_id: i64) -> Result<Option<ProcessingChain>> {
+    let data_processing = match list_records::<DataProcessing>(
+        db,
+        format!("SELECT * FROM data_processing WHERE id = {}", data_processing_id).as_str()
+    ).location(here!())?.pop() {
+        Some(data_processing) => data_processing,
+        None => return Ok(None),
+    };
+
+    let methods = list_records::<ProcessingMethod>(
+        db,
+        format!("SELECT * FROM processing_method WHERE data_processing_id = {} ORDER BY number", data_processing_id).as_str()
+    ).location(here!())?;
+
+    let software_by_id: HashMap<i64, Software> = list_software(db).location(here!())?
+        .into_iter()
+        .map(|software| (software.id, software))
+        .collect();
+
+    let mut steps = Vec::with_capacity(methods.len());
+    for method in methods {
+        let software = software_by_id.get(&method.software_id).cloned()
+            .context(format!("can't find software with ID={} of processing method with ID={}", method.software_id, method.id))
+            .location(here!())?;
+
+        let method_params = resolve_param_tree(db, _non_empty_xml(&method.param_tree), method.shared_param_tree_id)
+            .context(format!("can't resolve param tree of processing method with ID={}", method.id)).location(here!())?;
+        let software_params = resolve_param_tree(db, _non_empty_xml(&software.param_tree), software.shared_param_tree_id)
+            .context(format!("can't resolve param tree of software with ID={}", software.id)).location(here!())?;
+
+        steps.push(ProcessingStep { method, method_params, software, software_params });
+    }
+
+    Ok(Some(ProcessingChain { data_processing, steps }))
+}
+
+// Some writers store an empty string instead of an empty param tree
+fn _non_empty_xml(xml: &str) -> Option<&str> {
+    if xml.trim().is_empty() { None } else { Some(xml) }
+}
+
+/// List the scan settings
+pub fn list_scan_settings(db: &Connection) -> Result<Vec<ScanSettings>> {
+    list_records(db, "SELECT * FROM scan_settings")
+}
+
+/// List the runs
+pub fn list_runs(db: &Connection) -> Result<Vec<Run>> {
+    list_records(db, "SELECT * FROM run")
+}
+
+/// Get an instrument configuration from its ID
+pub fn get_instrument_configuration(db: &Connection, instrument_config_id: i64) -> Result<Option<InstrumentConfiguration>> {
+    let mut records = list_records::<InstrumentConfiguration>(
+        db,
+        format!("SELECT * FROM instrument_configuration WHERE id = {}", instrument_config_id).as_str()
+    ).location(here!())?;
+
+    Ok(records.pop())
+}
+
+/// Get the parsed component_list of an instrument configuration
+pub fn get_instrument_components(db: &Connection, instrument_config_id: i64) -> Result<Option<ComponentList>> {
+    let instrument_config_opt = get_instrument_configuration(db, instrument_config_id).location(here!())?;
+
+    instrument_config_opt
+        .map(|instrument_config| parse_component_list(&instrument_config.component_list))
+        .transpose()
+}
+
+/// Get the names of the sources, analyzers and detectors declared by all the instrument configurations
+pub fn get_instrument_summary(db: &Connection) -> Result<InstrumentSummary> {
+    let mut summary = InstrumentSummary {
+        source_names: Vec::new(),
+        analyzer_names: Vec::new(),
+        detector_names: Vec::new(),
+    };
+
+    for instrument_config in list_instrument_configurations(db).location(here!())? {
+        let component_list = parse_component_list(&instrument_config.component_list)
+            .context(format!("can't parse component list of instrument configuration with ID={}", instrument_config.id))
+            .location(here!())?;
+
+        let summary_names = [
+            (ComponentType::SOURCE, &mut summary.source_names),
+            (ComponentType::ANALYZER, &mut summary.analyzer_names),
+            (ComponentType::DETECTOR, &mut summary.detector_names),
+        ];
+
+        for (component_type, names) in summary_names {
+            for name in component_list.get_component_names(component_type) {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Gather the metadata tables of the file in a single FileMetadata tree, with parsed instrument component lists
+pub fn get_file_metadata(db: &Connection) -> Result<FileMetadata> {
+    let mut instruments = Vec::new();
+    for configuration in list_instrument_configurations(db).location(here!())? {
+        let components = parse_component_list(&configuration.component_list)
+            .context(format!("can't parse component list of instrument configuration with ID={}", configuration.id))
+            .location(here!())?;
+
+        instruments.push(InstrumentMetadata { configuration, components });
+    }
+
+    Ok(FileMetadata {
+        mzdb: get_mzdb_metadata(db).location(here!())?,
+        runs: list_runs(db).location(here!())?,
+        samples: list_samples(db).location(here!())?,
+        software: list_software(db).location(here!())?,
+        instruments,
+        source_files: list_source_files(db).location(here!())?,
+        data_processings: list_data_processings(db).location(here!())?,
+        processing_methods: list_processing_methods(db).location(here!())?,
+    })
+}
+
+/// Gather the provenance of the file: creation timestamp, mzDB writer, source files and original format
+pub fn get_file_provenance(db: &Connection) -> Result<FileProvenance> {
+    let mzdb_metadata_opt = get_mzdb_metadata(db).location(here!())?;
+
+    let creation_timestamp = mzdb_metadata_opt.as_ref()
+        .map(|mzdb_metadata| mzdb_metadata.creation_timestamp.trim().to_string())
+        .filter(|creation_timestamp| !creation_timestamp.is_empty());
+    let creation_time = creation_timestamp.as_deref().and_then(parse_timestamp);
+
+    let origin_file_format = match mzdb_metadata_opt.as_ref() {
+        Some(mzdb_metadata) if !mzdb_metadata.param_tree.trim().is_empty() => {
+            parse_user_params(&mzdb_metadata.param_tree).location(here!())?.into_iter()
+                .find(|user_param| user_param.name == "origin_file_format")
+                .map(|user_param| user_param.value)
+        },
+        _ => None,
+    };
+
+    // Same writer detection as compat::detect_quirks()
+    let writer_software = list_records::<Software>(
+        db,
+        "SELECT * FROM software WHERE lower(name) LIKE '%mzdb%' ORDER BY id DESC LIMIT 1"
+    ).location(here!())?.pop();
+
+    Ok(FileProvenance {
+        creation_timestamp,
+        creation_time,
+        writer_software,
+        source_files: list_source_files(db).location(here!())?,
+        origin_file_format,
+    })
+}
+
+/// Parse a timestamp into seconds since the Unix epoch (UTC), returns None if the format is not supported.
+/// The producers write either a Unix epoch (in seconds or milliseconds, possibly fractional)
+/// or an ISO 8601 like date time ("2022-05-24T11:41:29Z", "2022-05-24 11:41:29.123+02:00", "2022/05/24"...).
+/// Date times without UTC offset are assumed to be in UTC. They are parsed with chrono when the chrono feature is enabled.
+pub fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let timestamp = timestamp.trim();
+    if timestamp.is_empty() {
+        return None;
+    }
+
+    if let Result::Ok(epoch) = timestamp.parse::<f64>() {
+        if !epoch.is_finite() || epoch < 0.0 {
+            return None;
+        }
+
+        // 1e11 seconds is far in the future, while 1e11 milliseconds is in 1973
+        let epoch_secs = if epoch >= 1e11 { epoch / 1000.0 } else { epoch };
+        return Some(epoch_secs.floor() as i64);
+    }
+
+    _parse_date_time(timestamp)
+}
+
+#[cfg(feature = "chrono")]
+fn _parse_date_time(date_time: &str) -> Option<i64> {
+    use chrono::{DateTime, NaiveDate, NaiveDateTime};
+
+    if let Result::Ok(date_time) = DateTime::parse_from_rfc3339(date_time) {
+        return Some(date_time.timestamp());
+    }
+
+    // Also accept the '/' date separator, the ' ' date time separator and the "UTC" suffix
+    let date_time = date_time.replacen('/', "-", 2).replacen(' ', "T", 1);
+    let (date_time, is_utc) = match date_time.strip_suffix('Z').or_else(|| date_time.strip_suffix("UTC")) {
+        Some(utc_date_time) => (utc_date_time.trim_end(), true),
+        None => (date_time.as_str(), false),
+    };
+
+    if !is_utc {
+        if let Result::Ok(date_time) = DateTime::parse_from_str(date_time, "%Y-%m-%dT%H:%M:%S%.f%z") {
+            return Some(date_time.timestamp());
+        }
+    }
+
+    if let Result::Ok(date_time) = NaiveDateTime::parse_from_str(date_time, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Some(date_time.and_utc().timestamp());
+    }
+
+    NaiveDate::parse_from_str(date_time, "%Y-%m-%d").ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|date_time| date_time.and_utc().timestamp())
+}
+
+#[cfg(not(feature = "chrono"))]
+fn _parse_date_time(date_time: &str) -> Option<i64> {
+    let (date, time) = match date_time.find(['T', ' ']) {
+        Some(pos) => (&date_time[..pos], date_time[pos + 1..].trim()),
+        None => (date_time, ""),
+    };
+
+    let date_parts: Vec<&str> = date.split(['-', '/']).collect();
+    if date_parts.len() != 3 {
+        return None;
+    }
+
+    let year: i64 = date_parts[0].parse().ok()?;
+    let month: u32 = date_parts[1].parse().ok()?;
+    let day: u32 = date_parts[2].parse().ok()?;
+    if !(1..=12).contains(&month) || day == 0 || day > _days_in_month(year, month) {
+        return None;
+    }
+
+    let (time, utc_offset_secs) = _split_utc_offset(time)?;
+
+    let time_parts: Vec<&str> = if time.is_empty() { Vec::new() } else { time.split(':').collect() };
+    if time_parts.len() > 3 {
+        return None;
+    }
+
+    let hour: i64 = time_parts.first().map_or(Some(0), |hour| hour.parse().ok())?;
+    let minute: i64 = time_parts.get(1).map_or(Some(0), |minute| minute.parse().ok())?;
+    let second: f64 = time_parts.get(2).map_or(Some(0.0), |second| second.parse().ok())?;
+    if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0.0..61.0).contains(&second) {
+        return None;
+    }
+
+    Some(_days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second.floor() as i64 - utc_offset_secs)
+}
+
+// Split the time and its UTC offset (in seconds), supporting the "Z", "UTC", "+HH", "+HHMM" and "+HH:MM" suffixes
+#[cfg(not(feature = "chrono"))]
+fn _split_utc_offset(time: &str) -> Option<(&str, i64)> {
+    if let Some(utc_time) = time.strip_suffix('Z').or_else(|| time.strip_suffix("UTC")) {
+        return Some((utc_time.trim_end(), 0));
+    }
+
+    let sign_pos = match time.rfind(['+', '-']) {
+        Some(sign_pos) => sign_pos,
+        None => return Some((time, 0)),
+    };
+
+    let offset = time[sign_pos + 1..].replace(':', "");
+    if !(offset.len() == 2 || offset.len() == 4) || !offset.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let offset_hours: i64 = offset[..2].parse().ok()?;
+    let offset_minutes: i64 = if offset.len() == 4 { offset[2..].parse().ok()? } else { 0 };
+    let sign = if time[sign_pos..].starts_with('-') { -1 } else { 1 };
+
+    Some((time[..sign_pos].trim_end(), sign * (offset_hours * 3600 + offset_minutes * 60)))
+}
+
+#[cfg(not(feature = "chrono"))]
+fn _days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Number of days since 1970-01-01 of a date of the proleptic Gregorian calendar (see http://howardhinnant.github.io/date_algorithms.html)
+#[cfg(not(feature = "chrono"))]
+fn _days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Export the metadata of the file as a single JSON document (see get_file_metadata)
+pub fn export_metadata_json(db: &Connection) -> Result<String> {
+    let file_metadata = get_file_metadata(db).location(here!())?;
+    serde_json::to_string_pretty(&file_metadata).location(here!())
+}
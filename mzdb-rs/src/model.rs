@@ -1,10 +1,17 @@
 //use itertools::Itertools;
 //use rusqlite::{Connection, Result};
 
+use anyhow::{Context, Result};
+use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
 //use serde_rusqlite::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::anyhow_ext::*;
+use crate::diagnostics::DiagnosticsSink;
+use crate::mass::ppm_to_da;
 use crate::model::DataMode::FITTED;
 
 
@@ -18,10 +25,37 @@ pub const ACQUISITION_MODE_UNKNOWN: &str = "unknown acquisition mode";
 pub const PSI_MS_32_BIT_FLOAT: &str = "*0521";
 pub const PSI_MS_64_BIT_FLOAT: &str = "*0523";
 pub const ACQUISITION_PARAMETER: &str = "*1954";
-pub const ISOLATION_WINDOW_TARGET_MZ: &str = "MS:*0827";
-pub const ISOLATION_WINDOW_LOWER_OFFSET: &str = "MS:*0828";
-pub const ISOLATION_WINDOW_UPPER_OFFSET: &str = "MS:*0829";
-pub const SELECTED_ION_MZ: &str = "MS:*0744";
+pub const ISOLATION_WINDOW_TARGET_MZ: &str = "MS:1000827";
+pub const ISOLATION_WINDOW_LOWER_OFFSET: &str = "MS:1000828";
+pub const ISOLATION_WINDOW_UPPER_OFFSET: &str = "MS:1000829";
+pub const SELECTED_ION_MZ: &str = "MS:1000744";
+pub const CHARGE_STATE: &str = "MS:1000041";
+pub const PEAK_INTENSITY: &str = "MS:1000042";
+pub const COLLISION_ENERGY: &str = "MS:1000045";
+pub const SCAN_START_TIME: &str = "MS:1000016";
+pub const UNIT_SECOND: &str = "UO:0000010";
+pub const UNIT_MINUTE: &str = "UO:0000031";
+pub const TIME_ARRAY: &str = "MS:1000595";
+pub const NEGATIVE_SCAN: &str = "MS:1000129";
+pub const POSITIVE_SCAN: &str = "MS:1000130";
+pub const FILTER_STRING: &str = "MS:1000512";
+pub const ION_INJECTION_TIME: &str = "MS:1000927";
+pub const SCAN_WINDOW_LOWER_LIMIT: &str = "MS:1000501";
+pub const SCAN_WINDOW_UPPER_LIMIT: &str = "MS:1000500";
+pub const COLLISION_INDUCED_DISSOCIATION: &str = "MS:1000133";
+pub const BEAM_TYPE_COLLISION_INDUCED_DISSOCIATION: &str = "MS:1000422";
+pub const HIGHER_ENERGY_BEAM_TYPE_COLLISION_INDUCED_DISSOCIATION: &str = "MS:1002481";
+pub const SUPPLEMENTAL_BEAM_TYPE_COLLISION_INDUCED_DISSOCIATION: &str = "MS:1002678";
+pub const SUPPLEMENTAL_COLLISION_INDUCED_DISSOCIATION: &str = "MS:1002679";
+pub const ELECTRON_TRANSFER_DISSOCIATION: &str = "MS:1000598";
+pub const ELECTRON_CAPTURE_DISSOCIATION: &str = "MS:1000250";
+pub const PULSED_Q_DISSOCIATION: &str = "MS:1000599";
+pub const INFRARED_MULTIPHOTON_DISSOCIATION: &str = "MS:1000262";
+pub const ULTRAVIOLET_PHOTODISSOCIATION: &str = "MS:1003246";
+pub const TOTAL_ION_CURRENT_CHROMATOGRAM: &str = "MS:1000235";
+pub const SELECTED_ION_CURRENT_CHROMATOGRAM: &str = "MS:1000627";
+pub const BASEPEAK_CHROMATOGRAM: &str = "MS:1000628";
+pub const SELECTED_REACTION_MONITORING_CHROMATOGRAM: &str = "MS:1001473";
 
 //the acquisition mode
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -73,7 +107,7 @@ pub struct FittedPeak {
 }
 
 //ParamTree.h
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CvParam {
     pub cv_ref: String,
     pub accession: String,
@@ -84,7 +118,7 @@ pub struct CvParam {
     pub unit_name: String,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct UserParam {
     pub cv_ref: String,
     pub accession: String,
@@ -109,6 +143,71 @@ pub struct ParamTree {
     pub user_texts: Vec<UserText>,
 }
 
+impl ParamTree {
+    /// Merge the params of a shared param tree into this one, the own params (same accession or name) taking precedence
+    pub fn merge_shared(mut self, shared: ParamTree) -> ParamTree {
+        for cv_param in shared.cv_params {
+            if !self.cv_params.iter().any(|own_cv_param| own_cv_param.accession == cv_param.accession) {
+                self.cv_params.push(cv_param);
+            }
+        }
+
+        for user_param in shared.user_params {
+            if !self.user_params.iter().any(|own_user_param| own_user_param.name == user_param.name) {
+                self.user_params.push(user_param);
+            }
+        }
+
+        for user_text in shared.user_texts {
+            if !self.user_texts.iter().any(|own_user_text| own_user_text.name == user_text.name) {
+                self.user_texts.push(user_text);
+            }
+        }
+
+        self
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ComponentType {
+    SOURCE,
+    ANALYZER,
+    DETECTOR,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Component {
+    pub component_type: ComponentType,
+    pub order: i32,
+    pub cv_params: Vec<CvParam>,
+    pub user_params: Vec<UserParam>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ComponentList {
+    pub components: Vec<Component>, // sorted by order
+}
+
+impl ComponentList {
+    pub fn get_components(&self, component_type: ComponentType) -> Vec<&Component> {
+        self.components.iter().filter(|c| c.component_type == component_type).collect()
+    }
+
+    /// Returns the names of the cvParams describing the components of a given type
+    pub fn get_component_names(&self, component_type: ComponentType) -> Vec<String> {
+        self.get_components(component_type).iter()
+            .flat_map(|c| c.cv_params.iter().map(|cv_param| cv_param.name.clone()))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstrumentSummary {
+    pub source_names: Vec<String>,
+    pub analyzer_names: Vec<String>,
+    pub detector_names: Vec<String>,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct MzdbParamTree {
     pub ms1_bb_mz_width: f32,
@@ -119,6 +218,15 @@ pub struct MzdbParamTree {
     pub origin_file_format: String,
 }
 
+// Dimensions of the bounding boxes (m/z widths in Da, time widths in seconds)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BBSizes {
+    pub ms1_bb_mz_width: f32,
+    pub ms1_bb_time_width: f32,
+    pub msn_bb_mz_width: f32,
+    pub msn_bb_time_width: f32,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(i32)]
 pub enum DataMode {
@@ -198,10 +306,11 @@ impl DataEncoding {
 }
 
 
+// The maps are reference counted, so that caches having the same content can share them (see share_maps_with())
 #[derive(Clone, Debug, PartialEq)]
 pub struct DataEncodingsCache {
-    data_encoding_by_id: HashMap<i64, DataEncoding>,
-    data_encoding_id_by_spectrum_id: HashMap<i64, i64>,
+    data_encoding_by_id: Arc<HashMap<i64, DataEncoding>>,
+    data_encoding_id_by_spectrum_id: Arc<HashMap<i64, i64>>,
 }
 
 impl DataEncodingsCache {
@@ -210,7 +319,31 @@ impl DataEncodingsCache {
         data_encoding_by_id: HashMap<i64, DataEncoding>,
         data_encoding_id_by_spectrum_id: HashMap<i64, i64>
     ) -> Self {
-        Self { data_encoding_by_id, data_encoding_id_by_spectrum_id }
+        Self { data_encoding_by_id: Arc::new(data_encoding_by_id), data_encoding_id_by_spectrum_id: Arc::new(data_encoding_id_by_spectrum_id) }
+    }
+
+    /// Replace the maps of this cache by the ones of another cache when their content is identical, thus releasing the memory of the former.
+    /// Returns true if at least one map is now shared.
+    pub fn share_maps_with(&mut self, other: &DataEncodingsCache) -> bool {
+        let mut is_sharing = false;
+
+        if Arc::ptr_eq(&self.data_encoding_by_id, &other.data_encoding_by_id) || self.data_encoding_by_id == other.data_encoding_by_id {
+            self.data_encoding_by_id = Arc::clone(&other.data_encoding_by_id);
+            is_sharing = true;
+        }
+        if Arc::ptr_eq(&self.data_encoding_id_by_spectrum_id, &other.data_encoding_id_by_spectrum_id)
+            || self.data_encoding_id_by_spectrum_id == other.data_encoding_id_by_spectrum_id {
+            self.data_encoding_id_by_spectrum_id = Arc::clone(&other.data_encoding_id_by_spectrum_id);
+            is_sharing = true;
+        }
+
+        is_sharing
+    }
+
+    /// Returns true if both caches use the same map instances
+    pub fn shares_maps_with(&self, other: &DataEncodingsCache) -> bool {
+        Arc::ptr_eq(&self.data_encoding_by_id, &other.data_encoding_by_id)
+            && Arc::ptr_eq(&self.data_encoding_id_by_spectrum_id, &other.data_encoding_id_by_spectrum_id)
     }
 
     pub fn get_data_encoding_by_id(&self, de_id: &i64) -> Option<&DataEncoding> {
@@ -287,10 +420,182 @@ union IntensityArray {
 pub struct SpectrumData {
     pub data_encoding: DataEncoding,
     pub peak_count: usize,
-    pub mz_array: Vec<f64>,
-    pub intensity_array: Vec<f32>,
-    pub lwhm_array: Vec<f32>, // warning: can be NULL
-    pub rwhm_array: Vec<f32>, // warning: can be NULL
+    // Arrays are shared between clones, which makes cloning a SpectrumData (or passing it to the bindings) cheap
+    pub mz_array: Arc<[f64]>,
+    pub intensity_array: Arc<[f32]>,
+    pub lwhm_array: Arc<[f32]>, // warning: can be empty
+    pub rwhm_array: Arc<[f32]>, // warning: can be empty
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Peak {
+    pub mz: f64,
+    pub intensity: f32,
+    pub lwhm: Option<f32>, // only defined in fitted mode
+    pub rwhm: Option<f32>, // only defined in fitted mode
+}
+
+impl SpectrumData {
+
+    pub fn get_peak(&self, peak_idx: usize) -> Option<Peak> {
+        let mz = *self.mz_array.get(peak_idx)?;
+        let intensity = *self.intensity_array.get(peak_idx)?;
+
+        Some(Peak {
+            mz,
+            intensity,
+            lwhm: self.lwhm_array.get(peak_idx).copied(),
+            rwhm: self.rwhm_array.get(peak_idx).copied(),
+        })
+    }
+
+    pub fn get_mz_at(&self, peak_idx: usize) -> Result<f64> {
+        _get_value_at(&self.mz_array, peak_idx, "m/z")
+    }
+
+    pub fn get_intensity_at(&self, peak_idx: usize) -> Result<f32> {
+        _get_value_at(&self.intensity_array, peak_idx, "intensity")
+    }
+
+    /// Note: half widths are only defined in fitted mode
+    pub fn get_left_hwhm_at(&self, peak_idx: usize) -> Result<f32> {
+        _get_value_at(&self.lwhm_array, peak_idx, "left HWHM")
+    }
+
+    /// Note: half widths are only defined in fitted mode
+    pub fn get_right_hwhm_at(&self, peak_idx: usize) -> Result<f32> {
+        _get_value_at(&self.rwhm_array, peak_idx, "right HWHM")
+    }
+
+    pub fn iter_peaks(&self) -> impl Iterator<Item = Peak> + '_ {
+        (0..self.mz_array.len()).filter_map(move |peak_idx| self.get_peak(peak_idx))
+    }
+
+    /// Returns a copy of this SpectrumData restricted to the peaks in the [min_mz, max_mz] range.
+    /// Note: the m/z array is expected to be sorted.
+    pub fn crop(&self, min_mz: f64, max_mz: f64) -> SpectrumData {
+        let first_idx = self.mz_array.partition_point(|mz| *mz < min_mz);
+        let last_idx = self.mz_array.partition_point(|mz| *mz <= max_mz).max(first_idx);
+
+        let sub_array = |values: &Arc<[f32]>| -> Arc<[f32]> {
+            if values.is_empty() { Arc::clone(values) } else { Arc::from(&values[first_idx..last_idx]) }
+        };
+
+        SpectrumData {
+            data_encoding: self.data_encoding.clone(),
+            peak_count: last_idx - first_idx,
+            mz_array: Arc::from(&self.mz_array[first_idx..last_idx]),
+            intensity_array: sub_array(&self.intensity_array),
+            lwhm_array: sub_array(&self.lwhm_array),
+            rwhm_array: sub_array(&self.rwhm_array),
+        }
+    }
+
+    /// Returns true if the m/z array is sorted in ascending order, as expected by the binary searches (e.g. crop())
+    pub fn is_mz_sorted(&self) -> bool {
+        self.mz_array.windows(2).all(|w| w[0] <= w[1])
+    }
+
+    /// Returns a copy of this SpectrumData whose peaks are sorted by m/z (the other arrays being permuted accordingly)
+    pub fn sort_by_mz(&self) -> SpectrumData {
+        let mut peak_indices: Vec<usize> = (0..self.mz_array.len()).collect();
+        peak_indices.sort_by(|i1, i2| self.mz_array[*i1].total_cmp(&self.mz_array[*i2]));
+
+        let permute = |values: &Arc<[f32]>| -> Arc<[f32]> {
+            if values.is_empty() { Arc::clone(values) } else { peak_indices.iter().map(|idx| values[*idx]).collect() }
+        };
+
+        SpectrumData {
+            data_encoding: self.data_encoding.clone(),
+            peak_count: self.peak_count,
+            mz_array: peak_indices.iter().map(|idx| self.mz_array[*idx]).collect(),
+            intensity_array: permute(&self.intensity_array),
+            lwhm_array: permute(&self.lwhm_array),
+            rwhm_array: permute(&self.rwhm_array),
+        }
+    }
+
+    /// Returns the n most intense peaks, sorted by m/z
+    pub fn top_n(&self, n: usize) -> Vec<Peak> {
+        let mut peaks: Vec<Peak> = self.iter_peaks().collect();
+        peaks.sort_by(|p1, p2| p2.intensity.total_cmp(&p1.intensity));
+        peaks.truncate(n);
+        peaks.sort_by(|p1, p2| p1.mz.total_cmp(&p2.mz));
+
+        peaks
+    }
+
+    pub fn total_ion_current(&self) -> f64 {
+        self.intensity_array.iter().map(|intensity| *intensity as f64).sum()
+    }
+
+    /// Returns the most intense peak or None if the spectrum is empty
+    pub fn base_peak(&self) -> Option<Peak> {
+        let base_peak_idx = self.intensity_array.iter().enumerate()
+            .max_by(|(_i1, int1), (_i2, int2)| int1.total_cmp(int2))
+            .map(|(peak_idx, _intensity)| peak_idx)?;
+
+        self.get_peak(base_peak_idx)
+    }
+
+    /// Reduce the number of peaks for display purpose, using min-max decimation.
+    /// The m/z range is divided in n_buckets buckets of equal width, and only the least and the most intense peaks
+    /// of each bucket are kept, which preserves the peak apexes. The result thus contains at most 2 x n_buckets peaks.
+    pub fn downsample_minmax(&self, n_buckets: usize) -> SpectrumData {
+        if n_buckets == 0 || self.peak_count <= 2 * n_buckets {
+            return self.clone();
+        }
+
+        let min_mz = self.mz_array[0];
+        let bucket_width = (self.mz_array[self.peak_count - 1] - min_mz) / n_buckets as f64;
+
+        let mut kept_indices = Vec::with_capacity(2 * n_buckets);
+        let mut bucket_first_idx = 0;
+        while bucket_first_idx < self.peak_count {
+            let bucket_idx = if bucket_width > 0.0 {
+                (((self.mz_array[bucket_first_idx] - min_mz) / bucket_width) as usize).min(n_buckets - 1)
+            } else {
+                n_buckets - 1
+            };
+            let bucket_max_mz = min_mz + (bucket_idx + 1) as f64 * bucket_width;
+
+            let mut bucket_last_idx = bucket_first_idx + 1;
+            while bucket_last_idx < self.peak_count && (bucket_idx == n_buckets - 1 || self.mz_array[bucket_last_idx] < bucket_max_mz) {
+                bucket_last_idx += 1;
+            }
+
+            let bucket_intensities = &self.intensity_array[bucket_first_idx..bucket_last_idx];
+            let (min_offset, _) = bucket_intensities.iter().enumerate().min_by(|(_, i1), (_, i2)| i1.total_cmp(i2)).unwrap();
+            let (max_offset, _) = bucket_intensities.iter().enumerate().max_by(|(_, i1), (_, i2)| i1.total_cmp(i2)).unwrap();
+
+            // Keep the m/z order inside the bucket
+            kept_indices.push(bucket_first_idx + min_offset.min(max_offset));
+            if min_offset != max_offset {
+                kept_indices.push(bucket_first_idx + min_offset.max(max_offset));
+            }
+
+            bucket_first_idx = bucket_last_idx;
+        }
+
+        let select = |values: &Arc<[f32]>| -> Arc<[f32]> {
+            if values.is_empty() { Arc::clone(values) } else { kept_indices.iter().map(|idx| values[*idx]).collect() }
+        };
+
+        SpectrumData {
+            data_encoding: self.data_encoding.clone(),
+            peak_count: kept_indices.len(),
+            mz_array: kept_indices.iter().map(|idx| self.mz_array[*idx]).collect(),
+            intensity_array: select(&self.intensity_array),
+            lwhm_array: select(&self.lwhm_array),
+            rwhm_array: select(&self.rwhm_array),
+        }
+    }
+}
+
+fn _get_value_at<T: Copy>(values: &[T], peak_idx: usize, value_name: &str) -> Result<T> {
+    values.get(peak_idx).copied().ok_or_else(|| anyhow::anyhow!(
+        "can't get {} of peak at index {}: the array contains {} values", value_name, peak_idx, values.len()
+    ))
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -299,7 +604,7 @@ pub struct SpectrumHeaderRecord {
     pub initial_id: Option<i64>,
     pub title: Option<String>,
     pub cycle: Option<i64>,
-    pub time: Option<f32>,
+    pub time: Option<f64>,
     pub ms_level: Option<i64>,
     pub activation_type: Option<String>,
     pub tic: Option<f32>,
@@ -325,18 +630,19 @@ pub struct SpectrumHeaderRecord {
 pub struct SpectrumHeader {
     pub id: i64,
     pub initial_id: i64,
-    pub title: String,
+    pub title: Arc<str>, // interned, see StringInterner
     pub cycle: i64,
     pub time: f32,
+    pub time_f64: f64, // full precision value of the time column, to be used when comparing times with f64 bounds
     pub ms_level: i64,
-    pub activation_type: Option<String>,
+    pub activation_type: Option<Arc<str>>, // interned, see StringInterner
     pub tic: f32,
     pub base_peak_mz: f64,
     pub base_peak_intensity: f32,
     pub precursor_mz: Option<f64>,
     pub precursor_charge: Option<i32>,
     pub peaks_count: i64,
-    pub param_tree_str: String,
+    pub param_tree_str: Option<String>, // None when loaded in light mode (see queries::get_param_tree_spectrum)
     pub scan_list_str: Option<String>,
     pub precursor_list_str: Option<String>,
     pub product_list_str: Option<String>,
@@ -349,12 +655,274 @@ pub struct SpectrumHeader {
     pub bb_first_spectrum_id: i64,
 }
 
+impl SpectrumHeader {
+    /// Returns the first precursor of the precursor_list or None if there is no precursor (e.g. MS1 spectra)
+    pub fn precursor(&self) -> Result<Option<Precursor>> {
+        let precursors = match self.precursor_list_str.as_ref() {
+            Some(precursor_list_str) => crate::xml::parse_precursor_list(precursor_list_str).location(here!())?,
+            None => return Ok(None),
+        };
+
+        Ok(precursors.into_iter().next())
+    }
+
+    /// Returns the retention time in seconds, i.e. the full precision value of the time column.
+    /// Light and full headers thus return the same value, which includes the EntityCache.rt_offset (see mzdb::set_rt_offset())
+    /// and the compat corrections (see compat::apply_corrections()). The scan start time of the scan_list is not used:
+    /// it is written with less digits than the time column and ignores these corrections.
+    pub fn retention_time(&self) -> f64 {
+        self.time_f64
+    }
+
+    pub fn isolation_window(&self) -> Result<Option<IsolationWindow>> {
+        Ok(self.precursor().location(here!())?.and_then(|precursor| precursor.isolation_window))
+    }
+
+    pub fn collision_energy(&self) -> Result<Option<f64>> {
+        Ok(self.precursor().location(here!())?
+            .and_then(|precursor| precursor.activation)
+            .and_then(|activation| activation.collision_energy))
+    }
+
+    /// Returns the normalized activation type, or None if the spectrum has no activation (e.g. MS1 spectra).
+    /// The CV params of the precursor_list are used when available, otherwise the activation_type column is parsed.
+    /// Note: the raw activation_type string remains accessible through the activation_type field.
+    pub fn activation(&self) -> Result<Option<ActivationType>> {
+        // Some converters fill the activation_type column of MS1 spectra too
+        if self.ms_level < 2 {
+            return Ok(None);
+        }
+
+        let activation_opt = self.precursor().location(here!())?.and_then(|precursor| precursor.activation);
+
+        if let Some(activation) = activation_opt {
+            let activation_type = ActivationType::from_cv_params(&activation.cv_params);
+            if activation_type != ActivationType::UNKNOWN {
+                return Ok(Some(activation_type));
+            }
+        }
+
+        Ok(self.activation_type.as_ref()
+            .filter(|activation_str| !activation_str.trim().is_empty())
+            .map(|activation_str| ActivationType::parse(activation_str)))
+    }
+
+    /// Returns the scan polarity, read from the param_tree merged with the shared param tree or, when missing there, from the scan_list.
+    /// The XML fields are fetched when the header is light (see metadata::get_spectrum_polarity()).
+    pub fn polarity(&self, db: &Connection) -> Result<Polarity> {
+        crate::metadata::get_spectrum_polarity(db, self)
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Polarity {
+    POSITIVE,
+    NEGATIVE,
+    UNKNOWN,
+}
+
+impl Polarity {
+    pub fn from_cv_params(cv_params: &[CvParam]) -> Polarity {
+        cv_params.iter().find_map(|cv_param| match cv_param.accession.as_str() {
+            POSITIVE_SCAN => Some(Polarity::POSITIVE),
+            NEGATIVE_SCAN => Some(Polarity::NEGATIVE),
+            _ => None,
+        }).unwrap_or(Polarity::UNKNOWN)
+    }
+
+    /// Parse the polarity from the param_tree of a spectrum or, when missing there, from its scan_list
+    pub fn from_xml_fields(param_tree: Option<&str>, scan_list: Option<&str>) -> Result<Polarity> {
+        for xml in [param_tree, scan_list].into_iter().flatten() {
+            let polarity = Polarity::from_cv_params(&crate::xml::parse_cv_params(xml).location(here!())?);
+            if polarity != Polarity::UNKNOWN {
+                return Ok(polarity);
+            }
+        }
+
+        Ok(Polarity::UNKNOWN)
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ActivationType {
+    CID,
+    HCD,
+    ETD,
+    ECD,
+    ETHCD,
+    ETCID,
+    PQD,
+    IRMPD,
+    UVPD,
+    UNKNOWN,
+}
+
+impl ActivationType {
+
+    /// Parse an activation string, which can be an abbreviation ("HCD"), a CV term name or a CV accession
+    pub fn parse(activation_str: &str) -> ActivationType {
+        let activation_str = activation_str.trim();
+
+        let activation_type = Self::_from_accession(activation_str);
+        if activation_type != ActivationType::UNKNOWN {
+            return activation_type;
+        }
+
+        match activation_str.to_lowercase().as_str() {
+            "cid" | "collision-induced dissociation" | "supplemental collision-induced dissociation" => ActivationType::CID,
+            "hcd" | "beam-type collision-induced dissociation" | "higher energy beam-type collision-induced dissociation"
+            | "supplemental beam-type collision-induced dissociation" => ActivationType::HCD,
+            "etd" | "electron transfer dissociation" => ActivationType::ETD,
+            "ecd" | "electron capture dissociation" => ActivationType::ECD,
+            "ethcd" | "electron transfer/higher-energy collision dissociation" => ActivationType::ETHCD,
+            "etcid" | "etcad" => ActivationType::ETCID,
+            "pqd" | "pulsed q dissociation" => ActivationType::PQD,
+            "irmpd" | "infrared multiphoton dissociation" => ActivationType::IRMPD,
+            "uvpd" | "ultraviolet photodissociation" => ActivationType::UVPD,
+            _ => ActivationType::UNKNOWN,
+        }
+    }
+
+    /// Infer the activation type from the CV params of an activation element.
+    /// ETD combined with a supplemental activation is reported as ETHCD or ETCID.
+    pub fn from_cv_params(cv_params: &[CvParam]) -> ActivationType {
+        let activation_types: Vec<ActivationType> = cv_params.iter()
+            .map(|cv_param| Self::_from_accession(&cv_param.accession))
+            .filter(|activation_type| *activation_type != ActivationType::UNKNOWN)
+            .collect();
+
+        let has_type = |activation_type: ActivationType| activation_types.contains(&activation_type);
+
+        if has_type(ActivationType::ETD) && has_type(ActivationType::HCD) {
+            ActivationType::ETHCD
+        } else if has_type(ActivationType::ETD) && has_type(ActivationType::CID) {
+            ActivationType::ETCID
+        } else {
+            activation_types.first().copied().unwrap_or(ActivationType::UNKNOWN)
+        }
+    }
+
+    fn _from_accession(accession: &str) -> ActivationType {
+        match accession {
+            COLLISION_INDUCED_DISSOCIATION | SUPPLEMENTAL_COLLISION_INDUCED_DISSOCIATION => ActivationType::CID,
+            BEAM_TYPE_COLLISION_INDUCED_DISSOCIATION | HIGHER_ENERGY_BEAM_TYPE_COLLISION_INDUCED_DISSOCIATION
+            | SUPPLEMENTAL_BEAM_TYPE_COLLISION_INDUCED_DISSOCIATION => ActivationType::HCD,
+            ELECTRON_TRANSFER_DISSOCIATION => ActivationType::ETD,
+            ELECTRON_CAPTURE_DISSOCIATION => ActivationType::ECD,
+            PULSED_Q_DISSOCIATION => ActivationType::PQD,
+            INFRARED_MULTIPHOTON_DISSOCIATION => ActivationType::IRMPD,
+            ULTRAVIOLET_PHOTODISSOCIATION => ActivationType::UVPD,
+            _ => ActivationType::UNKNOWN,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Spectrum {
     pub header: SpectrumHeader,
     pub data: SpectrumData,
 }
 
+/// Downsampled TIC trace stored in the thumbnail table (see maintenance::generate_thumbnails)
+#[derive(Clone, Debug, PartialEq)]
+pub struct TicThumbnail {
+    pub times: Vec<f32>,
+    pub intensities: Vec<f32>, // max TIC of each time bin
+}
+
+/// Rasterized MS1 ion map stored in the thumbnail table (see maintenance::generate_thumbnails)
+#[derive(Clone, Debug, PartialEq)]
+pub struct IonMapThumbnail {
+    pub width: usize, // number of time bins
+    pub height: usize, // number of m/z bins
+    pub min_time: f32,
+    pub max_time: f32,
+    pub min_mz: f64,
+    pub max_mz: f64,
+    pub pixels: Vec<u8>, // row-major, one row per m/z bin starting from min_mz, log-scaled intensities
+}
+
+impl IonMapThumbnail {
+    pub fn get_pixel(&self, time_idx: usize, mz_idx: usize) -> Option<u8> {
+        if time_idx >= self.width || mz_idx >= self.height {
+            return None;
+        }
+
+        Some(self.pixels[mz_idx * self.width + time_idx])
+    }
+}
+
+/// Decoding statistics of a single spectrum (see queries::profile_spectrum)
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpectrumProfile {
+    pub spectrum_id: i64,
+    pub bb_count: usize, // number of bounding boxes loaded to build the spectrum
+    pub bb_bytes_read: usize, // sum of the blob sizes of the loaded bounding boxes
+    pub bb_row_spectra_count: usize, // number of spectra sharing the bounding boxes (low values denote tiny BB RT widths)
+    pub slices_count: usize, // number of spectrum slices indexed in the loaded bounding boxes
+    pub non_empty_slices_count: usize, // number of slices of the spectrum containing peaks
+    pub peaks_count: usize,
+    pub load_duration: Duration, // time spent querying and reading the bounding boxes
+    pub decode_duration: Duration, // time spent indexing, decoding and merging the spectrum slices
+}
+
+/// Columns of the spectrum table to be loaded by queries::get_spectrum_table_columns() (spectrum ids are always loaded)
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ColumnsSelection {
+    pub cycles: bool,
+    pub times: bool,
+    pub ms_levels: bool,
+    pub tics: bool,
+    pub base_peak_mzs: bool,
+    pub base_peak_intensities: bool,
+    pub precursor_mzs: bool,
+    pub precursor_charges: bool,
+    pub peaks_counts: bool,
+}
+
+impl ColumnsSelection {
+    pub fn all() -> Self {
+        ColumnsSelection {
+            cycles: true,
+            times: true,
+            ms_levels: true,
+            tics: true,
+            base_peak_mzs: true,
+            base_peak_intensities: true,
+            precursor_mzs: true,
+            precursor_charges: true,
+            peaks_counts: true,
+        }
+    }
+}
+
+/// Struct-of-arrays view of the spectrum table, ordered by spectrum id.
+/// Vectors of the columns which were not selected are empty, the other ones have the length of ids.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpectrumTableColumns {
+    pub ids: Vec<i64>,
+    pub cycles: Vec<i64>,
+    pub times: Vec<f64>,
+    pub ms_levels: Vec<i64>,
+    pub tics: Vec<f32>,
+    pub base_peak_mzs: Vec<f64>,
+    pub base_peak_intensities: Vec<f32>,
+    pub precursor_mzs: Vec<Option<f64>>,
+    pub precursor_charges: Vec<Option<i32>>,
+    pub peaks_counts: Vec<i64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanMetadataTable {
+    pub ids: Vec<i64>,
+    pub injection_times: Vec<Option<f32>>, // in milliseconds
+    pub filter_strings: Vec<Option<String>>,
+    pub scan_windows: Vec<Option<(f64, f64)>>, // lower and upper limits of the first scan window
+    pub precursor_intensities: Vec<Option<f32>>, // intensity of the first selected ion of the precursor list
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct SpectrumSlice {
     pub spectrum: Spectrum,
@@ -371,6 +939,17 @@ pub struct RunSliceHeader {
     pub run_id: i64,
 }
 
+/// Bounding boxes stored for a run slice (see queries::get_run_slice_coverage()).
+/// Run slices covering a shorter time range than the others may reveal a truncated conversion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunSliceCoverage {
+    pub header: RunSliceHeader,
+    pub bounding_boxes_count: i64,
+    pub min_time: Option<f64>, // time of the first spectrum of the bounding boxes, None if the run slice has no bounding box
+    pub max_time: Option<f64>, // time of the last spectrum of the bounding boxes
+    pub total_bytes: i64,      // summed sizes of the bounding box blobs
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct RunSliceData {
     pub id: i64,
@@ -411,6 +990,48 @@ pub struct BoundingBoxIndex {
     pub peaks_counts: Vec<usize>,// number of peaks in each spectrum slice of the blob
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundingBoxIntensity {
+    pub bb_id: i64,
+    pub first_spectrum_id: i64,
+    pub last_spectrum_id: i64,
+    pub intensity_sum: f64, // sum of the intensities of all the peaks stored in the bounding box
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MzTolerance {
+    PPM(f64),
+    DA(f64),
+    HYBRID { ppm: f64, min_da: f64 }, // ppm tolerance which can't be lower than min_da (useful for low mass ions)
+}
+
+impl MzTolerance {
+    /// Returns the tolerance in Da for the provided m/z value
+    pub fn to_da(self, mz: f64) -> f64 {
+        match self {
+            MzTolerance::PPM(ppm) => ppm_to_da(mz, ppm),
+            MzTolerance::DA(da) => da,
+            MzTolerance::HYBRID { ppm, min_da } => ppm_to_da(mz, ppm).max(min_da),
+        }
+    }
+
+    /// Returns the (min_mz, max_mz) range centered on the provided m/z value
+    pub fn mz_range(&self, mz: f64) -> (f64, f64) {
+        let mz_tol = self.to_da(mz);
+        (mz - mz_tol, mz + mz_tol)
+    }
+}
+
+// How table records are counted (see queries::get_table_records_count_with_mode)
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CountMode {
+    SEQUENCE, // value of sqlite_sequence, fast but may be stale on files edited by other tools
+    HINTED,   // sqlite_sequence value cross-checked against the max rowid, records are counted on mismatch
+    EXACT,    // records are always counted
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum XicMethod {
     MAX= 0,
@@ -418,14 +1039,439 @@ pub enum XicMethod {
     SUM= 2
 }
 
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimeUnit {
+    SECOND,
+    MINUTE,
+    UNKNOWN,
+}
+
+impl TimeUnit {
+    /// Read the unit of the time array cvParam or, when missing, of the first cvParam having a time unit
+    pub fn from_cv_params(cv_params: &[CvParam]) -> TimeUnit {
+        let time_unit_of = |cv_param: &CvParam| match cv_param.unit_accession.as_str() {
+            UNIT_SECOND => Some(TimeUnit::SECOND),
+            UNIT_MINUTE => Some(TimeUnit::MINUTE),
+            _ => None,
+        };
+
+        cv_params.iter()
+            .find(|cv_param| cv_param.accession == TIME_ARRAY)
+            .and_then(time_unit_of)
+            .or_else(|| cv_params.iter().find_map(time_unit_of))
+            .unwrap_or(TimeUnit::UNKNOWN)
+    }
+
+    /// Returns the factor converting times of this unit to seconds (times of UNKNOWN unit are assumed to be in seconds)
+    pub fn to_seconds_factor(self) -> f64 {
+        match self {
+            TimeUnit::MINUTE => 60.0,
+            _ => 1.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChromatogramType {
+    TIC,
+    BPC,
+    SIC,
+    SRM,
+    UNKNOWN,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChromatogramHeader {
+    pub id: i64,
+    pub name: String,
+    pub activation_type: Option<String>,
+    pub param_tree: String,
+    pub precursor: Option<String>,
+    pub product: Option<String>,
+    pub shared_param_tree_id: Option<i64>,
+    pub run_id: i64,
+    pub data_processing_id: Option<i64>,
+    pub data_encoding_id: i64,
+}
+
+impl ChromatogramHeader {
+
+    /// Returns the params of the chromatogram, its param_tree being merged with the shared param tree (see metadata::resolve_param_tree())
+    pub fn params(&self, db: &Connection) -> Result<ParamTree> {
+        crate::metadata::resolve_param_tree(db, Some(&self.param_tree), self.shared_param_tree_id)
+            .context(format!("can't resolve param tree of chromatogram with ID={}", self.id)).location(here!())
+    }
+
+    /// Returns the unit of the stored time array, read from the cvParams of the params (see TimeUnit::from_cv_params())
+    pub fn time_unit(&self, db: &Connection) -> Result<TimeUnit> {
+        Ok(TimeUnit::from_cv_params(&self.params(db).location(here!())?.cv_params))
+    }
+
+    /// Returns the polarity declared by the params, UNKNOWN if missing
+    pub fn polarity(&self, db: &Connection) -> Result<Polarity> {
+        Ok(Polarity::from_cv_params(&self.params(db).location(here!())?.cv_params))
+    }
+
+    /// Infer the chromatogram type from the cvParams of the param_tree, falling back to name heuristics.
+    /// Note: the cvParams of the shared param tree are ignored (see metadata::get_chromatogram_type()).
+    pub fn chromatogram_type(&self) -> ChromatogramType {
+        let cv_params = crate::xml::parse_cv_params(&self.param_tree).unwrap_or_default();
+        self.chromatogram_type_from_cv_params(&cv_params)
+    }
+
+    /// Infer the chromatogram type from the provided cvParams, falling back to name heuristics
+    pub fn chromatogram_type_from_cv_params(&self, cv_params: &[CvParam]) -> ChromatogramType {
+        for cv_param in cv_params.iter() {
+            let chrom_type_opt = match cv_param.accession.as_str() {
+                TOTAL_ION_CURRENT_CHROMATOGRAM => Some(ChromatogramType::TIC),
+                BASEPEAK_CHROMATOGRAM => Some(ChromatogramType::BPC),
+                SELECTED_ION_CURRENT_CHROMATOGRAM => Some(ChromatogramType::SIC),
+                SELECTED_REACTION_MONITORING_CHROMATOGRAM => Some(ChromatogramType::SRM),
+                _ => None,
+            };
+
+            if let Some(chrom_type) = chrom_type_opt {
+                return chrom_type;
+            }
+        }
+
+        let upper_name = self.name.to_uppercase();
+        if upper_name.contains("TIC") {
+            ChromatogramType::TIC
+        } else if upper_name.contains("BPC") || upper_name.contains("BPI") {
+            ChromatogramType::BPC
+        } else if upper_name.contains("SRM") {
+            ChromatogramType::SRM
+        } else if upper_name.contains("XIC") || upper_name.contains("SIC") {
+            ChromatogramType::SIC
+        } else {
+            ChromatogramType::UNKNOWN
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChromatogramData {
+    pub time_array: Vec<f64>, // in seconds
+    pub intensity_array: Vec<f32>,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct IsolationWindow {
     pub min_mz: f64,
     pub max_mz: f64,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelectedIon {
+    pub mz: f64,
+    pub charge: Option<i32>,
+    pub intensity: Option<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Activation {
+    pub cv_params: Vec<CvParam>, // dissociation method(s) and energy
+    pub collision_energy: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Precursor {
+    pub spectrum_ref: Option<String>,
+    pub isolation_window: Option<IsolationWindow>,
+    pub selected_ions: Vec<SelectedIon>,
+    pub activation: Option<Activation>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IsolationWindowIndex {
+    pub isolation_windows: Vec<IsolationWindow>, // sorted by min_mz
+    window_idx_by_spectrum_id: HashMap<i64, usize>,
+    spectrum_ids_by_window_idx: Vec<Vec<i64>>,
+}
+
+impl IsolationWindowIndex {
+
+    pub fn new(
+        isolation_windows: Vec<IsolationWindow>,
+        window_idx_by_spectrum_id: HashMap<i64, usize>
+    ) -> Self {
+        let mut spectrum_ids_by_window_idx = vec![Vec::new(); isolation_windows.len()];
+        for (spectrum_id, window_idx) in window_idx_by_spectrum_id.iter() {
+            spectrum_ids_by_window_idx[*window_idx].push(*spectrum_id);
+        }
+
+        for spectrum_ids in spectrum_ids_by_window_idx.iter_mut() {
+            spectrum_ids.sort();
+        }
+
+        Self { isolation_windows, window_idx_by_spectrum_id, spectrum_ids_by_window_idx }
+    }
+
+    pub fn get_isolation_window_by_spectrum_id(&self, spectrum_id: &i64) -> Option<&IsolationWindow> {
+        self.window_idx_by_spectrum_id.get(spectrum_id).map(|window_idx| &self.isolation_windows[*window_idx])
+    }
+
+    /// Returns the sorted IDs of the spectra acquired with the isolation window at the provided index
+    pub fn get_spectrum_ids(&self, window_idx: usize) -> Option<&Vec<i64>> {
+        self.spectrum_ids_by_window_idx.get(window_idx)
+    }
+
+    /// Returns the indexes of the isolation windows containing the provided m/z value
+    pub fn find_isolation_windows(&self, mz: f64) -> Vec<usize> {
+        self.isolation_windows.iter().enumerate()
+            .filter(|(_window_idx, window)| mz >= window.min_mz && mz <= window.max_mz)
+            .map(|(window_idx, _window)| window_idx)
+            .collect()
+    }
+
+    /// Returns the number of spectra acquired with each isolation window
+    pub fn get_spectra_count_by_window(&self) -> Vec<(IsolationWindow, usize)> {
+        self.isolation_windows.iter().zip(self.spectrum_ids_by_window_idx.iter())
+            .map(|(window, spectrum_ids)| (*window, spectrum_ids.len()))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AcquisitionSummary {
+    pub spectra_count_by_ms_level: BTreeMap<i64, usize>,
+    pub cycles_count: usize,
+    pub min_time: Option<f64>, // in seconds, None if the file has no spectrum
+    pub max_time: Option<f64>,
+    pub isolation_windows_count: Option<usize>, // distinct MSn isolation windows, None if the precursor lists are not loaded (light headers)
+    pub chromatograms_count: usize,
+    pub source_file_names: Vec<String>, // names of the source files the spectra were converted from, ordered by ID (several for merged files)
+}
+
+// Strings shared by the spectrum headers, e.g. the activation types of a file or the titles of the files of a cohort (see cohort::CohortReader)
+#[derive(Clone, Debug, Default)]
+pub struct StringInterner {
+    strings: HashSet<Arc<str>>,
+}
+
+impl StringInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the interned copy of the string, which is added to the interner on first occurrence
+    pub fn intern(&mut self, string: String) -> Arc<str> {
+        if let Some(interned_string) = self.strings.get(string.as_str()) {
+            return interned_string.clone();
+        }
+
+        let interned_string: Arc<str> = Arc::from(string);
+        self.strings.insert(interned_string.clone());
+
+        interned_string
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct EntityCache {
     pub data_encodings_cache: DataEncodingsCache,
-    pub spectrum_headers: Vec<SpectrumHeader>
-}
\ No newline at end of file
+    pub spectrum_headers: Vec<SpectrumHeader>,
+    pub isolation_window_index: Option<IsolationWindowIndex>, // see mzdb::build_isolation_window_index()
+    pub sort_mz_arrays: bool, // sort the m/z arrays found unsorted when decoding spectra (false by default, see queries::check_mz_order())
+    pub fitted_as_centroid: bool, // decode fitted spectra as centroids, skipping their HWHMs (false by default, see queries::read_spectrum_slice_centroids_at())
+    pub rt_offset: f64, // in seconds, added to the stored spectrum times and already applied to the spectrum headers (see mzdb::set_rt_offset())
+    pub time_factor: f64, // converts the stored spectrum times into seconds, already applied to the spectrum headers (see mzdb::set_time_factor())
+    pub diagnostics: DiagnosticsSink, // non-fatal anomalies encountered while creating the cache and reading the file
+}
+
+impl EntityCache {
+    /// Convert a stored time (spectrum table or R-trees) into the time of the spectrum headers, i.e. in seconds and including the rt_offset
+    pub fn to_header_time(&self, stored_time: f64) -> f64 {
+        stored_time * self.time_factor + self.rt_offset
+    }
+
+    /// Convert a time of the spectrum headers back into a stored time, e.g. to filter the times at the SQL level
+    pub fn to_stored_time(&self, header_time: f64) -> f64 {
+        (header_time - self.rt_offset) / self.time_factor
+    }
+}
+
+// --- Metadata tables --- //
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MzDbMetadata {
+    pub version: String,
+    pub creation_timestamp: String,
+    pub file_content: String,
+    pub contacts: String,
+    pub param_tree: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cv {
+    pub id: String,
+    pub full_name: String,
+    pub version: Option<String>,
+    pub uri: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CvTerm {
+    pub accession: String,
+    pub name: String,
+    pub unit_accession: Option<String>,
+    pub cv_id: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CvUnit {
+    pub accession: String,
+    pub name: String,
+    pub cv_id: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserTerm {
+    pub id: i64,
+    pub name: String,
+    pub r#type: String,
+    pub unit_accession: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SharedParamTree {
+    pub id: i64,
+    pub data: String,
+    pub schema_name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub id: i64,
+    pub name: String,
+    pub param_tree: Option<String>,
+    pub shared_param_tree_id: Option<i64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Software {
+    pub id: i64,
+    pub name: String,
+    pub version: String,
+    pub param_tree: String,
+    pub shared_param_tree_id: Option<i64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub id: i64,
+    pub name: String,
+    pub location: String,
+    pub param_tree: String,
+    pub shared_param_tree_id: Option<i64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InstrumentConfiguration {
+    pub id: i64,
+    pub name: String,
+    pub param_tree: Option<String>,
+    pub component_list: String,
+    pub shared_param_tree_id: Option<i64>,
+    pub software_id: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DataProcessing {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProcessingMethod {
+    pub id: i64,
+    pub number: i64,
+    pub param_tree: String,
+    pub shared_param_tree_id: Option<i64>,
+    pub data_processing_id: i64,
+    pub software_id: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScanSettings {
+    pub id: i64,
+    pub param_tree: Option<String>,
+    pub shared_param_tree_id: Option<i64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    pub id: i64,
+    pub name: String,
+    pub start_timestamp: Option<String>,
+    pub param_tree: Option<String>,
+    pub shared_param_tree_id: Option<i64>,
+    pub sample_id: i64,
+    pub default_instrument_config_id: i64,
+    pub default_source_file_id: Option<i64>,
+    pub default_scan_processing_id: i64,
+    pub default_chrom_processing_id: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InstrumentMetadata {
+    pub configuration: InstrumentConfiguration,
+    pub components: ComponentList, // parsed from configuration.component_list
+}
+
+/// A processing method with its software, both having their param trees resolved (see metadata::get_processing_chain)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessingStep {
+    pub method: ProcessingMethod,
+    pub method_params: ParamTree,
+    pub software: Software,
+    pub software_params: ParamTree,
+}
+
+/// The processing steps of a data processing, ordered by processing method number
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessingChain {
+    pub data_processing: DataProcessing,
+    pub steps: Vec<ProcessingStep>,
+}
+
+/// All the metadata of a file gathered in a single serializable tree (see metadata::get_file_metadata)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub mzdb: Option<MzDbMetadata>,
+    pub runs: Vec<Run>,
+    pub samples: Vec<Sample>,
+    pub software: Vec<Software>,
+    pub instruments: Vec<InstrumentMetadata>,
+    pub source_files: Vec<SourceFile>,
+    pub data_processings: Vec<DataProcessing>,
+    pub processing_methods: Vec<ProcessingMethod>,
+}
+
+/// Where a file comes from (see metadata::get_file_provenance), e.g. for cataloging pipelines
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileProvenance {
+    pub creation_timestamp: Option<String>, // trimmed value of mzdb.creation_timestamp, None if empty
+    pub creation_time: Option<i64>, // creation timestamp in seconds since the Unix epoch (UTC), None if it can't be parsed
+    pub writer_software: Option<Software>, // the mzDB writer, i.e. the last software whose name contains "mzDB"
+    pub source_files: Vec<SourceFile>,
+    pub origin_file_format: Option<String>, // origin_file_format userParam of the mzdb param tree
+}
+
+impl FileProvenance {
+    #[cfg(feature = "chrono")]
+    pub fn creation_datetime(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        use chrono::TimeZone;
+        self.creation_time.and_then(|creation_time| chrono::Utc.timestamp_opt(creation_time, 0).single())
+    }
+}
@@ -1,12 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use anyhow::*;
 use crate::anyhow_ext::*;
 
 use rusqlite::Connection;
 use serde_rusqlite::from_rows;
 
-use crate::model::{DataEncoding, DataEncodingsCache, EntityCache, SpectrumHeader, SpectrumHeaderRecord};
-use crate::queries::list_data_encodings;
+use crate::diagnostics::{DiagnosticKind, DiagnosticsSink};
+use crate::metadata::list_source_files;
+use crate::model::{AcquisitionSummary, BBSizes, CountMode, DataEncoding, DataEncodingsCache, EntityCache, IsolationWindow, IsolationWindowIndex, PeakEncoding, SpectrumHeader, SpectrumHeaderRecord, StringInterner};
+use crate::queries::{get_param_tree_mzdb, get_table_records_count_with_mode, list_data_encodings};
+use crate::xml::{extract_isolation_window, parse_precursor_list, parse_user_params};
 
 /*macro_rules! here {
     () => {
@@ -19,9 +22,54 @@ use crate::queries::list_data_encodings;
 }*/
 
 
+const SQLQUERY_LIGHT_SPECTRUM_HEADERS: &'static str = "SELECT id, initial_id, title, cycle, time, ms_level, activation_type, tic, \
+base_peak_mz, base_peak_intensity, main_precursor_mz, main_precursor_charge, data_points_count, \
+NULL AS param_tree, NULL AS scan_list, NULL AS precursor_list, NULL AS product_list, \
+shared_param_tree_id, instrument_configuration_id, source_file_id, run_id, data_processing_id, data_encoding_id, bb_first_spectrum_id \
+FROM spectrum";
+
+const SQLQUERY_MISSING_PRECURSOR_LISTS: &str = "SELECT id, precursor_list FROM spectrum \
+WHERE ms_level >= 2 AND main_precursor_mz IS NULL AND precursor_list IS NOT NULL";
+
+const SQLQUERY_MSN_PRECURSOR_LISTS: &str = "SELECT id, precursor_list FROM spectrum WHERE ms_level >= 2 AND precursor_list IS NOT NULL";
+
 pub fn get_spectrum_headers(db: &Connection) -> Result<Vec<SpectrumHeader>> {
+    _get_spectrum_headers(db, "SELECT * FROM spectrum", &mut StringInterner::new(), None)
+}
+
+/// Load a single spectrum header, returns None if the spectrum doesn't exist
+pub fn get_spectrum_header(db: &Connection, spectrum_id: i64) -> Result<Option<SpectrumHeader>> {
+    get_spectrum_header_from_table(db, "spectrum", spectrum_id)
+}
+
+/// Same as get_spectrum_header() but the header is read from the provided table,
+/// e.g. tmp_spectrum, where some writers insert the headers until the file is finalized (see live::tail_spectra())
+pub(crate) fn get_spectrum_header_from_table(db: &Connection, spectrum_table: &str, spectrum_id: i64) -> Result<Option<SpectrumHeader>> {
+    let s_headers = _get_spectrum_headers(
+        db,
+        format!("SELECT * FROM {} WHERE id = {}", spectrum_table, spectrum_id).as_str(),
+        &mut StringInterner::new(),
+        None
+    ).location(here!())?;
+    Ok(s_headers.into_iter().next())
+}
+
+/// Load the spectrum headers without their XML fields (param_tree, scan_list, precursor_list and product_list).
+/// These fields can then be fetched on demand using the queries::get_*_xml() functions.
+pub fn get_light_spectrum_headers(db: &Connection) -> Result<Vec<SpectrumHeader>> {
+    _get_spectrum_headers(db, SQLQUERY_LIGHT_SPECTRUM_HEADERS, &mut StringInterner::new(), None)
+}
+
+// The titles and activation types are interned using the provided interner.
+// The anomalies are logged when no diagnostics sink is provided.
+fn _get_spectrum_headers(
+    db: &Connection,
+    query_str: &str,
+    interner: &mut StringInterner,
+    diagnostics: Option<&DiagnosticsSink>
+) -> Result<Vec<SpectrumHeader>> {
 
-    let mut statement = db.prepare("SELECT * FROM spectrum").unwrap();
+    let mut statement = db.prepare(query_str).unwrap();
     let records = from_rows::<SpectrumHeaderRecord>(statement.query([]).unwrap());
 
     let mut s_headers = Vec::new();
@@ -31,18 +79,19 @@ pub fn get_spectrum_headers(db: &Connection) -> Result<Vec<SpectrumHeader>> {
         let sh = SpectrumHeader {
             id: sh_record.id,
             initial_id: sh_record.initial_id.unwrap(),
-            title: sh_record.title.unwrap(),
+            title: interner.intern(sh_record.title.unwrap()),
             cycle: sh_record.cycle.unwrap(),
-            time: sh_record.time.unwrap(),
+            time: sh_record.time.unwrap() as f32,
+            time_f64: sh_record.time.unwrap(),
             ms_level: sh_record.ms_level.unwrap(),
-            activation_type: sh_record.activation_type,
+            activation_type: sh_record.activation_type.map(|activation_type| interner.intern(activation_type)),
             tic: sh_record.tic.unwrap(),
             base_peak_mz: sh_record.base_peak_mz.unwrap(),
             base_peak_intensity: sh_record.base_peak_intensity.unwrap(),
             precursor_mz: sh_record.main_precursor_mz,
             precursor_charge: sh_record.main_precursor_charge,
             peaks_count: sh_record.data_points_count.unwrap(),
-            param_tree_str: sh_record.param_tree.unwrap(),
+            param_tree_str: sh_record.param_tree,
             scan_list_str: sh_record.scan_list,
             precursor_list_str: sh_record.precursor_list,
             product_list_str: sh_record.product_list,
@@ -52,16 +101,74 @@ pub fn get_spectrum_headers(db: &Connection) -> Result<Vec<SpectrumHeader>> {
             run_id: sh_record.run_id.unwrap(),
             data_processing_id: sh_record.data_processing_id.unwrap(),
             data_encoding_id: sh_record.data_encoding_id.unwrap(),
-            bb_first_spectrum_id: sh_record.data_encoding_id.unwrap(),
+            bb_first_spectrum_id: sh_record.bb_first_spectrum_id.unwrap(),
         };
 
         s_headers.push(sh);
     }
 
+    _fill_missing_precursors(db, &mut s_headers, diagnostics).location(here!())?;
+
     Ok(s_headers)
 }
 
+// Some converters (e.g. for Bruker files) leave the main_precursor_mz/charge columns NULL while the precursor_list holds the values:
+// they are then taken from the first selected ion of the precursor_list, whose missing values are fetched by one query (light headers).
+fn _fill_missing_precursors(db: &Connection, s_headers: &mut [SpectrumHeader], diagnostics: Option<&DiagnosticsSink>) -> Result<()> {
+    // The precursor lists missing from the headers (light headers) are loaded by a single query
+    let mut loaded_precursor_lists: HashMap<i64, String> = HashMap::new();
+    if s_headers.iter().any(|sh| sh.ms_level >= 2 && sh.precursor_mz.is_none() && sh.precursor_list_str.is_none()) {
+        let mut stmt = db.prepare(SQLQUERY_MISSING_PRECURSOR_LISTS).location(here!())?;
+        let mut rows = stmt.query([]).location(here!())?;
+        while let Some(row) = rows.next().location(here!())? {
+            loaded_precursor_lists.insert(row.get(0).location(here!())?, row.get(1).location(here!())?);
+        }
+    }
+
+    for sh in s_headers.iter_mut().filter(|sh| sh.ms_level >= 2 && sh.precursor_mz.is_none()) {
+        let precursor_list = match sh.precursor_list_str.as_ref().or_else(|| loaded_precursor_lists.get(&sh.id)) {
+            Some(precursor_list) => precursor_list.clone(),
+            None => continue,
+        };
+
+        match parse_precursor_list(&precursor_list) {
+            Result::Ok(precursors) => {
+                if let Some(selected_ion) = precursors.iter().flat_map(|precursor| precursor.selected_ions.iter()).next() {
+                    sh.precursor_mz = Some(selected_ion.mz);
+                    if sh.precursor_charge.is_none() {
+                        sh.precursor_charge = selected_ion.charge;
+                    }
+                }
+            }
+            Err(e) => {
+                let message = format!("can't parse precursor list of spectrum with ID={}: {}", sh.id, e);
+                match diagnostics {
+                    Some(diagnostics) => diagnostics.push(DiagnosticKind::INVALID_PRECURSOR_LIST, message),
+                    None => log::warn!("{}", message),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 pub fn create_entity_cache(db: &Connection) -> Result<EntityCache> {
+    _create_entity_cache(db, false, &mut StringInterner::new())
+}
+
+/// Create an EntityCache whose spectrum headers don't hold the XML fields, thus decoupling its memory usage from the file size
+pub fn create_light_entity_cache(db: &Connection) -> Result<EntityCache> {
+    _create_entity_cache(db, true, &mut StringInterner::new())
+}
+
+/// Same as create_light_entity_cache(), the header strings being interned using the provided interner,
+/// so that the caches of several files can share them (see cohort::CohortReader)
+pub fn create_light_entity_cache_with_interner(db: &Connection, interner: &mut StringInterner) -> Result<EntityCache> {
+    _create_entity_cache(db, true, interner)
+}
+
+fn _create_entity_cache(db: &Connection, light_headers: bool, interner: &mut StringInterner) -> Result<EntityCache> {
     let data_encodings = list_data_encodings(&db)?;
 
     let mut data_encoding_by_id:  HashMap<i64, DataEncoding> = HashMap::with_capacity(data_encodings.len());
@@ -85,8 +192,260 @@ pub fn create_entity_cache(db: &Connection) -> Result<EntityCache> {
         spectra_data_encoding_ids
     );
 
+    let diagnostics = DiagnosticsSink::new();
+    let headers_query = if light_headers { SQLQUERY_LIGHT_SPECTRUM_HEADERS } else { "SELECT * FROM spectrum" };
+    let spectrum_headers = _get_spectrum_headers(db, headers_query, interner, Some(&diagnostics)).location(here!())?;
+
     Ok(EntityCache {
         data_encodings_cache: de_cache,
-        spectrum_headers: get_spectrum_headers(db).location(here!())?
+        spectrum_headers,
+        isolation_window_index: None,
+        sort_mz_arrays: false,
+        fitted_as_centroid: false,
+        rt_offset: 0.0,
+        time_factor: 1.0,
+        diagnostics,
+    })
+}
+
+/// Shift the times of the spectrum headers so that they are equal to the stored times (see set_time_factor()) plus rt_offset (in seconds),
+/// e.g. to align runs on the gradient start or to get rid of the negative times written by some instruments.
+/// The queries taking an EntityCache convert their time ranges back to stored times when they filter at the SQL level.
+pub fn set_rt_offset(entity_cache: &mut EntityCache, rt_offset: f64) {
+    let delta = rt_offset - entity_cache.rt_offset;
+
+    for sh in entity_cache.spectrum_headers.iter_mut() {
+        sh.time_f64 += delta;
+        sh.time = sh.time_f64 as f32;
+    }
+
+    entity_cache.rt_offset = rt_offset;
+}
+
+/// Scale the times of the spectrum headers so that they are equal to the stored times multiplied by time_factor (plus the rt_offset),
+/// e.g. 60 for the files storing their times in minutes (see compat::apply_corrections()).
+/// The queries taking an EntityCache apply the same factor to the times they read from or filter in the database.
+pub fn set_time_factor(entity_cache: &mut EntityCache, time_factor: f64) {
+    let rt_offset = entity_cache.rt_offset;
+    let scale = time_factor / entity_cache.time_factor;
+
+    for sh in entity_cache.spectrum_headers.iter_mut() {
+        sh.time_f64 = (sh.time_f64 - rt_offset) * scale + rt_offset;
+        sh.time = sh.time_f64 as f32;
+    }
+
+    entity_cache.time_factor = time_factor;
+}
+
+/// Assign each MSn spectrum to its isolation window, parsing the precursor_list of each header only once.
+/// The precursor lists missing from the headers (light headers) are loaded by a single query over the MSn spectra.
+/// The result is intended to be stored in EntityCache.isolation_window_index.
+pub fn build_isolation_window_index(db: &Connection, entity_cache: &EntityCache) -> Result<IsolationWindowIndex> {
+
+    // Windows are grouped using a 0.0001 m/z precision to absorb floating point noise
+    let to_window_key = |window: &IsolationWindow| -> (i64, i64) {
+        ((window.min_mz * 10000.0).round() as i64, (window.max_mz * 10000.0).round() as i64)
+    };
+
+    let mut window_by_key: HashMap<(i64, i64), IsolationWindow> = HashMap::new();
+    let mut window_key_by_spectrum_id: HashMap<i64, (i64, i64)> = HashMap::new();
+
+    let mut loaded_precursor_lists: HashMap<i64, String> = HashMap::new();
+    if entity_cache.spectrum_headers.iter().any(|sh| sh.ms_level >= 2 && sh.precursor_list_str.is_none()) {
+        let mut stmt = db.prepare(SQLQUERY_MSN_PRECURSOR_LISTS).location(here!())?;
+        let mut rows = stmt.query([]).location(here!())?;
+        while let Some(row) = rows.next().location(here!())? {
+            loaded_precursor_lists.insert(row.get(0).location(here!())?, row.get(1).location(here!())?);
+        }
+    }
+
+    for sh in entity_cache.spectrum_headers.iter().filter(|sh| sh.ms_level >= 2) {
+        let precursor_list = match sh.precursor_list_str.as_ref().or_else(|| loaded_precursor_lists.get(&sh.id)) {
+            Some(precursor_list) => precursor_list,
+            None => continue,
+        };
+
+        let window_opt = extract_isolation_window(precursor_list)
+            .context(format!("can't parse precursor list of spectrum with ID={}", sh.id)).location(here!())?;
+
+        if let Some(window) = window_opt {
+            let window_key = to_window_key(&window);
+            window_by_key.entry(window_key).or_insert(window);
+            window_key_by_spectrum_id.insert(sh.id, window_key);
+        }
+    }
+
+    let mut sorted_window_keys: Vec<(i64, i64)> = window_by_key.keys().copied().collect();
+    sorted_window_keys.sort();
+
+    let window_idx_by_key: HashMap<(i64, i64), usize> = sorted_window_keys.iter().enumerate()
+        .map(|(window_idx, window_key)| (*window_key, window_idx))
+        .collect();
+
+    let window_idx_by_spectrum_id = window_key_by_spectrum_id.iter()
+        .map(|(spectrum_id, window_key)| (*spectrum_id, window_idx_by_key[window_key]))
+        .collect();
+
+    let isolation_windows = sorted_window_keys.iter().map(|window_key| window_by_key[window_key]).collect();
+
+    Ok(IsolationWindowIndex::new(isolation_windows, window_idx_by_spectrum_id))
+}
+
+/// Summarize the acquisition (spectra count per MS level, cycles, RT span, isolation windows, chromatograms)
+/// in a single pass over the cached spectrum headers, which can be light.
+/// The isolation windows are counted from EntityCache.isolation_window_index when available,
+/// otherwise they are indexed on the fly, unless the headers are light (precursor lists not loaded).
+pub fn get_acquisition_summary(db: &Connection, entity_cache: &EntityCache) -> Result<AcquisitionSummary> {
+    let mut spectra_count_by_ms_level = BTreeMap::new();
+    let mut cycles = HashSet::new();
+    let mut min_time: Option<f64> = None;
+    let mut max_time: Option<f64> = None;
+    let mut has_precursor_lists = true;
+    let mut source_file_ids = BTreeSet::new();
+
+    for sh in entity_cache.spectrum_headers.iter() {
+        *spectra_count_by_ms_level.entry(sh.ms_level).or_insert(0) += 1;
+        cycles.insert(sh.cycle);
+        source_file_ids.insert(sh.source_file_id);
+        min_time = Some(min_time.map_or(sh.time_f64, |time| time.min(sh.time_f64)));
+        max_time = Some(max_time.map_or(sh.time_f64, |time| time.max(sh.time_f64)));
+
+        if sh.ms_level >= 2 && sh.precursor_list_str.is_none() {
+            has_precursor_lists = false;
+        }
+    }
+
+    let isolation_windows_count = match entity_cache.isolation_window_index.as_ref() {
+        Some(window_index) => Some(window_index.isolation_windows.len()),
+        None if has_precursor_lists => Some(build_isolation_window_index(db, entity_cache).location(here!())?.isolation_windows.len()),
+        None => None,
+    };
+
+    let chromatograms_count = get_table_records_count_with_mode(db, "chromatogram", CountMode::EXACT).location(here!())?.unwrap_or(0);
+
+    let source_file_names = list_source_files(db).location(here!())?.into_iter()
+        .filter(|source_file| source_file_ids.contains(&source_file.id))
+        .map(|source_file| source_file.name)
+        .collect();
+
+    Ok(AcquisitionSummary {
+        spectra_count_by_ms_level,
+        cycles_count: cycles.len(),
+        min_time,
+        max_time,
+        isolation_windows_count,
+        chromatograms_count: chromatograms_count as usize,
+        source_file_names,
     })
-}
\ No newline at end of file
+}
+
+/// Get the bounding box sizes declared in the mzdb param_tree, or infer them from the file content if they are missing.
+/// An error is returned if both approaches fail.
+pub fn get_bb_sizes(db: &Connection) -> Result<BBSizes> {
+    let declared_bb_sizes = get_declared_bb_sizes(db).location(here!())?;
+    if let Some(bb_sizes) = declared_bb_sizes {
+        return Ok(bb_sizes);
+    }
+
+    infer_bb_sizes(db).context("the mzdb param_tree doesn't declare the bounding box sizes and they can't be inferred").location(here!())
+}
+
+/// Read the bounding box sizes from the userParams of the mzdb param_tree (None if one of them is missing)
+pub fn get_declared_bb_sizes(db: &Connection) -> Result<Option<BBSizes>> {
+    let param_tree_opt = get_param_tree_mzdb(db).location(here!())?;
+    if param_tree_opt.is_none() {
+        return Ok(None);
+    }
+
+    let user_params = parse_user_params(param_tree_opt.as_ref().unwrap()).location(here!())?;
+
+    let get_width = |name: &str| -> Result<Option<f32>> {
+        user_params.iter()
+            .find(|user_param| user_param.name == name)
+            .map(|user_param| user_param.value.parse::<f32>().context(format!("invalid value for {}: {}", name, user_param.value)))
+            .transpose()
+    };
+
+    let widths = [
+        get_width("ms1_bb_mz_width").location(here!())?,
+        get_width("ms1_bb_time_width").location(here!())?,
+        get_width("msn_bb_mz_width").location(here!())?,
+        get_width("msn_bb_time_width").location(here!())?,
+    ];
+
+    if widths.iter().any(|width| width.is_none()) {
+        return Ok(None);
+    }
+
+    Ok(Some(BBSizes {
+        ms1_bb_mz_width: widths[0].unwrap(),
+        ms1_bb_time_width: widths[1].unwrap(),
+        msn_bb_mz_width: widths[2].unwrap(),
+        msn_bb_time_width: widths[3].unwrap(),
+    }))
+}
+
+/// Infer the bounding box sizes from the file content.
+/// m/z widths are given by the run slices. Time widths are estimated from the largest time extent of the R-tree entries,
+/// rounded up to the next second (the actual width can't be lower). When the MSn R-tree is empty, a MSn time width of 0
+/// is inferred if each MSn bounding box contains a single spectrum (as in DDA files).
+pub fn infer_bb_sizes(db: &Connection) -> Result<BBSizes> {
+    let get_first_f64 = |query: &str| -> Result<Option<f64>> {
+        db.query_row(query, [], |row| row.get::<_, Option<f64>>(0)).location(here!())
+    };
+
+    let ms1_bb_mz_width = get_first_f64("SELECT max(end_mz - begin_mz) FROM run_slice WHERE ms_level = 1").location(here!())?
+        .context("can't infer the MS1 bounding box m/z width: no MS1 run slice found").location(here!())?;
+
+    let ms1_bb_time_width = get_first_f64("SELECT max(max_time - min_time) FROM bounding_box_rtree").location(here!())?
+        .context("can't infer the MS1 bounding box time width: bounding_box_rtree is empty").location(here!())?;
+
+    // MSn widths are only required if the file contains MSn spectra
+    let msn_bb_mz_width = get_first_f64("SELECT max(end_mz - begin_mz) FROM run_slice WHERE ms_level > 1").location(here!())?;
+
+    let msn_bb_time_width = match get_first_f64("SELECT max(max_time - min_time) FROM bounding_box_msn_rtree").location(here!())? {
+        Some(max_extent) => Some(max_extent.ceil()),
+        None => {
+            let multi_spectra_msn_bb_count = get_first_f64(
+                "SELECT count(bounding_box.id) FROM bounding_box, run_slice \
+                WHERE run_slice.id = bounding_box.run_slice_id AND run_slice.ms_level > 1 \
+                AND bounding_box.first_spectrum_id != bounding_box.last_spectrum_id"
+            ).location(here!())?.unwrap_or(0.0);
+
+            if multi_spectra_msn_bb_count == 0.0 { Some(0.0) } else { None }
+        }
+    };
+
+    if msn_bb_mz_width.is_some() && msn_bb_time_width.is_none() {
+        bail!("can't infer the MSn bounding box time width: bounding_box_msn_rtree is empty and MSn bounding boxes contain several spectra");
+    }
+
+    Ok(BBSizes {
+        ms1_bb_mz_width: ms1_bb_mz_width as f32,
+        ms1_bb_time_width: ms1_bb_time_width.ceil() as f32,
+        msn_bb_mz_width: msn_bb_mz_width.unwrap_or(0.0) as f32,
+        msn_bb_time_width: msn_bb_time_width.unwrap_or(0.0) as f32,
+    })
+}
+
+/// Tell if the peaks are stored without precision loss relative to the original acquisition.
+/// The is_lossless userParam of the mzdb param_tree is used when declared (older writers used is_loss_less),
+/// otherwise the file is considered lossless only if all its data encodings use 64-bit m/z values and intensities.
+pub fn is_lossless(db: &Connection) -> Result<bool> {
+    if let Some(param_tree) = get_param_tree_mzdb(db).location(here!())? {
+        let user_params = parse_user_params(&param_tree).location(here!())?;
+        let lossless_param_opt = user_params.iter().find(|user_param| user_param.name == "is_lossless" || user_param.name == "is_loss_less");
+
+        if let Some(lossless_param) = lossless_param_opt {
+            return match lossless_param.value.trim().to_lowercase().as_str() {
+                "true" | "1" => Ok(true),
+                "false" | "0" => Ok(false),
+                value => bail!("invalid value for {}: {}", lossless_param.name, value),
+            };
+        }
+    }
+
+    let data_encodings = list_data_encodings(db).location(here!())?;
+
+    Ok(!data_encodings.is_empty() && data_encodings.iter().all(|de| de.peak_encoding == PeakEncoding::NO_LOSS_PEAK))
+}
@@ -0,0 +1,89 @@
+// Progress reporting and cooperative cancellation for long running operations (iterations, scans...),
+// allowing CLIs and GUIs to display progress bars and to interrupt processing cleanly.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Receives the progress of an operation, as a number of processed items out of an expected total
+pub trait ProgressObserver {
+    fn on_progress(&mut self, processed_count: usize, total_count: usize);
+}
+
+impl<F> ProgressObserver for F where F: FnMut(usize, usize) {
+    fn on_progress(&mut self, processed_count: usize, total_count: usize) {
+        self(processed_count, total_count)
+    }
+}
+
+// Counts the processed items of an operation and notifies its optional observer
+pub(crate) struct ProgressTracker<'a> {
+    observer: Option<&'a mut dyn ProgressObserver>,
+    processed_count: usize,
+    total_count: usize,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub(crate) fn new(observer: Option<&'a mut dyn ProgressObserver>, total_count: usize) -> Self {
+        ProgressTracker { observer, processed_count: 0, total_count }
+    }
+
+    pub(crate) fn increment(&mut self) {
+        self.processed_count += 1;
+        if let Some(observer) = self.observer.as_mut() {
+            observer.on_progress(self.processed_count, self.total_count);
+        }
+    }
+
+    // Notifies the completion of the operation when some items have been skipped (e.g. steps which are not run)
+    pub(crate) fn finish(&mut self) {
+        if self.processed_count < self.total_count {
+            self.processed_count = self.total_count - 1;
+            self.increment();
+        }
+    }
+}
+
+/// Error returned by an operation interrupted through its CancellationToken.
+/// Use error.downcast_ref::<Cancelled>() to distinguish it from actual failures.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Cancellation flag shared between an operation and its controller (e.g. a UI thread).
+/// Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Returns a Cancelled error if the cancellation has been requested
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() { Err(Cancelled) } else { Ok(()) }
+    }
+}
+
+/// Same as CancellationToken::check() for the operations whose token is optional
+pub fn check_cancellation(cancellation_token: Option<&CancellationToken>) -> Result<(), Cancelled> {
+    cancellation_token.map_or(Ok(()), |token| token.check())
+}
@@ -0,0 +1,289 @@
+// Quality control summaries of a run.
+// compute_run_summary() is a cheap pass over the spectrum headers (neither spectrum data nor XML is decoded).
+// The MS2 quality scores (see score_ms2) require the spectrum data and are computed while iterating the spectra.
+
+use std::collections::HashMap;
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::iterator::for_each_spectrum;
+use crate::mass::isotope_mz;
+use crate::model::*;
+use crate::progress::{check_cancellation, CancellationToken};
+use crate::queries::get_cached_scan_metadata_table;
+use crate::xml::extract_isolation_window;
+
+const TOP_PEAKS_COUNT: usize = 20;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DistributionStats {
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub median: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunSummary {
+    pub ms1_count: usize,
+    pub msn_count: usize,
+    pub ms1_times: Vec<f32>, // x values of the tic, bpc and ms1_injection_times vectors
+    pub tic: Vec<f32>,
+    pub bpc: Vec<f32>,
+    pub ms1_injection_times: Vec<Option<f32>>, // in milliseconds, None when not reported by the spectrum
+    pub precursor_intensities: Vec<f32>, // only contains the precursors whose intensity is reported
+    pub tic_stats: Option<DistributionStats>,
+    pub ms1_injection_time_stats: Option<DistributionStats>,
+    pub precursor_intensity_stats: Option<DistributionStats>,
+}
+
+/// Compute the run-level TIC/BPC, MS1 injection times and precursor intensity distributions using a single pass over the spectrum headers,
+/// which may be light headers: the times are the ones of the headers, i.e. corrected with the EntityCache.rt_offset and time_factor.
+/// No XML is parsed: the injection times and precursor intensities are read from the cache table built by
+/// maintenance::build_scan_metadata_cache(), and are missing when the file has no up to date cache table.
+/// If a cancellation token is provided, it is polled for each spectrum (see progress::Cancelled).
+pub fn compute_run_summary(db: &Connection, entity_cache: &EntityCache, cancellation_token: Option<&CancellationToken>) -> Result<RunSummary> {
+    let mut summary = RunSummary {
+        ms1_count: 0,
+        msn_count: 0,
+        ms1_times: Vec::new(),
+        tic: Vec::new(),
+        bpc: Vec::new(),
+        ms1_injection_times: Vec::new(),
+        precursor_intensities: Vec::new(),
+        tic_stats: None,
+        ms1_injection_time_stats: None,
+        precursor_intensity_stats: None,
+    };
+
+    let scan_metadata_opt = get_cached_scan_metadata_table(db).location(here!())?;
+    let scan_metadata_idx_by_id: HashMap<i64, usize> = scan_metadata_opt.as_ref()
+        .map(|scan_metadata| scan_metadata.ids.iter().enumerate().map(|(idx, spectrum_id)| (*spectrum_id, idx)).collect())
+        .unwrap_or_default();
+
+    for sh in entity_cache.spectrum_headers.iter() {
+        check_cancellation(cancellation_token)?;
+
+        let scan_metadata_idx = scan_metadata_idx_by_id.get(&sh.id).copied();
+
+        if sh.ms_level == 1 {
+            summary.ms1_count += 1;
+            summary.ms1_times.push(sh.time);
+            summary.tic.push(sh.tic);
+            summary.bpc.push(sh.base_peak_intensity);
+            summary.ms1_injection_times.push(
+                scan_metadata_opt.as_ref().zip(scan_metadata_idx).and_then(|(scan_metadata, idx)| scan_metadata.injection_times[idx])
+            );
+        } else {
+            summary.msn_count += 1;
+
+            let precursor_intensity_opt = scan_metadata_opt.as_ref().zip(scan_metadata_idx)
+                .and_then(|(scan_metadata, idx)| scan_metadata.precursor_intensities[idx]);
+            if let Some(precursor_intensity) = precursor_intensity_opt {
+                summary.precursor_intensities.push(precursor_intensity);
+            }
+        }
+    }
+
+    let injection_times: Vec<f32> = summary.ms1_injection_times.iter().flatten().copied().collect();
+
+    summary.tic_stats = compute_distribution_stats(&summary.tic);
+    summary.ms1_injection_time_stats = compute_distribution_stats(&injection_times);
+    summary.precursor_intensity_stats = compute_distribution_stats(&summary.precursor_intensities);
+
+    Ok(summary)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ms2Scores {
+    pub spectral_entropy: f32, // Shannon entropy (natural logarithm) of the normalized intensities, 0 for spectra having a single peak
+    pub top20_intensity_fraction: f32, // fraction of the total intensity carried by the 20 most intense peaks
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ms2Quality {
+    pub spectrum_id: i64,
+    pub ms1_spectrum_id: Option<i64>, // the MS1 spectrum preceding the MS2 spectrum, None if there is none
+    pub scores: Ms2Scores,
+    pub precursor_contamination: Option<f32>, // see estimate_precursor_contamination(), None if it can't be estimated
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ms2QualitySummary {
+    pub ms2_count: usize,
+    pub spectral_entropy_stats: Option<DistributionStats>,
+    pub top20_intensity_fraction_stats: Option<DistributionStats>,
+    pub precursor_contamination_stats: Option<DistributionStats>, // only contains the MS2 spectra whose contamination was estimated
+}
+
+/// Compute the quality scores of a MS2 spectrum from its peaks.
+/// A low entropy and a high fraction of intensity in the top 20 peaks are expected for clean fragmentation spectra.
+pub fn score_ms2(spectrum_data: &SpectrumData) -> Ms2Scores {
+    let total_intensity: f64 = spectrum_data.intensity_array.iter().filter(|intensity| **intensity > 0.0).map(|intensity| *intensity as f64).sum();
+    if total_intensity <= 0.0 {
+        return Ms2Scores { spectral_entropy: 0.0, top20_intensity_fraction: 0.0 };
+    }
+
+    let spectral_entropy: f64 = spectrum_data.intensity_array.iter()
+        .filter(|intensity| **intensity > 0.0)
+        .map(|intensity| {
+            let p = *intensity as f64 / total_intensity;
+            -p * p.ln()
+        })
+        .sum();
+
+    let mut sorted_intensities: Vec<f32> = spectrum_data.intensity_array.iter().copied().filter(|intensity| *intensity > 0.0).collect();
+    sorted_intensities.sort_by(|a, b| b.total_cmp(a));
+    let top_intensity: f64 = sorted_intensities.iter().take(TOP_PEAKS_COUNT).map(|intensity| *intensity as f64).sum();
+
+    Ms2Scores {
+        spectral_entropy: spectral_entropy as f32,
+        top20_intensity_fraction: (top_intensity / total_intensity) as f32,
+    }
+}
+
+/// Estimate the contamination of a precursor as the fraction of the MS1 intensity of the isolation window which is not explained by the precursor.
+/// The precursor intensity is the one of the most intense peak matching the precursor m/z, plus the ones of its isotopes found in the window
+/// when the charge is known. Returns None if the isolation window of the MS1 spectrum has no signal.
+pub fn estimate_precursor_contamination(
+    ms1_data: &SpectrumData,
+    precursor_mz: f64,
+    precursor_charge: Option<i32>,
+    isolation_window: &IsolationWindow,
+    mz_tolerance: &MzTolerance,
+) -> Option<f32> {
+    let window_data = ms1_data.crop(isolation_window.min_mz, isolation_window.max_mz);
+    let window_intensity: f64 = window_data.intensity_array.iter().map(|intensity| *intensity as f64).sum();
+    if window_intensity <= 0.0 {
+        return None;
+    }
+
+    let max_isotope_idx = match precursor_charge {
+        Some(charge) if charge != 0 => (0..)
+            .take_while(|isotope_idx| isotope_mz(precursor_mz, charge, *isotope_idx) <= isolation_window.max_mz)
+            .last()
+            .unwrap_or(0),
+        _ => 0,
+    };
+
+    let precursor_intensity: f64 = (0..=max_isotope_idx).map(|isotope_idx| {
+        let mz = precursor_charge.map_or(precursor_mz, |charge| isotope_mz(precursor_mz, charge, isotope_idx));
+        let (min_mz, max_mz) = mz_tolerance.mz_range(mz);
+        window_data.crop(min_mz, max_mz).intensity_array.iter().fold(0f32, |max_intensity, intensity| max_intensity.max(*intensity)) as f64
+    }).sum();
+
+    Some((1.0 - precursor_intensity / window_intensity).max(0.0) as f32)
+}
+
+/// Compute the quality of each MS2 spectrum, in ID order, using a single iteration over the spectra.
+/// The precursor contamination is estimated in the MS1 spectrum preceding each MS2 spectrum, using the isolation window
+/// of EntityCache.isolation_window_index when available, otherwise the one of the precursor list (not loaded for light headers).
+/// If a cancellation token is provided, it is polled for each spectrum (see progress::Cancelled).
+pub fn for_each_ms2_quality<F>(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    mz_tolerance: &MzTolerance,
+    cancellation_token: Option<&CancellationToken>,
+    mut on_each_ms2_quality: F
+) -> Result<()> where F: FnMut(&Ms2Quality) -> Result<()> {
+
+    let mut last_ms1_spectrum: Option<(i64, SpectrumData)> = None;
+
+    for_each_spectrum(db, entity_cache, None, |spectrum: &Spectrum| {
+        check_cancellation(cancellation_token)?;
+
+        let sh = &spectrum.header;
+        if sh.ms_level == 1 {
+            // cheap since the spectrum arrays are shared between clones
+            last_ms1_spectrum = Some((sh.id, spectrum.data.clone()));
+            return Ok(());
+        } else if sh.ms_level != 2 {
+            return Ok(());
+        }
+
+        let isolation_window = match entity_cache.isolation_window_index.as_ref() {
+            Some(isolation_window_index) => isolation_window_index.get_isolation_window_by_spectrum_id(&sh.id).copied(),
+            None => match sh.precursor_list_str.as_ref() {
+                Some(precursor_list_str) => extract_isolation_window(precursor_list_str)
+                    .context(format!("can't parse precursor list of spectrum with ID={}", sh.id)).location(here!())?,
+                None => None,
+            },
+        };
+
+        let precursor_contamination = match (last_ms1_spectrum.as_ref(), sh.precursor_mz, isolation_window) {
+            (Some((_, ms1_data)), Some(precursor_mz), Some(isolation_window)) => estimate_precursor_contamination(
+                ms1_data, precursor_mz, sh.precursor_charge, &isolation_window, mz_tolerance
+            ),
+            _ => None,
+        };
+
+        on_each_ms2_quality(&Ms2Quality {
+            spectrum_id: sh.id,
+            ms1_spectrum_id: last_ms1_spectrum.as_ref().map(|(ms1_spectrum_id, _)| *ms1_spectrum_id),
+            scores: score_ms2(&spectrum.data),
+            precursor_contamination,
+        })
+    }).location(here!())?;
+
+    Ok(())
+}
+
+/// Summarize the quality scores of all the MS2 spectra of the file (see for_each_ms2_quality)
+pub fn compute_ms2_quality_summary(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    mz_tolerance: &MzTolerance,
+    cancellation_token: Option<&CancellationToken>
+) -> Result<Ms2QualitySummary> {
+    let mut spectral_entropies = Vec::new();
+    let mut top20_intensity_fractions = Vec::new();
+    let mut precursor_contaminations = Vec::new();
+
+    for_each_ms2_quality(db, entity_cache, mz_tolerance, cancellation_token, |ms2_quality| {
+        spectral_entropies.push(ms2_quality.scores.spectral_entropy);
+        top20_intensity_fractions.push(ms2_quality.scores.top20_intensity_fraction);
+        if let Some(precursor_contamination) = ms2_quality.precursor_contamination {
+            precursor_contaminations.push(precursor_contamination);
+        }
+
+        Ok(())
+    }).location(here!())?;
+
+    Ok(Ms2QualitySummary {
+        ms2_count: spectral_entropies.len(),
+        spectral_entropy_stats: compute_distribution_stats(&spectral_entropies),
+        top20_intensity_fraction_stats: compute_distribution_stats(&top20_intensity_fractions),
+        precursor_contamination_stats: compute_distribution_stats(&precursor_contaminations),
+    })
+}
+
+/// Compute the summary statistics of a list of values, returns None if the list is empty
+pub fn compute_distribution_stats(values: &[f32]) -> Option<DistributionStats> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let mut sorted_values = values.to_vec();
+    sorted_values.sort_by(|a, b| a.total_cmp(b));
+
+    let count = sorted_values.len();
+    let median = if count % 2 == 1 {
+        sorted_values[count / 2]
+    } else {
+        (sorted_values[count / 2 - 1] + sorted_values[count / 2]) / 2.0
+    };
+
+    let sum: f64 = sorted_values.iter().map(|value| *value as f64).sum();
+
+    Some(DistributionStats {
+        count,
+        min: sorted_values[0],
+        max: sorted_values[count - 1],
+        mean: (sum / count as f64) as f32,
+        median,
+    })
+}
@@ -0,0 +1,233 @@
+// Isobaric labeling quantification (TMT, iTRAQ...) based on the reporter ions of the MSn spectra,
+// and extraction of the MS1 isotope envelope XICs used by label-free quantification.
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::iterator::for_each_spectrum;
+use crate::mass::{isotope_mz, ppm_to_da};
+use crate::model::*;
+use crate::xic::collect_region_peaks;
+
+const TMT16_REPORTER_IONS: [(&str, f64); 16] = [
+    ("126", 126.127726), ("127N", 127.124761), ("127C", 127.131081), ("128N", 128.128116),
+    ("128C", 128.134436), ("129N", 129.131471), ("129C", 129.137790), ("130N", 130.134825),
+    ("130C", 130.141145), ("131N", 131.138180), ("131C", 131.144500), ("132N", 132.141535),
+    ("132C", 132.147855), ("133N", 133.144890), ("133C", 133.151210), ("134N", 134.148245),
+];
+
+const ITRAQ8_REPORTER_IONS: [(&str, f64); 8] = [
+    ("113", 113.107873), ("114", 114.111228), ("115", 115.108263), ("116", 116.111618),
+    ("117", 117.114973), ("118", 118.112008), ("119", 119.115363), ("121", 121.122072),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReporterIon {
+    pub name: String,
+    pub mz: f64,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReporterSet {
+    TMT16,
+    ITRAQ8,
+    CUSTOM(Vec<ReporterIon>),
+}
+
+impl ReporterSet {
+    pub fn reporter_ions(&self) -> Vec<ReporterIon> {
+        let to_reporter_ions = |ions: &[(&str, f64)]| -> Vec<ReporterIon> {
+            ions.iter().map(|(name, mz)| ReporterIon { name: name.to_string(), mz: *mz }).collect()
+        };
+
+        match self {
+            ReporterSet::TMT16 => to_reporter_ions(&TMT16_REPORTER_IONS),
+            ReporterSet::ITRAQ8 => to_reporter_ions(&ITRAQ8_REPORTER_IONS),
+            ReporterSet::CUSTOM(reporter_ions) => reporter_ions.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReporterIonMatrix {
+    pub reporter_ions: Vec<ReporterIon>,
+    pub spectrum_ids: Vec<i64>,
+    pub intensities: Vec<Vec<f32>>, // one row per spectrum, one column per reporter ion
+}
+
+/// Extract the reporter ion intensities of all the MSn spectra (MS2, MS3...).
+/// For each reporter ion, the most intense peak found in the m/z tolerance is retained (0 if no peak is found).
+/// If a correction matrix is provided (isotopic impurities, see correct_reporter_intensities), the intensities are corrected.
+pub fn extract_reporter_ions(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    reporter_set: &ReporterSet,
+    mz_tolerance: &MzTolerance,
+    correction_matrix: Option<&Vec<Vec<f64>>>,
+) -> Result<ReporterIonMatrix> {
+    let reporter_ions = reporter_set.reporter_ions();
+    if reporter_ions.is_empty() {
+        bail!("the reporter set must contain at least one reporter ion");
+    }
+
+    if let Some(matrix) = correction_matrix {
+        _check_correction_matrix(matrix, reporter_ions.len()).location(here!())?;
+    }
+
+    let min_reporter_mz = reporter_ions.iter().map(|ion| ion.mz).fold(f64::MAX, f64::min);
+    let max_reporter_mz = reporter_ions.iter().map(|ion| ion.mz).fold(f64::MIN, f64::max);
+    let max_mz_tol = mz_tolerance.to_da(max_reporter_mz);
+
+    let mut spectrum_ids = Vec::new();
+    let mut intensities = Vec::new();
+
+    // A single pass over the spectra, in the order of their IDs, retaining the MSn ones
+    for_each_spectrum(db, entity_cache, None, |spectrum: &Spectrum| {
+        if spectrum.header.ms_level < 2 {
+            return Ok(());
+        }
+
+        let reporter_region = spectrum.data.crop(min_reporter_mz - max_mz_tol, max_reporter_mz + max_mz_tol);
+
+        let mut reporter_intensities: Vec<f32> = reporter_ions.iter().map(|reporter_ion| {
+            let (min_mz, max_mz) = mz_tolerance.mz_range(reporter_ion.mz);
+            reporter_region.crop(min_mz, max_mz)
+                .intensity_array.iter().fold(0f32, |max_intensity, intensity| max_intensity.max(*intensity))
+        }).collect();
+
+        if let Some(matrix) = correction_matrix {
+            reporter_intensities = correct_reporter_intensities(&reporter_intensities, matrix).location(here!())?;
+        }
+
+        spectrum_ids.push(spectrum.header.id);
+        intensities.push(reporter_intensities);
+
+        Ok(())
+    }).location(here!())?;
+
+    Ok(ReporterIonMatrix { reporter_ions, spectrum_ids, intensities })
+}
+
+/// Correct the reporter intensities for isotopic impurities.
+/// correction_matrix[i][j] is the fraction of the signal of channel j observed in channel i (the diagonal is usually close to 1).
+/// The corrected intensities are obtained by solving the linear system, negative values being set to 0.
+pub fn correct_reporter_intensities(observed_intensities: &[f32], correction_matrix: &[Vec<f64>]) -> Result<Vec<f32>> {
+    let n = observed_intensities.len();
+    _check_correction_matrix(correction_matrix, n).location(here!())?;
+
+    // Build the augmented matrix [A | b]
+    let mut a: Vec<Vec<f64>> = correction_matrix.iter().zip(observed_intensities).map(|(row, intensity)| {
+        let mut augmented_row = row.clone();
+        augmented_row.push(*intensity as f64);
+        augmented_row
+    }).collect();
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..n {
+        let pivot_row = (col..n).max_by(|r1, r2| a[*r1][col].abs().total_cmp(&a[*r2][col].abs())).unwrap();
+        if a[pivot_row][col].abs() < 1e-12 {
+            bail!("the correction matrix is singular");
+        }
+        a.swap(col, pivot_row);
+
+        let (upper_rows, lower_rows) = a.split_at_mut(col + 1);
+        let pivot = &upper_rows[col];
+        for row in lower_rows.iter_mut() {
+            let factor = row[col] / pivot[col];
+            for (value, pivot_value) in row[col..].iter_mut().zip(pivot[col..].iter()) {
+                *value -= factor * pivot_value;
+            }
+        }
+    }
+
+    // Back substitution
+    let mut corrected_intensities = vec![0f64; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * corrected_intensities[k]).sum();
+        corrected_intensities[row] = (a[row][n] - sum) / a[row][row];
+    }
+
+    Ok(corrected_intensities.into_iter().map(|intensity| intensity.max(0.0) as f32).collect())
+}
+
+fn _check_correction_matrix(correction_matrix: &[Vec<f64>], reporters_count: usize) -> Result<()> {
+    if correction_matrix.len() != reporters_count || correction_matrix.iter().any(|row| row.len() != reporters_count) {
+        bail!("the correction matrix must be a square matrix of size {}", reporters_count);
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IsotopeXic {
+    pub isotope_mzs: Vec<f64>, // monoisotopic m/z first
+    pub spectrum_ids: Vec<i64>,
+    pub times: Vec<f64>, // SpectrumHeader.time_f64 of each MS1 spectrum
+    pub isotope_intensities: Vec<Vec<f32>>, // one trace per isotope, one value per spectrum (0 if no peak is found)
+    pub summed_intensities: Vec<f32>, // sum of the isotope traces
+}
+
+/// Extract the MS1 XICs of the first n_isotopes isotopes of an ion (monoisotopic m/z and charge), plus their sum.
+/// For each isotope, the most intense peak found in the tol_ppm window is retained (0 if no peak is found).
+/// The peaks are collected by the XIC extraction (see xic::get_ms1_xic()) in a single region spanning the whole envelope.
+/// The optional rt_range (in seconds, bounds are inclusive) is compared to SpectrumHeader.time_f64,
+/// and the traces contain one value per MS1 spectrum of this range, sorted by spectrum ID.
+pub fn get_xic_isotopes(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    mono_mz: f64,
+    charge: i32,
+    n_isotopes: usize,
+    tol_ppm: f64,
+    rt_range: Option<(f64, f64)>,
+) -> Result<IsotopeXic> {
+    if charge == 0 {
+        bail!("invalid charge state 0");
+    }
+    if n_isotopes == 0 {
+        bail!("at least one isotope must be extracted");
+    }
+
+    let isotope_mzs: Vec<f64> = (0..n_isotopes).map(|isotope_idx| isotope_mz(mono_mz, charge, isotope_idx)).collect();
+    let isotope_mz_ranges: Vec<(f64, f64)> = isotope_mzs.iter().map(|mz| {
+        let mz_tol = ppm_to_da(*mz, tol_ppm);
+        (mz - mz_tol, mz + mz_tol)
+    }).collect();
+
+    let min_mz = isotope_mz_ranges[0].0;
+    let max_mz = isotope_mz_ranges[n_isotopes - 1].1;
+
+    // A single region spanning the whole envelope, so that each bounding box is decoded once
+    let region_peaks = collect_region_peaks(db, entity_cache, 1, None, rt_range, min_mz, max_mz).location(here!())?;
+    let ms1_headers = region_peaks.headers;
+
+    let mut isotope_intensities = vec![Vec::with_capacity(ms1_headers.len()); n_isotopes];
+    let mut summed_intensities = Vec::with_capacity(ms1_headers.len());
+
+    for sh in ms1_headers.iter() {
+        let envelope_peaks = region_peaks.peaks_by_spectrum_id.get(&sh.id).map_or(&[][..], |peaks| peaks.as_slice());
+
+        let mut summed_intensity = 0f32;
+        for (isotope_idx, (iso_min_mz, iso_max_mz)) in isotope_mz_ranges.iter().enumerate() {
+            let intensity = envelope_peaks.iter()
+                .filter(|(mz, _)| mz >= iso_min_mz && mz <= iso_max_mz)
+                .fold(0f32, |max_intensity, (_, intensity)| max_intensity.max(*intensity));
+
+            isotope_intensities[isotope_idx].push(intensity);
+            summed_intensity += intensity;
+        }
+
+        summed_intensities.push(summed_intensity);
+    }
+
+    Ok(IsotopeXic {
+        isotope_mzs,
+        spectrum_ids: ms1_headers.iter().map(|sh| sh.id).collect(),
+        times: ms1_headers.iter().map(|sh| sh.time_f64).collect(),
+        isotope_intensities,
+        summed_intensities,
+    })
+}
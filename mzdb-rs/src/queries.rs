@@ -6,14 +6,28 @@ use anyhow::*;
 use crate::anyhow_ext::*;
 //use itertools::Itertools;
 
+use std::time::{Duration, Instant};
+
 use rusqlite::{Connection, OptionalExtension, Row, Statement};
 use rusqlite::{Result as RusqliteResult};
+use crate::blob_cursor::{BlobCursor, SpectrumSliceView};
+use crate::compat::normalize_compression;
+use crate::diagnostics::{DiagnosticKind, DiagnosticsSink};
+use crate::metadata::get_chromatogram_type;
+use crate::mzdb::get_spectrum_header_from_table;
+use crate::rtree::{RtreeEntry, RtreeRegion};
 use crate::model::*;
 use crate::model::DataMode::FITTED;
+use crate::xml::{find_cv_param_value, parse_cv_params_with_mode, XmlParsingMode};
 
 pub const BOUNDING_BOX_TABLE_NAME: &'static str = "bounding_box";
 pub const DATA_ENCODING_TABLE_NAME: &'static str = "data_encoding";
 pub const SPECTRUM_TABLE_NAME: &'static str = "spectrum";
+pub const THUMBNAIL_TABLE_NAME: &'static str = "thumbnail"; // optional table, see maintenance::generate_thumbnails
+pub const SPECTRUM_FTS_TABLE_NAME: &'static str = "spectrum_fts"; // optional FTS5 table, see maintenance::build_fts_index
+pub const SCAN_METADATA_TABLE_NAME: &'static str = "scan_metadata"; // optional table, see maintenance::build_scan_metadata_cache
+pub const TIC_THUMBNAIL_NAME: &'static str = "tic";
+pub const ION_MAP_THUMBNAIL_NAME: &'static str = "ion_map";
 
 //const SQLQUERY_SINGLEMSLEVEL: &'static str = "SELECT bounding_box.* FROM bounding_box, spectrum WHERE spectrum.id = bounding_box.first_spectrum_id AND spectrum.ms_level=?";
 
@@ -157,6 +171,76 @@ pub fn get_param_tree_chromatogram_res(db: &Connection) -> Result<Vec<String>> {
     )
 }
 
+/// List the headers of all the chromatograms
+pub fn list_chromatogram_headers(db: &Connection) -> Result<Vec<ChromatogramHeader>> {
+    let mut stmt = db.prepare(
+        "SELECT id, name, activation_type, param_tree, precursor, product, shared_param_tree_id, run_id, data_processing_id, data_encoding_id FROM chromatogram"
+    ).location(here!())?;
+
+    let records = serde_rusqlite::from_rows::<ChromatogramHeader>(stmt.query([]).location(here!())?);
+
+    let mut chrom_headers = Vec::new();
+    for record_res in records {
+        chrom_headers.push(record_res.location(here!())?);
+    }
+
+    Ok(chrom_headers)
+}
+
+/// Get the header of the TIC chromatogram, identified by its cvParam (MS:1000235) or by its name
+pub fn get_tic_chromatogram_header(db: &Connection) -> Result<Option<ChromatogramHeader>> {
+    let chrom_headers = list_chromatogram_headers(db).location(here!())?;
+
+    for chrom_header in chrom_headers {
+        if get_chromatogram_type(db, &chrom_header).location(here!())? == ChromatogramType::TIC {
+            return Ok(Some(chrom_header));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Decode the data points of a chromatogram, stored as (time, intensity) pairs using the layout of its data encoding.
+/// Times stored in minutes (see ChromatogramHeader::time_unit()) are converted to seconds.
+/// Returns None if the chromatogram doesn't exist.
+pub fn get_chromatogram_data(db: &Connection, chromatogram_id: i64) -> Result<Option<ChromatogramData>> {
+    let record_opt: Option<(Vec<u8>, i64, String)> = db.query_row(
+        "SELECT data_points, data_encoding_id, param_tree FROM chromatogram WHERE id = ?",
+        [chromatogram_id],
+        |row| RusqliteResult::Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    ).optional().location(here!())?;
+
+    let (data_points, data_encoding_id, param_tree) = match record_opt {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+
+    let data_encoding = list_data_encodings(db).location(here!())?.into_iter()
+        .find(|de| de.id == data_encoding_id)
+        .context(format!("can't find data encoding with ID={} of chromatogram with ID={}", data_encoding_id, chromatogram_id))
+        .location(here!())?;
+
+    if data_encoding.compression != "none" {
+        bail!("unsupported compression {} of chromatogram with ID={}", data_encoding.compression, chromatogram_id);
+    }
+
+    let point_size = data_encoding.get_peak_size();
+    if data_points.len() % point_size != 0 {
+        bail!("invalid data points size {} of chromatogram with ID={} (expected a multiple of {})", data_points.len(), chromatogram_id, point_size);
+    }
+
+    let view = SpectrumSliceView::new(chromatogram_id, data_points.len() / point_size, 0, &data_encoding, &data_points);
+    let points = view.to_spectrum_data();
+
+    let cv_params = parse_cv_params_with_mode(&param_tree, XmlParsingMode::LENIENT).location(here!())?;
+    let time_factor = TimeUnit::from_cv_params(&cv_params).to_seconds_factor();
+
+    Ok(Some(ChromatogramData {
+        time_array: points.mz_array.iter().map(|time| time * time_factor).collect(),
+        intensity_array: points.intensity_array.to_vec(),
+    }))
+}
+
 /// Get the param tree of the spectrum table from one spectrum id
 pub fn get_param_tree_spectrum(db: &Connection, spectrum_id: i64) -> Result<Option<String>> {
     get_first_string(
@@ -165,6 +249,30 @@ pub fn get_param_tree_spectrum(db: &Connection, spectrum_id: i64) -> Result<Opti
     )
 }
 
+/// Get the scan list of the spectrum table from one spectrum id
+pub fn get_scan_list_xml(db: &Connection, spectrum_id: i64) -> Result<Option<String>> {
+    get_first_string(
+        db,
+        format!("SELECT scan_list FROM spectrum WHERE id = {}", spectrum_id).as_str(),
+    )
+}
+
+/// Get the precursor list of the spectrum table from one spectrum id
+pub fn get_precursor_list_xml(db: &Connection, spectrum_id: i64) -> Result<Option<String>> {
+    get_first_string(
+        db,
+        format!("SELECT precursor_list FROM spectrum WHERE id = {}", spectrum_id).as_str(),
+    )
+}
+
+/// Get the product list of the spectrum table from one spectrum id
+pub fn get_product_list_xml(db: &Connection, spectrum_id: i64) -> Result<Option<String>> {
+    get_first_string(
+        db,
+        format!("SELECT product_list FROM spectrum WHERE id = {}", spectrum_id).as_str(),
+    )
+}
+
 /// Get param tree of the mzdb table
 pub fn get_param_tree_mzdb(db: &Connection) -> Result<Option<String>> {
     _get_first_string_from_query(
@@ -197,6 +305,113 @@ pub fn get_max_ms_level(db: &Connection) -> Result<Option<i64>> {
     )
 }
 
+/// Get the downsampled TIC trace of the thumbnail table, returns None if the thumbnails have not been generated
+pub fn get_tic_thumbnail(db: &Connection) -> Result<Option<TicThumbnail>> {
+    let record_opt = _get_thumbnail_record(db, TIC_THUMBNAIL_NAME).location(here!())?;
+    let (_width, _height, _min_x, _max_x, _min_y, _max_y, data) = match record_opt {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+
+    // Data is a sequence of (time, intensity) f32 pairs
+    let values: Vec<f32> = data.chunks_exact(4).map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap())).collect();
+
+    Ok(Some(TicThumbnail {
+        times: values.iter().step_by(2).copied().collect(),
+        intensities: values.iter().skip(1).step_by(2).copied().collect(),
+    }))
+}
+
+/// Get the rasterized MS1 ion map of the thumbnail table, returns None if the thumbnails have not been generated
+pub fn get_ion_map_thumbnail(db: &Connection) -> Result<Option<IonMapThumbnail>> {
+    let record_opt = _get_thumbnail_record(db, ION_MAP_THUMBNAIL_NAME).location(here!())?;
+    let (width, height, min_time, max_time, min_mz, max_mz, pixels) = match record_opt {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+
+    if pixels.len() != (width * height) as usize {
+        bail!("invalid ion map thumbnail: expected {}x{} pixels but got {}", width, height, pixels.len());
+    }
+
+    Ok(Some(IonMapThumbnail {
+        width: width as usize,
+        height: height as usize,
+        min_time: min_time as f32,
+        max_time: max_time as f32,
+        min_mz,
+        max_mz,
+        pixels,
+    }))
+}
+
+// Record of the thumbnail table: (width, height, min_x, max_x, min_y, max_y, data)
+type ThumbnailRecord = (i64, i64, f64, f64, f64, f64, Vec<u8>);
+
+fn _get_thumbnail_record(db: &Connection, name: &str) -> Result<Option<ThumbnailRecord>> {
+    if !table_exists(db, THUMBNAIL_TABLE_NAME).location(here!())? {
+        return Ok(None);
+    }
+
+    db.query_row(
+        format!("SELECT width, height, min_x, max_x, min_y, max_y, data FROM {} WHERE name = ?", THUMBNAIL_TABLE_NAME).as_str(),
+        [name],
+        |row| rusqlite::Result::Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?)),
+    ).optional().location(here!())
+}
+
+/// List the run slice headers, ordered by MS level and number
+pub fn list_run_slice_headers(db: &Connection) -> Result<Vec<RunSliceHeader>> {
+    let mut stmt = db.prepare(
+        "SELECT id, ms_level, number, begin_mz, end_mz, run_id FROM run_slice ORDER BY ms_level, number"
+    ).location(here!())?;
+
+    let run_slice_headers = stmt.query_map([], |row| {
+        rusqlite::Result::Ok(RunSliceHeader {
+            id: row.get(0)?,
+            ms_level: row.get(1)?,
+            number: row.get(2)?,
+            begin_mz: row.get(3)?,
+            end_mz: row.get(4)?,
+            run_id: row.get(5)?,
+        })
+    }).location(here!())?.collect::<rusqlite::Result<Vec<RunSliceHeader>>>().location(here!())?;
+
+    Ok(run_slice_headers)
+}
+
+/// Get the bounding boxes count, covered time range and data size of every run slice, ordered by MS level and number
+pub fn get_run_slice_coverage(db: &Connection) -> Result<Vec<RunSliceCoverage>> {
+    let mut stmt = db.prepare(
+        "SELECT rs.id, rs.ms_level, rs.number, rs.begin_mz, rs.end_mz, rs.run_id, \
+        count(bb.id), min(first_spectrum.time), max(last_spectrum.time), coalesce(sum(length(bb.data)), 0) \
+        FROM run_slice rs \
+        LEFT JOIN bounding_box bb ON bb.run_slice_id = rs.id \
+        LEFT JOIN spectrum first_spectrum ON first_spectrum.id = bb.first_spectrum_id \
+        LEFT JOIN spectrum last_spectrum ON last_spectrum.id = bb.last_spectrum_id \
+        GROUP BY rs.id ORDER BY rs.ms_level, rs.number"
+    ).location(here!())?;
+
+    let coverages = stmt.query_map([], |row| {
+        rusqlite::Result::Ok(RunSliceCoverage {
+            header: RunSliceHeader {
+                id: row.get(0)?,
+                ms_level: row.get(1)?,
+                number: row.get(2)?,
+                begin_mz: row.get(3)?,
+                end_mz: row.get(4)?,
+                run_id: row.get(5)?,
+            },
+            bounding_boxes_count: row.get(6)?,
+            min_time: row.get(7)?,
+            max_time: row.get(8)?,
+            total_bytes: row.get(9)?,
+        })
+    }).location(here!())?.collect::<rusqlite::Result<Vec<RunSliceCoverage>>>().location(here!())?;
+
+    Ok(coverages)
+}
+
 /// The number of bounding box from one run slice id
 pub fn get_run_slice_bounding_boxes_count(db: &Connection, run_slice_id: i64) -> Result<Option<i64>> {
     get_first_int(
@@ -213,8 +428,309 @@ pub fn get_spectra_count_single_ms_level(db: &Connection, ms_level: i64) -> Resu
     )
 }
 
-/// Get the number of records stored in a given table
+/// List the IDs of the spectra whose time is in the provided range (bounds are inclusive, in seconds).
+/// Times are compared at full precision, consistently with SpectrumHeader.time_f64.
+/// Without the optional time index (see maintenance::create_optional_indexes()), the time range is checked by scanning the spectrum table.
+/// The range includes the EntityCache.rt_offset and time_factor, consistently with the times of the spectrum headers (see EntityCache::to_stored_time()).
+pub fn list_spectrum_ids_in_time_range(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    min_time: f64,
+    max_time: f64,
+    ms_level: Option<i64>,
+) -> Result<Vec<i64>> {
+    let mut stmt = db.prepare(
+        "SELECT id FROM spectrum WHERE time >= ?1 AND time <= ?2 AND (?3 IS NULL OR ms_level = ?3) ORDER BY id"
+    ).location(here!())?;
+
+    // The spectrum table stores the times without the RT offset, possibly in minutes
+    let (min_stored_time, max_stored_time) = (entity_cache.to_stored_time(min_time), entity_cache.to_stored_time(max_time));
+
+    let spectrum_ids = stmt.query_map(rusqlite::params![min_stored_time, max_stored_time, ms_level], |row| row.get(0))
+        .location(here!())?
+        .collect::<rusqlite::Result<Vec<i64>>>()
+        .location(here!())?;
+
+    Ok(spectrum_ids)
+}
+
+/// List the IDs of the spectra converted from the provided source file (e.g. to split the content of a merged file by origin)
+pub fn list_spectrum_ids_for_source_file(db: &Connection, source_file_id: i64) -> Result<Vec<i64>> {
+    let mut stmt = db.prepare("SELECT id FROM spectrum WHERE source_file_id = ? ORDER BY id").location(here!())?;
+
+    let spectrum_ids = stmt.query_map([source_file_id], |row| row.get(0))
+        .location(here!())?
+        .collect::<rusqlite::Result<Vec<i64>>>()
+        .location(here!())?;
+
+    Ok(spectrum_ids)
+}
+
+/// List the IDs of the spectra whose main_precursor_mz column is in the provided range (bounds are inclusive).
+/// Note: spectra whose precursor m/z is only declared by the precursor_list (NULL main_precursor_mz) are not listed,
+/// the SpectrumHeader.precursor_mz values of the entity cache have to be used for such files.
+/// Without the optional precursor m/z index (see maintenance::create_optional_indexes()), the spectrum table is fully scanned.
+pub fn list_spectrum_ids_in_precursor_mz_range(db: &Connection, min_mz: f64, max_mz: f64) -> Result<Vec<i64>> {
+    let mut stmt = db.prepare(
+        "SELECT id FROM spectrum WHERE main_precursor_mz >= ? AND main_precursor_mz <= ? ORDER BY id"
+    ).location(here!())?;
+
+    let spectrum_ids = stmt.query_map(rusqlite::params![min_mz, max_mz], |row| row.get(0))
+        .location(here!())?
+        .collect::<rusqlite::Result<Vec<i64>>>()
+        .location(here!())?;
+
+    Ok(spectrum_ids)
+}
+
+/// Get the injection time, filter string, scan window and precursor intensity of every spectrum as parallel vectors, ordered by spectrum id.
+/// Values are read from the cache table built by maintenance::build_scan_metadata_cache() when it is up to date,
+/// otherwise they are parsed from the scan and precursor lists (see parse_scan_metadata_table()).
+pub fn get_scan_metadata_table(db: &Connection) -> Result<ScanMetadataTable> {
+    match get_cached_scan_metadata_table(db).location(here!())? {
+        Some(cached_table) => Ok(cached_table),
+        None => parse_scan_metadata_table(db),
+    }
+}
+
+/// Read the cache table built by maintenance::build_scan_metadata_cache(), without any XML parsing.
+/// Returns None if the file has no cache table, or if it is out of date (a warning is then logged).
+pub fn get_cached_scan_metadata_table(db: &Connection) -> Result<Option<ScanMetadataTable>> {
+    if !table_exists(db, SCAN_METADATA_TABLE_NAME).location(here!())? {
+        return Ok(None);
+    }
+
+    // Tables built by the previous versions don't store the precursor intensities
+    let has_precursor_intensities: bool = db.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?) WHERE name = 'precursor_intensity')", [SCAN_METADATA_TABLE_NAME], |row| row.get(0)
+    ).location(here!())?;
+
+    if has_precursor_intensities {
+        let cached_table = _read_scan_metadata_cache(db).location(here!())?;
+        let spectra_count: i64 = db.query_row("SELECT count(*) FROM spectrum", [], |row| row.get(0)).location(here!())?;
+        if cached_table.ids.len() as i64 == spectra_count {
+            return Ok(Some(cached_table));
+        }
+    }
+
+    log::warn!("the {} table is out of date and is ignored", SCAN_METADATA_TABLE_NAME);
+
+    Ok(None)
+}
+
+/// Parse the injection time, filter string, scan window and precursor intensity of every spectrum from the scan and precursor lists,
+/// the XML parsing being split over the available CPU cores.
+pub fn parse_scan_metadata_table(db: &Connection) -> Result<ScanMetadataTable> {
+    let mut stmt = db.prepare("SELECT id, scan_list, precursor_list FROM spectrum ORDER BY id").location(here!())?;
+    let records = stmt.query_map([], |row| RusqliteResult::Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, Option<String>>(2)?)))
+        .location(here!())?
+        .collect::<rusqlite::Result<Vec<(i64, Option<String>, Option<String>)>>>()
+        .location(here!())?;
+
+    let threads_count = std::thread::available_parallelism().map_or(1, |count| count.get());
+    let chunk_size = records.len().div_ceil(threads_count).max(1);
+
+    let chunk_results: Vec<Result<Vec<ScanMetadata>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = records.chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || {
+                chunk.iter()
+                    .map(|(spectrum_id, scan_list_opt, precursor_list_opt)| {
+                        _parse_scan_metadata(scan_list_opt.as_deref(), precursor_list_opt.as_deref())
+                            .context(format!("can't parse scan or precursor list of spectrum with ID={}", spectrum_id))
+                    })
+                    .collect::<Result<Vec<ScanMetadata>>>()
+            }))
+            .collect();
+
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow!("scan list parsing thread panicked"))))
+            .collect()
+    });
+
+    let mut table = ScanMetadataTable {
+        ids: records.iter().map(|(spectrum_id, _, _)| *spectrum_id).collect(),
+        injection_times: Vec::with_capacity(records.len()),
+        filter_strings: Vec::with_capacity(records.len()),
+        scan_windows: Vec::with_capacity(records.len()),
+        precursor_intensities: Vec::with_capacity(records.len()),
+    };
+
+    for chunk_result in chunk_results {
+        for (injection_time, filter_string, scan_window, precursor_intensity) in chunk_result.location(here!())? {
+            table.injection_times.push(injection_time);
+            table.filter_strings.push(filter_string);
+            table.scan_windows.push(scan_window);
+            table.precursor_intensities.push(precursor_intensity);
+        }
+    }
+
+    Ok(table)
+}
+
+type ScanMetadata = (Option<f32>, Option<String>, Option<(f64, f64)>, Option<f32>);
+
+fn _parse_scan_metadata(scan_list_opt: Option<&str>, precursor_list_opt: Option<&str>) -> Result<ScanMetadata> {
+    let precursor_intensity = match precursor_list_opt {
+        Some(precursor_list) => {
+            let cv_params = parse_cv_params_with_mode(precursor_list, XmlParsingMode::LENIENT).location(here!())?;
+            find_cv_param_value(&cv_params, PEAK_INTENSITY).and_then(|value| value.parse::<f32>().ok())
+        }
+        None => None,
+    };
+
+    let scan_list = match scan_list_opt {
+        Some(scan_list) => scan_list,
+        None => return Ok((None, None, None, precursor_intensity)),
+    };
+
+    let cv_params = parse_cv_params_with_mode(scan_list, XmlParsingMode::LENIENT).location(here!())?;
+    let find_f64 = |accession: &str| find_cv_param_value(&cv_params, accession).and_then(|value| value.parse::<f64>().ok());
+
+    let injection_time = find_f64(ION_INJECTION_TIME).map(|value| value as f32);
+    let filter_string = find_cv_param_value(&cv_params, FILTER_STRING).map(|value| value.to_string());
+    let scan_window = find_f64(SCAN_WINDOW_LOWER_LIMIT).zip(find_f64(SCAN_WINDOW_UPPER_LIMIT));
+
+    Ok((injection_time, filter_string, scan_window, precursor_intensity))
+}
+
+fn _read_scan_metadata_cache(db: &Connection) -> Result<ScanMetadataTable> {
+    let mut stmt = db.prepare(
+        format!(
+            "SELECT id, injection_time, filter_string, scan_window_lower_limit, scan_window_upper_limit, precursor_intensity FROM {} ORDER BY id",
+            SCAN_METADATA_TABLE_NAME
+        ).as_str()
+    ).location(here!())?;
+
+    let mut table = ScanMetadataTable {
+        ids: Vec::new(),
+        injection_times: Vec::new(),
+        filter_strings: Vec::new(),
+        scan_windows: Vec::new(),
+        precursor_intensities: Vec::new(),
+    };
+
+    let mut rows = stmt.query([]).location(here!())?;
+    while let Some(row) = rows.next().location(here!())? {
+        let lower_limit: Option<f64> = row.get(3).location(here!())?;
+        let upper_limit: Option<f64> = row.get(4).location(here!())?;
+
+        table.ids.push(row.get(0).location(here!())?);
+        table.injection_times.push(row.get(1).location(here!())?);
+        table.filter_strings.push(row.get(2).location(here!())?);
+        table.scan_windows.push(lower_limit.zip(upper_limit));
+        table.precursor_intensities.push(row.get(5).location(here!())?);
+    }
+
+    Ok(table)
+}
+
+/// Load the selected columns of the spectrum table as parallel vectors, ordered by spectrum id.
+/// This is cheaper than loading the spectrum headers when only a few columns are needed for many spectra.
+pub fn get_spectrum_table_columns(db: &Connection, selection: ColumnsSelection) -> Result<SpectrumTableColumns> {
+    let selectable_columns = [
+        (selection.cycles, "cycle"),
+        (selection.times, "time"),
+        (selection.ms_levels, "ms_level"),
+        (selection.tics, "tic"),
+        (selection.base_peak_mzs, "base_peak_mz"),
+        (selection.base_peak_intensities, "base_peak_intensity"),
+        (selection.precursor_mzs, "main_precursor_mz"),
+        (selection.precursor_charges, "main_precursor_charge"),
+        (selection.peaks_counts, "data_points_count"),
+    ];
+
+    let column_names: Vec<&str> = std::iter::once("id")
+        .chain(selectable_columns.iter().filter(|(is_selected, _name)| *is_selected).map(|(_is_selected, name)| *name))
+        .collect();
+
+    let spectra_count = get_table_records_count_with_mode(db, SPECTRUM_TABLE_NAME, CountMode::HINTED).location(here!())?.unwrap_or(0) as usize;
+    let capacity = |is_selected: bool| if is_selected { spectra_count } else { 0 };
+
+    let mut columns = SpectrumTableColumns {
+        ids: Vec::with_capacity(spectra_count),
+        cycles: Vec::with_capacity(capacity(selection.cycles)),
+        times: Vec::with_capacity(capacity(selection.times)),
+        ms_levels: Vec::with_capacity(capacity(selection.ms_levels)),
+        tics: Vec::with_capacity(capacity(selection.tics)),
+        base_peak_mzs: Vec::with_capacity(capacity(selection.base_peak_mzs)),
+        base_peak_intensities: Vec::with_capacity(capacity(selection.base_peak_intensities)),
+        precursor_mzs: Vec::with_capacity(capacity(selection.precursor_mzs)),
+        precursor_charges: Vec::with_capacity(capacity(selection.precursor_charges)),
+        peaks_counts: Vec::with_capacity(capacity(selection.peaks_counts)),
+    };
+
+    let mut stmt = db.prepare(
+        format!("SELECT {} FROM {} ORDER BY id", column_names.join(", "), SPECTRUM_TABLE_NAME).as_str()
+    ).location(here!())?;
+
+    let mut rows = stmt.query([]).location(here!())?;
+    while let Some(row) = rows.next().location(here!())? {
+        columns.ids.push(row.get("id").location(here!())?);
+
+        if selection.cycles { columns.cycles.push(row.get("cycle").location(here!())?); }
+        if selection.times { columns.times.push(row.get("time").location(here!())?); }
+        if selection.ms_levels { columns.ms_levels.push(row.get("ms_level").location(here!())?); }
+        if selection.tics { columns.tics.push(row.get("tic").location(here!())?); }
+        if selection.base_peak_mzs { columns.base_peak_mzs.push(row.get("base_peak_mz").location(here!())?); }
+        if selection.base_peak_intensities { columns.base_peak_intensities.push(row.get("base_peak_intensity").location(here!())?); }
+        if selection.precursor_mzs { columns.precursor_mzs.push(row.get("main_precursor_mz").location(here!())?); }
+        if selection.precursor_charges { columns.precursor_charges.push(row.get("main_precursor_charge").location(here!())?); }
+        if selection.peaks_counts { columns.peaks_counts.push(row.get("data_points_count").location(here!())?); }
+    }
+
+    Ok(columns)
+}
+
+/// Get the number of records stored in a given table, read from sqlite_sequence.
+/// Note: None is returned if the table has no sqlite_sequence entry (e.g. R-tree tables),
+/// and the value may be stale on files edited by other tools (see get_table_records_count_with_mode()).
 pub fn get_table_records_count(db: &Connection, name: &str) -> Result<Option<i64>> {
+    _get_table_sequence(db, name)
+}
+
+/// Get the number of records stored in a given table, using the provided CountMode.
+/// Note: in SEQUENCE mode, None is returned if the table has no sqlite_sequence entry (e.g. R-tree tables).
+pub fn get_table_records_count_with_mode(db: &Connection, name: &str, count_mode: CountMode) -> Result<Option<i64>> {
+    match count_mode {
+        CountMode::SEQUENCE => _get_table_sequence(db, name),
+        CountMode::HINTED => get_table_count_exact_with_hint(db, name).map(Some),
+        CountMode::EXACT => get_first_int(db, format!("SELECT count(*) FROM {:?}", name).as_str()),
+    }
+}
+
+/// Get the number of records of a table, using the sqlite_sequence value as a hint.
+/// The hint is trusted when it matches the max rowid of the table, otherwise the records are counted.
+pub fn get_table_count_exact_with_hint(db: &Connection, name: &str) -> Result<i64> {
+    let seq_opt = _get_table_sequence(db, name).location(here!())?;
+    let max_rowid = get_first_int(db, format!("SELECT ifnull(max(rowid), 0) FROM {:?}", name).as_str()).location(here!())?.unwrap_or(0);
+
+    let count = match seq_opt {
+        _ if max_rowid == 0 => 0, // empty table
+        Some(seq) if seq == max_rowid => seq,
+        _ => {
+            log::debug!("sqlite_sequence value {:?} of table {} doesn't match its max rowid, counting records", seq_opt, name);
+            get_first_int(db, format!("SELECT count(*) FROM {:?}", name).as_str()).location(here!())?.unwrap_or(0)
+        }
+    };
+
+    Ok(count)
+}
+
+/// Returns true if the file has a table with the provided name
+pub fn table_exists(db: &Connection, name: &str) -> Result<bool> {
+    db.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+        [name],
+        |row| row.get(0),
+    ).location(here!())
+}
+
+/// Quote an identifier (e.g. a table name) to be inserted in a SQL statement
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn _get_table_sequence(db: &Connection, name: &str) -> Result<Option<i64>> {
     get_first_int(
         &db,
         format!("SELECT seq FROM sqlite_sequence WHERE name =  {:?}", name).as_str(),
@@ -245,6 +761,38 @@ pub fn get_bounding_box_min_time(db: &Connection, bb_r_tree_id: i64) -> Result<O
     )
 }
 
+/// Get the bounds of a bounding box with a single query, bounding_box_rtree being looked up first, then bounding_box_msn_rtree.
+/// Returns None if the bounding box is indexed by none of them.
+/// Times are returned as stored: use EntityCache::to_header_time() to get them in seconds (see rtree::query_region_auto()).
+pub fn get_bounding_box_bounds(db: &Connection, bb_id: i64) -> Result<Option<RtreeEntry>> {
+    let ms1_entry_opt = db.query_row(
+        "SELECT min_mz, max_mz, min_time, max_time FROM bounding_box_rtree WHERE id = ?",
+        [bb_id],
+        |row| RusqliteResult::Ok(RtreeEntry::MS1 {
+            bb_id,
+            region: RtreeRegion { min_mz: row.get(0)?, max_mz: row.get(1)?, min_time: row.get(2)?, max_time: row.get(3)? },
+        })
+    ).optional().location(here!())?;
+
+    if ms1_entry_opt.is_some() {
+        return Ok(ms1_entry_opt);
+    }
+
+    db.query_row(
+        "SELECT min_ms_level, max_ms_level, min_parent_mz, max_parent_mz, min_mz, max_mz, min_time, max_time \
+        FROM bounding_box_msn_rtree WHERE id = ?",
+        [bb_id],
+        |row| RusqliteResult::Ok(RtreeEntry::MSN {
+            bb_id,
+            min_ms_level: row.get::<_, f64>(0)? as i64,
+            max_ms_level: row.get::<_, f64>(1)? as i64,
+            min_parent_mz: row.get(2)?,
+            max_parent_mz: row.get(3)?,
+            region: RtreeRegion { min_mz: row.get(4)?, max_mz: row.get(5)?, min_time: row.get(6)?, max_time: row.get(7)? },
+        })
+    ).optional().location(here!())
+}
+
 /// Get the run slice id of one bounding box
 pub fn get_run_slice_id(db: &Connection, bb_id: i64) -> Result<Option<i64>> {
     get_first_int(
@@ -330,7 +878,7 @@ pub fn list_data_encodings(db: &Connection) -> Result<Vec<DataEncoding>> {
                 id: row.get(0)?,
                 mode: mode,
                 peak_encoding: peak_encoding,
-                compression: row.get(2)?,
+                compression: normalize_compression(row.get(2)?),
                 byte_order: byte_order,
             })
         }).location(here!())?;
@@ -385,156 +933,24 @@ pub fn get_bounding_box_data(db: &Connection, bb_id: i64) -> Result<Vec<u8>> {
     Ok(bytes_vec)
 }*/
 
-fn read_spectrum_slice_data(
-    bb_bytes: &[u8],
-    peaks_start_pos: usize,
-    peaks_count: usize,
-    de: &DataEncoding,
+
+pub fn read_spectrum_slice_data_at(
+    bounding_box: &BoundingBox,
+    bbox_index: &BoundingBoxIndex,
+    data_encoding: &DataEncoding,
+    spectrum_slice_idx: usize,
     min_mz: Option<f64>,
     max_mz: Option<f64>,
 ) -> Result<SpectrumData> {
-    let data_mode = de.mode;
-    let pe = de.peak_encoding;
-    let byte_order = de.byte_order;
-
-    let peak_size = de.get_peak_size();
-    //let peaks_bytes_length= peaks_count * peak_size;
-
-    let mut float_bytes = [0u8; 4];
-    let mut double_bytes = [0u8; 8];
-
-    // Closure for bytes to double conversion
-    let mut _bytes_to_double = |offset: usize, decode_float: bool| -> (f64, usize) {
-        if decode_float {
-            float_bytes.clone_from_slice(&bb_bytes[offset..offset + 4]);
-
-            let value = if byte_order == ByteOrder::BIG_ENDIAN {
-                f32::from_be_bytes(float_bytes) as f64
-            } else {
-                f32::from_le_bytes(float_bytes) as f64
-            };
-            (value, 4)
-        } else {
-            double_bytes.clone_from_slice(&bb_bytes[offset..offset + 8]);
-
-            let value = if byte_order == ByteOrder::BIG_ENDIAN {
-                f64::from_be_bytes(double_bytes)
-            } else {
-                f64::from_le_bytes(double_bytes)
-            };
-            (value, 8)
-        }
-    };
-
-    let mut filtered_peaks_count = 0;
-    let mut filtered_peaks_start_idx = 0;
-
-    // If no m/z range is provided
-    if min_mz.is_none() && max_mz.is_none() {
-        // Compute the peaks count for the whole spectrum slice
-        filtered_peaks_count = peaks_count;
-        // Set peaks_start_idx value to spectrum_slice_start_pos
-        filtered_peaks_start_idx = peaks_start_pos;
-        // Else determine the peaks_start_idx and peaks_count corresponding to provided m/z filters
-    } else {
-        // Determine the max m/z threshold to use
-        let max_mz_threshold = if max_mz.is_none() { f64::MAX } else { max_mz.unwrap() };
-
-        let mut i = 0;
-        while i < peaks_count {
-            let peak_start_pos: usize = peaks_start_pos + 1;
-
-            // TODO: compare with memcpy C implementation (see https://doc.rust-lang.org/std/ptr/fn.copy_nonoverlapping.html)
-            let (mz, _offset) = _bytes_to_double(peak_start_pos, pe == PeakEncoding::LOW_RES_PEAK);
-
-            // Check if we are in the desired m/z range
-            if mz >= min_mz.unwrap() && mz <= max_mz_threshold {
-                // Increment the number of peaks to read
-                filtered_peaks_count += 1;
-                // Determine the peaks start idx
-                if mz >= min_mz.unwrap() && filtered_peaks_start_idx == 0 {
-                    filtered_peaks_start_idx = peak_start_pos;
-                }
-            }
-            i += 1;
-        }
-    }
-
-    // Create new arrays of primitives
-    let mut mz_array: Vec<f64> = Vec::with_capacity(filtered_peaks_count);
-    let mut intensity_array: Vec<f32> = Vec::with_capacity(filtered_peaks_count);
-
-    let mut lwhm_array: Vec<f32> = if data_mode == FITTED {
-        Vec::with_capacity(filtered_peaks_count)
-    } else {
-        Vec::new()
-    };
-
-    let mut rwhm_array: Vec<f32> = if data_mode == FITTED {
-        Vec::with_capacity(filtered_peaks_count)
-    } else {
-        Vec::new()
-    };
-
-    // Closure for bytes to float conversion
-    let mut float_bytes2 = [0u8; 4];
-    let mut double_bytes2 = [0u8; 8];
-
-    let mut _bytes_to_float = |offset: usize, decode_float: bool| -> (f32, usize) {
-        if decode_float {
-            float_bytes2.clone_from_slice(&bb_bytes[offset..offset + 4]);
-
-            let value = if byte_order == ByteOrder::BIG_ENDIAN {
-                f32::from_be_bytes(float_bytes2)
-            } else {
-                f32::from_le_bytes(float_bytes2)
-            };
-            (value, 4)
-        } else {
-            double_bytes2.clone_from_slice(&bb_bytes[offset..offset + 8]);
-
-            let value = if byte_order == ByteOrder::BIG_ENDIAN {
-                f64::from_be_bytes(double_bytes2) as f32
-            } else {
-                f64::from_le_bytes(double_bytes2) as f32
-            };
-            (value, 8)
-        }
-    };
-
-    let mut peak_idx = 0;
-    while peak_idx < filtered_peaks_count {
-        let peak_bytes_index = filtered_peaks_start_idx + peak_idx * peak_size;
-        let (mz, offset) = _bytes_to_double(peak_bytes_index, pe == PeakEncoding::LOW_RES_PEAK);
-        mz_array.push(mz);
-
-        let (intensity, _offset) = _bytes_to_float(peak_bytes_index + offset, pe != PeakEncoding::NO_LOSS_PEAK);
-        intensity_array.push(intensity);
-
-        // Read left and right HWHMs if needed
-        if data_mode == FITTED {
-            let mz_int_size = pe as usize;
-
-            lwhm_array.push(_bytes_to_float(peak_bytes_index + mz_int_size, true).0);
-            rwhm_array.push(_bytes_to_float(peak_bytes_index + mz_int_size + 4, true).0);
-        }
-
-        peak_idx += 1;
-    }
+    let slice_view = _get_spectrum_slice_view_at(bounding_box, bbox_index, data_encoding, spectrum_slice_idx).location(here!())?;
 
-    let sd = SpectrumData {
-        data_encoding: de.clone(),
-        peak_count: peaks_count,
-        mz_array: mz_array,
-        intensity_array: intensity_array,
-        lwhm_array: lwhm_array,
-        rwhm_array: rwhm_array,
-    };
-
-    Ok(sd)
+    // Instantiate a new SpectrumData for the corresponding spectrum slice
+    Ok(slice_view.to_spectrum_data_in_mz_range(min_mz, max_mz))
 }
 
-pub fn read_spectrum_slice_data_at(
+/// Same as read_spectrum_slice_data_at() but fitted peaks are decoded as centroids, without their HWHMs
+/// (see SpectrumSliceView::to_centroid_data_in_mz_range()).
+pub fn read_spectrum_slice_centroids_at(
     bounding_box: &BoundingBox,
     bbox_index: &BoundingBoxIndex,
     data_encoding: &DataEncoding,
@@ -542,15 +958,36 @@ pub fn read_spectrum_slice_data_at(
     min_mz: Option<f64>,
     max_mz: Option<f64>,
 ) -> Result<SpectrumData> {
+    let slice_view = _get_spectrum_slice_view_at(bounding_box, bbox_index, data_encoding, spectrum_slice_idx).location(here!())?;
+    Ok(slice_view.to_centroid_data_in_mz_range(min_mz, max_mz))
+}
 
-    // Retrieve the number of peaks
-    let peaks_count = bbox_index.peaks_counts[spectrum_slice_idx];
+fn _get_spectrum_slice_view_at<'a>(
+    bounding_box: &'a BoundingBox,
+    bbox_index: &BoundingBoxIndex,
+    data_encoding: &'a DataEncoding,
+    spectrum_slice_idx: usize,
+) -> Result<SpectrumSliceView<'a>> {
+
+    let (spectrum_id, slice_pos, peaks_count) = match (
+        bbox_index.spectra_ids.get(spectrum_slice_idx),
+        bbox_index.slices_indexes.get(spectrum_slice_idx),
+        bbox_index.peaks_counts.get(spectrum_slice_idx),
+    ) {
+        (Some(spectrum_id), Some(slice_pos), Some(peaks_count)) => (*spectrum_id, *slice_pos, *peaks_count),
+        _ => bail!("invalid spectrum slice index {} for bounding box with ID={}", spectrum_slice_idx, bounding_box.id),
+    };
 
-    // Skip spectrum id and peaks count (two integers)
-    let peaks_start_pos = bbox_index.slices_indexes[spectrum_slice_idx] + 8;
+    // Skip spectrum id and peaks count (two integers), the end position being checked for overflows
+    let peaks_bytes = slice_pos.checked_add(8)
+        .and_then(|peaks_start_pos| {
+            let peaks_end_pos = peaks_count.checked_mul(data_encoding.get_peak_size())?.checked_add(peaks_start_pos)?;
+            bounding_box.blob_data.get(peaks_start_pos..peaks_end_pos)
+        })
+        .context(format!("truncated spectrum slice at position {} of bounding box with ID={}", slice_pos, bounding_box.id))
+        .location(here!())?;
 
-    // Instantiate a new SpectrumData for the corresponding spectrum slice
-    read_spectrum_slice_data(&bounding_box.blob_data, peaks_start_pos, peaks_count, data_encoding, min_mz, max_mz)
+    Ok(SpectrumSliceView::new(spectrum_id, peaks_count, slice_pos, data_encoding, peaks_bytes))
 }
 
 // TODO: should be only public for the iterator mod
@@ -583,36 +1020,17 @@ pub fn index_bbox(bbox: &BoundingBox, cache: &DataEncodingsCache) -> Result<Boun
     let mut spectra_ids = Vec::with_capacity(estimated_slice_count);
     let mut peaks_counts = Vec::with_capacity(estimated_slice_count);
 
-    let mut slices_count = 0;
-
-    let blob_data = bbox.blob_data.as_slice();
-    let n_bytes = blob_data.len();
-    let mut int_as_bytes = [0u8; 4];
-
-    let mut bytes_idx = 0;
-    while bytes_idx < n_bytes { // for each spectrum slice store in the blob
-
-        slices_indexes.push(bytes_idx); // store the last byte index
-
-        int_as_bytes.clone_from_slice(&blob_data[bytes_idx..=bytes_idx + 3]);
-        let spectrum_id = _bytes_to_int(&int_as_bytes) as i64;
-        spectra_ids.push(spectrum_id);
-
-        int_as_bytes.clone_from_slice(&blob_data[bytes_idx + 4..=bytes_idx + 7]);
-        let peak_count = _bytes_to_int(&int_as_bytes) as usize;
-        peaks_counts.push(peak_count);
-
-        let de = cache.get_data_encoding_by_spectrum_id(&spectrum_id).ok_or(anyhow!("can't find data encoding")).location(here!())?;
+    for slice_view_res in BlobCursor::new(&bbox.blob_data, cache) {
+        let slice_view = slice_view_res.context(format!("can't index bounding box with ID={}", bbox.id)).location(here!())?;
 
-        let peak_size = de.get_peak_size();
-
-        slices_count += 1;
-        bytes_idx = bytes_idx + 8 + (peak_size * peak_count);
+        slices_indexes.push(slice_view.slice_pos);
+        spectra_ids.push(slice_view.spectrum_id);
+        peaks_counts.push(slice_view.peaks_count);
     }
 
     let indexed_bbox = BoundingBoxIndex {
         bb_id: bbox.id,
-        spectrum_slices_count: slices_count,
+        spectrum_slices_count: spectra_ids.len(),
         spectra_ids: spectra_ids,
         slices_indexes,
         peaks_counts: peaks_counts,
@@ -628,6 +1046,11 @@ pub fn merge_spectrum_slices(sd_slices: &mut Vec<SpectrumData>, peak_count: usiz
 
     let data_mode = data_encoding.mode;
 
+    // A single slice can be returned as is, its arrays being shared
+    if sd_slices.len() == 1 {
+        return Ok(sd_slices.pop().unwrap());
+    }
+
     // Create new vectors of primitives
     let mut mz_array: Vec<f64> = Vec::with_capacity(peak_count);
     let mut intensity_array: Vec<f32> = Vec::with_capacity(peak_count);
@@ -646,45 +1069,264 @@ pub fn merge_spectrum_slices(sd_slices: &mut Vec<SpectrumData>, peak_count: usiz
 
     // Merge vectors
     for sd_slice in sd_slices {
-        mz_array.append(&mut sd_slice.mz_array);
-        intensity_array.append(&mut sd_slice.intensity_array);
+        mz_array.extend_from_slice(&sd_slice.mz_array);
+        intensity_array.extend_from_slice(&sd_slice.intensity_array);
 
         if data_mode == FITTED {
-            lwhm_array.append(&mut sd_slice.lwhm_array);
-            rwhm_array.append(&mut sd_slice.rwhm_array);
+            lwhm_array.extend_from_slice(&sd_slice.lwhm_array);
+            rwhm_array.extend_from_slice(&sd_slice.rwhm_array);
         }
     }
 
     Ok(SpectrumData {
         data_encoding,
         peak_count,
-        mz_array,
-        intensity_array,
-        lwhm_array,
-        rwhm_array,
+        mz_array: mz_array.into(),
+        intensity_array: intensity_array.into(),
+        lwhm_array: lwhm_array.into(),
+        rwhm_array: rwhm_array.into(),
     })
 }
 
-fn _bytes_to_int(bytes: &[u8; 4]) -> i32 {
-    unsafe {
-        std::mem::transmute::<[u8; 4], i32>(*bytes)
+/// Check that the m/z array of a decoded spectrum is sorted, which is expected by binary searches (e.g. SpectrumData::crop()).
+/// Some producers write unsorted m/z arrays: they are sorted when sort_mz_arrays is true,
+/// otherwise an UNSORTED_MZ_ARRAY diagnostic is pushed to the provided sink (or logged as a warning without sink).
+pub fn check_mz_order(spectrum_data: SpectrumData, spectrum_id: i64, sort_mz_arrays: bool, diagnostics: Option<&DiagnosticsSink>) -> SpectrumData {
+    if spectrum_data.is_mz_sorted() {
+        return spectrum_data;
+    }
+
+    if sort_mz_arrays {
+        spectrum_data.sort_by_mz()
+    } else {
+        let message = format!("the m/z array of spectrum with ID={} is not sorted (see EntityCache.sort_mz_arrays)", spectrum_id);
+        match diagnostics {
+            Some(diagnostics) => diagnostics.push(DiagnosticKind::UNSORTED_MZ_ARRAY, message),
+            None => log::warn!("{}", message),
+        }
+        spectrum_data
+    }
+}
+
+/// Compute the summed intensity of each bounding box of a run slice, ordered by first spectrum id.
+/// Intensities are summed while scanning the blobs, no SpectrumData being created (useful for coarse LC-MS overview images).
+pub fn get_run_slice_intensity_profile(db: &Connection, run_slice_id: i64) -> Result<Vec<BoundingBoxIntensity>> {
+    let de_cache = _create_bb_data_encodings_cache(
+        db,
+        format!("bounding_box.run_slice_id = {}", run_slice_id).as_str()
+    ).location(here!())?;
+
+    let mut stmt = db.prepare(
+        format!("SELECT * FROM bounding_box WHERE bounding_box.run_slice_id = {} ORDER BY first_spectrum_id", run_slice_id).as_str()
+    ).location(here!())?;
+
+    let mut bb_intensities = Vec::new();
+    let mut rows = stmt.query([]).location(here!())?;
+    while let Some(row) = rows.next().location(here!())? {
+        let bb = create_bbox(row).location(here!())?;
+        let bb_index = index_bbox(&bb, &de_cache).location(here!())?;
+
+        let mut intensity_sum = 0.0;
+        for (spectrum_slice_idx, spectrum_id) in bb_index.spectra_ids.iter().enumerate() {
+            let data_encoding = de_cache.get_data_encoding_by_spectrum_id(spectrum_id)
+                .context(format!("can't retrieve data encoding for spectrum ID={}", spectrum_id)).location(here!())?;
+
+            // Skip spectrum id and peaks count (two integers)
+            let peaks_start_pos = bb_index.slices_indexes[spectrum_slice_idx] + 8;
+
+            intensity_sum += _sum_spectrum_slice_intensities(
+                &bb.blob_data,
+                peaks_start_pos,
+                bb_index.peaks_counts[spectrum_slice_idx],
+                data_encoding
+            ).context(format!("can't sum intensities of spectrum ID={} in bounding box with ID={}", spectrum_id, bb.id)).location(here!())?;
+        }
+
+        bb_intensities.push(BoundingBoxIntensity {
+            bb_id: bb.id,
+            first_spectrum_id: bb.first_spectrum_id,
+            last_spectrum_id: bb.last_spectrum_id,
+            intensity_sum,
+        });
     }
+
+    Ok(bb_intensities)
+}
+
+fn _sum_spectrum_slice_intensities(bb_bytes: &[u8], peaks_start_pos: usize, peaks_count: usize, de: &DataEncoding) -> Result<f64> {
+    let pe = de.peak_encoding;
+    let big_endian = de.byte_order == ByteOrder::BIG_ENDIAN;
+    let peak_size = de.get_peak_size();
+
+    let peaks_end_pos = peaks_count.checked_mul(peak_size).and_then(|peaks_size| peaks_start_pos.checked_add(peaks_size));
+    if peaks_end_pos.is_none_or(|end_pos| end_pos > bb_bytes.len()) {
+        bail!(
+            "{} peaks starting at position {} exceed the bounding box blob size ({} bytes)",
+            peaks_count, peaks_start_pos, bb_bytes.len()
+        );
+    }
+
+    // Intensities are stored right after the m/z values
+    let intensity_offset = if pe == PeakEncoding::LOW_RES_PEAK { 4 } else { 8 };
+
+    let mut intensity_sum = 0.0;
+    for peak_idx in 0..peaks_count {
+        let intensity_pos = peaks_start_pos + peak_idx * peak_size + intensity_offset;
+
+        let intensity = if pe == PeakEncoding::NO_LOSS_PEAK {
+            let double_bytes: [u8; 8] = bb_bytes[intensity_pos..intensity_pos + 8].try_into().unwrap();
+            if big_endian { f64::from_be_bytes(double_bytes) } else { f64::from_le_bytes(double_bytes) }
+        } else {
+            let float_bytes: [u8; 4] = bb_bytes[intensity_pos..intensity_pos + 4].try_into().unwrap();
+            (if big_endian { f32::from_be_bytes(float_bytes) } else { f32::from_le_bytes(float_bytes) }) as f64
+        };
+
+        intensity_sum += intensity;
+    }
+
+    Ok(intensity_sum)
 }
 
 pub fn get_spectrum(db: &Connection, spectrum_id: i64, entity_cache: &EntityCache) -> Result<Spectrum> {
     let spectrum_header = entity_cache.spectrum_headers.get((spectrum_id - 1) as usize)
         .context(format!("can't retrieve spectrum with ID={}", spectrum_id)).location(here!())?;
 
-    let bb_first_spec_id_opt = get_first_int(
-        &db,
-        format!("SELECT bb_first_spectrum_id FROM spectrum WHERE id = {}", spectrum_id).as_str(),
+    _get_spectrum(db, spectrum_header, entity_cache)
+}
+
+/// Retrieve the spectrum of the provided MS level whose time is the nearest to rt (in seconds), returns None if there is no such spectrum
+pub fn get_spectrum_at(db: &Connection, rt: f64, ms_level: i64, entity_cache: &EntityCache) -> Result<Option<Spectrum>> {
+    let header_opt = _find_nearest_spectrum_header(entity_cache, rt, |sh| sh.ms_level == ms_level);
+
+    match header_opt {
+        Some(spectrum_header) => Ok(Some(_get_spectrum(db, spectrum_header, entity_cache).location(here!())?)),
+        None => Ok(None),
+    }
+}
+
+/// Retrieve the MS2 spectrum whose precursor m/z matches precursor_mz (+/- tolerance) and whose time is the nearest to rt (in seconds).
+/// Returns None if no MS2 spectrum matches the precursor m/z.
+pub fn get_ms2_spectrum_at(
+    db: &Connection,
+    rt: f64,
+    precursor_mz: f64,
+    mz_tolerance: &MzTolerance,
+    entity_cache: &EntityCache,
+) -> Result<Option<Spectrum>> {
+    let (min_mz, max_mz) = mz_tolerance.mz_range(precursor_mz);
+
+    let header_opt = _find_nearest_spectrum_header(entity_cache, rt, |sh| {
+        sh.ms_level == 2 && sh.precursor_mz.is_some_and(|mz| mz >= min_mz && mz <= max_mz)
+    });
+
+    match header_opt {
+        Some(spectrum_header) => Ok(Some(_get_spectrum(db, spectrum_header, entity_cache).location(here!())?)),
+        None => Ok(None),
+    }
+}
+
+fn _find_nearest_spectrum_header<F>(entity_cache: &EntityCache, rt: f64, filter: F) -> Option<&SpectrumHeader> where F: Fn(&SpectrumHeader) -> bool {
+    entity_cache.spectrum_headers.iter()
+        .filter(|sh| filter(sh))
+        .min_by(|sh1, sh2| (sh1.time_f64 - rt).abs().total_cmp(&(sh2.time_f64 - rt).abs()))
+}
+
+/// Retrieve a spectrum without an EntityCache, by loading only the header and the data encodings it requires.
+/// Intended for tools accessing a handful of spectra, for which the EntityCache creation time is not worth it.
+pub fn get_spectrum_uncached(db: &Connection, spectrum_id: i64) -> Result<Spectrum> {
+    get_spectrum_uncached_from_table(db, "spectrum", spectrum_id)
+}
+
+/// Same as get_spectrum_uncached() but the spectrum headers are read from the provided table (see mzdb::get_spectrum_header_from_table())
+pub(crate) fn get_spectrum_uncached_from_table(db: &Connection, spectrum_table: &str, spectrum_id: i64) -> Result<Spectrum> {
+    let spectrum_header = get_spectrum_header_from_table(db, spectrum_table, spectrum_id).location(here!())?
+        .context(format!("can't retrieve spectrum with ID={}", spectrum_id)).location(here!())?;
+
+    let de_cache = _create_bb_data_encodings_cache_from_table(
+        db,
+        spectrum_table,
+        format!("bounding_box.first_spectrum_id = {}", spectrum_header.bb_first_spectrum_id).as_str()
+    ).location(here!())?;
+
+    _get_spectrum_with_profile(db, &spectrum_header, &de_cache, false, false, None, None)
+}
+
+/// Create a DataEncodingsCache restricted to the spectra stored in the bounding boxes matching the provided SQL condition
+fn _create_bb_data_encodings_cache(db: &Connection, bb_condition: &str) -> Result<DataEncodingsCache> {
+    _create_bb_data_encodings_cache_from_table(db, "spectrum", bb_condition)
+}
+
+fn _create_bb_data_encodings_cache_from_table(db: &Connection, spectrum_table: &str, bb_condition: &str) -> Result<DataEncodingsCache> {
+    let mut data_encoding_by_id = HashMap::new();
+    for de in list_data_encodings(db).location(here!())? {
+        data_encoding_by_id.insert(de.id, de);
+    }
+
+    let mut stmt = db.prepare(
+        format!(
+            "SELECT id, data_encoding_id FROM {} \
+            WHERE id >= (SELECT min(first_spectrum_id) FROM bounding_box WHERE {}) \
+            AND id <= (SELECT max(last_spectrum_id) FROM bounding_box WHERE {})",
+            spectrum_table, bb_condition, bb_condition
+        ).as_str()
     ).location(here!())?;
+    let mut rows = stmt.query([]).location(here!())?;
 
-    if bb_first_spec_id_opt.is_none() {
-        bail!("can't get bb_first_spectrum_id for spectrum with ID = {}", spectrum_id);
+    let mut spectra_data_encoding_ids = HashMap::new();
+    while let Some(row) = rows.next().location(here!())? {
+        let id: i64 = row.get(0).location(here!())?;
+        let data_encoding_id: i64 = row.get(1).location(here!())?;
+        spectra_data_encoding_ids.insert(id, data_encoding_id);
     }
 
-    let bb_first_spec_id = bb_first_spec_id_opt.unwrap();
+    Ok(DataEncodingsCache::new(data_encoding_by_id, spectra_data_encoding_ids))
+}
+
+/// Retrieve a spectrum while measuring how many bounding boxes are touched and how long their loading and decoding take.
+/// This is a debugging aid for spectra which are abnormally slow to decode (usually due to tiny BB RT widths).
+pub fn profile_spectrum(db: &Connection, spectrum_id: i64, entity_cache: &EntityCache) -> Result<(Spectrum, SpectrumProfile)> {
+    let spectrum_header = entity_cache.spectrum_headers.get((spectrum_id - 1) as usize)
+        .context(format!("can't retrieve spectrum with ID={}", spectrum_id)).location(here!())?;
+
+    let mut profile = SpectrumProfile {
+        spectrum_id,
+        bb_count: 0,
+        bb_bytes_read: 0,
+        bb_row_spectra_count: 0,
+        slices_count: 0,
+        non_empty_slices_count: 0,
+        peaks_count: 0,
+        load_duration: Duration::ZERO,
+        decode_duration: Duration::ZERO,
+    };
+
+    let spectrum = _get_spectrum_with_profile(
+        db, spectrum_header, &entity_cache.data_encodings_cache, entity_cache.sort_mz_arrays, entity_cache.fitted_as_centroid,
+        Some(&entity_cache.diagnostics), Some(&mut profile)
+    ).location(here!())?;
+
+    Ok((spectrum, profile))
+}
+
+fn _get_spectrum(db: &Connection, spectrum_header: &SpectrumHeader, entity_cache: &EntityCache) -> Result<Spectrum> {
+    _get_spectrum_with_profile(
+        db, spectrum_header, &entity_cache.data_encodings_cache, entity_cache.sort_mz_arrays, entity_cache.fitted_as_centroid,
+        Some(&entity_cache.diagnostics), None
+    )
+}
+
+fn _get_spectrum_with_profile(
+    db: &Connection,
+    spectrum_header: &SpectrumHeader,
+    de_cache: &DataEncodingsCache,
+    sort_mz_arrays: bool,
+    fitted_as_centroid: bool,
+    diagnostics: Option<&DiagnosticsSink>,
+    mut profile_opt: Option<&mut SpectrumProfile>,
+) -> Result<Spectrum> {
+    let load_start_time = Instant::now();
+    let spectrum_id = spectrum_header.id;
+    let bb_first_spec_id = spectrum_header.bb_first_spectrum_id;
 
     // Count the number of BBs to be loaded
     let bb_count_opt = get_first_int(
@@ -703,8 +1345,6 @@ pub fn get_spectrum(db: &Connection, spectrum_id: i64, entity_cache: &EntityCach
         format!("SELECT * FROM bounding_box WHERE bounding_box.first_spectrum_id = {}", bb_first_spec_id).as_str()
     ).location(here!())?;
 
-    let de_cache = &entity_cache.data_encodings_cache;
-
     // Determine peak size in bytes
     let de_opt = de_cache.get_data_encoding_by_spectrum_id(&spectrum_id);
     if de_opt.is_none() {
@@ -712,6 +1352,7 @@ pub fn get_spectrum(db: &Connection, spectrum_id: i64, entity_cache: &EntityCach
     }
 
     let data_encoding = de_opt.unwrap();
+    let read_slice_data = if fitted_as_centroid { read_spectrum_slice_centroids_at } else { read_spectrum_slice_data_at };
 
     // for each bounding box, will collect the data of the spectrum
     let mut target_slice_idx: Option<usize> = None;
@@ -720,10 +1361,19 @@ pub fn get_spectrum(db: &Connection, spectrum_id: i64, entity_cache: &EntityCach
     //let mut cur_bb: Vec<BoundingBox> = Vec::new();
     // Select the information in bouding box for one spectrum id
     let mut rows = stmt.query([])?;
+    let mut step_start_time = load_start_time;
     while let Some(row) = rows.next().location(here!())? {
         // put the information of each bouding box in the struc of bouding box
         let cur_bb = create_bbox(row).location(here!())?;
 
+        if let Some(profile) = profile_opt.as_deref_mut() {
+            profile.load_duration += step_start_time.elapsed();
+            step_start_time = Instant::now();
+            profile.bb_count += 1;
+            profile.bb_bytes_read += cur_bb.blob_data.len();
+            profile.bb_row_spectra_count = (cur_bb.last_spectrum_id - cur_bb.first_spectrum_id + 1) as usize;
+        }
+
         let bb_index = index_bbox(&cur_bb, de_cache).location(here!())?;
 
         if target_slice_idx == None {
@@ -737,7 +1387,7 @@ pub fn get_spectrum(db: &Connection, spectrum_id: i64, entity_cache: &EntityCach
             bail!("can't find slice index for spectrum with ID={} in bounding box with ID={}",spectrum_id, cur_bb.id);
         }
 
-        let spectrum_slice_data = read_spectrum_slice_data_at(
+        let spectrum_slice_data = read_slice_data(
             &cur_bb,
             &bb_index,
             data_encoding,
@@ -746,11 +1396,29 @@ pub fn get_spectrum(db: &Connection, spectrum_id: i64, entity_cache: &EntityCach
             None,
         ).location(here!())?;
 
+        if let Some(profile) = profile_opt.as_deref_mut() {
+            profile.slices_count += bb_index.spectra_ids.len();
+            profile.non_empty_slices_count += if spectrum_slice_data.peak_count > 0 { 1 } else { 0 };
+            profile.decode_duration += step_start_time.elapsed();
+            step_start_time = Instant::now();
+        }
+
         sd_slices.push(spectrum_slice_data);
     }
 
+    if let Some(profile) = profile_opt.as_deref_mut() {
+        profile.load_duration += step_start_time.elapsed();
+        step_start_time = Instant::now();
+    }
+
     let peak_count = sd_slices.iter().map(|slice| slice.peak_count).sum(); // .copied()
     let spectrum_data = merge_spectrum_slices(&mut sd_slices, peak_count).location(here!())?;
+    let spectrum_data = check_mz_order(spectrum_data, spectrum_id, sort_mz_arrays, diagnostics);
+
+    if let Some(profile) = profile_opt {
+        profile.peaks_count = peak_count;
+        profile.decode_duration += step_start_time.elapsed();
+    }
 
     Ok(Spectrum {
         header: spectrum_header.clone(),
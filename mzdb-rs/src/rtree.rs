@@ -0,0 +1,184 @@
+// Queries of the bounding box R-trees: bounding_box_rtree indexes the MS1 bounding boxes,
+// while bounding_box_msn_rtree indexes the MSn ones (and their parent m/z range, needed for DIA files).
+// query_region_auto() selects the right R-tree so that callers don't have to know which one to use.
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::model::{BoundingBox, EntityCache};
+use crate::queries::create_bbox;
+
+const SQLQUERY_MS1_RTREE_REGION: &str = "SELECT id, min_mz, max_mz, min_time, max_time FROM bounding_box_rtree \
+    WHERE min_mz <= ? AND max_mz >= ? AND min_time <= ? AND max_time >= ?";
+
+// R-tree tables are virtual tables, which never have a sqlite_sequence entry
+const SQLQUERY_MSN_RTREE_IS_POPULATED: &str = "SELECT EXISTS(SELECT 1 FROM bounding_box_msn_rtree)";
+
+const SQLQUERY_MSN_RTREE_REGION: &str = "SELECT id, min_ms_level, max_ms_level, min_parent_mz, max_parent_mz, min_mz, max_mz, min_time, max_time \
+    FROM bounding_box_msn_rtree \
+    WHERE min_ms_level <= ? AND max_ms_level >= ? AND min_parent_mz <= ? AND max_parent_mz >= ? \
+    AND min_mz <= ? AND max_mz >= ? AND min_time <= ? AND max_time >= ?";
+
+/// An m/z x time region (times in seconds, bounds are inclusive)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RtreeRegion {
+    pub min_mz: f64,
+    pub max_mz: f64,
+    pub min_time: f64,
+    pub max_time: f64,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RtreeEntry {
+    MS1 {
+        bb_id: i64,
+        region: RtreeRegion,
+    },
+    MSN {
+        bb_id: i64,
+        min_ms_level: i64,
+        max_ms_level: i64,
+        min_parent_mz: f64,
+        max_parent_mz: f64,
+        region: RtreeRegion,
+    },
+}
+
+impl RtreeEntry {
+    pub fn bb_id(&self) -> i64 {
+        match self {
+            RtreeEntry::MS1 { bb_id, .. } => *bb_id,
+            RtreeEntry::MSN { bb_id, .. } => *bb_id,
+        }
+    }
+
+    pub fn region(&self) -> &RtreeRegion {
+        match self {
+            RtreeEntry::MS1 { region, .. } => region,
+            RtreeEntry::MSN { region, .. } => region,
+        }
+    }
+}
+
+/// List the R-tree entries of the bounding boxes of the provided MS level intersecting the region, sorted by bounding box ID.
+/// bounding_box_rtree is queried for MS1, bounding_box_msn_rtree otherwise: its entries can then be restricted
+/// to the bounding boxes whose parent m/z range contains parent_mz_opt (ignored for MS1).
+/// Times of both the region and the returned entries are converted with the EntityCache.time_factor and rt_offset,
+/// consistently with the times of the spectrum headers (see EntityCache::to_header_time()).
+/// The time bounds of the region are widened by the f32 precision (see widen_time_bounds_for_f32()), so that entries ending
+/// (or starting) within one f32 ULP of the region may be returned.
+/// An error is returned for MSn levels if bounding_box_msn_rtree is empty (see ProducerQuirk::EMPTY_MSN_RTREE).
+pub fn query_region_auto(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    ms_level: u8,
+    region: &RtreeRegion,
+    parent_mz_opt: Option<f64>,
+) -> Result<Vec<RtreeEntry>> {
+    if ms_level == 0 {
+        bail!("invalid MS level {}", ms_level);
+    }
+
+    let (min_time, max_time) = widen_time_bounds_for_f32(
+        entity_cache.to_stored_time(region.min_time),
+        entity_cache.to_stored_time(region.max_time),
+    );
+
+    let mut entries = Vec::new();
+
+    if ms_level == 1 {
+        let mut stmt = db.prepare(SQLQUERY_MS1_RTREE_REGION).location(here!())?;
+        let mut rows = stmt.query(rusqlite::params![region.max_mz, region.min_mz, max_time, min_time]).location(here!())?;
+
+        while let Some(row) = rows.next().location(here!())? {
+            entries.push(RtreeEntry::MS1 {
+                bb_id: row.get(0).location(here!())?,
+                region: RtreeRegion {
+                    min_mz: row.get(1).location(here!())?,
+                    max_mz: row.get(2).location(here!())?,
+                    min_time: entity_cache.to_header_time(row.get(3).location(here!())?),
+                    max_time: entity_cache.to_header_time(row.get(4).location(here!())?),
+                },
+            });
+        }
+    } else {
+        if !is_msn_rtree_populated(db).location(here!())? {
+            bail!("can't query the MS{} bounding boxes: bounding_box_msn_rtree is empty", ms_level);
+        }
+
+        let (min_parent_mz, max_parent_mz) = parent_mz_opt.map_or((f64::MIN, f64::MAX), |parent_mz| (parent_mz, parent_mz));
+
+        let mut stmt = db.prepare(SQLQUERY_MSN_RTREE_REGION).location(here!())?;
+        let mut rows = stmt.query(rusqlite::params![
+            ms_level, ms_level, max_parent_mz, min_parent_mz, region.max_mz, region.min_mz, max_time, min_time
+        ]).location(here!())?;
+
+        while let Some(row) = rows.next().location(here!())? {
+            // MS levels are stored as REAL values by the R-tree
+            entries.push(RtreeEntry::MSN {
+                bb_id: row.get(0).location(here!())?,
+                min_ms_level: row.get::<_, f64>(1).location(here!())? as i64,
+                max_ms_level: row.get::<_, f64>(2).location(here!())? as i64,
+                min_parent_mz: row.get(3).location(here!())?,
+                max_parent_mz: row.get(4).location(here!())?,
+                region: RtreeRegion {
+                    min_mz: row.get(5).location(here!())?,
+                    max_mz: row.get(6).location(here!())?,
+                    min_time: entity_cache.to_header_time(row.get(7).location(here!())?),
+                    max_time: entity_cache.to_header_time(row.get(8).location(here!())?),
+                },
+            });
+        }
+    }
+
+    entries.sort_unstable_by_key(|entry| entry.bb_id());
+
+    Ok(entries)
+}
+
+/// Returns true if bounding_box_msn_rtree has at least one entry (it is left empty by some writers, see ProducerQuirk::EMPTY_MSN_RTREE)
+pub fn is_msn_rtree_populated(db: &Connection) -> Result<bool> {
+    db.query_row(SQLQUERY_MSN_RTREE_IS_POPULATED, [], |row| row.get(0)).location(here!())
+}
+
+/// Load the bounding boxes selected by query_region_auto() and pass them to the provided function, sorted by ID
+pub fn for_each_bb_in_region<F>(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    ms_level: u8,
+    region: &RtreeRegion,
+    parent_mz_opt: Option<f64>,
+    mut on_each_bb: F,
+) -> Result<()> where F: FnMut(BoundingBox) -> Result<()> {
+    let entries = query_region_auto(db, entity_cache, ms_level, region, parent_mz_opt).location(here!())?;
+
+    let mut stmt = db.prepare("SELECT * FROM bounding_box WHERE id = ?").location(here!())?;
+    for entry in entries.iter() {
+        let mut rows = stmt.query([entry.bb_id()]).location(here!())?;
+        let row = rows.next().location(here!())?
+            .context(format!("can't retrieve bounding box with ID={} indexed by the R-tree", entry.bb_id())).location(here!())?;
+
+        on_each_bb(create_bbox(row).location(here!())?).location(here!())?;
+    }
+
+    Ok(())
+}
+
+/// Widen time bounds (in the stored unit) by one f32 ULP at their magnitude. The R-tree coordinates are stored as 32-bit floats,
+/// and some writers compute them from the f32 spectrum times, so that they may differ from the f64 time of the spectrum
+/// at the 7th digit: without this margin, the bounding boxes of the spectra lying on the bounds of a time window could be missed.
+/// Callers needing exact bounds have to filter the spectra on their header time afterwards.
+pub fn widen_time_bounds_for_f32(min_time: f64, max_time: f64) -> (f64, f64) {
+    (min_time - _f32_ulp(min_time), max_time + _f32_ulp(max_time))
+}
+
+fn _f32_ulp(value: f64) -> f64 {
+    let value_f32 = value.abs() as f32;
+    let ulp = (f32::from_bits(value_f32.to_bits() + 1) - value_f32) as f64;
+
+    // no margin for unbounded values (e.g. f64::MIN/f64::MAX used as open bounds)
+    if ulp.is_finite() { ulp } else { 0.0 }
+}
@@ -0,0 +1,207 @@
+// Reference layout of the tables of a mzDB 0.7 file, and comparison of a file against it.
+// Declared column types are compared after reduction to their SQLite affinity (e.g. INT and INTEGER, or TEXT(10) and TEXT, are equivalent).
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColumnType {
+    INTEGER,
+    REAL,
+    TEXT,
+    BLOB,
+    NUMERIC,
+    ANY, // no declared type is expected
+}
+
+impl ColumnType {
+    /// Reduce a declared column type to its SQLite affinity (see https://www.sqlite.org/datatype3.html#determination_of_column_affinity)
+    pub fn from_declared_type(declared_type: &str) -> ColumnType {
+        let upper_type = declared_type.to_uppercase();
+
+        if upper_type.contains("INT") {
+            ColumnType::INTEGER
+        } else if upper_type.contains("CHAR") || upper_type.contains("CLOB") || upper_type.contains("TEXT") {
+            ColumnType::TEXT
+        } else if upper_type.contains("BLOB") || upper_type.trim().is_empty() {
+            ColumnType::BLOB
+        } else if upper_type.contains("REAL") || upper_type.contains("FLOA") || upper_type.contains("DOUB") {
+            ColumnType::REAL
+        } else {
+            ColumnType::NUMERIC
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TableSchema {
+    pub name: &'static str,
+    pub columns: &'static [(&'static str, ColumnType)],
+}
+
+use ColumnType::*;
+
+/// The tables which must be found in a mzDB 0.7 file, with their columns
+pub const REQUIRED_TABLES: &[TableSchema] = &[
+    TableSchema { name: "bounding_box", columns: &[
+        ("id", INTEGER), ("data", BLOB), ("run_slice_id", INTEGER), ("first_spectrum_id", INTEGER), ("last_spectrum_id", INTEGER),
+    ] },
+    TableSchema { name: "bounding_box_msn_rtree", columns: &[
+        ("id", INTEGER), ("min_ms_level", REAL), ("max_ms_level", REAL), ("min_parent_mz", REAL), ("max_parent_mz", REAL),
+        ("min_mz", REAL), ("max_mz", REAL), ("min_time", REAL), ("max_time", REAL),
+    ] },
+    TableSchema { name: "bounding_box_rtree", columns: &[
+        ("id", INTEGER), ("min_mz", REAL), ("max_mz", REAL), ("min_time", REAL), ("max_time", REAL),
+    ] },
+    TableSchema { name: "chromatogram", columns: &[
+        ("id", INTEGER), ("name", TEXT), ("activation_type", TEXT), ("data_points", BLOB), ("param_tree", TEXT), ("precursor", TEXT),
+        ("product", TEXT), ("shared_param_tree_id", INTEGER), ("run_id", INTEGER), ("data_processing_id", INTEGER), ("data_encoding_id", INTEGER),
+    ] },
+    TableSchema { name: "cv", columns: &[
+        ("id", TEXT), ("full_name", TEXT), ("version", TEXT), ("uri", TEXT),
+    ] },
+    TableSchema { name: "cv_term", columns: &[
+        ("accession", TEXT), ("name", TEXT), ("unit_accession", TEXT), ("cv_id", TEXT),
+    ] },
+    TableSchema { name: "cv_unit", columns: &[
+        ("accession", TEXT), ("name", TEXT), ("cv_id", TEXT),
+    ] },
+    TableSchema { name: "data_encoding", columns: &[
+        ("id", INTEGER), ("mode", TEXT), ("compression", TEXT), ("byte_order", TEXT), ("mz_precision", INTEGER),
+        ("intensity_precision", INTEGER), ("param_tree", TEXT),
+    ] },
+    TableSchema { name: "data_processing", columns: &[
+        ("id", INTEGER), ("name", TEXT),
+    ] },
+    TableSchema { name: "instrument_configuration", columns: &[
+        ("id", INTEGER), ("name", TEXT), ("param_tree", TEXT), ("component_list", TEXT), ("shared_param_tree_id", INTEGER), ("software_id", INTEGER),
+    ] },
+    TableSchema { name: "mzdb", columns: &[
+        ("version", TEXT), ("creation_timestamp", TEXT), ("file_content", TEXT), ("contacts", TEXT), ("param_tree", TEXT),
+    ] },
+    TableSchema { name: "param_tree_schema", columns: &[
+        ("name", TEXT), ("type", TEXT), ("schema", TEXT),
+    ] },
+    TableSchema { name: "processing_method", columns: &[
+        ("id", INTEGER), ("number", INTEGER), ("param_tree", TEXT), ("shared_param_tree_id", INTEGER), ("data_processing_id", INTEGER), ("software_id", INTEGER),
+    ] },
+    TableSchema { name: "run", columns: &[
+        ("id", INTEGER), ("name", TEXT), ("start_timestamp", TEXT), ("param_tree", TEXT), ("shared_param_tree_id", INTEGER), ("sample_id", INTEGER),
+        ("default_instrument_config_id", INTEGER), ("default_source_file_id", INTEGER), ("default_scan_processing_id", INTEGER),
+        ("default_chrom_processing_id", INTEGER),
+    ] },
+    TableSchema { name: "run_slice", columns: &[
+        ("id", INTEGER), ("ms_level", INTEGER), ("number", INTEGER), ("begin_mz", REAL), ("end_mz", REAL), ("param_tree", TEXT), ("run_id", INTEGER),
+    ] },
+    TableSchema { name: "sample", columns: &[
+        ("id", INTEGER), ("name", TEXT), ("param_tree", TEXT), ("shared_param_tree_id", INTEGER),
+    ] },
+    TableSchema { name: "scan_settings", columns: &[
+        ("id", INTEGER), ("param_tree", ANY), ("shared_param_tree_id", INTEGER),
+    ] },
+    TableSchema { name: "shared_param_tree", columns: &[
+        ("id", INTEGER), ("data", TEXT), ("schema_name", TEXT),
+    ] },
+    TableSchema { name: "software", columns: &[
+        ("id", INTEGER), ("name", TEXT), ("version", TEXT), ("param_tree", TEXT), ("shared_param_tree_id", INTEGER),
+    ] },
+    TableSchema { name: "source_file", columns: &[
+        ("id", INTEGER), ("name", TEXT), ("location", TEXT), ("param_tree", TEXT), ("shared_param_tree_id", INTEGER),
+    ] },
+    TableSchema { name: "source_file_scan_settings_map", columns: &[
+        ("scan_settings_id", INTEGER), ("source_file_id", INTEGER),
+    ] },
+    TableSchema { name: "spectrum", columns: &[
+        ("id", INTEGER), ("initial_id", INTEGER), ("title", TEXT), ("cycle", INTEGER), ("time", REAL), ("ms_level", INTEGER),
+        ("activation_type", TEXT), ("tic", REAL), ("base_peak_mz", REAL), ("base_peak_intensity", REAL), ("main_precursor_mz", REAL),
+        ("main_precursor_charge", INTEGER), ("data_points_count", INTEGER), ("param_tree", TEXT), ("scan_list", TEXT),
+        ("precursor_list", TEXT), ("product_list", TEXT), ("shared_param_tree_id", INTEGER), ("instrument_configuration_id", INTEGER),
+        ("source_file_id", INTEGER), ("run_id", INTEGER), ("data_processing_id", INTEGER), ("data_encoding_id", INTEGER),
+        ("bb_first_spectrum_id", INTEGER),
+    ] },
+    TableSchema { name: "table_param_tree_schema", columns: &[
+        ("table_name", TEXT), ("schema_name", TEXT),
+    ] },
+    TableSchema { name: "target", columns: &[
+        ("id", INTEGER), ("param_tree", TEXT), ("shared_param_tree_id", INTEGER), ("scan_settings_id", INTEGER),
+    ] },
+    TableSchema { name: "user_term", columns: &[
+        ("id", INTEGER), ("name", TEXT), ("type", TEXT), ("unit_accession", TEXT),
+    ] },
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnTypeMismatch {
+    pub table_name: String,
+    pub column_name: String,
+    pub expected_type: ColumnType,
+    pub declared_type: String,
+}
+
+/// Differences between the tables of a file and REQUIRED_TABLES (the columns of missing tables are not reported).
+/// Tables which are not required (e.g. the caches added by the maintenance functions) are ignored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SchemaDiff {
+    pub missing_tables: Vec<String>,
+    pub missing_columns: Vec<(String, String)>, // (table name, column name)
+    pub extra_columns: Vec<(String, String)>,   // (table name, column name)
+    pub type_mismatches: Vec<ColumnTypeMismatch>,
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing_tables.is_empty() && self.missing_columns.is_empty() && self.extra_columns.is_empty() && self.type_mismatches.is_empty()
+    }
+}
+
+/// Compare the tables of a file against REQUIRED_TABLES
+pub fn schema_diff(db: &Connection) -> Result<SchemaDiff> {
+    let mut diff = SchemaDiff::default();
+
+    for table_schema in REQUIRED_TABLES {
+        let declared_columns = _list_declared_columns(db, table_schema.name).location(here!())?;
+        if declared_columns.is_empty() {
+            diff.missing_tables.push(table_schema.name.to_string());
+            continue;
+        }
+
+        for (column_name, expected_type) in table_schema.columns.iter() {
+            let declared_type = match declared_columns.iter().find(|(name, _)| name == column_name) {
+                Some((_, declared_type)) => declared_type,
+                None => {
+                    diff.missing_columns.push((table_schema.name.to_string(), column_name.to_string()));
+                    continue;
+                }
+            };
+
+            if *expected_type != ColumnType::ANY && ColumnType::from_declared_type(declared_type) != *expected_type {
+                diff.type_mismatches.push(ColumnTypeMismatch {
+                    table_name: table_schema.name.to_string(),
+                    column_name: column_name.to_string(),
+                    expected_type: *expected_type,
+                    declared_type: declared_type.clone(),
+                });
+            }
+        }
+
+        for (column_name, _) in declared_columns.iter() {
+            if !table_schema.columns.iter().any(|(name, _)| name == column_name) {
+                diff.extra_columns.push((table_schema.name.to_string(), column_name.clone()));
+            }
+        }
+    }
+
+    Ok(diff)
+}
+
+// Returns the (name, declared type) pairs of the columns of a table, empty if the table doesn't exist
+fn _list_declared_columns(db: &Connection, table_name: &str) -> Result<Vec<(String, String)>> {
+    let mut stmt = db.prepare(format!("PRAGMA table_info({:?})", table_name).as_str()).location(here!())?;
+    let columns = stmt.query_map([], |row| rusqlite::Result::Ok((row.get(1)?, row.get(2)?))).location(here!())?
+        .collect::<rusqlite::Result<Vec<(String, String)>>>().location(here!())?;
+
+    Ok(columns)
+}
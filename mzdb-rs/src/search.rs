@@ -0,0 +1,201 @@
+// Search of spectra by peak content, performed at the bounding box level to avoid building full spectra,
+// and by header text, using the optional full-text index built by maintenance::build_fts_index.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::blob_cursor::BlobCursor;
+use crate::iterator::for_each_bb;
+use crate::mass::{neutral_mass_to_mz, ppm_to_da};
+use crate::model::*;
+use crate::queries::*;
+use crate::rtree::{for_each_bb_in_region, is_msn_rtree_populated, RtreeRegion};
+
+/// Find the MS2 spectra containing a fragment peak matching the provided m/z (+/- tolerance).
+/// The intensity of the matching peak must be at least min_intensity_rel times the base peak intensity of the spectrum.
+/// Bounding boxes are selected using the MSn R-tree when it is populated, otherwise all the MS2 bounding boxes are scanned.
+/// Returns the matching spectrum ids sorted in ascending order.
+pub fn find_ms2_with_fragment(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    mz: f64,
+    mz_tolerance: &MzTolerance,
+    min_intensity_rel: f32,
+) -> Result<Vec<i64>> {
+    let (min_mz, max_mz) = mz_tolerance.mz_range(mz);
+
+    let mut matching_spectrum_ids = Vec::new();
+
+    let on_each_bb = |bb: BoundingBox| -> Result<()> {
+        _search_fragment_in_bb(&bb, entity_cache, min_mz, max_mz, min_intensity_rel, &mut matching_spectrum_ids).location(here!())
+    };
+
+    if is_msn_rtree_populated(db).location(here!())? {
+        let region = RtreeRegion { min_mz, max_mz, min_time: f64::MIN, max_time: f64::MAX };
+        for_each_bb_in_region(db, entity_cache, 2, &region, None, on_each_bb).location(here!())?;
+    } else {
+        for_each_bb(db, Some(2), on_each_bb).location(here!())?;
+    }
+
+    matching_spectrum_ids.sort_unstable();
+    matching_spectrum_ids.dedup();
+
+    Ok(matching_spectrum_ids)
+}
+
+/// Find the MS2 spectra whose precursor matches the provided neutral mass (+/- tol_ppm) for one of the provided charge states.
+/// The neutral mass is converted to the m/z of each charge state, negative charges being used for negative ions.
+/// Spectra having a known precursor charge are only matched for this charge, while the others are matched for all charges.
+/// Returns the matching spectrum ids (sorted in ascending order) grouped by charge state.
+pub fn find_ms2_by_neutral_mass(
+    entity_cache: &EntityCache,
+    neutral_mass: f64,
+    tol_ppm: f64,
+    charges: &[i32],
+) -> Result<BTreeMap<i32, Vec<i64>>> {
+    let mass_tol = ppm_to_da(neutral_mass, tol_ppm);
+
+    let mut spectrum_ids_by_charge = BTreeMap::new();
+    for &charge in charges {
+        if charge == 0 {
+            bail!("invalid charge state 0");
+        }
+
+        let min_mz = neutral_mass_to_mz(neutral_mass - mass_tol, charge);
+        let max_mz = neutral_mass_to_mz(neutral_mass + mass_tol, charge);
+
+        let spectrum_ids: Vec<i64> = entity_cache.spectrum_headers.iter()
+            .filter(|sh| sh.ms_level == 2)
+            .filter(|sh| sh.precursor_charge.is_none_or(|precursor_charge| precursor_charge.abs() == charge.abs()))
+            .filter(|sh| sh.precursor_mz.is_some_and(|mz| mz >= min_mz && mz <= max_mz))
+            .map(|sh| sh.id)
+            .collect();
+
+        spectrum_ids_by_charge.insert(charge, spectrum_ids);
+    }
+
+    Ok(spectrum_ids_by_charge)
+}
+
+fn _search_fragment_in_bb(
+    bb: &BoundingBox,
+    entity_cache: &EntityCache,
+    min_mz: f64,
+    max_mz: f64,
+    min_intensity_rel: f32,
+    matching_spectrum_ids: &mut Vec<i64>,
+) -> Result<()> {
+    let de_cache = &entity_cache.data_encodings_cache;
+    let bb_index = index_bbox(bb, de_cache).location(here!())?;
+
+    for (spectrum_slice_idx, spectrum_id) in bb_index.spectra_ids.iter().enumerate() {
+        if bb_index.peaks_counts[spectrum_slice_idx] == 0 {
+            continue;
+        }
+
+        let spectrum_header = entity_cache.spectrum_headers.get((spectrum_id - 1) as usize)
+            .context(format!("can't retrieve header for spectrum ID={}", spectrum_id)).location(here!())?;
+        if spectrum_header.ms_level != 2 {
+            continue;
+        }
+
+        let data_encoding = de_cache.get_data_encoding_by_spectrum_id(spectrum_id)
+            .context(format!("can't retrieve data encoding for spectrum ID={}", spectrum_id)).location(here!())?;
+
+        let slice_data = read_spectrum_slice_data_at(bb, &bb_index, data_encoding, spectrum_slice_idx, None, None).location(here!())?;
+
+        let min_intensity = min_intensity_rel * spectrum_header.base_peak_intensity;
+        let has_fragment = slice_data.crop(min_mz, max_mz).intensity_array.iter().any(|intensity| *intensity >= min_intensity);
+        if has_fragment {
+            matching_spectrum_ids.push(*spectrum_id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the spectra of the provided MS level having at least one peak in the m/z window whose intensity is at least min_intensity.
+/// The optional rt_range (in seconds, bounds are inclusive) is compared to SpectrumHeader.time_f64.
+/// Bounding boxes are selected using the R-tree of the MS level when it is populated, and only the peaks of the m/z window are decoded.
+/// Returns the (spectrum id, max intensity in the m/z window) pairs sorted by spectrum id.
+pub fn find_spectra_with_signal(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    min_mz: f64,
+    max_mz: f64,
+    min_intensity: f32,
+    ms_level: u8,
+    rt_range: Option<(f64, f64)>,
+) -> Result<Vec<(i64, f32)>> {
+    let (min_time, max_time) = rt_range.unwrap_or((f64::MIN, f64::MAX));
+
+    // The R-tree time bounds are widened by the f32 precision (see rtree::query_region_auto()), spectra are thus filtered on their exact time
+    let region = RtreeRegion { min_mz, max_mz, min_time, max_time };
+
+    let mut max_intensity_by_spectrum_id: HashMap<i64, f32> = HashMap::new();
+
+    let on_each_bb = |bb: BoundingBox| -> Result<()> {
+        let bb_cursor = BlobCursor::new(&bb.blob_data, &entity_cache.data_encodings_cache);
+
+        for view_res in bb_cursor {
+            let view = view_res.location(here!())?;
+            if view.peaks_count == 0 {
+                continue;
+            }
+
+            let spectrum_header = entity_cache.spectrum_headers.get((view.spectrum_id - 1) as usize)
+                .context(format!("can't retrieve header for spectrum ID={}", view.spectrum_id)).location(here!())?;
+            if spectrum_header.ms_level != ms_level as i64 || spectrum_header.time_f64 < min_time || spectrum_header.time_f64 > max_time {
+                continue;
+            }
+
+            let window_data = view.to_spectrum_data_in_mz_range(Some(min_mz), Some(max_mz));
+            let max_intensity_opt = window_data.intensity_array.iter().copied().reduce(f32::max);
+
+            if let Some(max_intensity) = max_intensity_opt.filter(|max_intensity| *max_intensity >= min_intensity) {
+                // A spectrum may be split over several bounding boxes when the m/z window overlaps several run slices
+                let spectrum_max_intensity = max_intensity_by_spectrum_id.entry(view.spectrum_id).or_insert(max_intensity);
+                *spectrum_max_intensity = spectrum_max_intensity.max(max_intensity);
+            }
+        }
+
+        Ok(())
+    };
+
+    if ms_level == 1 || is_msn_rtree_populated(db).location(here!())? {
+        for_each_bb_in_region(db, entity_cache, ms_level, &region, None, on_each_bb).location(here!())?;
+    } else {
+        for_each_bb(db, Some(ms_level), on_each_bb).location(here!())?;
+    }
+
+    let mut matching_spectra: Vec<(i64, f32)> = max_intensity_by_spectrum_id.into_iter().collect();
+    matching_spectra.sort_unstable_by_key(|(spectrum_id, _)| *spectrum_id);
+
+    Ok(matching_spectra)
+}
+
+/// Find the spectra whose title or indexed param values (e.g. filter string) contain the provided text,
+/// which is matched as a sequence of tokens (e.g. "cid30.00" matches the filter string "ITMS + c NSI d Full ms2 476.20@cid30.00 [120.00-1440.00]").
+/// Requires the full-text index built by maintenance::build_fts_index().
+/// Returns the matching spectrum ids sorted in ascending order.
+pub fn query_headers_fts(db: &Connection, text: &str) -> Result<Vec<i64>> {
+    if !table_exists(db, SPECTRUM_FTS_TABLE_NAME).location(here!())? {
+        bail!("the file has no full-text index, it must be built first using maintenance::build_fts_index()");
+    }
+
+    // Quote the text to search it as a phrase, so that FTS5 operators and special characters are not interpreted
+    let phrase = format!("\"{}\"", text.replace('"', "\"\""));
+
+    let mut stmt = db.prepare(
+        format!("SELECT rowid FROM {} WHERE {} MATCH ? ORDER BY rowid", SPECTRUM_FTS_TABLE_NAME, SPECTRUM_FTS_TABLE_NAME).as_str()
+    ).location(here!())?;
+
+    let spectrum_ids = stmt.query_map([phrase], |row| row.get(0)).location(here!())?
+        .collect::<rusqlite::Result<Vec<i64>>>().location(here!())?;
+
+    Ok(spectrum_ids)
+}
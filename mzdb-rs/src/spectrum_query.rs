@@ -0,0 +1,154 @@
+// Fluent selection of spectra, e.g. spectra(&db, &entity_cache).ms_level(2).rt(10.0..20.0).collect()?
+// Filters are applied to the cached spectrum headers, spectra are only loaded when iterated.
+
+use std::ops::{Bound, RangeBounds};
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::model::*;
+use crate::queries::get_spectrum;
+
+#[derive(Clone, Debug)]
+pub struct SpectrumQuery<'a> {
+    db: &'a Connection,
+    entity_cache: &'a EntityCache,
+    ms_level: Option<i64>,
+    rt_bounds: (Bound<f64>, Bound<f64>), // compared to SpectrumHeader.time_f64
+    precursor_mz_range: Option<(f64, f64)>,
+    cycle_range: Option<(i64, i64)>,
+}
+
+/// Start a selection of the spectra of the file, all spectra being selected until filters are added
+pub fn spectra<'a>(db: &'a Connection, entity_cache: &'a EntityCache) -> SpectrumQuery<'a> {
+    SpectrumQuery {
+        db,
+        entity_cache,
+        ms_level: None,
+        rt_bounds: (Bound::Unbounded, Bound::Unbounded),
+        precursor_mz_range: None,
+        cycle_range: None,
+    }
+}
+
+impl<'a> SpectrumQuery<'a> {
+
+    pub fn ms_level(mut self, ms_level: i64) -> Self {
+        self.ms_level = Some(ms_level);
+        self
+    }
+
+    /// Keep the spectra whose time (in seconds) is in the range, e.g. rt(10.0..20.0) or rt(600.0..)
+    pub fn rt<R: RangeBounds<f64>>(mut self, rt_range: R) -> Self {
+        self.rt_bounds = (rt_range.start_bound().cloned(), rt_range.end_bound().cloned());
+        self
+    }
+
+    /// Keep the spectra whose precursor m/z matches the provided one (+/- tolerance), thus excluding MS1 spectra
+    pub fn precursor_mz(mut self, precursor_mz: f64, mz_tolerance: &MzTolerance) -> Self {
+        self.precursor_mz_range = Some(mz_tolerance.mz_range(precursor_mz));
+        self
+    }
+
+    /// Keep the spectra of the provided cycles (bounds are inclusive)
+    pub fn cycles(mut self, min_cycle: i64, max_cycle: i64) -> Self {
+        self.cycle_range = Some((min_cycle, max_cycle));
+        self
+    }
+
+    /// Returns the headers of the selected spectra, sorted by ID
+    pub fn headers(&self) -> Vec<&'a SpectrumHeader> {
+        self.entity_cache.spectrum_headers.iter().filter(|sh| self._matches(sh)).collect()
+    }
+
+    /// Returns the IDs of the selected spectra, sorted in ascending order
+    pub fn ids(&self) -> Vec<i64> {
+        self.headers().iter().map(|sh| sh.id).collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.entity_cache.spectrum_headers.iter().filter(|sh| self._matches(sh)).count()
+    }
+
+    /// Iterate the selected spectra, which are loaded one by one
+    pub fn iter(&self) -> SpectrumQueryIter<'a> {
+        SpectrumQueryIter {
+            db: self.db,
+            entity_cache: self.entity_cache,
+            spectrum_ids: self.ids().into_iter(),
+        }
+    }
+
+    /// Load all the selected spectra, stopping at the first error
+    pub fn collect(&self) -> Result<Vec<Spectrum>> {
+        self.iter().collect()
+    }
+
+    pub fn for_each<F>(&self, mut on_each_spectrum: F) -> Result<()> where F: FnMut(&Spectrum) -> Result<()> {
+        for spectrum_res in self.iter() {
+            on_each_spectrum(&spectrum_res.location(here!())?)?;
+        }
+
+        Ok(())
+    }
+
+    fn _matches(&self, sh: &SpectrumHeader) -> bool {
+        if self.ms_level.is_some_and(|ms_level| sh.ms_level != ms_level) {
+            return false;
+        }
+
+        if !self.rt_bounds.contains(&sh.time_f64) {
+            return false;
+        }
+
+        if let Some((min_mz, max_mz)) = self.precursor_mz_range {
+            if sh.precursor_mz.is_none_or(|mz| mz < min_mz || mz > max_mz) {
+                return false;
+            }
+        }
+
+        self.cycle_range.is_none_or(|(min_cycle, max_cycle)| sh.cycle >= min_cycle && sh.cycle <= max_cycle)
+    }
+}
+
+impl<'a> IntoIterator for SpectrumQuery<'a> {
+    type Item = Result<Spectrum>;
+    type IntoIter = SpectrumQueryIter<'a>;
+
+    fn into_iter(self) -> SpectrumQueryIter<'a> {
+        self.iter()
+    }
+}
+
+impl<'a> IntoIterator for &SpectrumQuery<'a> {
+    type Item = Result<Spectrum>;
+    type IntoIter = SpectrumQueryIter<'a>;
+
+    fn into_iter(self) -> SpectrumQueryIter<'a> {
+        self.iter()
+    }
+}
+
+/// Iterator over the spectra of a SpectrumQuery, yielding an error for the spectra which can't be loaded
+pub struct SpectrumQueryIter<'a> {
+    db: &'a Connection,
+    entity_cache: &'a EntityCache,
+    spectrum_ids: std::vec::IntoIter<i64>,
+}
+
+impl<'a> Iterator for SpectrumQueryIter<'a> {
+    type Item = Result<Spectrum>;
+
+    fn next(&mut self) -> Option<Result<Spectrum>> {
+        let spectrum_id = self.spectrum_ids.next()?;
+        Some(get_spectrum(self.db, spectrum_id, self.entity_cache))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.spectrum_ids.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for SpectrumQueryIter<'a> {}
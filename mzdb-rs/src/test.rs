@@ -14,6 +14,13 @@ use crate::model::*;
 use crate::mzdb::create_entity_cache;
 use crate::queries::*;
 
+// Copy the test file to a unique temporary path, for the tests modifying it (the copy is deleted when the path is dropped)
+fn _copy_test_file() -> Result<tempfile::TempPath> {
+    let file_path = tempfile::Builder::new().prefix("mzdb_test_").suffix(".mzDB").tempfile().location(here!())?.into_temp_path();
+    std::fs::copy("./data/OVEMB150205_12.mzDB", &file_path).location(here!())?;
+    Ok(file_path)
+}
+
 #[test]
 pub fn run_basic_tests() -> Result<()>  {
     let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
@@ -156,4 +163,2646 @@ pub fn run_basic_tests() -> Result<()>  {
         spectrum
     );
     return Ok(());
-}
\ No newline at end of file
+}
+#[test]
+pub fn run_count_mode_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    assert_eq!(get_table_records_count_with_mode(&db, "spectrum", CountMode::HINTED).location(here!())?, Some(1193));
+    assert_eq!(get_table_records_count_with_mode(&db, "spectrum", CountMode::EXACT).location(here!())?, Some(1193));
+    assert_eq!(get_table_records_count_with_mode(&db, "bounding_box_msn_rtree", CountMode::SEQUENCE).location(here!())?, None);
+    assert_eq!(get_table_records_count_with_mode(&db, "bounding_box_msn_rtree", CountMode::HINTED).location(here!())?, Some(0));
+
+    // Simulate a file edited by another tool, leaving a stale sqlite_sequence value
+    let db = Connection::open_in_memory()?;
+    db.execute_batch(
+        "CREATE TABLE spectrum (id INTEGER PRIMARY KEY AUTOINCREMENT, title TEXT); \
+        INSERT INTO spectrum (title) VALUES ('s1'), ('s2'), ('s3'), ('s4'); \
+        DELETE FROM spectrum WHERE id = 2; \
+        UPDATE sqlite_sequence SET seq = 10 WHERE name = 'spectrum';"
+    ).location(here!())?;
+
+    assert_eq!(get_table_records_count_with_mode(&db, "spectrum", CountMode::SEQUENCE).location(here!())?, Some(10));
+    assert_eq!(get_table_records_count_with_mode(&db, "spectrum", CountMode::HINTED).location(here!())?, Some(3));
+    assert_eq!(get_table_records_count_with_mode(&db, "spectrum", CountMode::EXACT).location(here!())?, Some(3));
+
+    Ok(())
+}
+
+#[test]
+pub fn run_metadata_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let mzdb_metadata = crate::metadata::get_mzdb_metadata(&db).location(here!())?;
+    assert_eq!(mzdb_metadata.unwrap().version, "0.7", "invalid mzdb version");
+
+    let samples = crate::metadata::list_samples(&db).location(here!())?;
+    assert_eq!(samples.len(), 1, "invalid number of samples");
+    assert_eq!(samples[0].name, "UPS1 5fmol R1", "invalid sample name");
+
+    let software_list = crate::metadata::list_software(&db).location(here!())?;
+    assert_eq!(software_list.len(), 2, "invalid number of software");
+
+    let instrument_configs = crate::metadata::list_instrument_configurations(&db).location(here!())?;
+    assert_eq!(instrument_configs[0].name, "IC1", "invalid instrument configuration name");
+
+    let source_files = crate::metadata::list_source_files(&db).location(here!())?;
+    assert_eq!(source_files[0].name, "OVEMB150205_12", "invalid source file name");
+
+    let processing_methods = crate::metadata::list_processing_methods(&db).location(here!())?;
+    assert_eq!(processing_methods.len(), 2, "invalid number of processing methods");
+
+    let runs = crate::metadata::list_runs(&db).location(here!())?;
+    assert_eq!(runs[0].sample_id, samples[0].id, "invalid run sample id");
+
+    let cv_terms = crate::metadata::list_cv_terms(&db).location(here!())?;
+    assert!(cv_terms.is_empty(), "unexpected cv terms");
+
+    let metadata_json = crate::metadata::export_metadata_json(&db).location(here!())?;
+    let file_metadata: FileMetadata = serde_json::from_str(&metadata_json).location(here!())?;
+    assert_eq!(file_metadata.samples, samples, "invalid exported samples");
+    assert_eq!(file_metadata.processing_methods.len(), 2, "invalid number of exported processing methods");
+    assert!(!file_metadata.instruments[0].components.components.is_empty(), "exported instrument should have components");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_isolation_window_index_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let window_index = crate::mzdb::build_isolation_window_index(&db, &entity_cache).location(here!())?;
+
+    let spec_id = 17;
+    let window = window_index.get_isolation_window_by_spectrum_id(&spec_id).unwrap();
+    assert!((window.min_mz - 475.199066162109).abs() < 1e-6, "invalid isolation window min m/z for spectrum {}", spec_id);
+    assert!((window.max_mz - 477.199066162109).abs() < 1e-6, "invalid isolation window max m/z for spectrum {}", spec_id);
+
+    let indexed_spectra_count: usize = window_index.get_spectra_count_by_window().iter().map(|(_w, count)| count).sum();
+    assert_eq!(indexed_spectra_count, 1035, "invalid number of indexed MS2 spectra");
+
+    let first_spec_id = 1;
+    assert!(window_index.get_isolation_window_by_spectrum_id(&first_spec_id).is_none(), "MS1 spectra should not be indexed");
+
+    // The precursor lists of light headers are loaded
+    let light_entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+    let light_window_index = crate::mzdb::build_isolation_window_index(&db, &light_entity_cache).location(here!())?;
+    assert_eq!(light_window_index, window_index);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_instrument_components_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let component_list = crate::metadata::get_instrument_components(&db, 1).location(here!())?.unwrap();
+    assert_eq!(component_list.components.len(), 3, "invalid number of instrument components");
+    assert_eq!(component_list.components[1].component_type, ComponentType::ANALYZER, "invalid type for second component");
+    assert_eq!(component_list.components[1].cv_params[0].accession, "MS:1000484", "invalid analyzer accession");
+
+    let summary = crate::metadata::get_instrument_summary(&db).location(here!())?;
+    assert_eq!(summary.source_names, vec!["nanoelectrospray", "nanospray"], "invalid source names");
+    assert_eq!(summary.analyzer_names, vec!["orbitrap"], "invalid analyzer names");
+    assert_eq!(summary.detector_names, vec!["inductive detector"], "invalid detector names");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_chromatogram_type_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let chrom_headers = list_chromatogram_headers(&db).location(here!())?;
+    assert!(chrom_headers.is_empty(), "unexpected chromatograms");
+    assert!(get_tic_chromatogram_header(&db).location(here!())?.is_none(), "unexpected TIC chromatogram");
+
+    let mut chrom_header = ChromatogramHeader {
+        id: 1,
+        name: "chrom1".to_string(),
+        activation_type: None,
+        param_tree: r#"<params><cvParams><cvParam cvRef="MS" accession="MS:1000235" name="total ion current chromatogram" value="" /></cvParams></params>"#.to_string(),
+        precursor: None,
+        product: None,
+        shared_param_tree_id: None,
+        run_id: 1,
+        data_processing_id: None,
+        data_encoding_id: 1,
+    };
+    assert_eq!(chrom_header.chromatogram_type(), ChromatogramType::TIC, "TIC not detected from cvParam");
+
+    chrom_header.param_tree = "<params />".to_string();
+    chrom_header.name = "BPC".to_string();
+    assert_eq!(chrom_header.chromatogram_type(), ChromatogramType::BPC, "BPC not detected from name");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_light_entity_cache_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+    assert_eq!(entity_cache.spectrum_headers.len(), 1193, "invalid number of light spectrum headers");
+
+    let sh = &entity_cache.spectrum_headers[16];
+    assert_eq!(sh.precursor_mz, Some(475.8724), "invalid precursor m/z for spectrum {}", sh.id);
+    assert!(sh.param_tree_str.is_none() && sh.precursor_list_str.is_none(), "XML fields should not be loaded");
+
+    let precursor_list_xml = get_precursor_list_xml(&db, sh.id).location(here!())?.unwrap();
+    assert!(precursor_list_xml.contains("isolationWindow"), "invalid precursor list for spectrum {}", sh.id);
+
+    let scan_list_xml = get_scan_list_xml(&db, sh.id).location(here!())?.unwrap();
+    assert!(scan_list_xml.contains("scanList"), "invalid scan list for spectrum {}", sh.id);
+
+    let spectrum = get_spectrum(&db, sh.id, &entity_cache).location(here!())?;
+    assert_eq!(spectrum.data.peak_count as i64, sh.peaks_count, "invalid peak count for spectrum {}", sh.id);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_compat_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let (entity_cache, report) = crate::compat::create_compatible_entity_cache(&db).location(here!())?;
+    assert_eq!(report.producer_name, Some("Thermo2mzDB".to_string()), "invalid producer name");
+    assert!(report.quirks.is_empty(), "unexpected quirks: {:?}", report.quirks);
+
+    let last_header = entity_cache.spectrum_headers.last().unwrap();
+    assert_eq!(last_header.time, 240.86351, "spectrum time should not be corrected");
+    assert_eq!(entity_cache.spectrum_headers[16].bb_first_spectrum_id, 17, "invalid bb_first_spectrum_id");
+
+    assert_eq!(crate::compat::normalize_compression(Some("None".to_string())), "none");
+    assert_eq!(crate::compat::normalize_compression(None), "none");
+
+    let ms1_bounds = get_bounding_box_bounds(&db, 4).location(here!())?.unwrap();
+
+    // Simulate a file storing its times in minutes, with bb_first_spectrum_id values matching no bounding box
+    let file_path = _copy_test_file().location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute_batch(
+        "UPDATE spectrum SET time = time / 60, bb_first_spectrum_id = bb_first_spectrum_id + 100000; \
+        UPDATE bounding_box_rtree SET min_time = min_time / 60, max_time = max_time / 60;"
+    ).location(here!())?;
+
+    let (entity_cache, report) = crate::compat::create_compatible_entity_cache(&db).location(here!())?;
+    assert!(report.quirks.contains(&crate::compat::ProducerQuirk::INCONSISTENT_BB_FIRST_SPECTRUM_ID), "invalid quirks: {:?}", report.quirks);
+    assert!(report.quirks.contains(&crate::compat::ProducerQuirk::RT_IN_MINUTES), "invalid quirks: {:?}", report.quirks);
+    assert!((entity_cache.spectrum_headers.last().unwrap().time - 240.86351).abs() < 1e-3, "spectrum time should be corrected");
+
+    assert_eq!(entity_cache.time_factor, 60.0);
+
+    // The R-tree times are converted by the queries taking the corrected entity cache, the raw getters return them as stored
+    let stored_ms1_bounds = get_bounding_box_bounds(&db, 4).location(here!())?.unwrap();
+    assert!((stored_ms1_bounds.region().max_time * 60.0 - ms1_bounds.region().max_time).abs() < 1e-3, "R-tree times should be returned as stored");
+
+    let full_region = crate::rtree::RtreeRegion { min_mz: 0.0, max_mz: 1e6, min_time: 0.0, max_time: 1e6 };
+    let corrected_entries = crate::rtree::query_region_auto(&db, &entity_cache, 1, &full_region, None).location(here!())?;
+    let corrected_ms1_bounds = corrected_entries.iter().find(|entry| entry.bb_id() == 4).unwrap();
+    assert!((corrected_ms1_bounds.region().max_time - ms1_bounds.region().max_time).abs() < 1e-3, "R-tree times should be corrected");
+
+    // Time ranges are converted too
+    let last_header = entity_cache.spectrum_headers.last().unwrap();
+    let spectrum_ids = list_spectrum_ids_in_time_range(&db, &entity_cache, last_header.time_f64 - 0.001, last_header.time_f64 + 0.001, None).location(here!())?;
+    assert_eq!(spectrum_ids, vec![last_header.id]);
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_spectrum_data_helpers_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let spectrum = get_spectrum(&db, 2, &entity_cache).location(here!())?;
+    let sd = &spectrum.data;
+
+    assert_eq!(sd.iter_peaks().count(), sd.mz_array.len(), "invalid number of iterated peaks");
+    assert!(sd.iter_peaks().all(|p| p.lwhm.is_none()), "centroid peaks should not have HWHM values");
+
+    let base_peak = sd.base_peak().unwrap();
+    assert!((base_peak.mz - spectrum.header.base_peak_mz).abs() < 1e-4, "invalid base peak m/z");
+
+    let tic = sd.total_ion_current();
+    assert!((tic - spectrum.header.tic as f64).abs() / tic < 1e-3, "invalid TIC");
+
+    let cropped_sd = sd.crop(500.0, 600.0);
+    assert!(cropped_sd.peak_count > 0 && cropped_sd.peak_count < sd.mz_array.len(), "invalid cropped peak count");
+    assert!(cropped_sd.mz_array.iter().all(|mz| *mz >= 500.0 && *mz <= 600.0), "invalid cropped m/z values");
+
+    let top_peaks = sd.top_n(10);
+    assert_eq!(top_peaks.len(), 10, "invalid number of top peaks");
+    assert!(top_peaks.contains(&base_peak), "top peaks should contain the base peak");
+    assert!(top_peaks.windows(2).all(|w| w[0].mz <= w[1].mz), "top peaks should be sorted by m/z");
+
+    assert_eq!(sd.get_mz_at(0).location(here!())?, sd.mz_array[0], "invalid m/z accessor");
+    assert_eq!(sd.get_intensity_at(sd.peak_count - 1).location(here!())?, sd.intensity_array[sd.peak_count - 1], "invalid intensity accessor");
+    assert!(sd.get_mz_at(sd.peak_count).is_err(), "out of bounds access should fail");
+    assert!(sd.get_left_hwhm_at(0).is_err() && sd.get_right_hwhm_at(0).is_err(), "centroid data has no HWHMs");
+
+    let downsampled_sd = sd.downsample_minmax(20);
+    assert!(downsampled_sd.peak_count <= 40 && downsampled_sd.peak_count == downsampled_sd.mz_array.len(), "invalid downsampled peak count");
+    assert!(downsampled_sd.mz_array.windows(2).all(|w| w[0] < w[1]), "downsampled peaks should be sorted by m/z");
+    assert_eq!(downsampled_sd.base_peak(), Some(base_peak), "downsampling should preserve the base peak");
+    assert_eq!(&sd.downsample_minmax(sd.peak_count), sd, "nothing to downsample");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_precursor_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let spectrum_headers = crate::mzdb::get_spectrum_headers(&db).location(here!())?;
+
+    let ms1_header = &spectrum_headers[0];
+    assert!(ms1_header.precursor().location(here!())?.is_none(), "MS1 spectrum should have no precursor");
+
+    let ms2_header = &spectrum_headers[16];
+    let precursor = ms2_header.precursor().location(here!())?.unwrap();
+    assert_eq!(precursor.spectrum_ref.as_deref(), Some("controllerType=0 controllerNumber=1 scan=16"));
+    assert_eq!(precursor.selected_ions.len(), 1, "invalid number of selected ions");
+    assert_eq!(precursor.selected_ions[0].mz, 475.8724, "invalid selected ion m/z");
+    assert_eq!(precursor.selected_ions[0].charge, Some(3), "invalid selected ion charge");
+
+    // cvParam elements may also be written with a closing tag
+    let precursors = crate::xml::parse_precursor_list(
+        r#"<precursorList count="1"><precursor><selectedIonList count="1"><selectedIon>
+        <cvParam cvRef="MS" accession="MS:1000744" name="selected ion m/z" value="475.8724"></cvParam>
+        <cvParam cvRef="MS" accession="MS:1000041" name="charge state" value="3"></cvParam>
+        </selectedIon></selectedIonList></precursor></precursorList>"#
+    ).location(here!())?;
+    assert_eq!(precursors.len(), 1);
+    assert_eq!(precursors[0].selected_ions.len(), 1, "cvParam elements with a closing tag should be parsed");
+    assert_eq!(precursors[0].selected_ions[0].mz, 475.8724);
+    assert_eq!(precursors[0].selected_ions[0].charge, Some(3));
+
+    let isolation_window = ms2_header.isolation_window().location(here!())?.unwrap();
+    assert!((isolation_window.min_mz - 475.199066).abs() < 1e-6, "invalid isolation window");
+    assert_eq!(ms2_header.collision_energy().location(here!())?, Some(30.0), "invalid collision energy");
+
+    assert_eq!(ms1_header.activation().location(here!())?, None, "MS1 spectrum should have no activation");
+    assert_eq!(ms2_header.activation().location(here!())?, Some(ActivationType::CID), "invalid activation type");
+    assert_eq!(ActivationType::parse("beam-type collision-induced dissociation"), ActivationType::HCD);
+    assert_eq!(ActivationType::parse("MS:1000598"), ActivationType::ETD);
+    assert_eq!(ActivationType::parse("EThcD"), ActivationType::ETHCD);
+
+    let ethcd_cv_params = crate::xml::parse_cv_params(
+        r#"<activation><cvParam cvRef="MS" accession="MS:1000598" name="electron transfer dissociation" value=""/>
+        <cvParam cvRef="MS" accession="MS:1002678" name="supplemental beam-type collision-induced dissociation" value=""/></activation>"#
+    ).location(here!())?;
+    assert_eq!(ActivationType::from_cv_params(&ethcd_cv_params), ActivationType::ETHCD);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_fragment_search_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let spectrum = get_spectrum(&db, 17, &entity_cache).location(here!())?;
+    let base_peak = spectrum.data.base_peak().unwrap();
+
+    let spectrum_ids = crate::search::find_ms2_with_fragment(&db, &entity_cache, base_peak.mz, &MzTolerance::PPM(5.0), 0.5).location(here!())?;
+    assert!(spectrum_ids.contains(&17), "spectrum 17 should match its own base peak");
+    assert!(spectrum_ids.iter().all(|id| entity_cache.spectrum_headers[(id - 1) as usize].ms_level == 2), "only MS2 spectra should match");
+
+    let spectrum_ids = crate::search::find_ms2_with_fragment(&db, &entity_cache, 5000.0, &MzTolerance::DA(0.01), 0.0).location(here!())?;
+    assert!(spectrum_ids.is_empty(), "no fragment should be found at m/z 5000");
+
+    assert_eq!(MzTolerance::PPM(10.0).to_da(1000.0), 0.01);
+    assert_eq!(MzTolerance::HYBRID { ppm: 10.0, min_da: 0.005 }.to_da(126.0), 0.005, "min_da should apply to low masses");
+    assert_eq!(MzTolerance::HYBRID { ppm: 10.0, min_da: 0.005 }.to_da(1000.0), 0.01);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_reporter_ions_tests() -> Result<()>  {
+    use crate::quant::*;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let reporter_matrix = extract_reporter_ions(&db, &entity_cache, &ReporterSet::TMT16, &MzTolerance::PPM(10.0), None).location(here!())?;
+    assert_eq!(reporter_matrix.reporter_ions.len(), 16, "invalid number of TMT16 reporter ions");
+    assert_eq!(reporter_matrix.spectrum_ids.len(), 1035, "one row per MS2 spectrum is expected");
+    assert!(reporter_matrix.intensities.iter().all(|row| row.len() == 16), "invalid number of columns");
+
+    // Channel 2 leaks 10% of its signal in channel 1
+    let correction_matrix = vec![vec![1.0, 0.1], vec![0.0, 0.9]];
+    let corrected = correct_reporter_intensities(&[110.0, 90.0], &correction_matrix).location(here!())?;
+    assert!((corrected[0] - 100.0).abs() < 1e-3 && (corrected[1] - 100.0).abs() < 1e-3, "invalid corrected intensities: {:?}", corrected);
+
+    assert!(correct_reporter_intensities(&[1.0], &correction_matrix).is_err(), "matrix size should be checked");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_uncached_spectrum_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    for spectrum_id in [1, 17, 1193] {
+        let cached_spectrum = get_spectrum(&db, spectrum_id, &entity_cache).location(here!())?;
+        let uncached_spectrum = get_spectrum_uncached(&db, spectrum_id).location(here!())?;
+        assert_eq!(uncached_spectrum, cached_spectrum, "uncached spectrum {} differs from the cached one", spectrum_id);
+    }
+
+    assert!(get_spectrum_uncached(&db, 100000).is_err(), "missing spectrum should not be found");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_run_slice_intensity_profile_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let mut stmt = db.prepare("SELECT id FROM run_slice WHERE ms_level = 1")?;
+    let run_slice_ids: Vec<i64> = stmt.query_map([], |row| row.get(0))?.collect::<RusqliteResult<_>>()?;
+
+    let mut profiles_intensity_sum = 0.0;
+    for run_slice_id in run_slice_ids {
+        let profile = get_run_slice_intensity_profile(&db, run_slice_id).location(here!())?;
+        assert!(profile.windows(2).all(|w| w[0].first_spectrum_id <= w[1].first_spectrum_id), "profile should be ordered by spectrum id");
+        profiles_intensity_sum += profile.iter().map(|bb_intensity| bb_intensity.intensity_sum).sum::<f64>();
+    }
+
+    let mut ms1_intensity_sum = 0.0;
+    crate::iterator::for_each_spectrum(&db, &entity_cache, Some(1), |spectrum| {
+        ms1_intensity_sum += spectrum.data.total_ion_current();
+        Ok(())
+    }).location(here!())?;
+
+    assert!((profiles_intensity_sum - ms1_intensity_sum).abs() / ms1_intensity_sum < 1e-9, "run slice profiles should sum to the MS1 intensities");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_blob_cursor_tests() -> Result<()>  {
+    use crate::blob_cursor::BlobCursor;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    let de_cache = &entity_cache.data_encodings_cache;
+
+    let mut checked_slices_count = 0;
+    crate::iterator::for_each_bb(&db, None, |bb: BoundingBox| {
+        if bb.id % 50 != 1 {
+            return Ok(());
+        }
+
+        let bb_index = index_bbox(&bb, de_cache).location(here!())?;
+
+        for (slice_idx, slice_view_res) in BlobCursor::new(&bb.blob_data, de_cache).enumerate() {
+            let slice_view = slice_view_res.location(here!())?;
+            assert_eq!(slice_view.spectrum_id, bb_index.spectra_ids[slice_idx], "invalid spectrum id");
+            assert_eq!(slice_view.peaks_count, bb_index.peaks_counts[slice_idx], "invalid peaks count");
+
+            let slice_data = slice_view.to_spectrum_data();
+            assert_eq!(slice_data, read_spectrum_slice_data_at(&bb, &bb_index, slice_view.data_encoding, slice_idx, None, None).location(here!())?);
+            assert_eq!(slice_view.get_mz_at(0), slice_data.mz_array.first().copied());
+
+            if slice_data.peak_count > 2 {
+                let (min_mz, max_mz) = (slice_data.mz_array[1], slice_data.mz_array[slice_data.peak_count - 2]);
+                assert_eq!(slice_view.to_spectrum_data_in_mz_range(Some(min_mz), Some(max_mz)), slice_data.crop(min_mz, max_mz), "invalid m/z range decoding");
+            }
+
+            checked_slices_count += 1;
+        }
+
+        Ok(())
+    }).location(here!())?;
+
+    assert!(checked_slices_count > 0, "no spectrum slice checked");
+
+    // Truncated blobs should be reported as errors
+    let mut blob: Vec<u8> = db.query_row("SELECT data FROM bounding_box WHERE id = 1", [], |row| row.get(0)).location(here!())?;
+    blob.truncate(blob.len() - 3);
+    let slice_results: Vec<Result<_>> = BlobCursor::new(&blob, de_cache).collect();
+    assert!(slice_results.last().unwrap().is_err(), "truncated blob should be reported");
+
+    // A negative peaks count should be reported instead of overflowing
+    blob[4..8].copy_from_slice(&(-1i32).to_le_bytes());
+    let first_slice_res = BlobCursor::new(&blob, de_cache).next().unwrap();
+    assert!(first_slice_res.is_err(), "negative peaks count should be reported");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_run_slice_iterator_tests() -> Result<()>  {
+    use crate::iterator::for_each_run_slice;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+
+    let mut run_slices_count = 0;
+    let mut prev_run_slice_number = 0;
+    let mut spectrum_1_peaks_count = 0;
+
+    for_each_run_slice(&db, &entity_cache, Some(1), |run_slice: &RunSlice| {
+        assert_eq!(run_slice.header.ms_level, 1, "invalid run slice MS level");
+        assert!(run_slice.header.number > prev_run_slice_number, "run slices should be ordered by number");
+        prev_run_slice_number = run_slice.header.number;
+        run_slices_count += 1;
+
+        for spectrum_slice in run_slice.data.spectrum_slice.iter() {
+            assert_eq!(spectrum_slice.run_slice_id, run_slice.header.id, "invalid spectrum slice run slice id");
+
+            let data = &spectrum_slice.spectrum.data;
+            if let (Some(first_mz), Some(last_mz)) = (data.mz_array.first(), data.mz_array.last()) {
+                assert!(*first_mz >= run_slice.header.begin_mz && *last_mz <= run_slice.header.end_mz, "spectrum slice outside of its run slice");
+            }
+
+            if spectrum_slice.spectrum.header.id == 1 {
+                spectrum_1_peaks_count += data.peak_count;
+            }
+        }
+
+        Ok(())
+    }).location(here!())?;
+
+    assert_eq!(run_slices_count, 160, "invalid number of MS1 run slices");
+
+    let spectrum_1 = get_spectrum(&db, 1, &entity_cache).location(here!())?;
+    assert_eq!(spectrum_1_peaks_count, spectrum_1.data.peak_count, "run slices should hold all the spectrum peaks");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_qc_summary_tests() -> Result<()>  {
+    use crate::qc::*;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let mut entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+    let summary = compute_run_summary(&db, &entity_cache, None).location(here!())?;
+
+    assert_eq!(summary.ms1_count, 158, "invalid number of MS1 spectra");
+    assert_eq!(summary.msn_count, 1035, "invalid number of MSn spectra");
+    assert_eq!(summary.tic.len(), summary.ms1_times.len());
+    assert_eq!(summary.bpc.len(), summary.ms1_times.len());
+
+    let tic_stats = summary.tic_stats.unwrap();
+    assert_eq!(tic_stats.max, summary.tic.iter().cloned().fold(0.0, f32::max), "invalid max TIC");
+
+    // Without scan metadata cache, the injection times and precursor intensities are not available
+    assert!(summary.ms1_injection_times.iter().all(|injection_time| injection_time.is_none()));
+    assert!(summary.ms1_injection_time_stats.is_none() && summary.precursor_intensities.is_empty());
+
+    // Times are the corrected ones
+    crate::mzdb::set_rt_offset(&mut entity_cache, 10.0);
+    let shifted_summary = compute_run_summary(&db, &entity_cache, None).location(here!())?;
+    assert_eq!(shifted_summary.ms1_times[0], summary.ms1_times[0] + 10.0);
+
+    let file_path = _copy_test_file().location(here!())?;
+    crate::maintenance::build_scan_metadata_cache(file_path.to_str().unwrap(), None, None).location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+    let summary = compute_run_summary(&db, &entity_cache, None).location(here!())?;
+
+    assert_eq!(summary.ms1_injection_times[0], Some(35.283), "invalid injection time of the first spectrum");
+    let injection_time_stats = summary.ms1_injection_time_stats.unwrap();
+    assert_eq!(injection_time_stats.count, 158, "each MS1 spectrum should report its injection time");
+    assert!(injection_time_stats.min <= injection_time_stats.median && injection_time_stats.median <= injection_time_stats.max);
+    // The selected ions of this file don't report their intensity
+    assert!(summary.precursor_intensities.is_empty());
+
+    let stats = compute_distribution_stats(&[4.0, 1.0, 3.0, 2.0]).unwrap();
+    assert_eq!((stats.min, stats.max, stats.mean, stats.median), (1.0, 4.0, 2.5, 2.5));
+    assert!(compute_distribution_stats(&[]).is_none());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_cohort_reader_tests() -> Result<()>  {
+    let file_path = "./data/OVEMB150205_12.mzDB";
+    let cohort_reader = crate::cohort::CohortReader::open(&[file_path, file_path]).location(here!())?;
+    assert_eq!(cohort_reader.files_count(), 2, "invalid number of files");
+
+    let mut ms1_counts = vec![0; 2];
+    cohort_reader.for_each_spectrum(Some(1), |file_idx, _spectrum| {
+        ms1_counts[file_idx] += 1;
+        Ok(())
+    }).location(here!())?;
+    assert_eq!(ms1_counts, vec![158, 158], "invalid number of MS1 spectra per file");
+
+    let ms2_headers = cohort_reader.get_spectrum_headers_by_ms_level(2);
+    assert_eq!(ms2_headers[1].len(), 1035, "invalid number of MS2 headers");
+
+    let spectrum = cohort_reader.get_spectrum(1, 17).location(here!())?;
+    assert_eq!(spectrum.header.precursor_mz, Some(475.8724), "invalid precursor m/z");
+    assert!(cohort_reader.get_spectrum(2, 17).is_err(), "file index should be checked");
+
+    let (first_cache, second_cache) = (&cohort_reader.files[0].entity_cache, &cohort_reader.files[1].entity_cache);
+    assert!(second_cache.data_encodings_cache.shares_maps_with(&first_cache.data_encodings_cache), "data encodings should be shared");
+
+    // Header strings are interned across the files
+    let (first_header, second_header) = (&first_cache.spectrum_headers[16], &second_cache.spectrum_headers[16]);
+    assert!(std::sync::Arc::ptr_eq(&first_header.title, &second_header.title), "titles should be shared");
+    let activation_types = (first_header.activation_type.as_ref().unwrap(), second_header.activation_type.as_ref().unwrap());
+    assert!(std::sync::Arc::ptr_eq(activation_types.0, activation_types.1), "activation types should be shared");
+    assert!(std::sync::Arc::ptr_eq(activation_types.0, second_cache.spectrum_headers[17].activation_type.as_ref().unwrap()));
+
+    let xics = cohort_reader.get_xic_isotopes(475.8724, 3, 2, 10.0, None).location(here!())?;
+    assert_eq!(xics.len(), 2, "invalid number of XICs");
+    assert_eq!(xics[0], xics[1], "the XICs of identical files should be identical");
+
+    let xics = cohort_reader.get_xic(475.8724, 10.0, 1, None, None).location(here!())?;
+    assert_eq!(xics.len(), 2, "invalid number of XICs");
+    assert!(!xics[0].peaks.is_empty() && xics[0] == xics[1], "the XICs of identical files should be identical");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_multi_mzdb_tests() -> Result<()>  {
+    use crate::cohort::MultiMzDb;
+
+    let file_path = "./data/OVEMB150205_12.mzDB";
+    let multi_mzdb = MultiMzDb::attach(&[file_path, file_path]).location(here!())?;
+    assert_eq!(multi_mzdb.files_count(), 2, "invalid number of files");
+    assert!(MultiMzDb::attach(&["./data/missing.mzDB"]).is_err(), "missing file should be rejected");
+
+    // Count the MS1 spectra of each file using a single query
+    let union_query = multi_mzdb.build_union_query("SELECT count(*) FROM {schema}.spectrum WHERE ms_level = 1").location(here!())?;
+    let mut stmt = multi_mzdb.db.prepare(&union_query).location(here!())?;
+    let ms1_counts = stmt.query_map([], |row| rusqlite::Result::Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))).location(here!())?
+        .collect::<rusqlite::Result<Vec<(i64, i64)>>>().location(here!())?;
+    assert_eq!(ms1_counts, vec![(0, 158), (1, 158)], "invalid number of MS1 spectra per file");
+
+    // Any SELECT syntax is supported since the query of each file is wrapped in a subquery
+    let union_query = multi_mzdb.build_union_query(
+        "WITH ms1 AS (SELECT DISTINCT cycle FROM {schema}.spectrum WHERE ms_level = 1) \
+        SELECT\ncycle FROM ms1 ORDER BY cycle DESC LIMIT 2"
+    ).location(here!())?;
+    let mut stmt = multi_mzdb.db.prepare(&union_query).location(here!())?;
+    let last_cycles = stmt.query_map([], |row| rusqlite::Result::Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))).location(here!())?
+        .collect::<rusqlite::Result<Vec<(i64, i64)>>>().location(here!())?;
+    assert_eq!(last_cycles.iter().map(|(file_idx, _)| *file_idx).collect::<Vec<i64>>(), vec![0, 0, 1, 1], "invalid rows: {:?}", last_cycles);
+    assert!(last_cycles[0].1 > last_cycles[1].1, "the ORDER BY of the query should be applied to each file");
+    assert_eq!(last_cycles[..2].iter().map(|(_, cycle)| *cycle).collect::<Vec<i64>>(), last_cycles[2..].iter().map(|(_, cycle)| *cycle).collect::<Vec<i64>>());
+    assert_eq!(crate::queries::quote_identifier("a\"b"), "\"a\"\"b\"");
+
+    // Align the MS1 spectra of both files on their cycle
+    let aligned_count: i64 = multi_mzdb.db.query_row(
+        "SELECT count(*) FROM file_0.spectrum s0 JOIN file_1.spectrum s1 ON s0.cycle = s1.cycle AND s0.ms_level = 1 AND s1.ms_level = 1 \
+        WHERE s0.tic = s1.tic",
+        [],
+        |row| row.get(0)
+    ).location(here!())?;
+    assert_eq!(aligned_count, 158, "invalid number of aligned MS1 spectra");
+
+    // Read a file through the shared connection
+    let spectrum = multi_mzdb.with_file(1, |db| {
+        let entity_cache = crate::mzdb::create_light_entity_cache(db).location(here!())?;
+        get_spectrum(db, 17, &entity_cache)
+    }).location(here!())?;
+    assert_eq!(spectrum.header.precursor_mz, Some(475.8724), "invalid precursor m/z");
+    let temp_views_count: i64 = multi_mzdb.db.query_row("SELECT count(*) FROM temp.sqlite_master", [], |row| row.get(0)).location(here!())?;
+    assert_eq!(temp_views_count, 0, "temporary views should be dropped");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_diagnostics_tests() -> Result<()>  {
+    use crate::diagnostics::*;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let mut diagnostics = Diagnostics::new();
+    collect_diagnostics(&db, &entity_cache, &mut diagnostics, None, None).location(here!())?;
+    assert!(diagnostics.is_empty(), "unexpected diagnostics: {:?}", diagnostics);
+
+    diagnostics.push(DiagnosticKind::INCONSISTENT_PEAK_COUNT, "test".to_string());
+    assert_eq!(diagnostics.take_diagnostics().len(), 1, "invalid number of diagnostics");
+    assert!(diagnostics.is_empty(), "diagnostics should be cleared once taken");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_progress_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let mut progress_updates = Vec::new();
+    let mut progress_observer = |processed_count: usize, total_count: usize| progress_updates.push((processed_count, total_count));
+
+    crate::iterator::for_each_spectrum_with_progress(&db, &entity_cache, Some(1), &mut progress_observer, None, |_spectrum| Ok(())).location(here!())?;
+
+    assert_eq!(progress_updates.len(), 158, "one progress update per MS1 spectrum is expected");
+    assert_eq!(progress_updates.last(), Some(&(158, 158)), "invalid last progress update");
+
+    // Progress of the other long running operations
+    let mut progress_updates = Vec::new();
+    let mut diagnostics = crate::diagnostics::Diagnostics::new();
+    crate::diagnostics::collect_diagnostics(
+        &db, &entity_cache, &mut diagnostics, Some(&mut |processed_count: usize, total_count: usize| progress_updates.push((processed_count, total_count))), None
+    ).location(here!())?;
+    let (last_processed_count, total_count) = *progress_updates.last().unwrap();
+    assert!(total_count > 1193 && last_processed_count == total_count, "invalid last progress update: {:?}", progress_updates.last());
+    assert!(progress_updates.windows(2).all(|updates| updates[0].0 < updates[1].0), "progress updates should be increasing");
+
+    let mut progress_updates = Vec::new();
+    crate::corpus::check_file(
+        "./data/OVEMB150205_12.mzDB", Some(&mut |processed_count: usize, total_count: usize| progress_updates.push((processed_count, total_count))), None
+    ).location(here!())?;
+    assert_eq!(progress_updates, (1..=6).map(|step_idx| (step_idx, 6)).collect::<Vec<_>>(), "one progress update per step is expected");
+
+    let mut progress_updates = Vec::new();
+    crate::corpus::check_file(
+        "./data/missing.mzDB", Some(&mut |processed_count: usize, total_count: usize| progress_updates.push((processed_count, total_count))), None
+    ).location(here!())?;
+    assert_eq!(progress_updates, vec![(1, 6), (6, 6)], "the steps which are not run should be reported as completed");
+
+    let mut progress_updates = Vec::new();
+    let output_dir = tempfile::tempdir()?;
+    let mzml_path = output_dir.path().join("msms.mzML");
+    crate::export::write_msms_mzml(
+        &db, &entity_cache, &[17, 21], mzml_path.to_str().unwrap(),
+        Some(&mut |processed_count: usize, total_count: usize| progress_updates.push((processed_count, total_count))), None
+    ).location(here!())?;
+    assert_eq!(progress_updates, vec![(1, 2), (2, 2)]);
+
+    // Cancel the iteration after the 10th spectrum
+    let cancellation_token = crate::progress::CancellationToken::new();
+    let mut iterated_count = 0;
+    let iteration_res = crate::iterator::for_each_spectrum_with_progress(
+        &db, &entity_cache, None, &mut |_processed_count: usize, _total_count: usize| {}, Some(&cancellation_token),
+        |_spectrum| {
+            iterated_count += 1;
+            if iterated_count == 10 {
+                cancellation_token.cancel();
+            }
+            Ok(())
+        }
+    );
+
+    assert_eq!(iterated_count, 10, "the iteration should stop once cancelled");
+    assert!(iteration_res.unwrap_err().downcast_ref::<crate::progress::Cancelled>().is_some(), "a Cancelled error is expected");
+
+    // The other long running operations stop as well
+    let is_cancelled = |res: Result<()>| res.is_err_and(|e| e.chain().any(|cause| cause.is::<crate::progress::Cancelled>()));
+    let mut diagnostics = crate::diagnostics::Diagnostics::new();
+    assert!(is_cancelled(crate::diagnostics::collect_diagnostics(&db, &entity_cache, &mut diagnostics, None, Some(&cancellation_token))));
+    assert!(is_cancelled(crate::qc::compute_run_summary(&db, &entity_cache, Some(&cancellation_token)).map(|_| ())));
+    assert!(is_cancelled(crate::corpus::check_file("./data/OVEMB150205_12.mzDB", None, Some(&cancellation_token)).map(|_| ())));
+
+    assert!(is_cancelled(crate::export::write_msms_mzml(&db, &entity_cache, &[17], mzml_path.to_str().unwrap(), None, Some(&cancellation_token)).map(|_| ())));
+
+    let file_path = _copy_test_file().location(here!())?;
+    assert!(is_cancelled(crate::maintenance::build_fts_index(file_path.to_str().unwrap(), None, Some(&cancellation_token)).map(|_| ())));
+    let fts_table_count: i64 = Connection::open(&file_path)?.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE name = ?", [crate::queries::SPECTRUM_FTS_TABLE_NAME], |row| row.get(0)
+    )?;
+    assert_eq!(fts_table_count, 0, "a cancelled maintenance operation should not modify the file");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_bb_sizes_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let expected_bb_sizes = BBSizes {
+        ms1_bb_mz_width: 5.0,
+        ms1_bb_time_width: 15.0,
+        msn_bb_mz_width: 10000.0,
+        msn_bb_time_width: 0.0,
+    };
+
+    assert_eq!(crate::mzdb::get_declared_bb_sizes(&db).location(here!())?, Some(expected_bb_sizes), "invalid declared BB sizes");
+    assert_eq!(crate::mzdb::infer_bb_sizes(&db).location(here!())?, expected_bb_sizes, "invalid inferred BB sizes");
+    assert_eq!(crate::mzdb::get_bb_sizes(&db).location(here!())?, expected_bb_sizes, "invalid BB sizes");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_usi_tests() -> Result<()>  {
+    use crate::usi::*;
+
+    let usi = parse_usi("mzspec:PXD000561:Adult_Frontalcortex_bRP_Elite_85_f09:scan:17555:VLHPLEGAVVIIFK/2").location(here!())?;
+    assert_eq!(usi.collection, "PXD000561");
+    assert_eq!(usi.ms_run_name, "Adult_Frontalcortex_bRP_Elite_85_f09");
+    assert_eq!(usi.index_type, UsiIndexType::SCAN);
+    assert_eq!(usi.index, "17555");
+    assert_eq!(usi.interpretation.as_deref(), Some("VLHPLEGAVVIIFK/2"));
+
+    assert!(parse_usi("mzspec:PXD000561:run:unknown:1").is_err(), "unknown index type should be rejected");
+    assert!(parse_usi("PXD000561:run:scan:1").is_err(), "missing prefix should be rejected");
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let spectrum = get_spectrum_by_usi(&db, &entity_cache, "mzspec:PXD000000:OVEMB150205_12:scan:17").location(here!())?;
+    assert_eq!(spectrum.header.id, 17, "invalid spectrum resolved from scan number");
+
+    let usi = parse_usi("mzspec:PXD000000:OVEMB150205_12:nativeId:controllerType=0 controllerNumber=1 scan=16").location(here!())?;
+    assert_eq!(find_spectrum_id(&db, &entity_cache, &usi).location(here!())?, Some(16), "invalid spectrum resolved from native id");
+
+    let usi = parse_usi("mzspec:PXD000000:OVEMB150205_12:index:0").location(here!())?;
+    assert_eq!(find_spectrum_id(&db, &entity_cache, &usi).location(here!())?, Some(1), "invalid spectrum resolved from index");
+
+    assert!(get_spectrum_by_usi(&db, &entity_cache, "mzspec:PXD000000:other_run:scan:17").is_err(), "run name should be checked");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_msms_mzml_export_tests() -> Result<()>  {
+    use crate::export::write_msms_mzml;
+    use quick_xml::events::Event;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let temp_dir = tempfile::tempdir().location(here!())?;
+    let file_path = temp_dir.path().join("msms_export.mzML");
+    let file_path_str = file_path.to_str().unwrap();
+
+    assert!(write_msms_mzml(&db, &entity_cache, &[17, 1], file_path_str, None, None).is_err(), "MS1 spectra can't be exported");
+    assert_eq!(write_msms_mzml(&db, &entity_cache, &[17, 21], file_path_str, None, None).location(here!())?, 2);
+    let mzml = std::fs::read_to_string(&file_path).location(here!())?;
+
+    assert!(mzml.contains("<spectrumList count=\"2\""));
+    assert!(mzml.contains("id=\"controllerType=0 controllerNumber=1 scan=17\""));
+    assert!(mzml.contains("<instrumentConfiguration id=\"IC2\">"), "instrument configurations referenced by the scans should be written");
+    assert!(mzml.contains("value=\"475.8724\""), "the original precursor list should be preserved");
+
+    // Check that the document is well-formed and that the peaks can be decoded
+    let mut reader = quick_xml::Reader::from_str(&mzml);
+    let mut buf = Vec::new();
+    let mut binary_arrays = Vec::new();
+    let mut in_binary = false;
+    loop {
+        match reader.read_event(&mut buf).location(here!())? {
+            Event::Start(ref e) if e.name() == b"binary" => in_binary = true,
+            Event::End(ref e) if e.name() == b"binary" => in_binary = false,
+            Event::Text(ref e) if in_binary => binary_arrays.push(base64::decode(e.unescaped()?.as_ref())?),
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    let spectrum = get_spectrum(&db, 17, &entity_cache).location(here!())?;
+    assert_eq!(binary_arrays.len(), 4);
+    assert_eq!(binary_arrays[0].len(), spectrum.data.peak_count * 8);
+    assert_eq!(f64::from_le_bytes(binary_arrays[0][..8].try_into()?), spectrum.data.mz_array[0]);
+    assert_eq!(f32::from_le_bytes(binary_arrays[1][..4].try_into()?), spectrum.data.intensity_array[0]);
+
+
+    Ok(())
+}
+
+#[test]
+pub fn run_maintenance_tests() -> Result<()>  {
+    use crate::maintenance::recompute_spectrum_summaries;
+
+    // Work on a copy since the file is modified in place
+    let file_path = _copy_test_file().location(here!())?;
+    let file_path_str = file_path.to_str().unwrap();
+
+    let stored_tic_of = |spectrum_id: i64| -> Result<f32> {
+        let db = Connection::open(&file_path).location(here!())?;
+        Ok(db.query_row("SELECT tic FROM spectrum WHERE id = ?", [spectrum_id], |row| row.get(0))?)
+    };
+    let stored_tic = stored_tic_of(100)?;
+
+    {
+        let db = Connection::open(&file_path).location(here!())?;
+        db.execute("UPDATE spectrum SET tic = 0, base_peak_mz = 0, base_peak_intensity = 0 WHERE id <= 20", []).location(here!())?;
+        // Non-zero values are not replaced, even if they differ from the data
+        db.execute("UPDATE spectrum SET tic = tic * 2 WHERE id = 100", []).location(here!())?;
+    }
+
+    let modified_count = recompute_spectrum_summaries(file_path_str, None, None).location(here!())?;
+    assert_eq!(modified_count, 20, "only the zeroed summaries should have been recomputed");
+    assert_eq!(stored_tic_of(100)?, stored_tic * 2.0, "non-zero values should be kept");
+
+    // A second run shouldn't modify anything
+    assert_eq!(recompute_spectrum_summaries(file_path_str, None, None).location(here!())?, 0);
+
+    let db = Connection::open(&file_path).location(here!())?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    let spectrum = get_spectrum(&db, 17, &entity_cache).location(here!())?;
+    let header = &spectrum.header;
+
+    let expected_tic: f64 = spectrum.data.intensity_array.iter().map(|intensity| *intensity as f64).sum();
+    assert!((header.tic as f64 - expected_tic).abs() <= expected_tic * 1e-6, "invalid recomputed TIC");
+    assert_eq!(header.base_peak_intensity, spectrum.data.intensity_array.iter().cloned().fold(0.0, f32::max));
+    assert!(spectrum.data.mz_array.contains(&header.base_peak_mz), "base peak m/z should be one of the spectrum m/z values");
+    assert_eq!(header.peaks_count, spectrum.data.peak_count as i64);
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_lossless_tests() -> Result<()>  {
+    use crate::mzdb::is_lossless;
+
+    // The test file doesn't declare the flag and stores 32-bit intensities
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    assert!(!is_lossless(&db).location(here!())?);
+
+    let db = Connection::open_in_memory()?;
+    db.execute("CREATE TABLE mzdb (param_tree TEXT)", [])?;
+    db.execute(r#"INSERT INTO mzdb VALUES ('<params><userParams><userParam name="is_lossless" value="true" type="xsd:boolean"/></userParams></params>')"#, [])?;
+    assert!(is_lossless(&db).location(here!())?);
+
+    db.execute(r#"UPDATE mzdb SET param_tree = '<params><userParams><userParam name="is_loss_less" value="0"/></userParams></params>'"#, [])?;
+    assert!(!is_lossless(&db).location(here!())?);
+
+    db.execute(r#"UPDATE mzdb SET param_tree = '<params><userParams><userParam name="is_lossless" value="maybe"/></userParams></params>'"#, [])?;
+    assert!(is_lossless(&db).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_live_reading_tests() -> Result<()>  {
+    use crate::live::*;
+    use crate::progress::CancellationToken;
+    use std::time::Duration;
+
+    // Simulate a file being written by removing its last bounding boxes and spectrum headers, the latter being stored in tmp_spectrum
+    let file_path = _copy_test_file().location(here!())?;
+    let file_path_str = file_path.to_str().unwrap();
+
+    let writer_db = Connection::open(&file_path).location(here!())?;
+    writer_db.execute_batch("PRAGMA foreign_keys = OFF")?; // bounding_box references the missing tmp_spectrum table
+    writer_db.execute("CREATE TABLE pending_bounding_box AS SELECT * FROM bounding_box WHERE last_spectrum_id > 1100", [])?;
+    writer_db.execute("DELETE FROM bounding_box WHERE last_spectrum_id > 1100", [])?;
+    writer_db.execute_batch(
+        "ALTER TABLE spectrum RENAME TO tmp_spectrum; \
+        CREATE TABLE pending_spectrum AS SELECT * FROM tmp_spectrum WHERE id > 1100; \
+        DELETE FROM tmp_spectrum WHERE id > 1100;"
+    )?;
+
+    // A locked file can't be opened once the retries are exhausted
+    writer_db.execute_batch("BEGIN EXCLUSIVE")?;
+    let options = OpenOptions { busy_timeout: Duration::from_millis(10), max_retries: 2, retry_delay: Duration::from_millis(10) };
+    assert!(open_with_options(file_path_str, &options).is_err());
+    writer_db.execute_batch("COMMIT")?;
+
+    let db = open_with_options(file_path_str, &options).location(here!())?;
+    let tail_options = TailOptions { poll_interval: Duration::from_millis(10), idle_timeout: Some(Duration::from_millis(100)), max_read_retries: 2 };
+    let mut tail = tail_spectra(&db, 1095, tail_options.clone(), CancellationToken::new());
+
+    let spectrum_ids: Vec<i64> = tail.by_ref().map(|spectrum_res| spectrum_res.map(|s| s.header.id)).collect::<Result<_>>()?;
+    assert_eq!(spectrum_ids, (1095..=1100).collect::<Vec<i64>>(), "the iteration should stop at the last written spectrum");
+
+    // The spectra are not available until their headers are written
+    let first_pending_bb_spectrum_id: i64 = writer_db.query_row("SELECT min(first_spectrum_id) FROM pending_bounding_box", [], |row| row.get(0))?;
+    writer_db.execute("INSERT INTO bounding_box SELECT * FROM pending_bounding_box WHERE first_spectrum_id > ?", [first_pending_bb_spectrum_id])?;
+    assert!(tail.next().is_none(), "spectra without header should not be yielded");
+
+    // A spectrum whose bounding boxes are not written yet can't be read, but it is not skipped
+    writer_db.execute("INSERT INTO tmp_spectrum SELECT * FROM pending_spectrum", [])?;
+    assert!(tail.next().unwrap().is_err(), "an error should be returned once the read retries are exhausted");
+
+    writer_db.execute("INSERT INTO bounding_box SELECT * FROM pending_bounding_box WHERE first_spectrum_id = ?", [first_pending_bb_spectrum_id])?;
+    let spectrum_ids: Vec<i64> = tail.by_ref().map(|spectrum_res| spectrum_res.map(|s| s.header.id)).collect::<Result<_>>()?;
+    assert_eq!(spectrum_ids, (1101..=1193).collect::<Vec<i64>>(), "new spectra should be yielded once written");
+
+    // The spectrum table is read once the file is finalized
+    writer_db.execute_batch("ALTER TABLE tmp_spectrum RENAME TO spectrum")?;
+    let spectrum_ids: Vec<i64> = tail_spectra(&db, 1190, tail_options.clone(), CancellationToken::new())
+        .map(|spectrum_res| spectrum_res.map(|s| s.header.id)).collect::<Result<_>>()?;
+    assert_eq!(spectrum_ids, (1190..=1193).collect::<Vec<i64>>());
+
+    let cancellation_token = CancellationToken::new();
+    cancellation_token.cancel();
+    assert!(tail_spectra(&db, 2000, TailOptions::default(), cancellation_token).next().is_none());
+
+    drop(db);
+    drop(writer_db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_thumbnail_tests() -> Result<()>  {
+    use crate::maintenance::generate_thumbnails;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    assert!(get_tic_thumbnail(&db).location(here!())?.is_none(), "the test file has no thumbnail table");
+    assert!(get_ion_map_thumbnail(&db).location(here!())?.is_none());
+
+    let max_ms1_tic: f32 = db.query_row("SELECT max(tic) FROM spectrum WHERE ms_level = 1", [], |row| row.get(0))?;
+
+    // Work on a copy since the file is modified in place
+    let file_path = _copy_test_file().location(here!())?;
+
+    generate_thumbnails(file_path.to_str().unwrap(), 50, 40, 30, None, None).location(here!())?;
+
+    let db = Connection::open(&file_path).location(here!())?;
+    let tic_thumbnail = get_tic_thumbnail(&db).location(here!())?.unwrap();
+    assert!(!tic_thumbnail.times.is_empty() && tic_thumbnail.times.len() <= 50);
+    assert_eq!(tic_thumbnail.times.len(), tic_thumbnail.intensities.len());
+    assert!(tic_thumbnail.times.windows(2).all(|w| w[0] < w[1]), "TIC times should be sorted");
+    assert_eq!(tic_thumbnail.intensities.iter().cloned().fold(0.0, f32::max), max_ms1_tic, "the TIC apex should be preserved");
+
+    let ion_map = get_ion_map_thumbnail(&db).location(here!())?.unwrap();
+    assert_eq!((ion_map.width, ion_map.height), (40, 30));
+    assert_eq!(ion_map.pixels.len(), 40 * 30);
+    assert_eq!(ion_map.pixels.iter().max(), Some(&255));
+    assert!(ion_map.get_pixel(40, 0).is_none());
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_time_axis_tests() -> Result<()>  {
+    use crate::time_axis::*;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    let headers = &entity_cache.spectrum_headers;
+
+    let time_axis = CycleTimeAxis::new(headers).location(here!())?;
+    assert_eq!(time_axis.first_cycle, headers[0].cycle);
+    assert_eq!(time_axis.last_cycle(), headers.last().unwrap().cycle);
+    assert!(time_axis.injected_cycles.is_empty(), "no cycle should be missing");
+
+    let spectrum_17_idx = headers.iter().position(|sh| sh.id == 17).unwrap();
+    let cycle = time_axis.get_cycle_by_spectrum_idx(spectrum_17_idx).unwrap();
+    assert_eq!(cycle, headers[spectrum_17_idx].cycle, "invalid cycle of spectrum 17");
+
+    let idx_range = time_axis.get_spectrum_idx_range(cycle).unwrap();
+    assert!(idx_range.contains(&spectrum_17_idx), "spectrum 17 should belong to its cycle range");
+    assert_eq!(time_axis.get_cycle_time(cycle), Some(headers[idx_range.start].time));
+    assert_eq!(time_axis.find_nearest_cycle(headers[idx_range.start].time), cycle);
+    assert_eq!(time_axis.find_nearest_cycle(-1.0), time_axis.first_cycle);
+    assert_eq!(time_axis.find_nearest_cycle(f32::MAX), time_axis.last_cycle());
+
+    // Remove a cycle and check that it is injected with an interpolated time
+    let missing_cycle = time_axis.first_cycle + 5;
+    let gapped_headers: Vec<SpectrumHeader> = headers.iter().filter(|sh| sh.cycle != missing_cycle).cloned().collect();
+    let gapped_time_axis = CycleTimeAxis::new(&gapped_headers).location(here!())?;
+    assert_eq!(gapped_time_axis.injected_cycles, vec![missing_cycle]);
+    assert!(gapped_time_axis.get_spectrum_idx_range(missing_cycle).unwrap().is_empty());
+
+    let injected_time = gapped_time_axis.get_cycle_time(missing_cycle).unwrap();
+    assert!(injected_time > time_axis.get_cycle_time(missing_cycle - 1).unwrap());
+    assert!(injected_time < time_axis.get_cycle_time(missing_cycle + 1).unwrap());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_lenient_xml_parsing_tests() -> Result<()>  {
+    use crate::xml::*;
+
+    // Namespaced fragment, as found in some vendor-converted files
+    let namespaced_xml = r#"<ms:params xmlns:ms="http://psi.hupo.org/ms/mzml"><ms:cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2"/></ms:params>"#;
+    assert!(parse_cv_params(namespaced_xml).location(here!())?.is_empty(), "namespaced cvParam shouldn't be matched in strict mode");
+    let cv_params = parse_cv_params_with_mode(namespaced_xml, XmlParsingMode::LENIENT).location(here!())?;
+    assert_eq!(cv_params.len(), 1);
+    assert_eq!(cv_params[0].accession, "MS:1000511");
+    assert_eq!(cv_params[0].value, "2");
+
+    // Undeclared entity in an attribute value
+    let entity_xml = r#"<params><userParam name="instrument&nbsp;model" value="Q &amp; A &#233;" type="xsd:string"/></params>"#;
+    assert!(parse_user_params(entity_xml).is_err(), "undeclared entity should be rejected in strict mode");
+    let user_params = parse_user_params_with_mode(entity_xml, XmlParsingMode::LENIENT).location(here!())?;
+    assert_eq!(user_params.len(), 1);
+    assert_eq!(user_params[0].name, "instrument&nbsp;model");
+    assert_eq!(user_params[0].value, "Q & A é");
+
+    // Stray text and mismatched end tag
+    let stray_xml = r#"<params>stray text<cvParam cvRef="MS" accession="MS:1000130" name="positive scan" value=""/></param>trailing"#;
+    assert!(parse_cv_params(stray_xml).is_err(), "mismatched end tag should be rejected in strict mode");
+    let cv_params = parse_cv_params_with_mode(stray_xml, XmlParsingMode::LENIENT).location(here!())?;
+    assert_eq!(cv_params.len(), 1);
+    assert_eq!(cv_params[0].accession, "MS:1000130");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_spectrum_at_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let ms2_header = &entity_cache.spectrum_headers[16];
+
+    // Spectrum 17 is a MS2 spectrum surrounded by MS1 spectra 16 and 18
+    let ms1_spectrum = get_spectrum_at(&db, ms2_header.time_f64, 1, &entity_cache).location(here!())?.unwrap();
+    assert!(ms1_spectrum.header.id == 16 || ms1_spectrum.header.id == 18);
+    assert_eq!(ms1_spectrum.header.ms_level, 1);
+
+    let ms2_spectrum = get_spectrum_at(&db, ms2_header.time_f64 + 0.001, 2, &entity_cache).location(here!())?.unwrap();
+    assert_eq!(ms2_spectrum.header.id, 17);
+
+    let ms2_spectrum = get_ms2_spectrum_at(&db, 0.0, 475.8724, &MzTolerance::PPM(10.0), &entity_cache).location(here!())?.unwrap();
+    assert_eq!(ms2_spectrum.header.id, 17, "spectrum 17 should be the first MS2 spectrum of precursor 475.8724");
+    assert_eq!(ms2_spectrum.data, get_spectrum(&db, 17, &entity_cache).location(here!())?.data);
+
+    assert!(get_spectrum_at(&db, 0.0, 3, &entity_cache).location(here!())?.is_none());
+    assert!(get_ms2_spectrum_at(&db, 0.0, 5000.0, &MzTolerance::DA(0.01), &entity_cache).location(here!())?.is_none());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_spectrum_profile_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    for spectrum_id in [1, 17] {
+        let (spectrum, profile) = profile_spectrum(&db, spectrum_id, &entity_cache).location(here!())?;
+        assert_eq!(spectrum, get_spectrum(&db, spectrum_id, &entity_cache).location(here!())?);
+
+        let bb_first_spectrum_id = spectrum.header.bb_first_spectrum_id;
+        let (bb_count, bb_bytes): (i64, i64) = db.query_row(
+            "SELECT count(id), sum(length(data)) FROM bounding_box WHERE first_spectrum_id = ?",
+            [bb_first_spectrum_id],
+            |row| rusqlite::Result::Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        assert_eq!(profile.spectrum_id, spectrum_id);
+        assert_eq!(profile.bb_count, bb_count as usize);
+        assert_eq!(profile.bb_bytes_read, bb_bytes as usize);
+        assert_eq!(profile.peaks_count, spectrum.data.peak_count);
+        assert!(profile.bb_row_spectra_count >= 1);
+        assert!(profile.slices_count >= profile.bb_count);
+        assert!(profile.non_empty_slices_count <= profile.bb_count);
+    }
+
+    assert!(profile_spectrum(&db, 100000, &entity_cache).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_signal_search_tests() -> Result<()>  {
+    use crate::search::find_spectra_with_signal;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let ms1_header = &entity_cache.spectrum_headers[0];
+    let (min_mz, max_mz) = MzTolerance::PPM(5.0).mz_range(ms1_header.base_peak_mz);
+
+    let matching_spectra = find_spectra_with_signal(&db, &entity_cache, min_mz, max_mz, 0.0, 1, None).location(here!())?;
+    assert!(matching_spectra.contains(&(ms1_header.id, ms1_header.base_peak_intensity)), "spectrum 1 should match its own base peak");
+    assert!(matching_spectra.iter().all(|(id, _)| entity_cache.spectrum_headers[(id - 1) as usize].ms_level == 1), "only MS1 spectra should match");
+    assert!(matching_spectra.windows(2).all(|w| w[0].0 < w[1].0), "matching spectra should be sorted by id");
+
+    let rt_range = (ms1_header.time_f64, ms1_header.time_f64);
+    let matching_spectra = find_spectra_with_signal(&db, &entity_cache, min_mz, max_mz, ms1_header.base_peak_intensity, 1, Some(rt_range)).location(here!())?;
+    assert_eq!(matching_spectra, vec![(ms1_header.id, ms1_header.base_peak_intensity)]);
+
+    // MS2 search falls back to a full scan since the MSn R-tree is empty
+    let spectrum = get_spectrum(&db, 17, &entity_cache).location(here!())?;
+    let base_peak = spectrum.data.base_peak().unwrap();
+    let matching_spectra = find_spectra_with_signal(&db, &entity_cache, base_peak.mz - 0.001, base_peak.mz + 0.001, base_peak.intensity, 2, None).location(here!())?;
+    assert!(matching_spectra.contains(&(17, base_peak.intensity)));
+
+    Ok(())
+}
+
+#[test]
+pub fn run_retention_time_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let spectrum_headers = crate::mzdb::get_spectrum_headers(&db).location(here!())?;
+
+    let ms2_header = &spectrum_headers[16];
+    assert_eq!(ms2_header.time_f64, 7.65179967880249);
+    assert_eq!(ms2_header.time, ms2_header.time_f64 as f32);
+
+    // Full and light headers share the same source
+    assert_eq!(ms2_header.retention_time(), ms2_header.time_f64);
+    let light_headers = crate::mzdb::get_light_spectrum_headers(&db).location(here!())?;
+    assert_eq!(light_headers[16].retention_time(), ms2_header.retention_time());
+
+    // Bounds matching exactly a spectrum time must include it
+    let mut entity_cache = create_entity_cache(&db).location(here!())?;
+    let spectrum_ids = list_spectrum_ids_in_time_range(&db, &entity_cache, ms2_header.time_f64, ms2_header.time_f64, None).location(here!())?;
+    assert_eq!(spectrum_ids, vec![ms2_header.id]);
+
+    let ms1_ids = list_spectrum_ids_in_time_range(&db, &entity_cache, 0.0, ms2_header.time_f64, Some(1)).location(here!())?;
+    assert!(!ms1_ids.is_empty());
+    assert!(ms1_ids.iter().all(|id| spectrum_headers[(*id - 1) as usize].ms_level == 1));
+
+    // The RT offset is applied
+    crate::mzdb::set_rt_offset(&mut entity_cache, 10.0);
+    let shifted_ms2_header = &entity_cache.spectrum_headers[16];
+    assert!((shifted_ms2_header.retention_time() - (ms2_header.time_f64 + 10.0)).abs() < 1e-9);
+    let spectrum_ids = list_spectrum_ids_in_time_range(
+        &db, &entity_cache, shifted_ms2_header.time_f64, shifted_ms2_header.time_f64, None
+    ).location(here!())?;
+    assert_eq!(spectrum_ids, vec![ms2_header.id]);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_corpus_check_tests() -> Result<()>  {
+    use crate::corpus::*;
+
+    let report = check_file("./data/OVEMB150205_12.mzDB", None, None).location(here!())?;
+    assert!(report.is_ok(), "failed steps: {:?}", report.failed_steps());
+    assert_eq!(report.step_results.len(), 6);
+    assert_eq!(report.step_results[0].step, CorpusStep::OPEN);
+
+    let missing_file_report = check_file("./data/missing.mzDB", None, None).location(here!())?;
+    assert!(!missing_file_report.is_ok());
+    assert_eq!(missing_file_report.failed_steps()[0].step, CorpusStep::OPEN);
+    assert_eq!(missing_file_report.step_results.len(), 1);
+
+    // A chromatogram whose data points can't be decoded (truncated point)
+    let file_path = _copy_test_file().location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute_batch("PRAGMA foreign_keys = OFF").location(here!())?;
+    db.execute(
+        "INSERT INTO chromatogram (id, name, data_points, param_tree, run_id, data_encoding_id) VALUES (1, 'TIC', ?, '<params />', 1, 1)",
+        rusqlite::params![vec![0u8; 10]]
+    ).location(here!())?;
+    drop(db);
+
+    let corrupted_file_report = check_file(file_path.to_str().unwrap(), None, None).location(here!())?;
+    let failed_steps: Vec<CorpusStep> = corrupted_file_report.failed_steps().iter().map(|step_result| step_result.step).collect();
+    assert_eq!(failed_steps, vec![CorpusStep::CHROMATOGRAMS]);
+
+    Ok(())
+}
+
+#[cfg(feature = "compressed")]
+#[test]
+pub fn run_compressed_container_tests() -> Result<()>  {
+    use crate::container::*;
+    use std::io::Write;
+
+    let file_path = "./data/OVEMB150205_12.mzDB";
+    assert_eq!(detect_container_format(file_path).location(here!())?, ContainerFormat::SQLITE);
+
+    let mut gz_file = tempfile::Builder::new().suffix(".mzDB.gz").tempfile()?;
+    let mut encoder = flate2::write::GzEncoder::new(gz_file.as_file_mut(), flate2::Compression::fast());
+    encoder.write_all(&std::fs::read(file_path)?)?;
+    encoder.finish()?;
+
+    let gz_path = gz_file.path().to_str().unwrap();
+    assert_eq!(detect_container_format(gz_path).location(here!())?, ContainerFormat::GZIP);
+
+    let auto_opened_db = open_auto(gz_path).location(here!())?;
+    assert_eq!(auto_opened_db.container_format, ContainerFormat::GZIP);
+    assert_eq!(get_table_records_count(&auto_opened_db.db, "spectrum").location(here!())?, Some(1193), "invalid number of spectra");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_encryption_tests() -> Result<()>  {
+    use crate::encryption::*;
+
+    let temp_dir = tempfile::tempdir().location(here!())?;
+    let file_path = temp_dir.path().join("encrypted.mzDB");
+    let file_path_str = file_path.to_str().unwrap();
+
+    if cfg!(feature = "sqlcipher") {
+        encrypt_file("./data/OVEMB150205_12.mzDB", file_path_str, "secret").location(here!())?;
+        assert!(encrypt_file("./data/OVEMB150205_12.mzDB", file_path_str, "secret").is_err(), "existing files shouldn't be overwritten");
+
+        let db = open_encrypted(file_path_str, "secret").location(here!())?;
+        assert_eq!(get_table_records_count_with_mode(&db, "spectrum", CountMode::EXACT).location(here!())?, Some(1193));
+        drop(db);
+
+        assert!(open_encrypted(file_path_str, "wrong key").is_err());
+        assert!(Connection::open(&file_path)?.query_row("SELECT count(*) FROM spectrum", [], |row| row.get::<_, i64>(0)).is_err());
+    } else {
+        let error = open_encrypted("./data/OVEMB150205_12.mzDB", "secret").unwrap_err();
+        assert!(error.to_string().contains("sqlcipher feature"));
+        assert!(encrypt_file("./data/OVEMB150205_12.mzDB", file_path_str, "secret").is_err());
+        assert!(!file_path.exists());
+    }
+
+    Ok(())
+}
+
+#[test]
+pub fn run_shared_reader_tests() -> Result<()>  {
+    use crate::ffi_support::SharedReader;
+
+    let reader = SharedReader::open("./data/OVEMB150205_12.mzDB").location(here!())?;
+    let reader_clone = reader.clone();
+
+    let spectrum = std::thread::spawn(move || {
+        reader_clone.with_connection(|db, entity_cache| get_spectrum(db, 17, entity_cache))
+    }).join().unwrap().location(here!())?;
+    assert_eq!(spectrum.header.id, 17);
+
+    let db = reader.connection().location(here!())?;
+    assert_eq!(get_mzdb_version(&db).location(here!())?, get_mzdb_version(&Connection::open("./data/OVEMB150205_12.mzDB")?)?);
+
+    // A reader in use must be reported instead of deadlocking
+    assert!(reader.try_connection().is_err(), "a reader in use should not provide its connection");
+    assert!(reader.try_close().is_err(), "a reader in use should not be closed");
+    assert!(!reader.is_closed(), "a reader in use is open");
+    drop(db);
+    assert!(reader.try_connection().is_ok(), "a released reader should provide its connection");
+
+    reader.close().location(here!())?;
+    assert!(reader.is_closed());
+    assert!(reader.connection().is_err(), "a closed reader should not provide its connection");
+    reader.close().location(here!())?;
+
+    // Metadata scalars are memoized until refreshed (work on a copy since the file is modified)
+    let file_path = _copy_test_file().location(here!())?;
+    let reader = SharedReader::open(file_path.to_str().unwrap()).location(here!())?;
+    let last_time = reader.get_last_time().location(here!())?;
+    assert_eq!(last_time, get_last_time(&Connection::open(&file_path)?)?);
+    assert_eq!(reader.get_max_ms_level().location(here!())?, Some(2));
+    assert!(reader.get_last_cycle_number().location(here!())?.is_some());
+
+    Connection::open(&file_path)?.execute("UPDATE spectrum SET time = time + 60 WHERE id = 1193", []).location(here!())?;
+
+    let db = reader.connection().location(here!())?;
+    assert_eq!(reader.get_last_time().location(here!())?, last_time, "the memoized value should be returned, even when the reader is in use");
+    drop(db);
+
+    reader.refresh().location(here!())?;
+    assert_eq!(reader.get_last_time().location(here!())?, last_time.map(|time| time + 60.0));
+
+    Ok(())
+}
+
+#[test]
+pub fn run_fts_tests() -> Result<()>  {
+    use crate::maintenance::build_fts_index;
+    use crate::search::query_headers_fts;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    assert!(query_headers_fts(&db, "ms2").is_err(), "the test file has no full-text index");
+    assert!(!table_exists(&db, SPECTRUM_FTS_TABLE_NAME).location(here!())?);
+    assert!(table_exists(&db, "spectrum").location(here!())?);
+    let cid30_count: usize = db.query_row("SELECT count(*) FROM spectrum WHERE scan_list LIKE '%@cid30.00 %'", [], |row| row.get(0))?;
+
+    // Work on a copy since the file is modified in place
+    let file_path = _copy_test_file().location(here!())?;
+
+    assert_eq!(build_fts_index(file_path.to_str().unwrap(), None, None).location(here!())?, 1193);
+
+    let db = Connection::open(&file_path).location(here!())?;
+    assert_eq!(query_headers_fts(&db, "scan=17").location(here!())?, vec![17], "titles should be indexed");
+    assert_eq!(query_headers_fts(&db, "Full ms2").location(here!())?.len(), 1035, "filter strings should be indexed");
+    assert_eq!(query_headers_fts(&db, "@cid30.00").location(here!())?.len(), cid30_count);
+    assert_eq!(query_headers_fts(&db, "positive scan").location(here!())?.len(), 1193, "flag cvParams should be indexed");
+    assert!(query_headers_fts(&db, "\"unknown\" OR").location(here!())?.is_empty(), "FTS5 syntax should not be interpreted");
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_polarity_tests() -> Result<()>  {
+    use crate::iterator::for_each_spectrum_with_polarity;
+    use crate::mzdb::create_light_entity_cache;
+
+    // Simulate a polarity switching experiment by turning the scans of a few cycles into negative scans
+    let file_path = _copy_test_file().location(here!())?;
+
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute(
+        "UPDATE spectrum SET param_tree = replace(replace(param_tree, 'MS:1000130', 'MS:1000129'), 'positive scan', 'negative scan') WHERE cycle <= 2",
+        [],
+    )?;
+    let negative_ids: Vec<i64> = db.prepare("SELECT id FROM spectrum WHERE cycle <= 2 ORDER BY id")?
+        .query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+    assert!(!negative_ids.is_empty());
+
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    assert_eq!(entity_cache.spectrum_headers[0].polarity(&db)?, Polarity::NEGATIVE);
+    assert_eq!(entity_cache.spectrum_headers.last().unwrap().polarity(&db)?, Polarity::POSITIVE);
+
+    let light_entity_cache = create_light_entity_cache(&db).location(here!())?;
+    assert_eq!(light_entity_cache.spectrum_headers[0].polarity(&db)?, Polarity::NEGATIVE, "the param_tree of light headers should be fetched");
+
+    for cache in [&entity_cache, &light_entity_cache] {
+        let mut iterated_ids = Vec::new();
+        for_each_spectrum_with_polarity(&db, cache, None, Polarity::NEGATIVE, |s| {
+            iterated_ids.push(s.header.id);
+            Ok(())
+        }).location(here!())?;
+        assert_eq!(iterated_ids, negative_ids);
+
+        let mut positive_ms1_count = 0;
+        for_each_spectrum_with_polarity(&db, cache, Some(1), Polarity::POSITIVE, |s| {
+            assert_eq!(s.header.ms_level, 1);
+            positive_ms1_count += 1;
+            Ok(())
+        }).location(here!())?;
+        assert_eq!(positive_ms1_count, 158 - 2);
+    }
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_mz_order_tests() -> Result<()>  {
+    use crate::diagnostics::*;
+
+    // Simulate a producer writing unsorted m/z values by swapping the first two peaks of a spectrum slice
+    let file_path = _copy_test_file().location(here!())?;
+
+    let db = Connection::open(&file_path).location(here!())?;
+    let mut entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let mut bb = db.query_row(
+        "SELECT * FROM bounding_box WHERE first_spectrum_id = 1 ORDER BY id LIMIT 1", [], |row| Result::Ok(create_bbox(row).unwrap())
+    )?;
+    let bb_index = index_bbox(&bb, &entity_cache.data_encodings_cache).location(here!())?;
+    assert!(bb_index.peaks_counts[0] >= 2);
+
+    let spectrum_id = bb_index.spectra_ids[0];
+    let peak_size = entity_cache.data_encodings_cache.get_data_encoding_by_spectrum_id(&spectrum_id).unwrap().get_peak_size();
+    let peaks_pos = bb_index.slices_indexes[0] + 8;
+    let (first_peak, second_peak) = bb.blob_data[peaks_pos..peaks_pos + 2 * peak_size].split_at_mut(peak_size);
+    first_peak.swap_with_slice(second_peak);
+    db.execute("UPDATE bounding_box SET data = ? WHERE id = ?", rusqlite::params![bb.blob_data, bb.id])?;
+
+    let unsorted_spectrum = get_spectrum(&db, spectrum_id, &entity_cache).location(here!())?;
+    assert!(!unsorted_spectrum.data.is_mz_sorted(), "m/z arrays should not be sorted by default");
+
+    let read_diagnostics = entity_cache.diagnostics.take_diagnostics();
+    assert_eq!(read_diagnostics.len(), 1, "the unsorted m/z array should be reported in the diagnostics of the entity cache");
+    assert_eq!(read_diagnostics[0].kind, DiagnosticKind::UNSORTED_MZ_ARRAY);
+    assert!(entity_cache.diagnostics.is_empty(), "diagnostics should be cleared once taken");
+
+    entity_cache.sort_mz_arrays = true;
+    let sorted_spectrum = get_spectrum(&db, spectrum_id, &entity_cache).location(here!())?;
+    assert!(sorted_spectrum.data.is_mz_sorted());
+    assert_eq!(sorted_spectrum.data.peak_count, unsorted_spectrum.data.peak_count);
+    assert_eq!(sorted_spectrum.data.get_peak(0), unsorted_spectrum.data.get_peak(1), "intensities should follow their m/z values");
+    assert_eq!(sorted_spectrum.data.get_peak(1), unsorted_spectrum.data.get_peak(0));
+
+    let mut iterated_spectrum_opt = None;
+    crate::iterator::for_each_spectrum(&db, &entity_cache, Some(1), |s| {
+        if s.header.id == spectrum_id { iterated_spectrum_opt = Some(s.clone()); }
+        Ok(())
+    }).location(here!())?;
+    assert_eq!(iterated_spectrum_opt, Some(sorted_spectrum));
+    assert!(entity_cache.diagnostics.is_empty(), "sorted m/z arrays should not be reported");
+
+    let mut diagnostics = Diagnostics::new();
+    collect_diagnostics(&db, &entity_cache, &mut diagnostics, None, None).location(here!())?;
+    let unsorted_diagnostics: Vec<&Diagnostic> = diagnostics.iter().filter(|d| d.kind == DiagnosticKind::UNSORTED_MZ_ARRAY).collect();
+    assert_eq!(unsorted_diagnostics.len(), 1);
+    assert!(unsorted_diagnostics[0].message.contains(format!("ID={} ", spectrum_id).as_str()));
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_spectrum_table_columns_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    let headers = &entity_cache.spectrum_headers;
+
+    let columns = get_spectrum_table_columns(&db, ColumnsSelection { times: true, precursor_mzs: true, ..Default::default() }).location(here!())?;
+    assert_eq!(columns.ids, headers.iter().map(|sh| sh.id).collect::<Vec<i64>>());
+    assert_eq!(columns.times, headers.iter().map(|sh| sh.time_f64).collect::<Vec<f64>>());
+    assert_eq!(columns.precursor_mzs, headers.iter().map(|sh| sh.precursor_mz).collect::<Vec<Option<f64>>>());
+    assert!(columns.ms_levels.is_empty() && columns.tics.is_empty(), "unselected columns should not be loaded");
+
+    let columns = get_spectrum_table_columns(&db, ColumnsSelection::all()).location(here!())?;
+    assert_eq!(columns.ms_levels.iter().filter(|ms_level| **ms_level == 1).count(), 158);
+    assert_eq!(columns.tics[16], headers[16].tic);
+    assert_eq!(columns.precursor_charges[16], headers[16].precursor_charge);
+    assert_eq!(columns.peaks_counts[16], headers[16].peaks_count);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_cache_registry_tests() -> Result<()>  {
+    use crate::cache_registry::ReaderCacheRegistry;
+    use crate::ffi_support::SharedReader;
+    use std::sync::Arc;
+
+    let file_path = _copy_test_file().location(here!())?;
+    let file_path_str = file_path.to_str().unwrap();
+
+    let registry = ReaderCacheRegistry::new();
+    let db = Connection::open(&file_path).location(here!())?;
+
+    let caches = registry.get_or_create(file_path_str, &db, false).location(here!())?;
+    assert_eq!(caches.entity_cache.spectrum_headers.len(), 1193);
+    assert_eq!(caches.bb_sizes, Some(crate::mzdb::get_bb_sizes(&db)?));
+    assert!(Arc::ptr_eq(&caches, &registry.get_or_create(file_path_str, &db, false)?), "caches should be shared");
+
+    let light_caches = registry.get_or_create(file_path_str, &db, true).location(here!())?;
+    assert!(light_caches.entity_cache.spectrum_headers[0].param_tree_str.is_none());
+    assert_eq!(registry.len()?, 2);
+    assert!(!registry.is_empty()?);
+
+    let reader_1 = SharedReader::open_with_registry(file_path_str, &registry).location(here!())?;
+    let reader_2 = SharedReader::open_with_registry(file_path_str, &registry).location(here!())?;
+    assert!(std::ptr::eq(reader_1.entity_cache(), reader_2.entity_cache()));
+    assert!(std::ptr::eq(reader_1.entity_cache(), caches.entity_cache.as_ref()));
+
+    // Modifying the file invalidates its caches
+    db.execute("DELETE FROM spectrum WHERE id = 1193", [])?;
+    db.execute_batch("VACUUM")?;
+    let new_caches = registry.get_or_create(file_path_str, &db, false).location(here!())?;
+    assert!(!Arc::ptr_eq(&caches, &new_caches));
+    assert_eq!(new_caches.entity_cache.spectrum_headers.len(), 1192);
+
+    registry.invalidate(file_path_str).location(here!())?;
+    assert!(registry.is_empty()?);
+
+    drop((db, reader_1, reader_2));
+
+    Ok(())
+}
+
+#[test]
+pub fn run_pseudo_spectrum_tests() -> Result<()>  {
+    use crate::dia::*;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let mut entity_cache = create_entity_cache(&db).location(here!())?;
+    let window_index = crate::mzdb::build_isolation_window_index(&db, &entity_cache).location(here!())?;
+
+    let spectrum = get_spectrum(&db, 17, &entity_cache).location(here!())?;
+    let window_idx = window_index.isolation_windows.iter()
+        .position(|w| Some(w) == window_index.get_isolation_window_by_spectrum_id(&17))
+        .unwrap();
+    let rt = spectrum.header.time_f64;
+    let mz_tolerance = MzTolerance::PPM(10.0);
+
+    // A single spectrum is aggregated when the RT tolerance is null
+    let pseudo_spectrum = build_pseudo_spectrum(&db, &entity_cache, window_idx, rt, 0.0, &mz_tolerance).location(here!())?.unwrap();
+    assert_eq!(pseudo_spectrum.spectrum_ids, vec![17]);
+    assert!(pseudo_spectrum.mz_array.len() <= spectrum.data.peak_count);
+    assert!(pseudo_spectrum.occurrences.iter().all(|occurrences| *occurrences == 1));
+    assert!((pseudo_spectrum.intensity_array.iter().sum::<f32>() - spectrum.data.intensity_array.iter().sum::<f32>()).abs() < 1.0);
+
+    entity_cache.isolation_window_index = Some(window_index.clone());
+    let pseudo_spectrum = build_pseudo_spectrum(&db, &entity_cache, window_idx, rt, 600.0, &mz_tolerance).location(here!())?.unwrap();
+    assert!(pseudo_spectrum.spectrum_ids.contains(&17));
+    assert!(pseudo_spectrum.mz_array.windows(2).all(|w| w[0] < w[1]), "pseudo-spectrum peaks should be sorted");
+    assert!(pseudo_spectrum.occurrences.iter().all(|occurrences| *occurrences >= 1 && *occurrences <= pseudo_spectrum.spectrum_ids.len()));
+    let filtered_pseudo_spectrum = pseudo_spectrum.filter_by_occurrences(2);
+    assert!(filtered_pseudo_spectrum.occurrences.iter().all(|occurrences| *occurrences >= 2));
+
+    assert!(build_pseudo_spectrum(&db, &entity_cache, window_idx, -1000.0, 1.0, &mz_tolerance)?.is_none());
+    assert!(build_pseudo_spectrum(&db, &entity_cache, window_index.isolation_windows.len(), rt, 1.0, &mz_tolerance).is_err());
+
+    let pseudo_spectra = build_pseudo_spectra(&db, &entity_cache, rt, 5.0, &mz_tolerance).location(here!())?;
+    let ms2_count_in_range = entity_cache.spectrum_headers.iter().filter(|sh| sh.ms_level == 2 && (sh.time_f64 - rt).abs() <= 5.0).count();
+    assert_eq!(pseudo_spectra.iter().map(|ps| ps.spectrum_ids.len()).sum::<usize>(), ms2_count_in_range);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_acquisition_summary_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    let summary = crate::mzdb::get_acquisition_summary(&db, &entity_cache).location(here!())?;
+    assert_eq!(summary.spectra_count_by_ms_level.get(&1), Some(&158), "invalid MS1 spectra count");
+    assert_eq!(summary.spectra_count_by_ms_level.get(&2), Some(&1035), "invalid MS2 spectra count");
+    assert_eq!(summary.cycles_count as i64, get_last_cycle_number(&db)?.unwrap(), "invalid cycles count");
+    assert_eq!(summary.min_time, Some(entity_cache.spectrum_headers[0].time_f64));
+    assert_eq!(summary.max_time.map(|time| time as f32), get_last_time(&db)?);
+    assert_eq!(summary.chromatograms_count, 0, "unexpected chromatograms");
+    assert_eq!(summary.source_file_names, vec!["OVEMB150205_12".to_string()]);
+
+    let window_index = crate::mzdb::build_isolation_window_index(&db, &entity_cache).location(here!())?;
+    assert_eq!(summary.isolation_windows_count, Some(window_index.isolation_windows.len()));
+
+    // Precursor lists are not loaded with light headers
+    let light_entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+    let light_summary = crate::mzdb::get_acquisition_summary(&db, &light_entity_cache).location(here!())?;
+    assert_eq!(light_summary.spectra_count_by_ms_level, summary.spectra_count_by_ms_level);
+    assert_eq!(light_summary.isolation_windows_count, None);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_missing_precursor_columns_tests() -> Result<()>  {
+    let mz_tolerance = MzTolerance::PPM(10.0);
+
+    // Work on a copy since the precursor columns are cleared
+    let file_path = _copy_test_file().location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+
+    let expected_header = crate::mzdb::get_spectrum_header(&db, 17).location(here!())?.unwrap();
+    db.execute("UPDATE spectrum SET main_precursor_mz = NULL, main_precursor_charge = NULL WHERE ms_level = 2", []).location(here!())?;
+
+    // Precursors are recovered from the precursor_list, for both full and light headers
+    for entity_cache in [create_entity_cache(&db)?, crate::mzdb::create_light_entity_cache(&db)?] {
+        let sh = &entity_cache.spectrum_headers[16];
+        assert!((sh.precursor_mz.unwrap() - expected_header.precursor_mz.unwrap()).abs() < 0.001, "precursor m/z not recovered");
+        assert_eq!(sh.precursor_charge, expected_header.precursor_charge);
+        assert!(entity_cache.spectrum_headers.iter().filter(|sh| sh.ms_level == 2).all(|sh| sh.precursor_mz.is_some()));
+
+        let spectrum = get_ms2_spectrum_at(&db, expected_header.time_f64, expected_header.precursor_mz.unwrap(), &mz_tolerance, &entity_cache).location(here!())?;
+        assert_eq!(spectrum.map(|s| s.header.id), Some(17));
+    }
+
+    let sh = crate::mzdb::get_spectrum_header(&db, 17).location(here!())?.unwrap();
+    assert!(sh.precursor_mz.is_some());
+
+    // Invalid precursor lists are reported in the diagnostics of the reader
+    db.execute("UPDATE spectrum SET precursor_list = '<precursorList count=\"1\"><precursor></selectedIonList>' WHERE id = 17", []).location(here!())?;
+    drop(db);
+
+    let reader = crate::ffi_support::SharedReader::open(file_path.to_str().unwrap()).location(here!())?;
+    assert!(reader.entity_cache().spectrum_headers[16].precursor_mz.is_none());
+    let read_diagnostics = reader.take_diagnostics();
+    assert_eq!(read_diagnostics.len(), 1);
+    assert_eq!(read_diagnostics[0].kind, crate::diagnostics::DiagnosticKind::INVALID_PRECURSOR_LIST);
+    assert!(read_diagnostics[0].message.contains("ID=17"));
+
+    Ok(())
+}
+
+#[test]
+pub fn run_rtree_tests() -> Result<()>  {
+    use crate::rtree::*;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let mut entity_cache = create_entity_cache(&db).location(here!())?;
+
+    let region = RtreeRegion { min_mz: 490.0, max_mz: 500.0, min_time: 10.0, max_time: 60.0 };
+    let entries = query_region_auto(&db, &entity_cache, 1, &region, None).location(here!())?;
+    assert!(!entries.is_empty(), "no MS1 bounding box found");
+    assert!(entries.windows(2).all(|w| w[0].bb_id() < w[1].bb_id()), "entries should be sorted by bounding box ID");
+
+    for entry in entries.iter() {
+        assert!(matches!(entry, RtreeEntry::MS1 { .. }));
+        let entry_region = entry.region();
+        assert!(entry_region.min_mz <= region.max_mz && entry_region.max_mz >= region.min_mz);
+        assert!(entry_region.min_time <= region.max_time && entry_region.max_time >= region.min_time);
+        assert_eq!(get_bounding_box_ms_level(&db, entry.bb_id())?, Some(1));
+    }
+
+    let expected_count: i64 = db.query_row(
+        "SELECT count(*) FROM bounding_box_rtree WHERE min_mz <= 500 AND max_mz >= 490 AND min_time <= 60 AND max_time >= 10", [], |row| row.get(0)
+    )?;
+    assert_eq!(entries.len() as i64, expected_count);
+
+    // The MSn R-tree of the test file is empty
+    assert!(query_region_auto(&db, &entity_cache, 2, &region, Some(476.0)).is_err());
+    assert!(query_region_auto(&db, &entity_cache, 0, &region, None).is_err());
+
+    // Both the region and the entries include the RT offset
+    crate::mzdb::set_rt_offset(&mut entity_cache, -5.0);
+    let shifted_region = RtreeRegion { min_time: region.min_time - 5.0, max_time: region.max_time - 5.0, ..region };
+    let shifted_entries = query_region_auto(&db, &entity_cache, 1, &shifted_region, None).location(here!())?;
+    assert_eq!(shifted_entries.len(), entries.len());
+    assert!((shifted_entries[0].region().min_time - (entries[0].region().min_time - 5.0)).abs() < 1e-9);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_shared_param_tree_tests() -> Result<()>  {
+    use crate::metadata::*;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let sh = crate::mzdb::get_spectrum_header(&db, 1).location(here!())?.unwrap();
+    assert_eq!(sh.shared_param_tree_id, Some(1));
+
+    // The instrument params are only declared by the shared param tree
+    let own_param_tree = crate::xml::parse_param_tree(sh.param_tree_str.as_ref().unwrap())?;
+    let param_tree = resolve_param_tree(&db, sh.param_tree_str.as_deref(), sh.shared_param_tree_id).location(here!())?;
+    assert_eq!(param_tree.cv_params.len(), own_param_tree.cv_params.len() + 2);
+    assert_eq!(crate::xml::find_cv_param_value(&param_tree.cv_params, "MS:1000529"), Some("03359B"));
+
+    // Own params take precedence over the shared ones
+    let own_xml = r#"<params><cvParams><cvParam cvRef="MS" accession="MS:1000529" name="instrument serial number" value="XYZ" /></cvParams></params>"#;
+    let param_tree = resolve_param_tree(&db, Some(own_xml), Some(1)).location(here!())?;
+    assert_eq!(param_tree.cv_params.len(), 2);
+    assert_eq!(crate::xml::find_cv_param_value(&param_tree.cv_params, "MS:1000529"), Some("XYZ"));
+
+    assert_eq!(resolve_param_tree(&db, Some(own_xml), None)?.cv_params.len(), 1);
+    assert!(resolve_param_tree(&db, Some(own_xml), Some(1000)).is_err(), "missing shared param tree should be reported");
+
+    // Work on a copy to move the polarity of a spectrum to a shared param tree
+    let file_path = _copy_test_file().location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute_batch("PRAGMA foreign_keys = OFF").location(here!())?;
+    db.execute(
+        r#"INSERT INTO shared_param_tree (id, data, schema_name) VALUES (2, '<params><cvParams><cvParam cvRef="MS" accession="MS:1000129" name="negative scan" value="" /></cvParams></params>', 'CommonInstrumentParams')"#,
+        []
+    ).location(here!())?;
+    db.execute("UPDATE spectrum SET param_tree = '<params />', shared_param_tree_id = 2 WHERE id = 1", []).location(here!())?;
+
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    assert_eq!(entity_cache.spectrum_headers[0].polarity(&db)?, Polarity::NEGATIVE, "the shared param tree should be used");
+    assert_eq!(get_spectrum_polarity(&db, &entity_cache.spectrum_headers[0])?, Polarity::NEGATIVE);
+    assert_eq!(get_spectrum_polarity(&db, &entity_cache.spectrum_headers[1])?, Polarity::POSITIVE);
+
+    let light_entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+    assert_eq!(get_spectrum_polarity(&db, &light_entity_cache.spectrum_headers[0])?, Polarity::NEGATIVE);
+
+    let mut negative_spectrum_ids = Vec::new();
+    crate::iterator::for_each_spectrum_with_polarity(&db, &light_entity_cache, Some(1), Polarity::NEGATIVE, |spectrum| {
+        negative_spectrum_ids.push(spectrum.header.id);
+        Ok(())
+    }).location(here!())?;
+    assert_eq!(negative_spectrum_ids, vec![1]);
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_rt_offset_tests() -> Result<()>  {
+    use crate::search::find_spectra_with_signal;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let stored_entity_cache = create_entity_cache(&db).location(here!())?;
+    let mut entity_cache = stored_entity_cache.clone();
+
+    // Shift the first spectra before the gradient start
+    let rt_offset = -30.0;
+    crate::mzdb::set_rt_offset(&mut entity_cache, rt_offset);
+    crate::mzdb::set_rt_offset(&mut entity_cache, rt_offset); // setting the same offset twice is a no-op
+    assert_eq!(entity_cache.rt_offset, rt_offset);
+    assert!(entity_cache.spectrum_headers[0].time_f64 < 0.0);
+    for (sh, stored_sh) in entity_cache.spectrum_headers.iter().zip(stored_entity_cache.spectrum_headers.iter()) {
+        assert!((sh.time_f64 - (stored_sh.time_f64 + rt_offset)).abs() < 1e-9);
+    }
+
+    let spectrum = get_spectrum_at(&db, entity_cache.spectrum_headers[0].time_f64, 1, &entity_cache).location(here!())?.unwrap();
+    assert_eq!(spectrum.header.id, 1);
+    assert_eq!(get_spectrum_at(&db, -1000.0, 1, &entity_cache)?.map(|s| s.header.id), Some(1));
+
+    // Search results are the same as for the stored times, with the shifted RT range
+    let ms1_header = &stored_entity_cache.spectrum_headers[0];
+    let (min_mz, max_mz) = MzTolerance::PPM(5.0).mz_range(ms1_header.base_peak_mz);
+    let stored_rt_range = (0.0, 60.0);
+    let expected_spectra = find_spectra_with_signal(&db, &stored_entity_cache, min_mz, max_mz, 0.0, 1, Some(stored_rt_range)).location(here!())?;
+    let shifted_rt_range = (stored_rt_range.0 + rt_offset, stored_rt_range.1 + rt_offset);
+    let matching_spectra = find_spectra_with_signal(&db, &entity_cache, min_mz, max_mz, 0.0, 1, Some(shifted_rt_range)).location(here!())?;
+    assert!(!matching_spectra.is_empty());
+    assert_eq!(matching_spectra, expected_spectra);
+
+    crate::mzdb::set_rt_offset(&mut entity_cache, 0.0);
+    assert_eq!(entity_cache.spectrum_headers[0].time_f64, ms1_header.time_f64);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_chromatogram_export_tests() -> Result<()>  {
+    use crate::export::write_chromatograms_csv;
+
+    // Work on a copy since the test file has no chromatogram
+    let file_path = _copy_test_file().location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute_batch("PRAGMA foreign_keys = OFF").location(here!())?;
+
+    // Data points are (f64 time, f32 intensity) pairs with the HIGH_RES data encoding of the file
+    let to_data_points = |points: &[(f64, f32)]| -> Vec<u8> {
+        points.iter().flat_map(|(time, intensity)| time.to_le_bytes().into_iter().chain(intensity.to_le_bytes())).collect()
+    };
+    let tic_param_tree = r#"<params><cvParams><cvParam cvRef="MS" accession="MS:1000235" name="total ion current chromatogram" value="" /></cvParams></params>"#;
+    db.execute(
+        "INSERT INTO chromatogram (id, name, data_points, param_tree, run_id, data_encoding_id) VALUES (1, 'TIC', ?, ?, 1, 1)",
+        rusqlite::params![to_data_points(&[(0.5, 100.0), (1.5, 250.0), (2.5, 50.0)]), tic_param_tree]
+    ).location(here!())?;
+    db.execute(
+        "INSERT INTO chromatogram (id, name, data_points, param_tree, run_id, data_encoding_id) VALUES (2, 'SIC 500.2, 501.3', ?, '<params />', 1, 1)",
+        rusqlite::params![to_data_points(&[(0.5, 10.0)])]
+    ).location(here!())?;
+
+    let chrom_data = get_chromatogram_data(&db, 1).location(here!())?.unwrap();
+    assert_eq!(chrom_data.time_array, vec![0.5, 1.5, 2.5]);
+    assert_eq!(chrom_data.intensity_array, vec![100.0, 250.0, 50.0]);
+    assert!(get_chromatogram_data(&db, 3)?.is_none());
+
+    let temp_dir = tempfile::tempdir().location(here!())?;
+    let csv_path = temp_dir.path().join("chromatograms.csv");
+    let rows_count = write_chromatograms_csv(&db, None, csv_path.to_str().unwrap(), None, None).location(here!())?;
+    assert_eq!(rows_count, 4);
+
+    let csv_content = std::fs::read_to_string(&csv_path).location(here!())?;
+    let lines: Vec<&str> = csv_content.lines().collect();
+    assert_eq!(lines[0], "chrom_id,name,type,rt,intensity");
+    assert_eq!(lines[1], "1,TIC,TIC,0.5,100");
+    assert_eq!(lines[4], "2,\"SIC 500.2, 501.3\",SIC,0.5,10");
+
+    let sidecar: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(csv_path.with_extension("json"))?).location(here!())?;
+    assert_eq!(sidecar.as_array().unwrap().len(), 2);
+    assert_eq!(sidecar[0]["name"], "TIC");
+    assert_eq!(sidecar[0]["chromatogram_type"], "TIC");
+    assert_eq!(sidecar[0]["data_points_count"], 3);
+
+    let tsv_path = temp_dir.path().join("selected_chromatograms.tsv");
+    assert_eq!(write_chromatograms_csv(&db, Some(&[2]), tsv_path.to_str().unwrap(), None, None).location(here!())?, 1);
+    let tsv_content = std::fs::read_to_string(&tsv_path).location(here!())?;
+    assert_eq!(tsv_content.lines().nth(1), Some("2\tSIC 500.2, 501.3\tSIC\t0.5\t10"));
+
+    assert!(write_chromatograms_csv(&db, Some(&[3]), tsv_path.to_str().unwrap(), None, None).is_err(), "unknown chromatogram should be reported");
+
+
+    Ok(())
+}
+
+#[test]
+pub fn run_spectrum_query_tests() -> Result<()>  {
+    use crate::spectrum_query::spectra;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    assert_eq!(spectra(&db, &entity_cache).count(), 1193);
+    assert_eq!(spectra(&db, &entity_cache).ms_level(2).count(), 1035);
+
+    let ms2_spectra = spectra(&db, &entity_cache).ms_level(2).rt(10.0..20.0).collect().location(here!())?;
+    let expected_ids: Vec<i64> = entity_cache.spectrum_headers.iter()
+        .filter(|sh| sh.ms_level == 2 && sh.time_f64 >= 10.0 && sh.time_f64 < 20.0)
+        .map(|sh| sh.id)
+        .collect();
+    assert!(!expected_ids.is_empty());
+    assert_eq!(ms2_spectra.iter().map(|s| s.header.id).collect::<Vec<i64>>(), expected_ids);
+    assert!(ms2_spectra.iter().all(|s| s.data.peak_count == s.header.peaks_count as usize));
+
+    // Open and inclusive ranges
+    let time_17 = entity_cache.spectrum_headers[16].time_f64;
+    assert_eq!(spectra(&db, &entity_cache).rt(time_17..=time_17).ids(), vec![17]);
+    assert_eq!(spectra(&db, &entity_cache).rt(..time_17).count(), 16);
+
+    let query = spectra(&db, &entity_cache).precursor_mz(475.8724, &MzTolerance::PPM(10.0));
+    assert!(query.ids().contains(&17));
+    assert!(query.headers().iter().all(|sh| sh.ms_level == 2));
+
+    let mut iterated_ids = Vec::new();
+    for spectrum_res in &spectra(&db, &entity_cache).cycles(1, 2) {
+        iterated_ids.push(spectrum_res?.header.id);
+    }
+    assert_eq!(iterated_ids, spectra(&db, &entity_cache).cycles(1, 2).ids());
+    assert_eq!(spectra(&db, &entity_cache).cycles(1, 2).into_iter().len(), iterated_ids.len());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_scan_metadata_tests() -> Result<()>  {
+    use crate::maintenance::build_scan_metadata_cache;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let table = get_scan_metadata_table(&db).location(here!())?;
+    assert_eq!(table.ids.len(), 1193);
+    assert_eq!(table.injection_times.len(), 1193);
+    assert_eq!(table.ids[16], 17);
+    assert_eq!(table.injection_times[16], Some(100.0));
+    assert_eq!(table.filter_strings[16].as_deref(), Some("ITMS + c NSI d Full ms2 476.20@cid30.00 [120.00-1440.00]"));
+    assert_eq!(table.scan_windows[16], Some((120.0, 1440.0)));
+    assert!(table.precursor_intensities.iter().all(|precursor_intensity| precursor_intensity.is_none()));
+    assert!(table.filter_strings.iter().all(|filter_string| filter_string.is_some()));
+
+    // Work on a copy since the cache table is added to the file
+    let file_path = _copy_test_file().location(here!())?;
+    assert_eq!(build_scan_metadata_cache(file_path.to_str().unwrap(), None, None).location(here!())?, 1193);
+
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute_batch(
+        format!("UPDATE {} SET filter_string = 'cached' WHERE id = 1", SCAN_METADATA_TABLE_NAME).as_str()
+    ).location(here!())?;
+    let cached_table = get_scan_metadata_table(&db).location(here!())?;
+    assert_eq!(cached_table.filter_strings[0].as_deref(), Some("cached"), "the cache table should be used");
+    assert_eq!(cached_table.scan_windows, table.scan_windows);
+    assert_eq!(cached_table.injection_times, table.injection_times);
+    assert_eq!(cached_table.precursor_intensities, table.precursor_intensities);
+
+    // An incomplete cache table is ignored
+    db.execute_batch(format!("DELETE FROM {} WHERE id = 2", SCAN_METADATA_TABLE_NAME).as_str()).location(here!())?;
+    assert_eq!(get_scan_metadata_table(&db).location(here!())?, table);
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_bounding_box_bounds_tests() -> Result<()>  {
+    use crate::rtree::RtreeEntry;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let bb_entry = get_bounding_box_bounds(&db, 22)?.expect("bounding box 22 should be indexed");
+    assert!(matches!(bb_entry, RtreeEntry::MS1 { .. }));
+    assert_eq!(bb_entry.bb_id(), 22);
+
+    let region = bb_entry.region();
+    assert_eq!(region.min_mz, 490.0);
+    assert_eq!(region.max_mz, 495.0);
+    assert!((region.min_time - 0.192800000309944).abs() < 1e-6);
+    assert!((region.max_time - 15.0022993087769).abs() < 1e-6);
+
+    // the dedicated getters must agree with the bounds
+    assert_eq!(get_bounding_box_min_mz(&db, 22)?, Some(region.min_mz as f32));
+    assert_eq!(get_bounding_box_min_time(&db, 22)?, Some(region.min_time));
+
+    assert!(get_bounding_box_bounds(&db, 1_000_000)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_processing_chain_tests() -> Result<()>  {
+    use crate::metadata::get_processing_chain;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let chain = get_processing_chain(&db, 2)?.expect("data processing 2 should exist");
+    assert_eq!(chain.data_processing.name, "mzML to mzDB conversion");
+    assert_eq!(chain.steps.len(), 1);
+
+    let step = &chain.steps[0];
+    assert_eq!(step.method.number, 2);
+    assert_eq!(step.software.name, "ThermoRawFileParser");
+    assert!(step.method_params.user_params.iter().any(|user_param| user_param.name == "Conversion to mzDB"));
+    assert!(step.software_params.cv_params.is_empty());
+
+    let chain = get_processing_chain(&db, 1)?.unwrap();
+    assert_eq!(chain.steps[0].method_params.cv_params[0].accession, "MS:1000544");
+
+    assert!(get_processing_chain(&db, 42)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_rtree_time_precision_tests() -> Result<()>  {
+    use crate::rtree::*;
+    use crate::search::find_spectra_with_signal;
+
+    // Spectrum 35 is the last MS1 spectrum of its bounding boxes
+    let spectrum_id = 35;
+
+    // Simulate a writer storing the R-tree max times as f32 values rounded down from the spectrum time
+    let file_path = _copy_test_file().location(here!())?;
+
+    let db = Connection::open(&file_path).location(here!())?;
+    let time: f64 = db.query_row("SELECT time FROM spectrum WHERE id = ?", [spectrum_id], |row| row.get(0))?;
+    let time_f32 = time as f32;
+    let rounded_down_time = if time_f32 as f64 >= time { f32::from_bits(time_f32.to_bits() - 1) } else { time_f32 };
+    assert!((rounded_down_time as f64) < time);
+
+    let updated_count = db.execute(
+        "UPDATE bounding_box_rtree SET max_time = ? WHERE id IN (SELECT id FROM bounding_box WHERE last_spectrum_id = ?)",
+        rusqlite::params![rounded_down_time as f64, spectrum_id],
+    )?;
+    assert!(updated_count > 0);
+
+    // Query a window reduced to the exact time of the spectrum
+    let region = RtreeRegion { min_mz: 0.0, max_mz: 10000.0, min_time: time, max_time: time };
+    let entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+    let entries = query_region_auto(&db, &entity_cache, 1, &region, None).location(here!())?;
+    assert_eq!(entries.len(), updated_count);
+
+    let entity_cache = crate::mzdb::create_entity_cache(&db).location(here!())?;
+    let matching_spectra = find_spectra_with_signal(&db, &entity_cache, 0.0, 10000.0, 0.0, 1, Some((time, time))).location(here!())?;
+    assert_eq!(matching_spectra.iter().map(|(id, _)| *id).collect::<Vec<i64>>(), vec![spectrum_id]);
+
+    let (min_time, max_time) = widen_time_bounds_for_f32(time, time);
+    assert!(min_time < time && max_time > time);
+    assert_eq!(widen_time_bounds_for_f32(f64::MIN, f64::MAX), (f64::MIN, f64::MAX));
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_chromatogram_params_tests() -> Result<()>  {
+    let minutes_param_tree = r#"<params><cvParams>
+        <cvParam cvRef="MS" accession="MS:1000628" name="basepeak chromatogram" value="" />
+        <cvParam cvRef="MS" accession="MS:1000129" name="negative scan" value="" />
+        <cvParam cvRef="MS" accession="MS:1000595" name="time array" value="" unitCvRef="UO" unitAccession="UO:0000031" unitName="minute" />
+    </cvParams></params>"#;
+
+    let mut chrom_header = ChromatogramHeader {
+        id: 1,
+        name: "BPC".to_string(),
+        activation_type: None,
+        param_tree: minutes_param_tree.to_string(),
+        precursor: None,
+        product: None,
+        shared_param_tree_id: None,
+        run_id: 1,
+        data_processing_id: None,
+        data_encoding_id: 1,
+    };
+    // Work on a copy since the test file has no chromatogram
+    let file_path = _copy_test_file().location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute_batch("PRAGMA foreign_keys = OFF").location(here!())?;
+
+    assert_eq!(chrom_header.params(&db)?.cv_params.len(), 3);
+    assert_eq!(chrom_header.time_unit(&db)?, TimeUnit::MINUTE);
+    assert_eq!(chrom_header.polarity(&db)?, Polarity::NEGATIVE);
+
+    chrom_header.param_tree = "<params />".to_string();
+    assert_eq!(chrom_header.time_unit(&db)?, TimeUnit::UNKNOWN);
+    assert_eq!(chrom_header.polarity(&db)?, Polarity::UNKNOWN);
+
+    // The params of the shared param tree are used too
+    db.execute(
+        "INSERT INTO shared_param_tree (id, data, schema_name) VALUES (2, ?, 'ChromatogramParams')",
+        [minutes_param_tree]
+    ).location(here!())?;
+    chrom_header.shared_param_tree_id = Some(2);
+    assert_eq!(chrom_header.time_unit(&db)?, TimeUnit::MINUTE);
+    assert_eq!(chrom_header.polarity(&db)?, Polarity::NEGATIVE);
+
+    let data_points: Vec<u8> = [(0.5f64, 100.0f32), (1.5, 250.0)].iter()
+        .flat_map(|(time, intensity)| time.to_le_bytes().into_iter().chain(intensity.to_le_bytes()))
+        .collect();
+    db.execute(
+        "INSERT INTO chromatogram (id, name, data_points, param_tree, run_id, data_encoding_id) VALUES (1, 'BPC', ?, ?, 1, 1)",
+        rusqlite::params![data_points, minutes_param_tree]
+    ).location(here!())?;
+
+    // Times stored in minutes are returned in seconds
+    let chrom_data = get_chromatogram_data(&db, 1).location(here!())?.unwrap();
+    assert_eq!(chrom_data.time_array, vec![30.0, 90.0]);
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_fix_sequences_tests() -> Result<()>  {
+    use crate::maintenance::fix_sequences;
+
+    // Work on a copy since the sequences are modified
+    let file_path = _copy_test_file().location(here!())?;
+    let file_path_str = file_path.to_str().unwrap();
+
+    // Consistent sequences are left untouched
+    assert_eq!(fix_sequences(file_path_str, None, None).location(here!())?, 0);
+
+    // Simulate a file edited by another tool
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute_batch(
+        "UPDATE sqlite_sequence SET seq = 10 WHERE name = 'spectrum'; \
+        DELETE FROM sqlite_sequence WHERE name = 'run_slice';"
+    ).location(here!())?;
+    assert_eq!(get_table_records_count(&db, "spectrum").location(here!())?, Some(10));
+    assert_eq!(get_table_records_count(&db, "run_slice").location(here!())?, None);
+
+    assert_eq!(fix_sequences(file_path_str, None, None).location(here!())?, 2);
+    assert_eq!(get_table_records_count(&db, "spectrum").location(here!())?, Some(1193));
+    assert_eq!(get_table_records_count(&db, "run_slice").location(here!())?, Some(161));
+    assert_eq!(fix_sequences(file_path_str, None, None).location(here!())?, 0);
+
+    // Sequences are never lowered, which would allow the reuse of deleted IDs
+    db.execute("UPDATE sqlite_sequence SET seq = 5000 WHERE name = 'spectrum'", []).location(here!())?;
+    assert_eq!(fix_sequences(file_path_str, None, None).location(here!())?, 0);
+    assert_eq!(get_table_records_count(&db, "spectrum").location(here!())?, Some(5000));
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_neutral_mass_search_tests() -> Result<()>  {
+    use crate::search::find_ms2_by_neutral_mass;
+    use crate::mass::PROTON_MASS;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    // The precursor of spectrum 17 is a 3+ ion at m/z 475.8724
+    let neutral_mass = (475.8724 - PROTON_MASS) * 3.0;
+    let spectrum_ids_by_charge = find_ms2_by_neutral_mass(&entity_cache, neutral_mass, 10.0, &[2, 3]).location(here!())?;
+    assert_eq!(spectrum_ids_by_charge.keys().copied().collect::<Vec<i32>>(), vec![2, 3]);
+    assert!(spectrum_ids_by_charge[&3].contains(&17));
+    assert!(!spectrum_ids_by_charge[&2].contains(&17));
+
+    for spectrum_id in spectrum_ids_by_charge[&3].iter() {
+        let sh = &entity_cache.spectrum_headers[(*spectrum_id - 1) as usize];
+        assert_eq!(sh.ms_level, 2);
+        assert!((sh.precursor_mz.unwrap() - 475.8724).abs() < 475.8724 * 10.0 / 1e6 + 1e-9);
+    }
+
+    assert!(find_ms2_by_neutral_mass(&entity_cache, neutral_mass, 10.0, &[0]).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_schema_diff_tests() -> Result<()>  {
+    use crate::diagnostics::*;
+    use crate::schema::*;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let diff = schema_diff(&db).location(here!())?;
+    assert!(diff.is_empty(), "unexpected schema differences: {:?}", diff);
+
+    assert_eq!(ColumnType::from_declared_type("TEXT(10)"), ColumnType::TEXT);
+    assert_eq!(ColumnType::from_declared_type("INT"), ColumnType::INTEGER);
+    assert_eq!(ColumnType::from_declared_type(""), ColumnType::BLOB);
+
+    // Work on a copy since tables are modified
+    let file_path = _copy_test_file().location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute_batch(
+        "PRAGMA foreign_keys = OFF; \
+        DROP TABLE target; \
+        ALTER TABLE spectrum ADD COLUMN extra_col TEXT; \
+        DROP TABLE user_term; \
+        CREATE TABLE user_term (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, type REAL NOT NULL);"
+    ).location(here!())?;
+
+    let diff = schema_diff(&db).location(here!())?;
+    assert_eq!(diff.missing_tables, vec!["target".to_string()]);
+    assert_eq!(diff.missing_columns, vec![("user_term".to_string(), "unit_accession".to_string())]);
+    assert_eq!(diff.extra_columns, vec![("spectrum".to_string(), "extra_col".to_string())]);
+    assert_eq!(diff.type_mismatches.len(), 1);
+    assert_eq!(diff.type_mismatches[0].column_name, "type");
+    assert_eq!(diff.type_mismatches[0].expected_type, ColumnType::TEXT);
+    assert_eq!(diff.type_mismatches[0].declared_type, "REAL");
+
+    // The differences are reported as diagnostics too
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    let mut diagnostics = Diagnostics::new();
+    collect_diagnostics(&db, &entity_cache, &mut diagnostics, None, None).location(here!())?;
+    let count_diagnostics = |kind: DiagnosticKind| diagnostics.iter().filter(|d| d.kind == kind).count();
+    assert_eq!(count_diagnostics(DiagnosticKind::MISSING_TABLE), 1);
+    assert_eq!(count_diagnostics(DiagnosticKind::MISSING_COLUMN), 1);
+    assert_eq!(count_diagnostics(DiagnosticKind::EXTRA_COLUMN), 1);
+    assert_eq!(count_diagnostics(DiagnosticKind::COLUMN_TYPE_MISMATCH), 1);
+    assert!(diagnostics.iter().any(|d| d.message.contains("'extra_col' of table 'spectrum'")));
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_ms1_window_tests() -> Result<()>  {
+    use std::sync::Arc;
+    use crate::iterator::for_each_ms1_window;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    let ms1_ids: Vec<i64> = entity_cache.spectrum_headers.iter().filter(|sh| sh.ms_level == 1).map(|sh| sh.id).collect();
+    assert_eq!(ms1_ids.len(), 158);
+
+    let mut windows: Vec<Vec<Arc<Spectrum>>> = Vec::new();
+    for_each_ms1_window(&db, &entity_cache, 5, 2, |window| {
+        windows.push(window.to_vec());
+        Ok(())
+    }).location(here!())?;
+
+    assert_eq!(windows.len(), (158 - 5) / 2 + 1);
+    assert!(windows.iter().all(|window| window.len() == 5));
+    assert_eq!(windows[0].iter().map(|s| s.header.id).collect::<Vec<i64>>(), ms1_ids[0..5].to_vec());
+    assert_eq!(windows[1][0].header.id, ms1_ids[2]);
+
+    // Overlapping windows share the same decoded spectra
+    assert!(Arc::ptr_eq(&windows[0][2], &windows[1][0]));
+
+    // Spectra between windows are skipped when the step is greater than the window size
+    let mut first_ids = Vec::new();
+    for_each_ms1_window(&db, &entity_cache, 2, 5, |window| {
+        first_ids.push(window[0].header.id);
+        Ok(())
+    }).location(here!())?;
+    assert_eq!(first_ids.len(), (158 - 2) / 5 + 1);
+    assert_eq!(first_ids[1], ms1_ids[5]);
+
+    assert!(for_each_ms1_window(&db, &entity_cache, 0, 1, |_| Ok(())).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn run_optional_indexes_tests() -> Result<()>  {
+    use crate::maintenance::create_optional_indexes;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+    let time_range_ids = list_spectrum_ids_in_time_range(&db, &entity_cache, 10.0, 20.0, Some(1)).location(here!())?;
+    let precursor_ids = list_spectrum_ids_in_precursor_mz_range(&db, 475.87, 475.88).location(here!())?;
+    assert!(precursor_ids.contains(&17));
+
+    // Work on a copy since indexes are added to the file
+    let file_path = _copy_test_file().location(here!())?;
+    assert_eq!(create_optional_indexes(file_path.to_str().unwrap(), None, None).location(here!())?, 2);
+    assert_eq!(create_optional_indexes(file_path.to_str().unwrap(), None, None).location(here!())?, 0);
+
+    let db = Connection::open(&file_path).location(here!())?;
+    let query_plan: String = db.query_row(
+        "EXPLAIN QUERY PLAN SELECT id FROM spectrum WHERE time >= 10 AND time <= 20", [], |row| row.get(3)
+    )?;
+    assert!(query_plan.contains("idx_mzdbrs_spectrum_time"), "unexpected query plan: {}", query_plan);
+
+    // Results are not modified by the indexes
+    assert_eq!(list_spectrum_ids_in_time_range(&db, &entity_cache, 10.0, 20.0, Some(1)).location(here!())?, time_range_ids);
+    assert_eq!(list_spectrum_ids_in_precursor_mz_range(&db, 475.87, 475.88).location(here!())?, precursor_ids);
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_run_slice_coverage_tests() -> Result<()>  {
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let coverages = get_run_slice_coverage(&db).location(here!())?;
+    assert_eq!(coverages.len(), 161);
+    assert_eq!(coverages.iter().map(|coverage| coverage.header.clone()).collect::<Vec<RunSliceHeader>>(), list_run_slice_headers(&db)?);
+
+    let first_coverage = &coverages[0];
+    assert_eq!(first_coverage.header.id, 1);
+    assert_eq!(first_coverage.bounding_boxes_count, 15);
+    let (first_time, last_ms1_time): (f64, f64) = db.query_row(
+        "SELECT min(time), max(time) FROM spectrum WHERE ms_level = 1", [], |row| rusqlite::Result::Ok((row.get(0)?, row.get(1)?))
+    )?;
+    assert_eq!(first_coverage.min_time, Some(first_time));
+    assert_eq!(first_coverage.max_time, Some(last_ms1_time));
+    assert_eq!(first_coverage.total_bytes, 57364);
+
+    let bb_count: i64 = db.query_row("SELECT count(*) FROM bounding_box", [], |row| row.get(0))?;
+    let total_bytes: i64 = db.query_row("SELECT sum(length(data)) FROM bounding_box", [], |row| row.get(0))?;
+    assert_eq!(coverages.iter().map(|coverage| coverage.bounding_boxes_count).sum::<i64>(), bb_count);
+    assert_eq!(coverages.iter().map(|coverage| coverage.total_bytes).sum::<i64>(), total_bytes);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_fitted_as_centroid_tests() -> Result<()>  {
+    use std::collections::HashMap;
+
+    // A bounding box holding a single fitted spectrum slice of two peaks (m/z, intensity, left HWHM, right HWHM)
+    let fitted_de = DataEncoding {
+        id: 1,
+        mode: DataMode::FITTED,
+        peak_encoding: PeakEncoding::HIGH_RES_PEAK,
+        compression: "none".to_string(),
+        byte_order: ByteOrder::LITTLE_ENDIAN,
+    };
+
+    let mut blob_data = Vec::new();
+    blob_data.extend_from_slice(&1i32.to_le_bytes());
+    blob_data.extend_from_slice(&2i32.to_le_bytes());
+    for (mz, intensity, lwhm, rwhm) in [(400.5f64, 100.0f32, 0.01f32, 0.02f32), (401.5, 200.0, 0.03, 0.04)] {
+        blob_data.extend_from_slice(&mz.to_le_bytes());
+        blob_data.extend_from_slice(&intensity.to_le_bytes());
+        blob_data.extend_from_slice(&lwhm.to_le_bytes());
+        blob_data.extend_from_slice(&rwhm.to_le_bytes());
+    }
+
+    let bb = BoundingBox { id: 1, first_spectrum_id: 1, last_spectrum_id: 1, run_slice_id: 1, blob_data };
+    let de_cache = DataEncodingsCache::new(HashMap::from([(1, fitted_de.clone())]), HashMap::from([(1, 1)]));
+    let bb_index = index_bbox(&bb, &de_cache).location(here!())?;
+
+    let fitted_data = read_spectrum_slice_data_at(&bb, &bb_index, &fitted_de, 0, None, None).location(here!())?;
+    assert_eq!(fitted_data.data_encoding.mode, DataMode::FITTED);
+    assert_eq!(fitted_data.lwhm_array.to_vec(), vec![0.01, 0.03]);
+    assert_eq!(fitted_data.rwhm_array.to_vec(), vec![0.02, 0.04]);
+
+    let centroid_data = read_spectrum_slice_centroids_at(&bb, &bb_index, &fitted_de, 0, None, None).location(here!())?;
+    assert_eq!(centroid_data.data_encoding.mode, DataMode::CENTROID);
+    assert_eq!(centroid_data.mz_array.to_vec(), vec![400.5, 401.5]);
+    assert_eq!(centroid_data.intensity_array.to_vec(), vec![100.0, 200.0]);
+    assert!(centroid_data.lwhm_array.is_empty() && centroid_data.rwhm_array.is_empty());
+
+    // Spectra of other modes are not modified by the option
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let mut entity_cache = create_entity_cache(&db).location(here!())?;
+    let spectrum = get_spectrum(&db, 1, &entity_cache).location(here!())?;
+    entity_cache.fitted_as_centroid = true;
+    assert_eq!(get_spectrum(&db, 1, &entity_cache).location(here!())?.data, spectrum.data);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_source_file_spectra_tests() -> Result<()>  {
+    // Work on a copy to simulate a merged file, the last spectra being converted from a second source file
+    let file_path = _copy_test_file().location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+
+    assert_eq!(list_spectrum_ids_for_source_file(&db, 1).location(here!())?.len(), 1193);
+
+    db.execute_batch(
+        "PRAGMA foreign_keys = OFF; \
+        INSERT INTO source_file (id, name, location, param_tree) VALUES (2, 'OVEMB150205_13', 'file:///OVEMB150205_13.raw', '<params />'); \
+        UPDATE spectrum SET source_file_id = 2 WHERE id > 1000;"
+    ).location(here!())?;
+
+    let first_file_ids = list_spectrum_ids_for_source_file(&db, 1).location(here!())?;
+    let second_file_ids = list_spectrum_ids_for_source_file(&db, 2).location(here!())?;
+    assert_eq!(first_file_ids, (1..=1000).collect::<Vec<i64>>());
+    assert_eq!(second_file_ids, (1001..=1193).collect::<Vec<i64>>());
+    assert!(list_spectrum_ids_for_source_file(&db, 3).location(here!())?.is_empty());
+
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    let summary = crate::mzdb::get_acquisition_summary(&db, &entity_cache).location(here!())?;
+    assert_eq!(summary.source_file_names, vec!["OVEMB150205_12".to_string(), "OVEMB150205_13".to_string()]);
+
+    drop(db);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_mass_tests() -> Result<()>  {
+    use crate::mass::*;
+
+    assert!((ppm_to_da(500.0, 10.0) - 0.005).abs() < 1e-12);
+    assert!((da_to_ppm(500.0, 0.005) - 10.0).abs() < 1e-9);
+    assert!((ppm_error(500.0025, 500.0) - 5.0).abs() < 1e-6);
+    assert_eq!(MzTolerance::PPM(10.0).to_da(500.0), ppm_to_da(500.0, 10.0));
+
+    // Charge state conversions are reversible, for positive and negative ions
+    let neutral_mass = 1424.595;
+    for charge in [1, 2, 3, -1, -2] {
+        let mz = neutral_mass_to_mz(neutral_mass, charge);
+        assert!((mz_to_neutral_mass(mz, charge) - neutral_mass).abs() < 1e-9);
+    }
+    assert!((neutral_mass_to_mz(neutral_mass, 2) - (neutral_mass / 2.0 + PROTON_MASS)).abs() < 1e-9);
+    assert!((neutral_mass_to_mz(neutral_mass, -1) - (neutral_mass - PROTON_MASS)).abs() < 1e-9);
+
+    // CH2 has a Kendrick mass of 14 and the members of a homologous series share the same Kendrick mass defect
+    assert!((kendrick_mass(CH2_MASS, CH2_MASS) - 14.0).abs() < 1e-9);
+    let kmd = kendrick_mass_defect(300.2, CH2_MASS);
+    assert!((kendrick_mass_defect(300.2 + 3.0 * CH2_MASS, CH2_MASS) - kmd).abs() < 1e-9);
+    assert!((kendrick_mass_defect(300.2 + 0.01, CH2_MASS) - kmd).abs() > 1e-3);
+
+    Ok(())
+}
+
+#[test]
+pub fn run_xic_isotopes_tests() -> Result<()>  {
+    use crate::mass::{isotope_mz, ppm_to_da};
+    use crate::quant::get_xic_isotopes;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    // Precursor of spectrum 17 (charge 3)
+    let mono_mz = 475.8724;
+    let ms2_time = entity_cache.spectrum_headers[16].time_f64;
+    let rt_range = (ms2_time - 60.0, ms2_time + 60.0);
+
+    let xic = get_xic_isotopes(&db, &entity_cache, mono_mz, 3, 3, 10.0, Some(rt_range)).location(here!())?;
+    assert_eq!(xic.isotope_mzs.len(), 3);
+    assert!((xic.isotope_mzs[1] - xic.isotope_mzs[0] - 1.0033548378 / 3.0).abs() < 1e-9, "invalid isotope spacing");
+    assert_eq!(xic.isotope_intensities.len(), 3, "one trace per isotope is expected");
+
+    let expected_ms1_count = entity_cache.spectrum_headers.iter()
+        .filter(|sh| sh.ms_level == 1 && sh.time_f64 >= rt_range.0 && sh.time_f64 <= rt_range.1).count();
+    assert!(expected_ms1_count > 0);
+    assert_eq!(xic.spectrum_ids.len(), expected_ms1_count, "one value per MS1 spectrum is expected");
+    assert!(xic.isotope_intensities.iter().all(|trace| trace.len() == expected_ms1_count));
+    assert!(xic.summed_intensities.iter().any(|intensity| *intensity > 0.0), "the precursor should be detected");
+
+    for (spectrum_idx, summed_intensity) in xic.summed_intensities.iter().enumerate() {
+        let isotopes_sum: f32 = xic.isotope_intensities.iter().map(|trace| trace[spectrum_idx]).sum();
+        assert!((summed_intensity - isotopes_sum).abs() <= 1e-3 * summed_intensity.max(1.0));
+    }
+
+    // The traces match the peaks of the full spectra
+    for (spectrum_idx, spectrum_id) in xic.spectrum_ids.iter().enumerate() {
+        let spectrum = get_spectrum(&db, *spectrum_id, &entity_cache).location(here!())?;
+        for isotope_idx in 0..3 {
+            let mz = isotope_mz(mono_mz, 3, isotope_idx);
+            let mz_tol = ppm_to_da(mz, 10.0);
+            let max_intensity = spectrum.data.crop(mz - mz_tol, mz + mz_tol).intensity_array.iter().fold(0f32, |max, i| max.max(*i));
+            assert_eq!(xic.isotope_intensities[isotope_idx][spectrum_idx], max_intensity, "invalid intensity for spectrum ID={}", spectrum_id);
+        }
+    }
+
+    let full_xic = get_xic_isotopes(&db, &entity_cache, mono_mz, 3, 2, 10.0, None).location(here!())?;
+    assert_eq!(full_xic.spectrum_ids.len(), 158, "all MS1 spectra are expected without RT range");
+
+    assert!(get_xic_isotopes(&db, &entity_cache, mono_mz, 0, 3, 10.0, None).is_err(), "charge 0 should be rejected");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_xic_tests() -> Result<()>  {
+    use crate::mass::ppm_to_da;
+    use crate::xic::{get_ms1_xic, get_msn_xic, get_xic};
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+
+    // Precursor of spectrum 17
+    let target_mz = 475.8724;
+    let mz_tol = ppm_to_da(target_mz, 10.0);
+    let xic = get_ms1_xic(&db, &entity_cache, target_mz, 10.0, None).location(here!())?;
+    assert_eq!((xic.target_mz, xic.tol_ppm, xic.ms_level, xic.parent_mz, xic.chromatogram_id), (target_mz, 10.0, 1, None, None));
+    assert!(!xic.peaks.is_empty(), "the precursor should be detected");
+    assert!(xic.peaks.windows(2).all(|peaks| peaks[0].time < peaks[1].time), "the peaks should be sorted by time");
+
+    // The peaks match the most intense peaks of the full spectra
+    let mut expected_peaks_count = 0;
+    crate::iterator::for_each_spectrum(&db, &entity_cache, Some(1), |spectrum: &Spectrum| {
+        let cropped_data = spectrum.data.crop(target_mz - mz_tol, target_mz + mz_tol);
+        let max_intensity = cropped_data.intensity_array.iter().fold(0f32, |max, i| max.max(*i));
+        if cropped_data.peak_count > 0 {
+            let peak = xic.peaks.iter().find(|peak| peak.spectrum_id == Some(spectrum.header.id)).unwrap();
+            assert_eq!(peak.intensity, max_intensity, "invalid intensity for spectrum ID={}", spectrum.header.id);
+            assert!((peak.mz - target_mz).abs() <= mz_tol);
+            expected_peaks_count += 1;
+        }
+        Ok(())
+    }).location(here!())?;
+    assert_eq!(xic.peaks.len(), expected_peaks_count, "spectra without peak in the m/z window should be skipped");
+
+    let chrom_data = xic.to_chromatogram_data();
+    assert_eq!(chrom_data.time_array.len(), xic.peaks.len());
+    assert_eq!(chrom_data.intensity_array[0], xic.peaks[0].intensity);
+
+    let rt_range = (xic.peaks[0].time, xic.peaks[2].time);
+    let ranged_xic = get_ms1_xic(&db, &entity_cache, target_mz, 10.0, Some(rt_range)).location(here!())?;
+    assert_eq!(ranged_xic.peaks, xic.peaks[0..3].to_vec(), "the RT range bounds should be inclusive");
+
+    // bounding_box_msn_rtree is empty: the MS2 spectra are selected using their isolation windows (476.2 +/- 1)
+    let spectrum_17 = get_spectrum(&db, 17, &entity_cache).location(here!())?;
+    let fragment_idx = spectrum_17.data.intensity_array.iter().enumerate()
+        .max_by(|(_, i1), (_, i2)| i1.total_cmp(i2)).unwrap().0;
+    let fragment_mz = spectrum_17.data.mz_array[fragment_idx];
+    let msn_xic = get_msn_xic(&db, &entity_cache, 476.2, fragment_mz, 10.0, None).location(here!())?;
+    assert_eq!((msn_xic.ms_level, msn_xic.parent_mz), (2, Some(476.2)));
+
+    let peak_17 = msn_xic.peaks.iter().find(|peak| peak.spectrum_id == Some(17)).unwrap();
+    assert_eq!(peak_17.intensity, spectrum_17.data.intensity_array[fragment_idx]);
+    for peak in msn_xic.peaks.iter() {
+        let spectrum_id = peak.spectrum_id.unwrap();
+        let window = entity_cache.spectrum_headers[(spectrum_id - 1) as usize].isolation_window()?.unwrap();
+        assert!(window.min_mz <= 476.2 && 476.2 <= window.max_mz, "spectrum ID={} doesn't isolate the parent m/z", spectrum_id);
+    }
+
+    // Two overlapping windows sharing the MS2 spectra of cycles 34 and 35
+    let mut light_entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+    let cycle_ms2_ids = |cycle: i64| -> Vec<i64> {
+        entity_cache.spectrum_headers.iter().filter(|sh| sh.cycle == cycle && sh.ms_level == 2).map(|sh| sh.id).collect()
+    };
+    let (cycle_34_ids, cycle_35_ids) = (cycle_ms2_ids(34), cycle_ms2_ids(35));
+    let window_idx_by_spectrum_id = HashMap::from([(cycle_34_ids[0], 0), (cycle_35_ids[0], 0), (cycle_34_ids[1], 1)]);
+    let windows = vec![IsolationWindow { min_mz: 470.0, max_mz: 476.5 }, IsolationWindow { min_mz: 476.0, max_mz: 482.0 }];
+    light_entity_cache.isolation_window_index = Some(IsolationWindowIndex::new(windows, window_idx_by_spectrum_id));
+
+    // A fragment found in the spectra of both windows
+    let spectrum_a = get_spectrum(&db, cycle_34_ids[0], &entity_cache).location(here!())?;
+    let spectrum_b = get_spectrum(&db, cycle_34_ids[1], &entity_cache).location(here!())?;
+    let fragment_mz = *spectrum_b.data.mz_array.iter()
+        .find(|mz| spectrum_a.data.crop(**mz - ppm_to_da(**mz, 5.0), **mz + ppm_to_da(**mz, 5.0)).peak_count > 0)
+        .unwrap();
+
+    let window_xic = |parent_mz: f64| get_msn_xic(&db, &light_entity_cache, parent_mz, fragment_mz, 10.0, None);
+    let (xic_1, xic_2) = (window_xic(473.25).location(here!())?, window_xic(479.0).location(here!())?);
+    assert_eq!(xic_1.peaks[0].spectrum_id, Some(cycle_34_ids[0]));
+    assert_eq!(xic_2.peaks.len(), 1);
+
+    // The second window is the nearest one from the precursor m/z
+    let merged_xic = crate::xic::get_msn_xic_across_windows(&db, &light_entity_cache, 476.3, 10.0, fragment_mz, 10.0, None).location(here!())?;
+    assert_eq!(merged_xic.parent_mz, Some(476.3));
+    let merged_ids: Vec<i64> = merged_xic.peaks.iter().filter_map(|peak| peak.spectrum_id).collect();
+    let mut expected_ids = vec![cycle_34_ids[1]];
+    expected_ids.extend(xic_1.peaks.iter().skip(1).filter_map(|peak| peak.spectrum_id));
+    assert_eq!(merged_ids, expected_ids, "one peak per cycle is expected, taken from the nearest window");
+
+    // A precursor isolated by a single window
+    let single_window_xic = crate::xic::get_msn_xic_across_windows(&db, &light_entity_cache, 471.0, 10.0, fragment_mz, 10.0, None).location(here!())?;
+    assert_eq!(single_window_xic.peaks, xic_1.peaks);
+    assert!(crate::xic::get_msn_xic_across_windows(&db, &light_entity_cache, 490.0, 10.0, fragment_mz, 10.0, None).is_err());
+
+    assert!(get_xic(&db, &entity_cache, target_mz, 10.0, 1, Some(476.2), None).is_err(), "a parent m/z is not allowed for MS1");
+    assert!(get_xic(&db, &entity_cache, target_mz, 0.0, 1, None, None).is_err(), "the tolerance should be positive");
+
+    Ok(())
+}
+
+#[test]
+pub fn run_stored_xic_tests() -> Result<()>  {
+    use crate::xic::{get_ms1_xic, get_msn_xic};
+
+    // Work on a copy since the test file has no chromatogram
+    let file_path = _copy_test_file().location(here!())?;
+    let db = Connection::open(&file_path).location(here!())?;
+    db.execute_batch("PRAGMA foreign_keys = OFF").location(here!())?;
+    let entity_cache = crate::mzdb::create_light_entity_cache(&db).location(here!())?;
+
+    let to_data_points = |points: &[(f64, f32)]| -> Vec<u8> {
+        points.iter().flat_map(|(time, intensity)| time.to_le_bytes().into_iter().chain(intensity.to_le_bytes())).collect()
+    };
+    let to_isolation_window = |element_name: &str, target_mz: f64| -> String {
+        format!(
+            r#"<{0}><isolationWindow><cvParam cvRef="MS" accession="MS:1000827" name="isolation window target m/z" value="{1}" /></isolationWindow></{0}>"#,
+            element_name, target_mz
+        )
+    };
+    let srm_param_tree = r#"<params><cvParams><cvParam cvRef="MS" accession="MS:1001473" name="selected reaction monitoring chromatogram" value="" /></cvParams></params>"#;
+    let sic_param_tree = r#"<params><cvParams><cvParam cvRef="MS" accession="MS:1000627" name="selected ion current chromatogram" value="" /></cvParams></params>"#;
+
+    db.execute(
+        "INSERT INTO chromatogram (id, name, data_points, param_tree, precursor, product, run_id, data_encoding_id) VALUES (1, 'SRM 500.3 > 600.4', ?, ?, ?, ?, 1, 1)",
+        rusqlite::params![
+            to_data_points(&[(10.0, 100.0), (20.0, 250.0), (30.0, 50.0)]), srm_param_tree,
+            to_isolation_window("precursor", 500.3), to_isolation_window("product", 600.4)
+        ]
+    ).location(here!())?;
+    db.execute(
+        "INSERT INTO chromatogram (id, name, data_points, param_tree, precursor, run_id, data_encoding_id) VALUES (2, 'SIC 475.8724', ?, ?, ?, 1, 1)",
+        rusqlite::params![to_data_points(&[(5.0, 10.0)]), sic_param_tree, to_isolation_window("precursor", 475.8724)]
+    ).location(here!())?;
+
+    let srm_xic = get_msn_xic(&db, &entity_cache, 500.3, 600.4, 10.0, Some((15.0, 30.0))).location(here!())?;
+    assert_eq!(srm_xic.chromatogram_id, Some(1));
+    assert_eq!(srm_xic.to_chromatogram_data().time_array, vec![20.0, 30.0], "the RT range should be applied to the stored data points");
+    assert!(srm_xic.peaks.iter().all(|peak| peak.spectrum_id.is_none() && peak.mz == 600.4));
+
+    let sic_xic = get_ms1_xic(&db, &entity_cache, 475.8724, 10.0, None).location(here!())?;
+    assert_eq!(sic_xic.chromatogram_id, Some(2));
+    assert_eq!(sic_xic.to_chromatogram_data().intensity_array, vec![10.0]);
+
+    // Chromatograms out of the tolerance are ignored, the spectra are then decoded
+    let ms1_xic = get_ms1_xic(&db, &entity_cache, 475.88, 10.0, None).location(here!())?;
+    assert!(ms1_xic.chromatogram_id.is_none() && ms1_xic.peaks.iter().all(|peak| peak.spectrum_id.is_some()));
+
+    Ok(())
+}
+
+#[test]
+pub fn run_file_provenance_tests() -> Result<()>  {
+    use crate::metadata::{get_file_provenance, parse_timestamp};
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+
+    let provenance = get_file_provenance(&db).location(here!())?;
+    assert_eq!(provenance.creation_timestamp.as_deref(), Some("1653392489"));
+    assert_eq!(provenance.creation_time, Some(1653392489));
+    let writer_software = provenance.writer_software.as_ref().context("missing writer software")?;
+    assert_eq!((writer_software.name.as_str(), writer_software.version.as_str()), ("Thermo2mzDB", "0.9.10"));
+    assert_eq!(provenance.source_files.len(), 1);
+    assert_eq!(provenance.source_files[0].name, "OVEMB150205_12");
+    assert_eq!(provenance.origin_file_format.as_deref(), Some("Thermo RAW format"));
+
+    // Same instant written in the different supported formats
+    for timestamp in [
+        "1653392489", " 1653392489.75 ", "1653392489000", "2022-05-24T11:41:29Z", "2022-05-24 11:41:29", "2022/05/24 11:41:29 UTC",
+        "2022-05-24T11:41:29.500Z", "2022-05-24T13:41:29+02:00", "2022-05-24T06:41:29-0500",
+    ] {
+        assert_eq!(parse_timestamp(timestamp), Some(1653392489), "can't parse timestamp {:?}", timestamp);
+    }
+    assert_eq!(parse_timestamp("2022-05-24"), Some(1653350400));
+    assert_eq!(parse_timestamp("2000-02-29T00:00:00"), Some(951782400));
+
+    for timestamp in ["", "unknown", "2022-13-01", "2021-02-29", "2022-05-24T25:00:00", "2022-05-24T11:41:29+2"] {
+        assert_eq!(parse_timestamp(timestamp), None, "timestamp {:?} should not be parsed", timestamp);
+    }
+
+    #[cfg(feature = "chrono")]
+    assert_eq!(provenance.creation_datetime().map(|datetime| datetime.to_rfc3339()), Some("2022-05-24T11:41:29+00:00".to_string()));
+
+    Ok(())
+}
+
+#[test]
+pub fn run_peak_layouts_decoding_tests() -> Result<()>  {
+    use crate::blob_cursor::SpectrumSliceView;
+
+    let mzs = [400.25f64, 512.125, 1024.5];
+    let intensities = [1000.5f32, 2.25, 350000.0];
+    let hwhms = [(0.01f32, 0.02f32), (0.03, 0.04), (0.05, 0.06)];
+
+    for mode in [DataMode::PROFILE, DataMode::FITTED] {
+        for peak_encoding in [PeakEncoding::LOW_RES_PEAK, PeakEncoding::HIGH_RES_PEAK, PeakEncoding::NO_LOSS_PEAK] {
+            for byte_order in [ByteOrder::LITTLE_ENDIAN, ByteOrder::BIG_ENDIAN] {
+                let de = DataEncoding { id: 1, mode, peak_encoding, compression: "none".to_string(), byte_order };
+                let big_endian = byte_order == ByteOrder::BIG_ENDIAN;
+                let f32_bytes = |value: f32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+                let f64_bytes = |value: f64| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+
+                let mut peaks_bytes = Vec::new();
+                for peak_idx in 0..mzs.len() {
+                    match peak_encoding {
+                        PeakEncoding::LOW_RES_PEAK => peaks_bytes.extend(f32_bytes(mzs[peak_idx] as f32)),
+                        _ => peaks_bytes.extend(f64_bytes(mzs[peak_idx])),
+                    }
+                    match peak_encoding {
+                        PeakEncoding::NO_LOSS_PEAK => peaks_bytes.extend(f64_bytes(intensities[peak_idx] as f64)),
+                        _ => peaks_bytes.extend(f32_bytes(intensities[peak_idx])),
+                    }
+                    if mode == DataMode::FITTED {
+                        peaks_bytes.extend(f32_bytes(hwhms[peak_idx].0));
+                        peaks_bytes.extend(f32_bytes(hwhms[peak_idx].1));
+                    }
+                }
+                assert_eq!(peaks_bytes.len(), mzs.len() * de.get_peak_size(), "invalid peak size for {:?}", de);
+
+                let slice_view = SpectrumSliceView::new(1, mzs.len(), 0, &de, &peaks_bytes);
+                let slice_data = slice_view.to_spectrum_data();
+                assert_eq!(&slice_data.mz_array[..], &mzs[..], "invalid m/z values for {:?}", de);
+                assert_eq!(&slice_data.intensity_array[..], &intensities[..], "invalid intensities for {:?}", de);
+
+                if mode == DataMode::FITTED {
+                    assert_eq!(slice_data.lwhm_array.iter().copied().collect::<Vec<f32>>(), hwhms.iter().map(|hwhm| hwhm.0).collect::<Vec<f32>>());
+                    assert_eq!(slice_data.rwhm_array.iter().copied().collect::<Vec<f32>>(), hwhms.iter().map(|hwhm| hwhm.1).collect::<Vec<f32>>());
+                } else {
+                    assert!(slice_data.lwhm_array.is_empty() && slice_data.rwhm_array.is_empty());
+                }
+
+                // The bulk decoding matches the per-peak accessors
+                for peak_idx in 0..mzs.len() {
+                    assert_eq!(slice_view.get_mz_at(peak_idx), Some(slice_data.mz_array[peak_idx]));
+                    assert_eq!(slice_view.get_intensity_at(peak_idx), Some(slice_data.intensity_array[peak_idx]));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+pub fn run_ms2_quality_tests() -> Result<()>  {
+    use crate::qc::*;
+
+    let db = Connection::open("./data/OVEMB150205_12.mzDB")?;
+    let entity_cache = create_entity_cache(&db).location(here!())?;
+    let data_encoding = entity_cache.data_encodings_cache.get_data_encoding_by_spectrum_id(&1).context("missing data encoding")?.clone();
+
+    let to_spectrum_data = |mzs: &[f64], intensities: &[f32]| SpectrumData {
+        data_encoding: data_encoding.clone(),
+        peak_count: mzs.len(),
+        mz_array: mzs.into(),
+        intensity_array: intensities.into(),
+        lwhm_array: Vec::new().into(),
+        rwhm_array: Vec::new().into(),
+    };
+
+    // Uniform intensities maximize the entropy
+    let scores = score_ms2(&to_spectrum_data(&[100.0, 200.0, 300.0, 400.0], &[5.0, 5.0, 5.0, 5.0]));
+    assert!((scores.spectral_entropy - 4f32.ln()).abs() < 1e-6, "invalid entropy {}", scores.spectral_entropy);
+    assert_eq!(scores.top20_intensity_fraction, 1.0);
+    assert_eq!(score_ms2(&to_spectrum_data(&[100.0], &[5.0])).spectral_entropy, 0.0);
+
+    let mzs: Vec<f64> = (0..40).map(|i| 100.0 + i as f64).collect();
+    let intensities: Vec<f32> = (0..40).map(|i| if i < 20 { 3.0 } else { 1.0 }).collect();
+    assert_eq!(score_ms2(&to_spectrum_data(&mzs, &intensities)).top20_intensity_fraction, 0.75);
+
+    // The precursor and its isotopes explain 150 out of 200
+    let ms1_data = to_spectrum_data(&[470.0, 475.8724, 476.2068, 476.5], &[1000.0, 100.0, 50.0, 50.0]);
+    let isolation_window = IsolationWindow { min_mz: 474.87, max_mz: 476.87 };
+    let contamination = estimate_precursor_contamination(&ms1_data, 475.8724, Some(3), &isolation_window, &MzTolerance::PPM(10.0));
+    assert_eq!(contamination, Some(0.25));
+    let contamination = estimate_precursor_contamination(&ms1_data, 475.8724, None, &isolation_window, &MzTolerance::PPM(10.0));
+    assert_eq!(contamination, Some(0.5), "only the precursor peak is expected without charge");
+    let empty_window = IsolationWindow { min_mz: 600.0, max_mz: 602.0 };
+    assert_eq!(estimate_precursor_contamination(&ms1_data, 601.0, Some(2), &empty_window, &MzTolerance::PPM(10.0)), None);
+
+    let mut qualities = Vec::new();
+    for_each_ms2_quality(&db, &entity_cache, &MzTolerance::PPM(10.0), None, |ms2_quality| {
+        qualities.push(*ms2_quality);
+        Ok(())
+    }).location(here!())?;
+    assert_eq!(qualities.len(), 1035, "one quality per MS2 spectrum is expected");
+
+    let quality_17 = qualities.iter().find(|quality| quality.spectrum_id == 17).context("missing quality of spectrum 17")?;
+    let expected_ms1_id = entity_cache.spectrum_headers[..16].iter().rev().find(|sh| sh.ms_level == 1).map(|sh| sh.id);
+    assert_eq!(quality_17.ms1_spectrum_id, expected_ms1_id);
+    assert!(quality_17.precursor_contamination.is_some_and(|contamination| (0.0..=1.0).contains(&contamination)));
+    assert!(quality_17.scores.spectral_entropy > 0.0 && quality_17.scores.top20_intensity_fraction <= 1.0);
+
+    let summary = compute_ms2_quality_summary(&db, &entity_cache, &MzTolerance::PPM(10.0), None).location(here!())?;
+    assert_eq!(summary.ms2_count, 1035);
+    assert_eq!(summary.spectral_entropy_stats.map(|stats| stats.count), Some(1035));
+    assert_eq!(summary.precursor_contamination_stats.map(|stats| stats.count), Some(qualities.iter().filter(|q| q.precursor_contamination.is_some()).count()));
+
+    Ok(())
+}
@@ -0,0 +1,130 @@
+// Conversions between the different time axes of a run: spectrum index, acquisition cycle and retention time.
+// Retention times are expressed in seconds, as in the spectrum table.
+
+use std::ops::Range;
+
+use anyhow::*;
+
+use crate::model::SpectrumHeader;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CycleTimeAxis {
+    pub first_cycle: i64,
+    pub cycle_times: Vec<f32>, // time of each cycle from first_cycle to the last cycle, without gap
+    pub injected_cycles: Vec<i64>, // cycles missing from the file, whose time has been interpolated
+    spectrum_idx_ranges: Vec<Range<usize>>, // empty range for injected cycles
+    spectrum_cycles: Vec<i64>,
+}
+
+impl CycleTimeAxis {
+    /// Build the time axis from spectrum headers ordered by acquisition (as in EntityCache.spectrum_headers).
+    /// The time of a cycle is the time of its first spectrum. Missing cycles are injected with a linearly interpolated time.
+    pub fn new(spectrum_headers: &[SpectrumHeader]) -> Result<CycleTimeAxis> {
+        if spectrum_headers.is_empty() {
+            bail!("can't build a time axis without spectrum headers");
+        }
+
+        let first_cycle = spectrum_headers[0].cycle;
+        let last_cycle = spectrum_headers.last().unwrap().cycle;
+        if last_cycle < first_cycle {
+            bail!("spectrum headers are not ordered by cycle");
+        }
+
+        let cycles_count = (last_cycle - first_cycle + 1) as usize;
+        let mut cycle_time_opts: Vec<Option<f32>> = vec![None; cycles_count];
+        let mut spectrum_idx_ranges: Vec<Range<usize>> = vec![0..0; cycles_count];
+
+        let mut cycle_first_idx = 0;
+        for spectrum_idx in 1..=spectrum_headers.len() {
+            let is_cycle_end = spectrum_idx == spectrum_headers.len()
+                || spectrum_headers[spectrum_idx].cycle != spectrum_headers[cycle_first_idx].cycle;
+            if !is_cycle_end {
+                continue;
+            }
+
+            let cycle = spectrum_headers[cycle_first_idx].cycle;
+            if spectrum_idx < spectrum_headers.len() && spectrum_headers[spectrum_idx].cycle < cycle {
+                bail!("spectrum headers are not ordered by cycle (spectrum with ID={})", spectrum_headers[spectrum_idx].id);
+            }
+
+            let cycle_offset = (cycle - first_cycle) as usize;
+            cycle_time_opts[cycle_offset] = Some(spectrum_headers[cycle_first_idx].time);
+            spectrum_idx_ranges[cycle_offset] = cycle_first_idx..spectrum_idx;
+
+            cycle_first_idx = spectrum_idx;
+        }
+
+        // Interpolate the time of the missing cycles from the surrounding ones
+        let mut injected_cycles = Vec::new();
+        let mut cycle_times = Vec::with_capacity(cycles_count);
+        for cycle_offset in 0..cycles_count {
+            match cycle_time_opts[cycle_offset] {
+                Some(time) => cycle_times.push(time),
+                None => {
+                    let prev_offset = (0..cycle_offset).rev().find(|offset| cycle_time_opts[*offset].is_some()).unwrap();
+                    let next_offset = (cycle_offset + 1..cycles_count).find(|offset| cycle_time_opts[*offset].is_some()).unwrap();
+                    let (prev_time, next_time) = (cycle_time_opts[prev_offset].unwrap(), cycle_time_opts[next_offset].unwrap());
+
+                    let ratio = (cycle_offset - prev_offset) as f32 / (next_offset - prev_offset) as f32;
+                    cycle_times.push(prev_time + ratio * (next_time - prev_time));
+                    injected_cycles.push(first_cycle + cycle_offset as i64);
+                }
+            }
+        }
+
+        Ok(CycleTimeAxis {
+            first_cycle,
+            cycle_times,
+            injected_cycles,
+            spectrum_idx_ranges,
+            spectrum_cycles: spectrum_headers.iter().map(|sh| sh.cycle).collect(),
+        })
+    }
+
+    pub fn last_cycle(&self) -> i64 {
+        self.first_cycle + self.cycle_times.len() as i64 - 1
+    }
+
+    pub fn get_cycle_time(&self, cycle: i64) -> Option<f32> {
+        self._cycle_offset(cycle).map(|cycle_offset| self.cycle_times[cycle_offset])
+    }
+
+    /// Returns the cycle whose time is the nearest to the provided time
+    pub fn find_nearest_cycle(&self, time: f32) -> i64 {
+        let next_offset = self.cycle_times.partition_point(|cycle_time| *cycle_time < time);
+
+        let nearest_offset = if next_offset == 0 {
+            0
+        } else if next_offset == self.cycle_times.len()
+            || time - self.cycle_times[next_offset - 1] <= self.cycle_times[next_offset] - time {
+            next_offset - 1
+        } else {
+            next_offset
+        };
+
+        self.first_cycle + nearest_offset as i64
+    }
+
+    /// Returns the cycle of the spectrum at the provided index (position in the spectrum headers)
+    pub fn get_cycle_by_spectrum_idx(&self, spectrum_idx: usize) -> Option<i64> {
+        self.spectrum_cycles.get(spectrum_idx).copied()
+    }
+
+    /// Returns the range of spectrum indices of a cycle (empty for injected cycles)
+    pub fn get_spectrum_idx_range(&self, cycle: i64) -> Option<Range<usize>> {
+        self._cycle_offset(cycle).map(|cycle_offset| self.spectrum_idx_ranges[cycle_offset].clone())
+    }
+
+    /// Returns the time of the cycle containing the spectrum at the provided index
+    pub fn get_cycle_time_by_spectrum_idx(&self, spectrum_idx: usize) -> Option<f32> {
+        self.get_cycle_by_spectrum_idx(spectrum_idx).and_then(|cycle| self.get_cycle_time(cycle))
+    }
+
+    fn _cycle_offset(&self, cycle: i64) -> Option<usize> {
+        if cycle < self.first_cycle || cycle > self.last_cycle() {
+            None
+        } else {
+            Some((cycle - self.first_cycle) as usize)
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Universal Spectrum Identifier (USI) support, as defined by the HUPO-PSI (see https://www.psidev.info/usi).
+// Format: mzspec:<collection>:<msRunName>:<indexType>:<index>[:<interpretation>]
+// Example: mzspec:PXD000561:Adult_Frontalcortex_bRP_Elite_85_f09:scan:17555:VLHPLEGAVVIIFK/2
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::metadata::list_runs;
+use crate::model::*;
+use crate::queries::get_spectrum;
+
+const USI_PREFIX: &str = "mzspec";
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UsiIndexType {
+    SCAN,      // scan number, matched against spectrum.initial_id
+    INDEX,     // zero-based position of the spectrum in the run
+    NATIVE_ID, // native identifier, matched against spectrum.title
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Usi {
+    pub collection: String,
+    pub ms_run_name: String,
+    pub index_type: UsiIndexType,
+    pub index: String,
+    pub interpretation: Option<String>, // e.g. peptidoform/charge
+}
+
+/// Parse a USI string
+pub fn parse_usi(usi_str: &str) -> Result<Usi> {
+    let parts: Vec<&str> = usi_str.trim().split(':').collect();
+    if parts.len() < 5 || parts[0] != USI_PREFIX {
+        bail!("invalid USI '{}': expected format is {}:<collection>:<msRunName>:<indexType>:<index>", usi_str, USI_PREFIX);
+    }
+
+    // The run name may contain colons, so the index type is searched from the right of the collection
+    let to_index_type = |part: &str| -> Option<UsiIndexType> {
+        match part {
+            "scan" => Some(UsiIndexType::SCAN),
+            "index" => Some(UsiIndexType::INDEX),
+            "nativeId" => Some(UsiIndexType::NATIVE_ID),
+            _ => None,
+        }
+    };
+
+    let index_type_pos = (3..parts.len() - 1)
+        .find(|pos| to_index_type(parts[*pos]).is_some())
+        .context(format!("invalid USI '{}': missing or unknown index type (expected scan, index or nativeId)", usi_str))?;
+
+    let index = parts[index_type_pos + 1];
+    if index.is_empty() {
+        bail!("invalid USI '{}': empty index", usi_str);
+    }
+
+    let interpretation_parts = &parts[index_type_pos + 2..];
+
+    Ok(Usi {
+        collection: parts[1].to_string(),
+        ms_run_name: parts[2..index_type_pos].join(":"),
+        index_type: to_index_type(parts[index_type_pos]).unwrap(),
+        index: index.to_string(),
+        interpretation: if interpretation_parts.is_empty() { None } else { Some(interpretation_parts.join(":")) },
+    })
+}
+
+/// Find the id of the spectrum referenced by a USI.
+/// An error is returned if the USI run name doesn't match the run of the file, None if the spectrum can't be found.
+pub fn find_spectrum_id(db: &Connection, entity_cache: &EntityCache, usi: &Usi) -> Result<Option<i64>> {
+    let runs = list_runs(db).location(here!())?;
+    if !runs.iter().any(|run| run.name == usi.ms_run_name) {
+        let run_names: Vec<&str> = runs.iter().map(|run| run.name.as_str()).collect();
+        bail!("the USI run name '{}' doesn't match the file runs {:?}", usi.ms_run_name, run_names);
+    }
+
+    let headers = &entity_cache.spectrum_headers;
+
+    let spectrum_id_opt = match usi.index_type {
+        UsiIndexType::SCAN => {
+            let scan_number = usi.index.parse::<i64>().context(format!("invalid scan number: {}", usi.index)).location(here!())?;
+            headers.iter().find(|sh| sh.initial_id == scan_number).map(|sh| sh.id)
+        }
+        UsiIndexType::INDEX => {
+            let spectrum_idx = usi.index.parse::<usize>().context(format!("invalid spectrum index: {}", usi.index)).location(here!())?;
+            headers.get(spectrum_idx).map(|sh| sh.id)
+        }
+        UsiIndexType::NATIVE_ID => headers.iter().find(|sh| *sh.title == *usi.index).map(|sh| sh.id),
+    };
+
+    Ok(spectrum_id_opt)
+}
+
+/// Parse a USI and retrieve the corresponding spectrum
+pub fn get_spectrum_by_usi(db: &Connection, entity_cache: &EntityCache, usi_str: &str) -> Result<Spectrum> {
+    let usi = parse_usi(usi_str).location(here!())?;
+
+    let spectrum_id = find_spectrum_id(db, entity_cache, &usi).location(here!())?
+        .context(format!("can't find the spectrum referenced by USI '{}'", usi_str)).location(here!())?;
+
+    get_spectrum(db, spectrum_id, entity_cache).location(here!())
+}
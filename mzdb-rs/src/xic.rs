@@ -0,0 +1,325 @@
+// Extraction of XICs (extracted ion chromatograms), shared by the readers, the cohort, the quantification and the tools.
+// Stored SRM/SIC chromatograms equivalent to the requested XIC are returned as is (e.g. for SRM files).
+// Otherwise, only the bounding boxes of the m/z x time region of interest are decoded: they are selected by an R-tree query,
+// or by the spectrum headers when bounding_box_msn_rtree is empty (see ProducerQuirk::EMPTY_MSN_RTREE).
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use rusqlite::Connection;
+
+use crate::blob_cursor::BlobCursor;
+use crate::dia::get_isolation_window_index;
+use crate::mass::ppm_to_da;
+use crate::metadata::get_chromatogram_type;
+use crate::model::*;
+use crate::queries::{create_bbox, get_chromatogram_data, list_chromatogram_headers};
+use crate::xml::{find_cv_param_value, parse_cv_params_with_mode, XmlParsingMode};
+use crate::rtree::{for_each_bb_in_region, is_msn_rtree_populated, RtreeRegion};
+
+const SQLQUERY_BBS_OF_FIRST_SPECTRUM: &str = "SELECT * FROM bounding_box WHERE first_spectrum_id = ?";
+
+/// A point of an XIC: the most intense peak found in the m/z window of a spectrum, or a data point of a stored chromatogram
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct XicPeak {
+    pub spectrum_id: Option<i64>, // None for the data points of a stored chromatogram
+    pub time: f64, // SpectrumHeader.time_f64 of the spectrum
+    pub mz: f64,
+    pub intensity: f32,
+}
+
+/// An XIC, along with the parameters it was extracted with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Xic {
+    pub target_mz: f64,
+    pub tol_ppm: f64,
+    pub ms_level: u8,
+    pub parent_mz: Option<f64>, // always None for MS1 XICs
+    pub chromatogram_id: Option<i64>, // stored chromatogram the peaks were read from (see get_xic())
+    pub peaks: Vec<XicPeak>, // sorted by time, spectra without peak in the m/z window are skipped
+}
+
+impl Xic {
+    /// Convert the peaks into chromatogram data points, so that the chromatogram utilities can be applied to the XIC
+    pub fn to_chromatogram_data(&self) -> ChromatogramData {
+        ChromatogramData {
+            time_array: self.peaks.iter().map(|peak| peak.time).collect(),
+            intensity_array: self.peaks.iter().map(|peak| peak.intensity).collect(),
+        }
+    }
+}
+
+/// Extract the XIC of target_mz (+/- tol_ppm) from the spectra of the provided MS level.
+/// For MSn levels, the spectra can be restricted to the ones whose isolation window contains parent_mz (not allowed for MS1).
+/// For each spectrum, the most intense peak found in the m/z window is retained.
+/// The optional rt_range (in seconds, bounds are inclusive) is compared to SpectrumHeader.time_f64.
+/// If a stored chromatogram is equivalent to the requested XIC, its data points are returned instead of decoding the spectra
+/// (m/z values being set to target_mz, and times being shifted by the EntityCache.rt_offset):
+/// SRM chromatograms are matched on their precursor (parent_mz) and product (target_mz) isolation window targets,
+/// and SIC chromatograms on their precursor isolation window target (MS1 XICs only).
+pub fn get_xic(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    target_mz: f64,
+    tol_ppm: f64,
+    ms_level: u8,
+    parent_mz: Option<f64>,
+    rt_range: Option<(f64, f64)>,
+) -> Result<Xic> {
+    if tol_ppm <= 0.0 {
+        bail!("invalid m/z tolerance {} ppm", tol_ppm);
+    }
+    if ms_level == 1 && parent_mz.is_some() {
+        bail!("a parent m/z can't be provided for MS1 XICs");
+    }
+
+    if let Some(stored_xic) = _get_stored_xic(db, entity_cache, target_mz, tol_ppm, ms_level, parent_mz, rt_range).location(here!())? {
+        return Ok(stored_xic);
+    }
+
+    _extract_xic(db, entity_cache, target_mz, tol_ppm, ms_level, parent_mz, rt_range)
+}
+
+// Extract the XIC from the peaks of the spectra (see get_xic())
+fn _extract_xic(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    target_mz: f64,
+    tol_ppm: f64,
+    ms_level: u8,
+    parent_mz: Option<f64>,
+    rt_range: Option<(f64, f64)>,
+) -> Result<Xic> {
+    let mz_tol = ppm_to_da(target_mz, tol_ppm);
+    let region_peaks = collect_region_peaks(
+        db, entity_cache, ms_level, parent_mz, rt_range, target_mz - mz_tol, target_mz + mz_tol
+    ).location(here!())?;
+
+    let peaks = region_peaks.headers.iter().filter_map(|sh| {
+        let (mz, intensity) = region_peaks.peaks_by_spectrum_id.get(&sh.id)?.iter()
+            .max_by(|(_, intensity1), (_, intensity2)| intensity1.total_cmp(intensity2))?;
+
+        Some(XicPeak { spectrum_id: Some(sh.id), time: sh.time_f64, mz: *mz, intensity: *intensity })
+    }).collect();
+
+    Ok(Xic { target_mz, tol_ppm, ms_level, parent_mz, chromatogram_id: None, peaks })
+}
+
+// Look for a stored chromatogram equivalent to the requested XIC (see get_xic())
+fn _get_stored_xic(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    target_mz: f64,
+    tol_ppm: f64,
+    ms_level: u8,
+    parent_mz: Option<f64>,
+    rt_range: Option<(f64, f64)>,
+) -> Result<Option<Xic>> {
+    let matches_mz = |xml_opt: Option<&String>, mz: f64| -> Result<bool> {
+        let xml = match xml_opt {
+            Some(xml) => xml,
+            None => return Ok(false),
+        };
+
+        let cv_params = parse_cv_params_with_mode(xml, XmlParsingMode::LENIENT).location(here!())?;
+        let target_mz_opt = find_cv_param_value(&cv_params, ISOLATION_WINDOW_TARGET_MZ).and_then(|value| value.parse::<f64>().ok());
+
+        Ok(target_mz_opt.is_some_and(|stored_mz| (stored_mz - mz).abs() <= ppm_to_da(mz, tol_ppm)))
+    };
+
+    for chrom_header in list_chromatogram_headers(db).location(here!())? {
+        let is_equivalent = match (get_chromatogram_type(db, &chrom_header).location(here!())?, parent_mz) {
+            (ChromatogramType::SRM, Some(parent_mz)) if ms_level > 1 => {
+                matches_mz(chrom_header.precursor.as_ref(), parent_mz).location(here!())?
+                    && matches_mz(chrom_header.product.as_ref(), target_mz).location(here!())?
+            }
+            (ChromatogramType::SIC, None) if ms_level == 1 => matches_mz(chrom_header.precursor.as_ref(), target_mz).location(here!())?,
+            _ => false,
+        };
+
+        if !is_equivalent {
+            continue;
+        }
+
+        let chrom_data = get_chromatogram_data(db, chrom_header.id).location(here!())?
+            .context(format!("can't find chromatogram with ID={}", chrom_header.id)).location(here!())?;
+
+        let (min_time, max_time) = rt_range.unwrap_or((f64::MIN, f64::MAX));
+        let peaks = chrom_data.time_array.iter().zip(chrom_data.intensity_array.iter())
+            .map(|(time, intensity)| XicPeak { spectrum_id: None, time: time + entity_cache.rt_offset, mz: target_mz, intensity: *intensity })
+            .filter(|peak| peak.time >= min_time && peak.time <= max_time)
+            .collect();
+
+        return Ok(Some(Xic { target_mz, tol_ppm, ms_level, parent_mz, chromatogram_id: Some(chrom_header.id), peaks }));
+    }
+
+    Ok(None)
+}
+
+/// Extract the XIC of an m/z value from the MS1 spectra (see get_xic())
+pub fn get_ms1_xic(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    mz: f64,
+    tol_ppm: f64,
+    rt_range: Option<(f64, f64)>,
+) -> Result<Xic> {
+    get_xic(db, entity_cache, mz, tol_ppm, 1, None, rt_range)
+}
+
+/// Extract the XIC of a fragment m/z value from the MS2 spectra isolating parent_mz (see get_xic())
+pub fn get_msn_xic(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    parent_mz: f64,
+    fragment_mz: f64,
+    tol_ppm: f64,
+    rt_range: Option<(f64, f64)>,
+) -> Result<Xic> {
+    get_xic(db, entity_cache, fragment_mz, tol_ppm, 2, Some(parent_mz), rt_range)
+}
+
+/// Extract the XIC of a fragment m/z value from the MS2 spectra of all the isolation windows intersecting precursor_mz +/- precursor_tol_ppm,
+/// so that the traces of DIA precursors lying on a window border are not truncated.
+/// Each window is queried separately (stored chromatograms being ignored), and the traces are merged per cycle: when several windows report a peak,
+/// the one of the window whose center is the nearest from precursor_mz is retained, as Skyline does.
+/// Returns an error if no isolation window intersects the precursor m/z range (see dia::get_isolation_window_index()).
+pub fn get_msn_xic_across_windows(
+    db: &Connection,
+    entity_cache: &EntityCache,
+    precursor_mz: f64,
+    precursor_tol_ppm: f64,
+    fragment_mz: f64,
+    tol_ppm: f64,
+    rt_range: Option<(f64, f64)>,
+) -> Result<Xic> {
+    let precursor_mz_tol = ppm_to_da(precursor_mz, precursor_tol_ppm);
+    let (min_precursor_mz, max_precursor_mz) = (precursor_mz - precursor_mz_tol, precursor_mz + precursor_mz_tol);
+
+    let window_index = get_isolation_window_index(db, entity_cache).location(here!())?;
+
+    // Nearest window centers first
+    let window_center = |window: &IsolationWindow| (window.min_mz + window.max_mz) / 2.0;
+    let mut window_centers: Vec<f64> = window_index.isolation_windows.iter()
+        .filter(|window| window.min_mz <= max_precursor_mz && window.max_mz >= min_precursor_mz)
+        .map(window_center)
+        .collect();
+    window_centers.sort_by(|center1, center2| (center1 - precursor_mz).abs().total_cmp(&(center2 - precursor_mz).abs()));
+
+    if window_centers.is_empty() {
+        bail!("no isolation window intersects the precursor m/z range [{}, {}]", min_precursor_mz, max_precursor_mz);
+    }
+
+    let mut peak_by_cycle: HashMap<i64, XicPeak> = HashMap::new();
+    for window_center in window_centers {
+        let window_xic = _extract_xic(db, entity_cache, fragment_mz, tol_ppm, 2, Some(window_center), rt_range).location(here!())?;
+
+        // Peaks extracted from the spectra always have a spectrum ID
+        for peak in window_xic.peaks {
+            if let Some(spectrum_id) = peak.spectrum_id {
+                let cycle = entity_cache.spectrum_headers[(spectrum_id - 1) as usize].cycle;
+                peak_by_cycle.entry(cycle).or_insert(peak);
+            }
+        }
+    }
+
+    let mut peaks: Vec<XicPeak> = peak_by_cycle.into_values().collect();
+    peaks.sort_by(|peak1, peak2| peak1.time.total_cmp(&peak2.time));
+
+    Ok(Xic { target_mz: fragment_mz, tol_ppm, ms_level: 2, parent_mz: Some(precursor_mz), chromatogram_id: None, peaks })
+}
+
+// Spectra selected by collect_region_peaks() and their peaks in the m/z range
+pub(crate) struct RegionPeaks<'a> {
+    pub headers: Vec<&'a SpectrumHeader>, // sorted by ID
+    pub peaks_by_spectrum_id: HashMap<i64, Vec<(f64, f32)>>, // spectra without peak in the m/z range are missing
+}
+
+/// Select the spectra of the MS level in the RT range, and collect their peaks in the m/z range.
+/// MS1 spectra are all selected, even if no bounding box of the R-tree region contains them.
+/// MSn spectra are restricted to parent_mz using the parent m/z ranges of bounding_box_msn_rtree,
+/// or the isolation windows of the spectra when the R-tree is empty (see dia::get_isolation_window_index()).
+pub(crate) fn collect_region_peaks<'a>(
+    db: &Connection,
+    entity_cache: &'a EntityCache,
+    ms_level: u8,
+    parent_mz_opt: Option<f64>,
+    rt_range: Option<(f64, f64)>,
+    min_mz: f64,
+    max_mz: f64,
+) -> Result<RegionPeaks<'a>> {
+    if ms_level == 0 {
+        bail!("invalid MS level {}", ms_level);
+    }
+
+    let (min_time, max_time) = rt_range.unwrap_or((f64::MIN, f64::MAX));
+
+    let mut headers: Vec<&SpectrumHeader> = entity_cache.spectrum_headers.iter()
+        .filter(|sh| sh.ms_level == ms_level as i64 && sh.time_f64 >= min_time && sh.time_f64 <= max_time)
+        .collect();
+
+    let mut peaks_by_spectrum_id: HashMap<i64, Vec<(f64, f32)>> = HashMap::new();
+    let mut add_bb_peaks = |bb: &BoundingBox, bb_spectrum_ids: &mut HashSet<i64>| -> Result<()> {
+        let bb_cursor = BlobCursor::new(&bb.blob_data, &entity_cache.data_encodings_cache);
+
+        for view_res in bb_cursor {
+            let view = view_res.location(here!())?;
+            bb_spectrum_ids.insert(view.spectrum_id);
+            if view.peaks_count == 0 {
+                continue;
+            }
+
+            let region_data = view.to_spectrum_data_in_mz_range(Some(min_mz), Some(max_mz));
+            if region_data.peak_count == 0 {
+                continue;
+            }
+
+            peaks_by_spectrum_id.entry(view.spectrum_id).or_default().extend(
+                region_data.mz_array.iter().copied().zip(region_data.intensity_array.iter().copied())
+            );
+        }
+
+        Ok(())
+    };
+
+    // Spectra found in the selected bounding boxes
+    let mut bb_spectrum_ids: HashSet<i64> = HashSet::new();
+
+    if ms_level == 1 || is_msn_rtree_populated(db).location(here!())? {
+        // The R-tree time bounds are widened by the f32 precision (see rtree::query_region_auto()), spectra are thus filtered on their exact time below
+        let region = RtreeRegion { min_mz, max_mz, min_time, max_time };
+        for_each_bb_in_region(db, entity_cache, ms_level, &region, parent_mz_opt, |bb: BoundingBox| {
+            add_bb_peaks(&bb, &mut bb_spectrum_ids)
+        }).location(here!())?;
+
+        if ms_level > 1 && parent_mz_opt.is_some() {
+            headers.retain(|sh| bb_spectrum_ids.contains(&sh.id));
+        }
+    } else {
+        if let Some(parent_mz) = parent_mz_opt {
+            let window_index = get_isolation_window_index(db, entity_cache).location(here!())?;
+            headers.retain(|sh| {
+                window_index.get_isolation_window_by_spectrum_id(&sh.id)
+                    .is_some_and(|window| window.min_mz <= parent_mz && parent_mz <= window.max_mz)
+            });
+        }
+
+        let mut bb_first_spectrum_ids: Vec<i64> = headers.iter().map(|sh| sh.bb_first_spectrum_id).collect();
+        bb_first_spectrum_ids.dedup();
+
+        let mut stmt = db.prepare(SQLQUERY_BBS_OF_FIRST_SPECTRUM).location(here!())?;
+        for bb_first_spectrum_id in bb_first_spectrum_ids {
+            let mut rows = stmt.query([bb_first_spectrum_id]).location(here!())?;
+            while let Some(row) = rows.next().location(here!())? {
+                add_bb_peaks(&create_bbox(row).location(here!())?, &mut bb_spectrum_ids).location(here!())?;
+            }
+        }
+    }
+
+    let header_ids: HashSet<i64> = headers.iter().map(|sh| sh.id).collect();
+    peaks_by_spectrum_id.retain(|spectrum_id, _| header_ids.contains(spectrum_id));
+
+    Ok(RegionPeaks { headers, peaks_by_spectrum_id })
+}
@@ -0,0 +1,374 @@
+use anyhow::*;
+use crate::anyhow_ext::*;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::model::*;
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum XmlParsingMode {
+    STRICT,
+    // Ignores namespace prefixes, keeps undeclared entities as is and tolerates mismatched end tags,
+    // which are occasionally found in fragments produced by vendor converters
+    LENIENT,
+}
+
+fn _create_reader(xml: &str, mode: XmlParsingMode) -> Reader<&[u8]> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    if mode == XmlParsingMode::LENIENT {
+        reader.check_end_names(false);
+    }
+
+    reader
+}
+
+/// Returns the element (or attribute) name, without its namespace prefix in lenient mode
+fn _local_name(name: &[u8], mode: XmlParsingMode) -> &[u8] {
+    match mode {
+        XmlParsingMode::STRICT => name,
+        XmlParsingMode::LENIENT => name.rsplit(|b| *b == b':').next().unwrap_or(name),
+    }
+}
+
+/// Replace the predefined XML entities and the character references, keeping the other entities as is
+fn _unescape_lenient(raw_value: &str) -> String {
+    let mut value = String::with_capacity(raw_value.len());
+
+    let mut remaining = raw_value;
+    while let Some(amp_pos) = remaining.find('&') {
+        value.push_str(&remaining[..amp_pos]);
+        remaining = &remaining[amp_pos..];
+
+        let entity_opt = remaining.find(';').map(|semicolon_pos| &remaining[1..semicolon_pos]);
+        let replacement_opt = entity_opt.and_then(|entity| match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "apos" => Some('\''),
+            "quot" => Some('"'),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse::<u32>().ok().and_then(char::from_u32),
+            _ => None,
+        });
+
+        match (entity_opt, replacement_opt) {
+            (Some(entity), Some(replacement)) => {
+                value.push(replacement);
+                remaining = &remaining[entity.len() + 2..];
+            }
+            _ => {
+                value.push('&');
+                remaining = &remaining[1..];
+            }
+        }
+    }
+    value.push_str(remaining);
+
+    value
+}
+
+fn _get_attribute_value(reader: &Reader<&[u8]>, element: &BytesStart, attr_name: &[u8], mode: XmlParsingMode) -> Result<Option<String>> {
+    for attr_res in element.attributes() {
+        let attr = attr_res.location(here!())?;
+        if _local_name(attr.key, mode) == attr_name {
+            let value = match (attr.unescape_and_decode_value(reader), mode) {
+                (Result::Ok(value), _) => value,
+                (Err(_), XmlParsingMode::LENIENT) => _unescape_lenient(std::str::from_utf8(&attr.value).location(here!())?),
+                (Err(e), XmlParsingMode::STRICT) => bail!("can't decode value of attribute {}: {}", String::from_utf8_lossy(attr_name), e),
+            };
+            return Ok(Some(value));
+        }
+    }
+
+    Ok(None)
+}
+
+fn _create_cv_param(reader: &Reader<&[u8]>, element: &BytesStart, mode: XmlParsingMode) -> Result<CvParam> {
+    let get_attr = |attr_name: &[u8]| -> Result<String> {
+        _get_attribute_value(reader, element, attr_name, mode).map(|v_opt| v_opt.unwrap_or_default())
+    };
+
+    Ok(CvParam {
+        cv_ref: get_attr(b"cvRef")?,
+        accession: get_attr(b"accession")?,
+        name: get_attr(b"name")?,
+        value: get_attr(b"value")?,
+        unit_cv_ref: get_attr(b"unitCvRef")?,
+        unit_accession: get_attr(b"unitAccession")?,
+        unit_name: get_attr(b"unitName")?,
+    })
+}
+
+fn _create_user_param(reader: &Reader<&[u8]>, element: &BytesStart, mode: XmlParsingMode) -> Result<UserParam> {
+    let get_attr = |attr_name: &[u8]| -> Result<String> {
+        _get_attribute_value(reader, element, attr_name, mode).map(|v_opt| v_opt.unwrap_or_default())
+    };
+
+    Ok(UserParam {
+        cv_ref: get_attr(b"cvRef")?,
+        accession: get_attr(b"accession")?,
+        name: get_attr(b"name")?,
+        value: get_attr(b"value")?,
+        r#type: get_attr(b"type")?,
+    })
+}
+
+/// Parse all the cvParam elements of a XML fragment, whatever their nesting level
+pub fn parse_cv_params(xml: &str) -> Result<Vec<CvParam>> {
+    parse_cv_params_with_mode(xml, XmlParsingMode::STRICT)
+}
+
+/// Same as parse_cv_params, using the provided parsing mode
+pub fn parse_cv_params_with_mode(xml: &str, mode: XmlParsingMode) -> Result<Vec<CvParam>> {
+    let mut reader = _create_reader(xml, mode);
+
+    let mut cv_params = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Result::Ok(Event::Start(ref e)) | Result::Ok(Event::Empty(ref e)) if _local_name(e.name(), mode) == b"cvParam" => {
+                cv_params.push(_create_cv_param(&reader, e, mode).location(here!())?);
+            }
+            Result::Ok(Event::Eof) => break,
+            Err(e) => bail!("can't parse XML at position {}: {}", reader.buffer_position(), e),
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(cv_params)
+}
+
+/// Parse all the userParam elements of a XML fragment, whatever their nesting level
+pub fn parse_user_params(xml: &str) -> Result<Vec<UserParam>> {
+    parse_user_params_with_mode(xml, XmlParsingMode::STRICT)
+}
+
+/// Same as parse_user_params, using the provided parsing mode
+pub fn parse_user_params_with_mode(xml: &str, mode: XmlParsingMode) -> Result<Vec<UserParam>> {
+    let mut reader = _create_reader(xml, mode);
+
+    let mut user_params = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Result::Ok(Event::Start(ref e)) | Result::Ok(Event::Empty(ref e)) if _local_name(e.name(), mode) == b"userParam" => {
+                user_params.push(_create_user_param(&reader, e, mode).location(here!())?);
+            }
+            Result::Ok(Event::Eof) => break,
+            Err(e) => bail!("can't parse XML at position {}: {}", reader.buffer_position(), e),
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(user_params)
+}
+
+/// Parse the cvParams and userParams of a param tree (userTexts are not parsed)
+pub fn parse_param_tree(xml: &str) -> Result<ParamTree> {
+    Ok(ParamTree {
+        cv_params: parse_cv_params(xml).location(here!())?,
+        user_params: parse_user_params(xml).location(here!())?,
+        user_texts: Vec::new(),
+    })
+}
+
+/// Get the value of the first cvParam having the provided accession
+pub fn find_cv_param_value<'a>(cv_params: &'a [CvParam], accession: &str) -> Option<&'a str> {
+    cv_params.iter().find(|cv_param| cv_param.accession == accession).map(|cv_param| cv_param.value.as_str())
+}
+
+/// Extract the isolation window of the first precursor found in a precursor_list
+pub fn extract_isolation_window(precursor_list_xml: &str) -> Result<Option<IsolationWindow>> {
+    let cv_params = parse_cv_params(precursor_list_xml).location(here!())?;
+
+    _create_isolation_window(&cv_params)
+}
+
+/// Parse the component_list of an instrument configuration (sources, analyzers and detectors)
+pub fn parse_component_list(component_list_xml: &str) -> Result<ComponentList> {
+    let mut reader = Reader::from_str(component_list_xml);
+    reader.trim_text(true);
+
+    let to_component_type = |element_name: &[u8]| -> Option<ComponentType> {
+        match element_name {
+            b"source" => Some(ComponentType::SOURCE),
+            b"analyzer" => Some(ComponentType::ANALYZER),
+            b"detector" => Some(ComponentType::DETECTOR),
+            _ => None,
+        }
+    };
+
+    let mut components = Vec::new();
+    let mut cur_component: Option<Component> = None;
+
+    let mut buf = Vec::new();
+    loop {
+        let event = reader.read_event(&mut buf);
+        let is_empty_element = matches!(event, Result::Ok(Event::Empty(_)));
+
+        match event {
+            Result::Ok(Event::Start(ref e)) | Result::Ok(Event::Empty(ref e)) => {
+                if let Some(component_type) = to_component_type(e.name()) {
+                    let order_str = _get_attribute_value(&reader, e, b"order", XmlParsingMode::STRICT).location(here!())?.unwrap_or_default();
+
+                    let component = Component {
+                        component_type,
+                        order: order_str.parse::<i32>().unwrap_or(0),
+                        cv_params: Vec::new(),
+                        user_params: Vec::new(),
+                    };
+
+                    // A self-closing component has no params
+                    if is_empty_element {
+                        components.push(component);
+                    } else {
+                        cur_component = Some(component);
+                    }
+                } else if let Some(component) = cur_component.as_mut() {
+                    if e.name() == b"cvParam" {
+                        component.cv_params.push(_create_cv_param(&reader, e, XmlParsingMode::STRICT).location(here!())?);
+                    } else if e.name() == b"userParam" {
+                        component.user_params.push(_create_user_param(&reader, e, XmlParsingMode::STRICT).location(here!())?);
+                    }
+                }
+            }
+            Result::Ok(Event::End(ref e)) if to_component_type(e.name()).is_some() => {
+                if let Some(component) = cur_component.take() {
+                    components.push(component);
+                }
+            }
+            Result::Ok(Event::Eof) => break,
+            Err(e) => bail!("can't parse XML at position {}: {}", reader.buffer_position(), e),
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    components.sort_by_key(|c| c.order);
+
+    Ok(ComponentList { components })
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PrecursorSection {
+    ISOLATION_WINDOW,
+    SELECTED_ION,
+    ACTIVATION,
+}
+
+/// Parse the precursors of a precursor_list (isolation window, selected ions and activation)
+pub fn parse_precursor_list(precursor_list_xml: &str) -> Result<Vec<Precursor>> {
+    let mut reader = Reader::from_str(precursor_list_xml);
+    reader.trim_text(true);
+
+    let to_section = |element_name: &[u8]| -> Option<PrecursorSection> {
+        match element_name {
+            b"isolationWindow" => Some(PrecursorSection::ISOLATION_WINDOW),
+            b"selectedIon" => Some(PrecursorSection::SELECTED_ION),
+            b"activation" => Some(PrecursorSection::ACTIVATION),
+            _ => None,
+        }
+    };
+
+    let mut precursors = Vec::new();
+    let mut cur_precursor: Option<Precursor> = None;
+    let mut cur_section: Option<PrecursorSection> = None;
+    let mut section_cv_params: Vec<CvParam> = Vec::new();
+
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Result::Ok(Event::Start(ref e)) => {
+                if e.name() == b"precursor" {
+                    cur_precursor = Some(Precursor {
+                        spectrum_ref: _get_attribute_value(&reader, e, b"spectrumRef", XmlParsingMode::STRICT).location(here!())?,
+                        isolation_window: None,
+                        selected_ions: Vec::new(),
+                        activation: None,
+                    });
+                } else if let Some(section) = to_section(e.name()) {
+                    cur_section = Some(section);
+                    section_cv_params.clear();
+                } else if e.name() == b"cvParam" && cur_section.is_some() {
+                    // cvParam element written with a closing tag
+                    section_cv_params.push(_create_cv_param(&reader, e, XmlParsingMode::STRICT).location(here!())?);
+                }
+            }
+            Result::Ok(Event::Empty(ref e)) if e.name() == b"cvParam" && cur_section.is_some() => {
+                section_cv_params.push(_create_cv_param(&reader, e, XmlParsingMode::STRICT).location(here!())?);
+            }
+            Result::Ok(Event::End(ref e)) => {
+                if e.name() == b"precursor" {
+                    if let Some(precursor) = cur_precursor.take() {
+                        precursors.push(precursor);
+                    }
+                } else if let (Some(section), Some(precursor)) = (to_section(e.name()), cur_precursor.as_mut()) {
+                    let cv_params = std::mem::take(&mut section_cv_params);
+                    match section {
+                        PrecursorSection::ISOLATION_WINDOW => {
+                            precursor.isolation_window = _create_isolation_window(&cv_params).location(here!())?;
+                        }
+                        PrecursorSection::SELECTED_ION => {
+                            if let Some(selected_ion) = _create_selected_ion(&cv_params).location(here!())? {
+                                precursor.selected_ions.push(selected_ion);
+                            }
+                        }
+                        PrecursorSection::ACTIVATION => {
+                            let collision_energy = _parse_cv_param_value::<f64>(&cv_params, COLLISION_ENERGY).location(here!())?;
+                            precursor.activation = Some(Activation { cv_params, collision_energy });
+                        }
+                    }
+                    cur_section = None;
+                }
+            }
+            Result::Ok(Event::Eof) => break,
+            Err(e) => bail!("can't parse XML at position {}: {}", reader.buffer_position(), e),
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(precursors)
+}
+
+fn _parse_cv_param_value<T>(cv_params: &[CvParam], accession: &str) -> Result<Option<T>>
+    where T: std::str::FromStr, T::Err: std::error::Error + Send + Sync + 'static {
+    find_cv_param_value(cv_params, accession)
+        .map(|value| value.parse::<T>().context(format!("invalid value for {}: {}", accession, value)))
+        .transpose()
+}
+
+fn _create_isolation_window(cv_params: &[CvParam]) -> Result<Option<IsolationWindow>> {
+    let target_mz_opt = _parse_cv_param_value::<f64>(cv_params, ISOLATION_WINDOW_TARGET_MZ).location(here!())?;
+    if target_mz_opt.is_none() {
+        return Ok(None);
+    }
+
+    let target_mz = target_mz_opt.unwrap();
+    let lower_offset = _parse_cv_param_value::<f64>(cv_params, ISOLATION_WINDOW_LOWER_OFFSET).location(here!())?.unwrap_or(0.0);
+    let upper_offset = _parse_cv_param_value::<f64>(cv_params, ISOLATION_WINDOW_UPPER_OFFSET).location(here!())?.unwrap_or(0.0);
+
+    Ok(Some(IsolationWindow {
+        min_mz: target_mz - lower_offset,
+        max_mz: target_mz + upper_offset,
+    }))
+}
+
+fn _create_selected_ion(cv_params: &[CvParam]) -> Result<Option<SelectedIon>> {
+    let mz_opt = _parse_cv_param_value::<f64>(cv_params, SELECTED_ION_MZ).location(here!())?;
+    if mz_opt.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(SelectedIon {
+        mz: mz_opt.unwrap(),
+        charge: _parse_cv_param_value::<i32>(cv_params, CHARGE_STATE).location(here!())?,
+        intensity: _parse_cv_param_value::<f32>(cv_params, PEAK_INTENSITY).location(here!())?,
+    }))
+}
@@ -0,0 +1,81 @@
+// Checks of the C API, called as a C program would do.
+// Usage: cargo test --features mzdb-capi --test capi
+#![cfg(feature = "mzdb-capi")]
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+use mzdb::capi::*;
+
+extern "C" fn count_ms1_headers(header: *const MzdbSpectrumHeader, user_data: *mut c_void) -> c_int {
+    let ms1_count = unsafe { &mut *(user_data as *mut usize) };
+    if unsafe { (*header).ms_level } == 1 {
+        *ms1_count += 1;
+    }
+
+    0
+}
+
+#[test]
+fn run_capi_checks() {
+    unsafe { _run_capi_checks() }
+}
+
+unsafe fn _run_capi_checks() {
+    let missing_path = CString::new("./data/missing.mzDB").unwrap();
+    assert!(mzdb_open(missing_path.as_ptr()).is_null());
+    assert!(!mzdb_last_error().is_null(), "the error message should be available");
+
+    let path = CString::new("./data/OVEMB150205_12.mzDB").unwrap();
+    let reader = mzdb_open(path.as_ptr());
+    assert!(!reader.is_null(), "{:?}", CStr::from_ptr(mzdb_last_error()));
+    assert_eq!(mzdb_get_spectra_count(reader), 1193);
+
+    let mut header = std::mem::MaybeUninit::<MzdbSpectrumHeader>::uninit();
+    assert_eq!(mzdb_get_spectrum_header(reader, 17, header.as_mut_ptr()), 0);
+    let header = header.assume_init();
+    assert_eq!((header.id, header.ms_level), (17, 2));
+    assert!((header.precursor_mz - 475.8724).abs() < 0.001);
+
+    let mut other_header = header;
+    assert_eq!(mzdb_get_spectrum_header(reader, 0, &mut other_header), -1, "spectrum IDs start at 1");
+
+    let mut ms1_count = 0usize;
+    assert_eq!(mzdb_for_each_spectrum_header(reader, count_ms1_headers, &mut ms1_count as *mut usize as *mut c_void), 0);
+    assert_eq!(ms1_count, 158);
+
+    let mut data = MzdbSpectrumData { peaks_count: 0, mz_array: ptr::null_mut(), intensity_array: ptr::null_mut() };
+    assert_eq!(mzdb_get_spectrum_data(reader, 17, &mut data), 0);
+    assert_eq!(data.peaks_count as i64, header.peaks_count);
+
+    let mz_values = std::slice::from_raw_parts(data.mz_array, data.peaks_count);
+    assert!(mz_values.windows(2).all(|w| w[0] <= w[1]));
+
+    mzdb_free_spectrum_data(&mut data);
+    assert!(data.mz_array.is_null() && data.intensity_array.is_null());
+
+    let empty_xic_data = || MzdbXicData {
+        points_count: 0, spectrum_ids: ptr::null_mut(), time_array: ptr::null_mut(), mz_array: ptr::null_mut(), intensity_array: ptr::null_mut()
+    };
+    let mut xic_data = empty_xic_data();
+    assert_eq!(mzdb_get_xic(reader, header.precursor_mz, 10.0, 1, f64::NAN, f64::NAN, f64::NAN, &mut xic_data), 0);
+    assert!(xic_data.points_count > 0, "the precursor should be detected");
+
+    let xic_times = std::slice::from_raw_parts(xic_data.time_array, xic_data.points_count);
+    let xic_spectrum_ids = std::slice::from_raw_parts(xic_data.spectrum_ids, xic_data.points_count);
+    assert!(xic_times.windows(2).all(|w| w[0] < w[1]));
+    assert!(xic_spectrum_ids.iter().all(|spectrum_id| *spectrum_id > 0));
+
+    let mut ranged_xic_data = empty_xic_data();
+    assert_eq!(mzdb_get_xic(reader, header.precursor_mz, 10.0, 1, f64::NAN, xic_times[1], f64::NAN, &mut ranged_xic_data), 0);
+    assert_eq!(ranged_xic_data.points_count, xic_data.points_count - 1);
+    mzdb_free_xic_data(&mut ranged_xic_data);
+
+    mzdb_free_xic_data(&mut xic_data);
+    assert!(xic_data.time_array.is_null() && xic_data.points_count == 0);
+    assert_eq!(mzdb_get_xic(reader, header.precursor_mz, 10.0, 1, 476.2, f64::NAN, f64::NAN, &mut xic_data), -1, "a parent m/z is not allowed for MS1");
+
+    assert_eq!(mzdb_close(reader), 0);
+    assert_eq!(mzdb_close(ptr::null_mut()), -1, "NULL reader should be reported");
+}
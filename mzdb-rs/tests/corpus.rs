@@ -0,0 +1,36 @@
+// Compatibility checks over a directory of real-world mzDB files.
+// Usage: MZDB_CORPUS_DIR=/path/to/corpus cargo test --test corpus -- --nocapture
+// The test is skipped when MZDB_CORPUS_DIR is not defined.
+
+use mzdb::corpus::check_directory;
+
+const CORPUS_DIR_ENV_VAR: &str = "MZDB_CORPUS_DIR";
+
+#[test]
+fn run_corpus_checks() {
+    let corpus_dir = match std::env::var(CORPUS_DIR_ENV_VAR) {
+        Ok(corpus_dir) => corpus_dir,
+        Err(_) => {
+            println!("{} is not defined, skipping the corpus checks", CORPUS_DIR_ENV_VAR);
+            return;
+        }
+    };
+
+    let reports = check_directory(&corpus_dir, None, None).unwrap();
+    assert!(!reports.is_empty(), "no mzDB file found in {}", corpus_dir);
+
+    let mut failures = Vec::new();
+    for report in reports.iter() {
+        println!("{}: quirks={:?}, diagnostics={}", report.path, report.quirks, report.diagnostics_count);
+
+        for step_result in report.step_results.iter() {
+            println!("  {:?} ({:?})", step_result.step, step_result.duration);
+
+            if let Some(error) = step_result.error.as_ref() {
+                failures.push(format!("{} [{:?}]: {}", report.path, step_result.step, error));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{} of {} files failed:\n{}", failures.len(), reports.len(), failures.join("\n"));
+}
@@ -3,27 +3,31 @@ dead_code,
 unused_imports
 )]
 
-mod sqlite_helper;
+mod metadata_types;
 
 extern crate mzdb;
 
 use std::fmt::format;
 use anyhow::*;
 use std::io::Write;
+use std::sync::Arc;
 use mzdb::anyhow_ext::ErrorLocation;
 
 use pyo3::prelude::*;
 use rusqlite::Connection;
 
-use crate::sqlite_helper::*;
+use crate::metadata_types::*;
 
 use mzdb::anyhow_ext::Location;
+use mzdb::ffi_support::{SharedConnection, SharedReader};
 use mzdb::{here, iterator};
+use mzdb::metadata;
 use mzdb::model::*;
 use mzdb::mzdb::create_entity_cache;
 use mzdb::queries;
 use mzdb::iterator::*;
 use mzdb::queries::{BOUNDING_BOX_TABLE_NAME, DATA_ENCODING_TABLE_NAME, SPECTRUM_TABLE_NAME};
+use mzdb::rtree::RtreeEntry;
 
 
 /// Formats the sum of two numbers as string.
@@ -35,10 +39,7 @@ fn sum_as_string(a: usize, b: usize) -> Result<String> {
 #[derive(Clone)]
 #[pyclass]
 pub struct MzdbReader {
-    #[pyo3(get)]
-    pub is_closed: bool,
-    _dbh_address: i64, // memory address of *mut rusqlite::ffi::sqlite3
-    _entity_cache: EntityCache
+    _reader: SharedReader
 }
 
 #[pymethods]
@@ -46,35 +47,23 @@ impl MzdbReader {
 
     #[new]
     fn new(path: String) -> Result<Self> {
-        let db = Connection::open(path)?;
-        let entity_cache = create_entity_cache(&db)?;
+        let reader = SharedReader::open(&path).location(here!())?;
 
-       Ok(MzdbReader {
-            is_closed: false,
-            _dbh_address: {
-                let mem_address =  unsafe {
-                    let sqlite3_handle = _connection_to_dbh(db)?;
-                    std::mem::transmute::<*mut rusqlite::ffi::sqlite3, i64>(sqlite3_handle)
-                };
-                mem_address
-            },
-            _entity_cache: entity_cache
-        })
+        Ok(MzdbReader { _reader: reader })
     }
 
-    unsafe fn close(&mut self) -> Result<()> {
-        let dbh: *mut rusqlite::ffi::sqlite3 = std::mem::transmute::<i64, *mut rusqlite::ffi::sqlite3>(self._dbh_address);
-
-        let rc = rusqlite::ffi::sqlite3_close(dbh);
+    #[getter]
+    fn is_closed(&self) -> bool {
+        self._reader.is_closed()
+    }
 
-        if rc == rusqlite::ffi::SQLITE_OK {
-            self.is_closed = true;
-            Ok(())
-        } else {
-            let msg = _sqlite_errmsg_from_handle(dbh).unwrap_or(format!("unkown error (code={}", rc));
+    fn close(&mut self) -> Result<()> {
+        self._reader.try_close()
+    }
 
-            Err(anyhow!("can't close connection because {}",msg))
-        }
+    /// Read the memoized metadata values (e.g. last time) again on next access, for files being written concurrently
+    fn refresh(&self) -> Result<()> {
+        self._reader.refresh()
     }
 
     //----------------------------------------------------------------------//
@@ -107,28 +96,22 @@ impl MzdbReader {
     }
 
     fn get_last_cycle_number(&self) -> Result<i64>{
-        let db = self._connection().location(here!())?;
-
         _result_option_to_result(
-            queries::get_last_cycle_number(&db),
+            self._reader.get_last_cycle_number(),
             || "unexpected error: no spectrum.cycle found".to_string()
         )
     }
 
     fn get_last_time(&self)-> Result<f32>{
-        let db = self._connection().location(here!())?;
-
         _result_option_to_result(
-            queries::get_last_time(&db),
+            self._reader.get_last_time(),
             || "unexpected error: no spectrum.time found".to_string()
         )
     }
 
     fn get_max_ms_level(&self) -> Result<i64> {
-        let db = self._connection().location(here!())?;
-
         _result_option_to_result(
-            queries::get_max_ms_level(&db),
+            self._reader.get_max_ms_level(),
             || "unexpected error: no spectrum.ms_level found".to_string()
         )
     }
@@ -174,20 +157,25 @@ impl MzdbReader {
 
     fn get_bounding_box_min_mz(&self, bb_r_tree_id: i64) -> Result<f32> {
         let db = self._connection().location(here!())?;
+        let bb_entry = _get_bounding_box_entry(&db, bb_r_tree_id).location(here!())?;
 
-        _result_option_to_result(
-            queries::get_bounding_box_min_mz(&db, bb_r_tree_id),
-            || "unexpected error: no bb_r_tree_id found".to_string(),
-        )
+        Ok(bb_entry.region().min_mz as f32)
     }
 
     fn get_bounding_box_min_time(&self, bb_r_tree_id: i64) -> Result<f64> {
         let db = self._connection().location(here!())?;
+        let bb_entry = _get_bounding_box_entry(&db, bb_r_tree_id).location(here!())?;
 
-        _result_option_to_result(
-            queries::get_bounding_box_min_time(&db, bb_r_tree_id),
-            || "unexpected error: no bb_r_tree_id found".to_string(),
-        )
+        Ok(bb_entry.region().min_time)
+    }
+
+    /// Returns the bounds of the bounding box as (min_mz, max_mz, min_time, max_time)
+    fn get_bounding_box_bounds(&self, bb_r_tree_id: i64) -> Result<(f64, f64, f64, f64)> {
+        let db = self._connection().location(here!())?;
+        let bb_entry = _get_bounding_box_entry(&db, bb_r_tree_id).location(here!())?;
+        let region = bb_entry.region();
+
+        Ok((region.min_mz, region.max_mz, region.min_time, region.max_time))
     }
 
     fn get_run_slice_id(&self, bb_id: i64) -> Result<i64> {
@@ -236,10 +224,137 @@ impl MzdbReader {
     }
 
 
+    fn get_mzdb_metadata(&self) -> Result<MzdbFileMetadata> {
+        let db = self._connection().location(here!())?;
+
+        let mzdb_metadata = _result_option_to_result(
+            metadata::get_mzdb_metadata(&db),
+            || "unexpected error: no mzdb record found".to_string()
+        ).location(here!())?;
+
+        Ok(MzdbFileMetadata::new(&mzdb_metadata))
+    }
+
+    fn list_cvs(&self) -> Result<Vec<MzdbCv>> {
+        let db = self._connection().location(here!())?;
+        let cvs = metadata::list_cvs(&db).location(here!())?;
+
+        Ok(cvs.iter().map(MzdbCv::new).collect())
+    }
+
+    fn list_cv_terms(&self) -> Result<Vec<MzdbCvTerm>> {
+        let db = self._connection().location(here!())?;
+        let cv_terms = metadata::list_cv_terms(&db).location(here!())?;
+
+        Ok(cv_terms.iter().map(MzdbCvTerm::new).collect())
+    }
+
+    fn list_cv_units(&self) -> Result<Vec<MzdbCvUnit>> {
+        let db = self._connection().location(here!())?;
+        let cv_units = metadata::list_cv_units(&db).location(here!())?;
+
+        Ok(cv_units.iter().map(MzdbCvUnit::new).collect())
+    }
+
+    fn list_user_terms(&self) -> Result<Vec<MzdbUserTerm>> {
+        let db = self._connection().location(here!())?;
+        let user_terms = metadata::list_user_terms(&db).location(here!())?;
+
+        Ok(user_terms.iter().map(MzdbUserTerm::new).collect())
+    }
+
+    fn list_shared_param_trees(&self) -> Result<Vec<MzdbSharedParamTree>> {
+        let db = self._connection().location(here!())?;
+        let shared_param_trees = metadata::list_shared_param_trees(&db).location(here!())?;
+
+        Ok(shared_param_trees.iter().map(MzdbSharedParamTree::new).collect())
+    }
+
+    fn list_samples(&self) -> Result<Vec<MzdbSample>> {
+        let db = self._connection().location(here!())?;
+        let samples = metadata::list_samples(&db).location(here!())?;
+
+        Ok(samples.iter().map(MzdbSample::new).collect())
+    }
+
+    fn list_software(&self) -> Result<Vec<MzdbSoftware>> {
+        let db = self._connection().location(here!())?;
+        let software_list = metadata::list_software(&db).location(here!())?;
+
+        Ok(software_list.iter().map(MzdbSoftware::new).collect())
+    }
+
+    fn list_source_files(&self) -> Result<Vec<MzdbSourceFile>> {
+        let db = self._connection().location(here!())?;
+        let source_files = metadata::list_source_files(&db).location(here!())?;
+
+        Ok(source_files.iter().map(MzdbSourceFile::new).collect())
+    }
+
+    fn get_file_provenance(&self) -> Result<MzdbFileProvenance> {
+        let db = self._connection().location(here!())?;
+        let file_provenance = metadata::get_file_provenance(&db).location(here!())?;
+
+        Ok(MzdbFileProvenance::new(&file_provenance))
+    }
+
+    fn list_instrument_configurations(&self) -> Result<Vec<MzdbInstrumentConfiguration>> {
+        let db = self._connection().location(here!())?;
+        let instrument_configs = metadata::list_instrument_configurations(&db).location(here!())?;
+
+        Ok(instrument_configs.iter().map(MzdbInstrumentConfiguration::new).collect())
+    }
+
+    fn list_data_processings(&self) -> Result<Vec<MzdbDataProcessing>> {
+        let db = self._connection().location(here!())?;
+        let data_processings = metadata::list_data_processings(&db).location(here!())?;
+
+        Ok(data_processings.iter().map(MzdbDataProcessing::new).collect())
+    }
+
+    fn list_processing_methods(&self) -> Result<Vec<MzdbProcessingMethod>> {
+        let db = self._connection().location(here!())?;
+        let processing_methods = metadata::list_processing_methods(&db).location(here!())?;
+
+        Ok(processing_methods.iter().map(MzdbProcessingMethod::new).collect())
+    }
+
+    fn list_scan_settings(&self) -> Result<Vec<MzdbScanSettings>> {
+        let db = self._connection().location(here!())?;
+        let scan_settings = metadata::list_scan_settings(&db).location(here!())?;
+
+        Ok(scan_settings.iter().map(MzdbScanSettings::new).collect())
+    }
+
+    fn list_runs(&self) -> Result<Vec<MzdbRun>> {
+        let db = self._connection().location(here!())?;
+        let runs = metadata::list_runs(&db).location(here!())?;
+
+        Ok(runs.iter().map(MzdbRun::new).collect())
+    }
+
+    fn get_instrument_components(&self, instrument_config_id: i64) -> Result<Vec<MzdbInstrumentComponent>> {
+        let db = self._connection().location(here!())?;
+
+        let component_list = _result_option_to_result(
+            metadata::get_instrument_components(&db, instrument_config_id),
+            || format!("unexpected error: no instrument_configuration found with ID={}", instrument_config_id)
+        ).location(here!())?;
+
+        Ok(component_list.components.iter().map(MzdbInstrumentComponent::new).collect())
+    }
+
+    fn get_instrument_summary(&self) -> Result<MzdbInstrumentSummary> {
+        let db = self._connection().location(here!())?;
+        let instrument_summary = metadata::get_instrument_summary(&db).location(here!())?;
+
+        Ok(MzdbInstrumentSummary::new(&instrument_summary))
+    }
+
     fn get_spectrum(&self, spectrum_id: i64)-> Result<MzdbSpectrum> {
         let db = self._connection().location(here!())?;
 
-        let spectrum = queries::get_spectrum(&db, spectrum_id, &self._entity_cache).location(here!())?;
+        let spectrum = queries::get_spectrum(&db, spectrum_id, self._reader.entity_cache()).location(here!())?;
         let mzdb_spectrum = MzdbSpectrum {
             header: MzdbSpectrumHeader::new(&spectrum.header),
             data: MzdbSpectrumData::new(&spectrum.data),
@@ -253,7 +368,7 @@ impl MzdbReader {
         let db = self._connection().location(here!())?;
 
         //let entity_cache = create_entity_cache(&db).location(here!())?;
-        let spectrum = queries::get_spectrum(&db, spectrum_id, &self._entity_cache).location(here!())?;
+        let spectrum = queries::get_spectrum(&db, spectrum_id, self._reader.entity_cache()).location(here!())?;
 
         Ok(MzdbSpectrumData::new(&spectrum.data))
     }
@@ -262,7 +377,7 @@ impl MzdbReader {
         let db = self._connection().location(here!())?;
 
         let mut count = 0;
-        for_each_spectrum(&db, &self._entity_cache, ms_level, |s: &Spectrum| {
+        for_each_spectrum(&db, self._reader.entity_cache(), ms_level, |s: &Spectrum| {
 
             // WARNNING: this only works for more than one single parameter
             let args = (MzdbSpectrum{
@@ -285,7 +400,7 @@ impl MzdbReader {
         let db = self._connection().location(here!())?;
 
         let mut count = 0;
-        for_each_spectrum(&db, &self._entity_cache, ms_level, |s: &Spectrum| {
+        for_each_spectrum(&db, self._reader.entity_cache(), ms_level, |s: &Spectrum| {
             // WARNNING: this only works for more than one single parameter
             let args = (MzdbSpectrumData::new(&s.data), count);
             on_each_spectrum_data.call(py, args, None)?;
@@ -299,6 +414,13 @@ impl MzdbReader {
     }
 }
 
+fn _get_bounding_box_entry(db: &Connection, bb_r_tree_id: i64) -> anyhow::Result<RtreeEntry> {
+    _result_option_to_result(
+        queries::get_bounding_box_bounds(db, bb_r_tree_id),
+        || "unexpected error: no bb_r_tree_id found".to_string(),
+    )
+}
+
 fn _result_option_to_result<'a, V, F>(wrapped_value: anyhow::Result<Option<V>>, error_msg: F) -> anyhow::Result<V>
     where F: Fn() -> String, V: std::fmt::Debug {
 
@@ -315,21 +437,15 @@ fn _result_option_to_result<'a, V, F>(wrapped_value: anyhow::Result<Option<V>>,
 
 impl MzdbReader {
 
-    fn _connection(&self) -> Result<Connection> {
-        if self.is_closed {
-            bail!("database is closed");
-        }
-
-        let db = unsafe { _cast_connection(self._dbh_address).location(here!())? };
-
-        Ok(db)
+    fn _connection(&self) -> Result<SharedConnection<'_>> {
+        self._reader.try_connection()
     }
 
     fn _get_table_records_count(&self, table_name: &str) -> Result<i64> {
         let db = self._connection().location(here!())?;
 
         _result_option_to_result(
-            queries::get_table_records_count(&db, table_name),
+            queries::get_table_records_count_with_mode(&db, table_name, CountMode::HINTED),
             || format!("unexpected error: no record found for table {}", table_name)
         )
     }
@@ -374,7 +490,7 @@ pub struct MzdbSpectrumHeader {
     #[pyo3(get)]
     pub peaks_count: i64,
     #[pyo3(get)]
-    pub param_tree_str: String,
+    pub param_tree_str: Option<String>,
     #[pyo3(get)]
     pub scan_list_str: Option<String>,
     #[pyo3(get)]
@@ -429,11 +545,11 @@ impl MzdbSpectrumHeader {
         MzdbSpectrumHeader {
             id: spectrum_header.id,
             initial_id: spectrum_header.initial_id,
-            title: spectrum_header.title.clone(),
+            title: spectrum_header.title.to_string(),
             cycle: spectrum_header.cycle,
             time: spectrum_header.time,
             ms_level: spectrum_header.ms_level,
-            activation_type: spectrum_header.activation_type.clone(),
+            activation_type: spectrum_header.activation_type.as_deref().map(str::to_string),
             tic: spectrum_header.tic,
             base_peak_mz: spectrum_header.base_peak_mz,
             base_peak_intensity: spectrum_header.base_peak_intensity,
@@ -450,7 +566,7 @@ impl MzdbSpectrumHeader {
             run_id: spectrum_header.run_id,
             data_processing_id: spectrum_header.data_processing_id,
             data_encoding_id: spectrum_header.data_encoding_id,
-            bb_first_spectrum_id: spectrum_header.data_encoding_id,
+            bb_first_spectrum_id: spectrum_header.bb_first_spectrum_id,
         }
     }
 }
@@ -458,27 +574,36 @@ impl MzdbSpectrumHeader {
 #[derive(Clone, Debug, PartialEq)]
 #[pyclass]
 pub struct MzdbSpectrumData {
-    #[pyo3(get)]
-    pub mz_list: Vec<f64>,
-    #[pyo3(get)]
-    pub intensity_list: Vec<f32>,
+    pub mz_list: Arc<[f64]>, // shared with the SpectrumData, only copied when accessed from Python
+    pub intensity_list: Arc<[f32]>,
 }
 
 impl MzdbSpectrumData {
     fn new(spectrum_data: &SpectrumData) -> Self {
         MzdbSpectrumData {
-            mz_list: spectrum_data.mz_array.clone(),
-            intensity_list: spectrum_data.intensity_array.clone(),
+            mz_list: Arc::clone(&spectrum_data.mz_array),
+            intensity_list: Arc::clone(&spectrum_data.intensity_array),
         }
     }
 }
 
+#[pymethods]
+impl MzdbSpectrumData {
+    #[getter]
+    fn mz_list(&self) -> Vec<f64> {
+        self.mz_list.to_vec()
+    }
+
+    #[getter]
+    fn intensity_list(&self) -> Vec<f32> {
+        self.intensity_list.to_vec()
+    }
+}
+
 // TODO: delete me
 #[pyfunction]
 fn get_mzdb_version(path: String) -> Result<String> {
-    let db = unsafe {
-        Connection::from_handle(_connection_to_dbh( Connection::open(path)?).location(here!())?).location(here!())?
-    };
+    let db = Connection::open(path).location(here!())?;
 
     queries::get_mzdb_version(&db).map(|v_opt| v_opt.unwrap_or("".to_string()))
 }
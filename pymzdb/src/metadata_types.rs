@@ -0,0 +1,471 @@
+use pyo3::prelude::*;
+
+use mzdb::model::*;
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbFileMetadata {
+    #[pyo3(get)]
+    pub version: String,
+    #[pyo3(get)]
+    pub creation_timestamp: String,
+    #[pyo3(get)]
+    pub file_content: String,
+    #[pyo3(get)]
+    pub contacts: String,
+    #[pyo3(get)]
+    pub param_tree: String,
+}
+
+impl MzdbFileMetadata {
+    pub(crate) fn new(mzdb_metadata: &MzDbMetadata) -> Self {
+        MzdbFileMetadata {
+            version: mzdb_metadata.version.clone(),
+            creation_timestamp: mzdb_metadata.creation_timestamp.clone(),
+            file_content: mzdb_metadata.file_content.clone(),
+            contacts: mzdb_metadata.contacts.clone(),
+            param_tree: mzdb_metadata.param_tree.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbCv {
+    #[pyo3(get)]
+    pub id: String,
+    #[pyo3(get)]
+    pub full_name: String,
+    #[pyo3(get)]
+    pub version: Option<String>,
+    #[pyo3(get)]
+    pub uri: String,
+}
+
+impl MzdbCv {
+    pub(crate) fn new(cv: &Cv) -> Self {
+        MzdbCv {
+            id: cv.id.clone(),
+            full_name: cv.full_name.clone(),
+            version: cv.version.clone(),
+            uri: cv.uri.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbCvTerm {
+    #[pyo3(get)]
+    pub accession: String,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub unit_accession: Option<String>,
+    #[pyo3(get)]
+    pub cv_id: String,
+}
+
+impl MzdbCvTerm {
+    pub(crate) fn new(cv_term: &CvTerm) -> Self {
+        MzdbCvTerm {
+            accession: cv_term.accession.clone(),
+            name: cv_term.name.clone(),
+            unit_accession: cv_term.unit_accession.clone(),
+            cv_id: cv_term.cv_id.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbCvUnit {
+    #[pyo3(get)]
+    pub accession: String,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub cv_id: String,
+}
+
+impl MzdbCvUnit {
+    pub(crate) fn new(cv_unit: &CvUnit) -> Self {
+        MzdbCvUnit {
+            accession: cv_unit.accession.clone(),
+            name: cv_unit.name.clone(),
+            cv_id: cv_unit.cv_id.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbUserTerm {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub r#type: String,
+    #[pyo3(get)]
+    pub unit_accession: Option<String>,
+}
+
+impl MzdbUserTerm {
+    pub(crate) fn new(user_term: &UserTerm) -> Self {
+        MzdbUserTerm {
+            id: user_term.id,
+            name: user_term.name.clone(),
+            r#type: user_term.r#type.clone(),
+            unit_accession: user_term.unit_accession.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbSharedParamTree {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub data: String,
+    #[pyo3(get)]
+    pub schema_name: String,
+}
+
+impl MzdbSharedParamTree {
+    pub(crate) fn new(shared_param_tree: &SharedParamTree) -> Self {
+        MzdbSharedParamTree {
+            id: shared_param_tree.id,
+            data: shared_param_tree.data.clone(),
+            schema_name: shared_param_tree.schema_name.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbSample {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub param_tree: Option<String>,
+    #[pyo3(get)]
+    pub shared_param_tree_id: Option<i64>,
+}
+
+impl MzdbSample {
+    pub(crate) fn new(sample: &Sample) -> Self {
+        MzdbSample {
+            id: sample.id,
+            name: sample.name.clone(),
+            param_tree: sample.param_tree.clone(),
+            shared_param_tree_id: sample.shared_param_tree_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbSoftware {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub version: String,
+    #[pyo3(get)]
+    pub param_tree: String,
+    #[pyo3(get)]
+    pub shared_param_tree_id: Option<i64>,
+}
+
+impl MzdbSoftware {
+    pub(crate) fn new(software: &Software) -> Self {
+        MzdbSoftware {
+            id: software.id,
+            name: software.name.clone(),
+            version: software.version.clone(),
+            param_tree: software.param_tree.clone(),
+            shared_param_tree_id: software.shared_param_tree_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbSourceFile {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub location: String,
+    #[pyo3(get)]
+    pub param_tree: String,
+    #[pyo3(get)]
+    pub shared_param_tree_id: Option<i64>,
+}
+
+impl MzdbSourceFile {
+    pub(crate) fn new(source_file: &SourceFile) -> Self {
+        MzdbSourceFile {
+            id: source_file.id,
+            name: source_file.name.clone(),
+            location: source_file.location.clone(),
+            param_tree: source_file.param_tree.clone(),
+            shared_param_tree_id: source_file.shared_param_tree_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbInstrumentConfiguration {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub param_tree: Option<String>,
+    #[pyo3(get)]
+    pub component_list: String,
+    #[pyo3(get)]
+    pub shared_param_tree_id: Option<i64>,
+    #[pyo3(get)]
+    pub software_id: i64,
+}
+
+impl MzdbInstrumentConfiguration {
+    pub(crate) fn new(instrument_configuration: &InstrumentConfiguration) -> Self {
+        MzdbInstrumentConfiguration {
+            id: instrument_configuration.id,
+            name: instrument_configuration.name.clone(),
+            param_tree: instrument_configuration.param_tree.clone(),
+            component_list: instrument_configuration.component_list.clone(),
+            shared_param_tree_id: instrument_configuration.shared_param_tree_id,
+            software_id: instrument_configuration.software_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbDataProcessing {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub name: String,
+}
+
+impl MzdbDataProcessing {
+    pub(crate) fn new(data_processing: &DataProcessing) -> Self {
+        MzdbDataProcessing {
+            id: data_processing.id,
+            name: data_processing.name.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbProcessingMethod {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub number: i64,
+    #[pyo3(get)]
+    pub param_tree: String,
+    #[pyo3(get)]
+    pub shared_param_tree_id: Option<i64>,
+    #[pyo3(get)]
+    pub data_processing_id: i64,
+    #[pyo3(get)]
+    pub software_id: i64,
+}
+
+impl MzdbProcessingMethod {
+    pub(crate) fn new(processing_method: &ProcessingMethod) -> Self {
+        MzdbProcessingMethod {
+            id: processing_method.id,
+            number: processing_method.number,
+            param_tree: processing_method.param_tree.clone(),
+            shared_param_tree_id: processing_method.shared_param_tree_id,
+            data_processing_id: processing_method.data_processing_id,
+            software_id: processing_method.software_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbScanSettings {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub param_tree: Option<String>,
+    #[pyo3(get)]
+    pub shared_param_tree_id: Option<i64>,
+}
+
+impl MzdbScanSettings {
+    pub(crate) fn new(scan_settings: &ScanSettings) -> Self {
+        MzdbScanSettings {
+            id: scan_settings.id,
+            param_tree: scan_settings.param_tree.clone(),
+            shared_param_tree_id: scan_settings.shared_param_tree_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbRun {
+    #[pyo3(get)]
+    pub id: i64,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub start_timestamp: Option<String>,
+    #[pyo3(get)]
+    pub param_tree: Option<String>,
+    #[pyo3(get)]
+    pub shared_param_tree_id: Option<i64>,
+    #[pyo3(get)]
+    pub sample_id: i64,
+    #[pyo3(get)]
+    pub default_instrument_config_id: i64,
+    #[pyo3(get)]
+    pub default_source_file_id: Option<i64>,
+    #[pyo3(get)]
+    pub default_scan_processing_id: i64,
+    #[pyo3(get)]
+    pub default_chrom_processing_id: i64,
+}
+
+impl MzdbRun {
+    pub(crate) fn new(run: &Run) -> Self {
+        MzdbRun {
+            id: run.id,
+            name: run.name.clone(),
+            start_timestamp: run.start_timestamp.clone(),
+            param_tree: run.param_tree.clone(),
+            shared_param_tree_id: run.shared_param_tree_id,
+            sample_id: run.sample_id,
+            default_instrument_config_id: run.default_instrument_config_id,
+            default_source_file_id: run.default_source_file_id,
+            default_scan_processing_id: run.default_scan_processing_id,
+            default_chrom_processing_id: run.default_chrom_processing_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbCvParam {
+    #[pyo3(get)]
+    pub cv_ref: String,
+    #[pyo3(get)]
+    pub accession: String,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub value: String,
+    #[pyo3(get)]
+    pub unit_cv_ref: String,
+    #[pyo3(get)]
+    pub unit_accession: String,
+    #[pyo3(get)]
+    pub unit_name: String,
+}
+
+impl MzdbCvParam {
+    pub(crate) fn new(cv_param: &CvParam) -> Self {
+        MzdbCvParam {
+            cv_ref: cv_param.cv_ref.clone(),
+            accession: cv_param.accession.clone(),
+            name: cv_param.name.clone(),
+            value: cv_param.value.clone(),
+            unit_cv_ref: cv_param.unit_cv_ref.clone(),
+            unit_accession: cv_param.unit_accession.clone(),
+            unit_name: cv_param.unit_name.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbInstrumentComponent {
+    #[pyo3(get)]
+    pub component_type: String,
+    #[pyo3(get)]
+    pub order: i32,
+    #[pyo3(get)]
+    pub cv_params: Vec<MzdbCvParam>,
+}
+
+impl MzdbInstrumentComponent {
+    pub(crate) fn new(component: &Component) -> Self {
+        let component_type = match component.component_type {
+            ComponentType::SOURCE => "source",
+            ComponentType::ANALYZER => "analyzer",
+            ComponentType::DETECTOR => "detector",
+        };
+
+        MzdbInstrumentComponent {
+            component_type: component_type.to_string(),
+            order: component.order,
+            cv_params: component.cv_params.iter().map(MzdbCvParam::new).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbInstrumentSummary {
+    #[pyo3(get)]
+    pub source_names: Vec<String>,
+    #[pyo3(get)]
+    pub analyzer_names: Vec<String>,
+    #[pyo3(get)]
+    pub detector_names: Vec<String>,
+}
+
+impl MzdbInstrumentSummary {
+    pub(crate) fn new(instrument_summary: &InstrumentSummary) -> Self {
+        MzdbInstrumentSummary {
+            source_names: instrument_summary.source_names.clone(),
+            analyzer_names: instrument_summary.analyzer_names.clone(),
+            detector_names: instrument_summary.detector_names.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[pyclass]
+pub struct MzdbFileProvenance {
+    #[pyo3(get)]
+    pub creation_timestamp: Option<String>,
+    #[pyo3(get)]
+    pub creation_time: Option<i64>,
+    #[pyo3(get)]
+    pub writer_software: Option<MzdbSoftware>,
+    #[pyo3(get)]
+    pub source_files: Vec<MzdbSourceFile>,
+    #[pyo3(get)]
+    pub origin_file_format: Option<String>,
+}
+
+impl MzdbFileProvenance {
+    pub(crate) fn new(file_provenance: &FileProvenance) -> Self {
+        MzdbFileProvenance {
+            creation_timestamp: file_provenance.creation_timestamp.clone(),
+            creation_time: file_provenance.creation_time,
+            writer_software: file_provenance.writer_software.as_ref().map(MzdbSoftware::new),
+            source_files: file_provenance.source_files.iter().map(MzdbSourceFile::new).collect(),
+            origin_file_format: file_provenance.origin_file_format.clone(),
+        }
+    }
+}
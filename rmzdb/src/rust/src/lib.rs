@@ -3,21 +3,20 @@ dead_code
 )]
 
 mod reader;
-mod sqlite_helper;
 
 use extendr_api::prelude::*;
 use rusqlite::Connection;
+use std::sync::Arc;
 
 use anyhow;
 use mzdb::anyhow_ext::*;
+use mzdb::ffi_support::SharedReader;
 use mzdb::model::*;
 use mzdb::mzdb::create_entity_cache;
 use mzdb::queries;
 
 //use crate::reader::*;
 
-use crate::sqlite_helper::*;
-
 /// Return string `"Hello world!"` to R.
 /// @export
 #[extendr]
@@ -39,38 +38,25 @@ fn get_mzdb_version(path: String) -> String { //&'static str
 
 fn _get_mzdb_version(path: String) -> anyhow::Result<String> {
 
-    let db = unsafe {
-        Connection::from_handle(_connection_to_dbh( Connection::open(path)?)?)?
-    };
+    let db = Connection::open(path)?;
 
     Ok(queries::get_mzdb_version(&db).map(|v_opt| v_opt.unwrap_or("".to_string()))?)
 }
 
 
 fn _create_mzdb_reader(path: String) -> anyhow::Result<MzdbReader> {
-    let db = Connection::open(path)?;
-    let entity_cache = create_entity_cache(&db)?;
+    let reader = SharedReader::open(&path)?;
 
     Ok(MzdbReader {
         //name: "".to_string(),
-        is_closed: false,
-        _dbh_address: {
-            let mem_address =  unsafe {
-                let sqlite3_handle = _connection_to_dbh(db)?;
-                std::mem::transmute::<*mut rusqlite::ffi::sqlite3, i64>(sqlite3_handle)
-            };
-            mem_address
-        },
-        _entity_cache: entity_cache
+        _reader: reader
     })
 }
 
 #[derive(Clone, Debug)]
 pub struct MzdbReader {
     //pub name: String,
-    is_closed: bool,
-    _dbh_address: i64, // memory address of *mut rusqlite::ffi::sqlite3
-    _entity_cache: EntityCache
+    _reader: SharedReader
 }
 
 #[extendr]
@@ -82,19 +68,17 @@ impl MzdbReader {
         })
     }
 
-    unsafe fn close(&mut self) -> Result<()> {
-        let dbh: *mut rusqlite::ffi::sqlite3 = std::mem::transmute::<i64, *mut rusqlite::ffi::sqlite3>(self._dbh_address);
-
-        let rc = rusqlite::ffi::sqlite3_close(dbh);
-
-        if rc == rusqlite::ffi::SQLITE_OK {
-            self.is_closed = true;
-            Ok(())
-        } else {
-            let msg = _sqlite_errmsg_from_handle(dbh).unwrap_or(format!("unkown error (code={}", rc));
+    fn close(&mut self) -> Result<()> {
+        self._reader.try_close().map_err(|e| {
+            Error::from(e.to_string())
+        })
+    }
 
-            Err(Error::from(msg))
-        }
+    /// Read the memoized metadata values (e.g. last time) again on next access, for files being written concurrently
+    fn refresh(&self) -> Result<()> {
+        self._reader.refresh().map_err(|e| {
+            Error::from(e.to_string())
+        })
     }
 
     /*fn set_name(&mut self, name: &str) {
@@ -106,7 +90,7 @@ impl MzdbReader {
     }*/
 
     fn is_closed(&self) -> bool {
-        self.is_closed
+        self._reader.is_closed()
     }
 
     fn get_mzdb_version(&self) -> String {
@@ -173,6 +157,10 @@ impl MzdbReader {
         _unwrap_result_safely(self._get_bounding_box_min_time(bb_r_tree_id))
     }
 
+    fn get_bounding_box_bounds(&self, bb_r_tree_id: i64) -> Vec<f64> {
+        _unwrap_result_safely(self._get_bounding_box_bounds(bb_r_tree_id))
+    }
+
     fn get_run_slice_id(&self, bb_id: i64) -> i64 {
         _unwrap_result_safely(self._get_run_slice_id(bb_id))
     }
@@ -284,7 +272,7 @@ pub struct MzdbSpectrumHeader {
     pub precursor_mz: Option<f64>,
     pub precursor_charge: Option<i32>,
     pub peaks_count: i64,
-    pub param_tree_str: String,
+    pub param_tree_str: Option<String>,
     pub scan_list_str: Option<String>,
     pub precursor_list_str: Option<String>,
     pub product_list_str: Option<String>,
@@ -302,11 +290,11 @@ impl MzdbSpectrumHeader {
         MzdbSpectrumHeader {
             id: spectrum_header.id,
             initial_id: spectrum_header.initial_id,
-            title: spectrum_header.title.clone(),
+            title: spectrum_header.title.to_string(),
             cycle: spectrum_header.cycle,
             time: spectrum_header.time,
             ms_level: spectrum_header.ms_level,
-            activation_type: spectrum_header.activation_type.clone(),
+            activation_type: spectrum_header.activation_type.as_deref().map(str::to_string),
             tic: spectrum_header.tic,
             base_peak_mz: spectrum_header.base_peak_mz,
             base_peak_intensity: spectrum_header.base_peak_intensity,
@@ -323,7 +311,7 @@ impl MzdbSpectrumHeader {
             run_id: spectrum_header.run_id,
             data_processing_id: spectrum_header.data_processing_id,
             data_encoding_id: spectrum_header.data_encoding_id,
-            bb_first_spectrum_id: spectrum_header.data_encoding_id,
+            bb_first_spectrum_id: spectrum_header.bb_first_spectrum_id,
         }
     }
 }
@@ -343,7 +331,7 @@ impl MzdbSpectrumHeader {
     fn precursor_mz(&self) -> Option<f64> { self.precursor_mz }
     fn precursor_charge(&self) -> Option<i32> { self.precursor_charge }
     fn peaks_count(&self) -> i64 { self.peaks_count }
-    fn param_tree_str(&self) -> String { self.param_tree_str.clone() }
+    fn param_tree_str(&self) -> String { self.param_tree_str.as_ref().unwrap_or(&"".to_string()).clone() }
     fn scan_list_str(&self) -> String { self.scan_list_str.as_ref().unwrap_or(&"".to_string()).clone() }
     fn precursor_list_str(&self) -> String { self.precursor_list_str.as_ref().unwrap_or(&"".to_string()).clone() }
     fn product_list_str(&self) -> String { self.product_list_str.as_ref().unwrap_or(&"".to_string()).clone() }
@@ -358,15 +346,15 @@ impl MzdbSpectrumHeader {
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct MzdbSpectrumData {
-    pub mz_list: Vec<f64>,
-    pub intensity_list: Vec<f32>,
+    pub mz_list: Arc<[f64]>, // shared with the SpectrumData, only copied when accessed from R
+    pub intensity_list: Arc<[f32]>,
 }
 
 impl MzdbSpectrumData {
     fn new(spectrum_data: &SpectrumData) -> Self {
         MzdbSpectrumData {
-            mz_list: spectrum_data.mz_array.clone(),
-            intensity_list: spectrum_data.intensity_array.clone(),
+            mz_list: Arc::clone(&spectrum_data.mz_array),
+            intensity_list: Arc::clone(&spectrum_data.intensity_array),
         }
     }
 }
@@ -375,17 +363,17 @@ impl MzdbSpectrumData {
 impl MzdbSpectrumData {
 
     fn mz_list(&self) -> Vec<f64> {
-        self.mz_list.clone()
+        self.mz_list.to_vec()
     }
 
     fn intensity_list(&self) -> Vec<f32> {
-        self.intensity_list.clone()
+        self.intensity_list.to_vec()
     }
 
     fn as_matrix(&self) -> RMatrix<f64> {
         let n_rows = self.mz_list.len();
         let matrix = RMatrix::new_matrix(n_rows, 2, |r, c| [
-            self.mz_list.as_slice(),
+            &self.mz_list[..],
             self.intensity_list.iter().map(|&intensity| intensity as f64).collect::<Vec<f64>>().as_slice()
         ][c][r]);
 
@@ -1,14 +1,14 @@
-use rusqlite::Connection;
-
 use anyhow::*;
 
 use mzdb::anyhow_ext::*;
+use mzdb::ffi_support::SharedConnection;
+use mzdb::rtree::RtreeEntry;
 use mzdb::model::*;
 use mzdb::{iterator, queries};
 use mzdb::queries::{BOUNDING_BOX_TABLE_NAME, DATA_ENCODING_TABLE_NAME, SPECTRUM_TABLE_NAME};
+use rusqlite::Connection;
 
 use crate::{MzdbReader, MzdbSpectrum, MzdbSpectrumHeader, MzdbSpectrumData};
-use crate::sqlite_helper::*;
 
 #[macro_export]
 macro_rules! here {
@@ -23,21 +23,15 @@ macro_rules! here {
 
 impl MzdbReader {
 
-    fn _connection(&self) -> Result<Connection> {
-        if self.is_closed {
-            bail!("database is closed");
-        }
-
-        let db = unsafe { _cast_connection(self._dbh_address).location(here!())? };
-
-        Ok(db)
+    fn _connection(&self) -> Result<SharedConnection<'_>> {
+        self._reader.try_connection()
     }
 
     fn _get_table_records_count(&self, table_name: &str) -> Result<i64> {
         let db = self._connection().location(here!())?;
 
         _result_option_to_result(
-            queries::get_table_records_count(&db, table_name),
+            queries::get_table_records_count_with_mode(&db, table_name, CountMode::HINTED),
             || format!("unexpected error: no record found for table {}", table_name)
         )
     }
@@ -73,29 +67,23 @@ impl MzdbReader {
 
 
     pub (crate)fn _get_last_cycle_number(&self) -> Result<i64>{
-        let db = self._connection().location(here!())?;
-
         _result_option_to_result(
-            queries::get_last_cycle_number(&db),
+            self._reader.get_last_cycle_number(),
             || "unexpected error: no spectrum.cycle found".to_string()
         )
     }
 
     pub (crate)fn _get_last_time(&self)-> Result<f32>{
-        let db = self._connection().location(here!())?;
-
         _result_option_to_result(
-            queries::get_last_time(&db),
+            self._reader.get_last_time(),
             || "unexpected error: no spectrum.time found".to_string()
         )
     }
 
 
     pub(crate) fn _get_max_ms_level(&self) -> Result<i64> {
-        let db = self._connection().location(here!())?;
-
         _result_option_to_result(
-            queries::get_max_ms_level(&db),
+            self._reader.get_max_ms_level(),
             || "unexpected error: no spectrum.ms_level found".to_string()
         )
     }
@@ -141,20 +129,25 @@ impl MzdbReader {
 
     pub(crate) fn _get_bounding_box_min_mz(&self, bb_r_tree_id: i64) -> Result<f32> {
         let db = self._connection().location(here!())?;
+        let bb_entry = _get_bounding_box_entry(&db, bb_r_tree_id).location(here!())?;
 
-        _result_option_to_result(
-            queries::get_bounding_box_min_mz(&db, bb_r_tree_id),
-            || "unexpected error: no bb_r_tree_id found".to_string(),
-        )
+        Ok(bb_entry.region().min_mz as f32)
     }
 
     pub(crate) fn _get_bounding_box_min_time(&self, bb_r_tree_id: i64) -> Result<f64> {
         let db = self._connection().location(here!())?;
+        let bb_entry = _get_bounding_box_entry(&db, bb_r_tree_id).location(here!())?;
 
-        _result_option_to_result(
-            queries::get_bounding_box_min_time(&db, bb_r_tree_id),
-            || "unexpected error: no bb_r_tree_id found".to_string(),
-        )
+        Ok(bb_entry.region().min_time)
+    }
+
+    /// Returns the bounds of the bounding box as (min_mz, max_mz, min_time, max_time)
+    pub(crate) fn _get_bounding_box_bounds(&self, bb_r_tree_id: i64) -> Result<Vec<f64>> {
+        let db = self._connection().location(here!())?;
+        let bb_entry = _get_bounding_box_entry(&db, bb_r_tree_id).location(here!())?;
+        let region = bb_entry.region();
+
+        Ok(vec![region.min_mz, region.max_mz, region.min_time, region.max_time])
     }
 
     pub(crate) fn _get_run_slice_id(&self, bb_id: i64) -> Result<i64> {
@@ -205,7 +198,7 @@ impl MzdbReader {
     pub(crate) fn _get_spectrum(&self, spectrum_id: i64)-> Result<MzdbSpectrum> {
         let db = self._connection().location(here!())?;
 
-        let spectrum = queries::get_spectrum(&db, spectrum_id, &self._entity_cache).location(here!())?;
+        let spectrum = queries::get_spectrum(&db, spectrum_id, self._reader.entity_cache()).location(here!())?;
         let mzdb_spectrum = MzdbSpectrum {
             header: MzdbSpectrumHeader::new(&spectrum.header),
             data: MzdbSpectrumData::new(&spectrum.data),
@@ -219,7 +212,7 @@ impl MzdbReader {
 
         let db = self._connection().location(here!())?;
 
-        iterator::for_each_spectrum(&db, &self._entity_cache, ms_level, |s: &Spectrum| {
+        iterator::for_each_spectrum(&db, self._reader.entity_cache(), ms_level, |s: &Spectrum| {
 
             let mzdb_spectrum = MzdbSpectrum {
                 header: MzdbSpectrumHeader::new(&s.header),
@@ -238,6 +231,13 @@ impl MzdbReader {
 
 
 
+fn _get_bounding_box_entry(db: &Connection, bb_r_tree_id: i64) -> anyhow::Result<RtreeEntry> {
+    _result_option_to_result(
+        queries::get_bounding_box_bounds(db, bb_r_tree_id),
+        || "unexpected error: no bb_r_tree_id found".to_string(),
+    )
+}
+
 fn _result_option_to_result<'a, V, F>(wrapped_value: anyhow::Result<Option<V>>, error_msg: F) -> anyhow::Result<V>
     where F: Fn() -> String, V: std::fmt::Debug {
 